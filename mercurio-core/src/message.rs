@@ -1,6 +1,8 @@
+use std::time::Instant;
+
 use bytes::Bytes;
 
-use crate::qos::QoS;
+use crate::{properties::UserProperty, qos::QoS};
 
 #[derive(Clone, Debug)]
 pub struct Message {
@@ -10,4 +12,51 @@ pub struct Message {
     pub qos: QoS,
     pub retain: bool,
     pub payload: Option<Bytes>,
+
+    /// Client identifier of the publisher, if the message was published by
+    /// a client (or a bridge) rather than the broker itself.
+    pub origin: Option<String>,
+
+    /// User properties of the PUBLISH packet, forwarded to subscribers
+    /// unaltered.
+    pub user_properties: Option<Vec<UserProperty>>,
+
+    /// Topic the receivers are expected to respond on, if the message is a
+    /// request.
+    pub response_topic: Option<String>,
+
+    /// Data the requester uses to tell which request a response is for.
+    pub correlation_data: Option<Bytes>,
+
+    /// Content type of the payload, forwarded to subscribers unaltered.
+    pub content_type: Option<String>,
+
+    /// Whether the payload is UTF-8 encoded character data, if told.
+    pub payload_format_indicator: Option<u8>,
+
+    /// Time after which the message isn't delivered anymore, if any.
+    pub expires_at: Option<Instant>,
+}
+
+impl Message {
+    /// Creates a message with a topic, payload and QoS, and nothing else, as
+    /// the broker publishes itself. The rest can be filled in with struct
+    /// update syntax.
+    pub fn new(topic: impl Into<String>, payload: impl Into<Bytes>, qos: QoS) -> Message {
+        Message {
+            packet_id: None,
+            topic: topic.into(),
+            dup: false,
+            qos,
+            retain: false,
+            payload: Some(payload.into()),
+            origin: None,
+            user_properties: None,
+            response_topic: None,
+            correlation_data: None,
+            content_type: None,
+            payload_format_indicator: None,
+            expires_at: None,
+        }
+    }
 }
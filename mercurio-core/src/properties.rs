@@ -46,6 +46,12 @@ macro_rules! def_prop {
                 Ok($t::new($(<$s>::decode(buffer)?,)*))
             }
         }
+
+        impl From<$t> for Property {
+            fn from(property: $t) -> Property {
+                Property::$t(property)
+            }
+        }
     }
 }
 
@@ -239,7 +245,7 @@ fn decode_with_id<T: Buf>(id: u32, buffer: &mut T) -> crate::Result<Property> {
         AuthenticationData::ID => dec_prop!(AuthenticationData, buffer),
         RequestProblemInformation::ID => dec_prop!(RequestProblemInformation, buffer),
         WillDelayInterval::ID => dec_prop!(WillDelayInterval, buffer),
-        RequestResponseInformation::ID => dec_prop!(RequestProblemInformation, buffer),
+        RequestResponseInformation::ID => dec_prop!(RequestResponseInformation, buffer),
         ResponseInformation::ID => dec_prop!(ResponseInformation, buffer),
         ServerReference::ID => dec_prop!(ServerReference, buffer),
         ReasonString::ID => dec_prop!(ReasonString, buffer),
@@ -266,3 +272,301 @@ impl Decoder for Property {
         decode_with_id(id, buffer)
     }
 }
+
+impl Property {
+    /// Returns the identifier the property is encoded with.
+    pub fn id(&self) -> u32 {
+        match self {
+            Property::PayloadFormatIndicator(_) => PayloadFormatIndicator::ID,
+            Property::MessageExpiryInterval(_) => MessageExpiryInterval::ID,
+            Property::ContentType(_) => ContentType::ID,
+            Property::ResponseTopic(_) => ResponseTopic::ID,
+            Property::CorrelationData(_) => CorrelationData::ID,
+            Property::SubscriptionIdentifier(_) => SubscriptionIdentifier::ID,
+            Property::SessionExpiryInterval(_) => SessionExpiryInterval::ID,
+            Property::AssignedClientIdentifier(_) => AssignedClientIdentifier::ID,
+            Property::ServerKeepAlive(_) => ServerKeepAlive::ID,
+            Property::AuthenticationMethod(_) => AuthenticationMethod::ID,
+            Property::AuthenticationData(_) => AuthenticationData::ID,
+            Property::RequestProblemInformation(_) => RequestProblemInformation::ID,
+            Property::WillDelayInterval(_) => WillDelayInterval::ID,
+            Property::RequestResponseInformation(_) => RequestResponseInformation::ID,
+            Property::ResponseInformation(_) => ResponseInformation::ID,
+            Property::ServerReference(_) => ServerReference::ID,
+            Property::ReasonString(_) => ReasonString::ID,
+            Property::ReceiveMaximum(_) => ReceiveMaximum::ID,
+            Property::TopicAliasMaximum(_) => TopicAliasMaximum::ID,
+            Property::TopicAlias(_) => TopicAlias::ID,
+            Property::MaximumQoS(_) => MaximumQoS::ID,
+            Property::RetainAvailable(_) => RetainAvailable::ID,
+            Property::UserProperty(_) => UserProperty::ID,
+            Property::MaximumPacketSize(_) => MaximumPacketSize::ID,
+            Property::WildcardSubscriptionAvailable(_) => WildcardSubscriptionAvailable::ID,
+            Property::SubscriptionIdentifierAvailable(_) => SubscriptionIdentifierAvailable::ID,
+            Property::SharedSubscriptionAvailable(_) => SharedSubscriptionAvailable::ID,
+        }
+    }
+
+    /// Fails if the property has a value it can't have, wherever it's
+    /// found.
+    fn check_value(&self) -> crate::Result<()> {
+        let valid = match self {
+            // [MQTT-1.5.4-2]
+            // A UTF-8 Encoded String MUST NOT include an encoding of the null
+            // character U+0000.
+            Property::ContentType(ContentType { value })
+            | Property::ResponseTopic(ResponseTopic { value })
+            | Property::AssignedClientIdentifier(AssignedClientIdentifier { value })
+            | Property::AuthenticationMethod(AuthenticationMethod { value })
+            | Property::ResponseInformation(ResponseInformation { value })
+            | Property::ServerReference(ServerReference { value })
+            | Property::ReasonString(ReasonString { value }) => is_valid_string(value),
+            Property::UserProperty(UserProperty { key, value }) => {
+                is_valid_string(key) && is_valid_string(value)
+            }
+            Property::CorrelationData(CorrelationData { value })
+            | Property::AuthenticationData(AuthenticationData { value }) => {
+                value.len() <= MAX_LENGTH
+            }
+            _ => true,
+        };
+
+        if !valid {
+            return Err(ReasonCode::MalformedPacket.into());
+        }
+
+        // It is a Protocol Error for the properties below to have any other
+        // value.
+        let valid = match self {
+            // [MQTT-3.1.2.11.3], [MQTT-3.2.2.3.3]
+            Property::ReceiveMaximum(v) => v.value > 0,
+            // [MQTT-3.1.2.11.4], [MQTT-3.2.2.3.6]
+            Property::MaximumPacketSize(v) => v.value > 0,
+            // [MQTT-3.1.2.11.6], [MQTT-3.1.2.11.7], [MQTT-3.2.2.3.4]
+            Property::RequestResponseInformation(RequestResponseInformation { value })
+            | Property::RequestProblemInformation(RequestProblemInformation { value })
+            | Property::MaximumQoS(MaximumQoS { value }) => *value <= 1,
+            _ => true,
+        };
+
+        if !valid {
+            return Err(ReasonCode::ProtocolError.into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Longest string or binary data a property can have, as its length is
+/// encoded on two bytes.
+const MAX_LENGTH: usize = u16::MAX as usize;
+
+fn is_valid_string(value: &str) -> bool {
+    value.len() <= MAX_LENGTH && !value.contains('\0')
+}
+
+/// Where properties are found: the packets having some, and the will of a
+/// CONNECT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PropertyContext {
+    Connect,
+    Will,
+    ConnAck,
+    Publish,
+    PubAck,
+    PubRec,
+    PubRel,
+    PubComp,
+    Subscribe,
+    SubAck,
+    Unsubscribe,
+    UnsubAck,
+    Disconnect,
+    Auth,
+}
+
+/// Where each property can be found, as in table 2-4 of the specification.
+const PROPERTY_CONTEXTS: &[(u32, &[PropertyContext])] = {
+    use PropertyContext::*;
+
+    &[
+        (PayloadFormatIndicator::ID, &[Publish, Will]),
+        (MessageExpiryInterval::ID, &[Publish, Will]),
+        (ContentType::ID, &[Publish, Will]),
+        (ResponseTopic::ID, &[Publish, Will]),
+        (CorrelationData::ID, &[Publish, Will]),
+        (SubscriptionIdentifier::ID, &[Publish, Subscribe]),
+        (SessionExpiryInterval::ID, &[Connect, ConnAck, Disconnect]),
+        (AssignedClientIdentifier::ID, &[ConnAck]),
+        (ServerKeepAlive::ID, &[ConnAck]),
+        (AuthenticationMethod::ID, &[Connect, ConnAck, Auth]),
+        (AuthenticationData::ID, &[Connect, ConnAck, Auth]),
+        (RequestProblemInformation::ID, &[Connect]),
+        (WillDelayInterval::ID, &[Will]),
+        (RequestResponseInformation::ID, &[Connect]),
+        (ResponseInformation::ID, &[ConnAck]),
+        (ServerReference::ID, &[ConnAck, Disconnect]),
+        (
+            ReasonString::ID,
+            &[
+                ConnAck, PubAck, PubRec, PubRel, PubComp, SubAck, UnsubAck, Disconnect, Auth,
+            ],
+        ),
+        (ReceiveMaximum::ID, &[Connect, ConnAck]),
+        (TopicAliasMaximum::ID, &[Connect, ConnAck]),
+        (TopicAlias::ID, &[Publish]),
+        (MaximumQoS::ID, &[ConnAck]),
+        (RetainAvailable::ID, &[ConnAck]),
+        (
+            UserProperty::ID,
+            &[
+                Connect,
+                Will,
+                ConnAck,
+                Publish,
+                PubAck,
+                PubRec,
+                PubRel,
+                PubComp,
+                Subscribe,
+                SubAck,
+                Unsubscribe,
+                UnsubAck,
+                Disconnect,
+                Auth,
+            ],
+        ),
+        (MaximumPacketSize::ID, &[Connect, ConnAck]),
+        (WildcardSubscriptionAvailable::ID, &[ConnAck]),
+        (SubscriptionIdentifierAvailable::ID, &[ConnAck]),
+        (SharedSubscriptionAvailable::ID, &[ConnAck]),
+    ]
+};
+
+impl PropertyContext {
+    /// Returns `true` if the property can be found here.
+    pub fn allows(self, id: u32) -> bool {
+        PROPERTY_CONTEXTS
+            .iter()
+            .any(|(property, contexts)| *property == id && contexts.contains(&self))
+    }
+
+    /// Returns `true` if the property can be found more than once here.
+    pub fn allows_repeated(self, id: u32) -> bool {
+        id == UserProperty::ID
+            || (id == SubscriptionIdentifier::ID && self == PropertyContext::Publish)
+    }
+}
+
+/// Checks the properties of a packet, or will, one after the other as
+/// they're decoded, or before they're encoded.
+#[derive(Debug)]
+pub struct PropertyChecker {
+    context: PropertyContext,
+
+    /// Identifiers of the properties found so far, as bits.
+    seen: u64,
+}
+
+impl PropertyChecker {
+    pub fn new(context: PropertyContext) -> PropertyChecker {
+        PropertyChecker { context, seen: 0 }
+    }
+
+    /// Fails with Malformed Packet if the property can't be found here, and
+    /// with Protocol Error if it was already found while it can only be
+    /// once. Fails as well if its value is one it can't have, such as a
+    /// string with a null character or a Receive Maximum of 0.
+    pub fn check(&mut self, property: &Property) -> crate::Result<()> {
+        let id = property.id();
+
+        if !self.context.allows(id) {
+            return Err(ReasonCode::MalformedPacket.into());
+        }
+
+        let bit = 1 << id;
+
+        if self.seen & bit != 0 && !self.context.allows_repeated(id) {
+            return Err(ReasonCode::ProtocolError.into());
+        }
+
+        self.seen |= bit;
+
+        property.check_value()
+    }
+
+    /// Checks a field of a properties struct, if set.
+    pub fn check_field<P>(&mut self, field: &Option<P>) -> crate::Result<()>
+    where
+        P: Clone + Into<Property>,
+    {
+        match field {
+            Some(property) => self.check(&property.clone().into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks a field of a properties struct holding a property found any
+    /// number of times.
+    pub fn check_fields<P>(&mut self, field: &Option<Vec<P>>) -> crate::Result<()>
+    where
+        P: Clone + Into<Property>,
+    {
+        field
+            .iter()
+            .flatten()
+            .try_for_each(|property| self.check(&property.clone().into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Property, PropertyChecker, PropertyContext, ReasonString, ReceiveMaximum,
+        SubscriptionIdentifier, UserProperty,
+    };
+    use crate::{codec::VariableByteInteger, error::Error, reason::ReasonCode};
+
+    #[test]
+    fn test_property_checker() {
+        let subscription_identifier =
+            Property::SubscriptionIdentifier(SubscriptionIdentifier::new(VariableByteInteger(1)));
+        let user_property = Property::UserProperty(UserProperty::new("a".into(), "b".into()));
+        let reason_string = Property::ReasonString(ReasonString::new("reason".into()));
+
+        let mut checker = PropertyChecker::new(PropertyContext::PubAck);
+        assert!(matches!(
+            checker.check(&subscription_identifier),
+            Err(Error::MQTTReasonCode(ReasonCode::MalformedPacket))
+        ));
+        assert!(checker.check(&user_property).is_ok());
+        assert!(checker.check(&user_property).is_ok());
+        assert!(checker.check(&reason_string).is_ok());
+        assert!(matches!(
+            checker.check(&reason_string),
+            Err(Error::MQTTReasonCode(ReasonCode::ProtocolError))
+        ));
+
+        // Only messages can have several subscription identifiers
+        let mut checker = PropertyChecker::new(PropertyContext::Publish);
+        assert!(checker.check(&subscription_identifier).is_ok());
+        assert!(checker.check(&subscription_identifier).is_ok());
+
+        let mut checker = PropertyChecker::new(PropertyContext::Subscribe);
+        assert!(checker.check(&subscription_identifier).is_ok());
+        assert!(checker.check(&subscription_identifier).is_err());
+
+        // Nor can they have any value
+        let mut checker = PropertyChecker::new(PropertyContext::Connect);
+        assert!(matches!(
+            checker.check(&Property::ReceiveMaximum(ReceiveMaximum::new(0))),
+            Err(Error::MQTTReasonCode(ReasonCode::ProtocolError))
+        ));
+
+        let mut checker = PropertyChecker::new(PropertyContext::PubAck);
+        assert!(matches!(
+            checker.check(&Property::ReasonString(ReasonString::new("\0".into()))),
+            Err(Error::MQTTReasonCode(ReasonCode::MalformedPacket))
+        ));
+    }
+}
@@ -9,10 +9,24 @@ use mercurio_core::{
 
 #[derive(Default, PartialEq, Eq, Debug)]
 pub struct AuthProperties {
-    auth_method: Option<AuthenticationMethod>,
-    auth_data: Option<AuthenticationData>,
-    reason_string: Option<ReasonString>,
-    user_property: Option<Vec<UserProperty>>,
+    pub auth_method: Option<AuthenticationMethod>,
+    pub auth_data: Option<AuthenticationData>,
+    pub reason_string: Option<ReasonString>,
+    pub user_property: Option<Vec<UserProperty>>,
+}
+
+impl AuthProperties {
+    /// Checks the properties against what [`PropertyContext::Auth`] allows.
+    pub fn validate(&self) -> crate::Result<()> {
+        let mut checker = PropertyChecker::new(PropertyContext::Auth);
+
+        checker.check_field(&self.auth_method)?;
+        checker.check_field(&self.auth_data)?;
+        checker.check_field(&self.reason_string)?;
+        checker.check_fields(&self.user_property)?;
+
+        Ok(())
+    }
 }
 
 impl Encoder for AuthProperties {
@@ -49,9 +63,13 @@ impl Decoder for AuthProperties {
         }
 
         let mut encoded_properties = buffer.take(len.0 as usize);
+        let mut checker = PropertyChecker::new(PropertyContext::Auth);
 
         while encoded_properties.has_remaining() {
-            match Property::decode(&mut encoded_properties)? {
+            let property = Property::decode(&mut encoded_properties)?;
+            checker.check(&property)?;
+
+            match property {
                 AuthenticationMethod(v) => properties.auth_method = Some(v),
                 AuthenticationData(v) => properties.auth_data = Some(v),
                 ReasonString(v) => properties.reason_string = Some(v),
@@ -73,8 +91,8 @@ impl Decoder for AuthProperties {
 
 #[derive(Eq, PartialEq, Debug)]
 pub struct AuthPacket {
-    reason: ReasonCode,
-    properties: AuthProperties,
+    pub reason: ReasonCode,
+    pub properties: AuthProperties,
 }
 
 const PACKET_TYPE: u8 = 0x0f;
@@ -85,10 +103,12 @@ impl Encoder for AuthPacket {
 
         buffer.put_u8(PACKET_TYPE << 4);
         remaining_len += self.reason.encoded_size();
+        remaining_len += VariableByteInteger(self.properties.encoded_size() as u32).encoded_size();
         remaining_len += self.properties.encoded_size();
         VariableByteInteger(remaining_len as u32).encode(buffer);
 
         self.reason.encode(buffer);
+        VariableByteInteger(self.properties.encoded_size() as u32).encode(buffer);
         self.properties.encode(buffer);
     }
 }
@@ -101,10 +121,83 @@ impl Decoder for AuthPacket {
             return Err(ReasonCode::MalformedPacket.into());
         }
 
-        let _ = VariableByteInteger::decode(buffer)?; //Remaining length
+        let remaining_len = VariableByteInteger::decode(buffer)?.0;
+
+        // The Reason Code and Property Length can be omitted if the Reason
+        // Code is 0x00 (Success) and there are no Properties.
+        if remaining_len == 0 {
+            return Ok(AuthPacket {
+                reason: ReasonCode::Success,
+                properties: AuthProperties::default(),
+            });
+        }
+
         let reason = ReasonCode::decode(buffer)?;
         let properties = AuthProperties::decode(buffer)?;
 
         Ok(AuthPacket { reason, properties })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Bytes, BytesMut};
+
+    use mercurio_core::{
+        codec::{Decoder, Encoder},
+        error::Error,
+        properties::{AuthenticationData, AuthenticationMethod},
+        reason::ReasonCode,
+    };
+
+    use crate::{
+        auth::{AuthPacket, AuthProperties},
+        ControlPacket,
+    };
+
+    #[test]
+    fn test_auth_packet_encode_decode() {
+        let expected = vec![
+            0xf0, 0x0c, 0x18, 0x0a, 0x15, 0x00, 0x02, 0x6d, 0x31, 0x16, 0x00, 0x02, 0x01, 0x02,
+        ];
+
+        let packet = AuthPacket {
+            reason: ReasonCode::ContinueAuthentication,
+            properties: AuthProperties {
+                auth_method: AuthenticationMethod::new("m1".to_string()).into(),
+                auth_data: AuthenticationData::new(Bytes::from_static(&[1, 2])).into(),
+                ..Default::default()
+            },
+        };
+
+        let mut encoded = BytesMut::new();
+        packet.encode(&mut encoded);
+
+        assert_eq!(encoded, expected);
+
+        let mut bytes = Bytes::from(expected);
+
+        let new_packet = AuthPacket::decode(&mut bytes).expect("Unexpected error");
+        assert_eq!(packet, new_packet);
+
+        let mut bytes = Bytes::from_static(&[0xf0, 0x00]);
+        let new_packet = AuthPacket::decode(&mut bytes).expect("Unexpected error");
+        assert_eq!(new_packet.reason, ReasonCode::Success);
+    }
+
+    #[test]
+    fn test_auth_invalid_properties_are_not_encoded() {
+        let packet = ControlPacket::Auth(AuthPacket {
+            reason: ReasonCode::ContinueAuthentication,
+            properties: AuthProperties {
+                auth_method: Some(AuthenticationMethod::new("SCRAM\0".to_string())),
+                ..Default::default()
+            },
+        });
+
+        assert!(matches!(
+            packet.validate(),
+            Err(Error::MQTTReasonCode(ReasonCode::MalformedPacket))
+        ));
+    }
+}
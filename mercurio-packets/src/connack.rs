@@ -60,6 +60,33 @@ pub struct ConnAckProperties {
     pub authentication_data: Option<AuthenticationData>,
 }
 
+impl ConnAckProperties {
+    /// Checks the properties against what [`PropertyContext::ConnAck`] allows.
+    pub fn validate(&self) -> crate::Result<()> {
+        let mut checker = PropertyChecker::new(PropertyContext::ConnAck);
+
+        checker.check_field(&self.session_expiry_interval)?;
+        checker.check_field(&self.receive_maximum)?;
+        checker.check_field(&self.maximum_qos)?;
+        checker.check_field(&self.retain_available)?;
+        checker.check_field(&self.maximum_packet_size)?;
+        checker.check_field(&self.assigned_client_id)?;
+        checker.check_field(&self.topic_alias_max)?;
+        checker.check_field(&self.reason_string)?;
+        checker.check_fields(&self.user_property)?;
+        checker.check_field(&self.wildcard_subscription_available)?;
+        checker.check_field(&self.subscription_identifier_available)?;
+        checker.check_field(&self.shared_subscription_available)?;
+        checker.check_field(&self.server_keepalive)?;
+        checker.check_field(&self.response_information)?;
+        checker.check_field(&self.server_reference)?;
+        checker.check_field(&self.authentication_method)?;
+        checker.check_field(&self.authentication_data)?;
+
+        Ok(())
+    }
+}
+
 impl Encoder for ConnAckProperties {
     fn encode(&self, buffer: &mut bytes::BytesMut) {
         self.session_expiry_interval.encode(buffer);
@@ -120,9 +147,13 @@ impl Decoder for ConnAckProperties {
         }
 
         let mut encoded_properties = buffer.take(len.0 as usize);
+        let mut checker = PropertyChecker::new(PropertyContext::ConnAck);
 
         while encoded_properties.has_remaining() {
-            match Property::decode(&mut encoded_properties)? {
+            let property = Property::decode(&mut encoded_properties)?;
+            checker.check(&property)?;
+
+            match property {
                 SessionExpiryInterval(v) => properties.session_expiry_interval = Some(v),
                 ReceiveMaximum(v) => properties.receive_maximum = Some(v),
                 MaximumQoS(v) => properties.maximum_qos = Some(v),
@@ -211,7 +242,7 @@ impl Decoder for ConnAckPacket {
 
 #[cfg(test)]
 mod tests {
-    use crate::connack::*;
+    use crate::{connack::*, ControlPacket};
     use bytes::{Bytes, BytesMut};
 
     #[test]
@@ -260,4 +291,32 @@ mod tests {
 
         assert_eq!(packet, new_packet);
     }
+
+    #[test]
+    fn test_connack_invalid_properties_are_not_encoded() {
+        let packet = |properties| {
+            ControlPacket::ConnAck(ConnAckPacket {
+                properties: Some(properties),
+                ..Default::default()
+            })
+        };
+
+        let invalid = [
+            ConnAckProperties {
+                receive_maximum: ReceiveMaximum::new(0).into(),
+                ..Default::default()
+            },
+            ConnAckProperties {
+                maximum_qos: MaximumQoS::new(2).into(),
+                ..Default::default()
+            },
+        ];
+
+        for properties in invalid {
+            assert!(matches!(
+                packet(properties).validate(),
+                Err(Error::MQTTReasonCode(ReasonCode::ProtocolError))
+            ));
+        }
+    }
 }
@@ -75,6 +75,11 @@ impl Decoder for ConnectFlags {
         if (byte & 0b0000_0100) != 0 {
             flags.will_flag = true;
             flags.will_qos = ((byte >> 3) & 0b0000_0011).into();
+        } else if (byte & 0b0011_1000) != 0 {
+            // [MQTT-3.1.2-11], [MQTT-3.1.2-14]
+            // If the Will Flag is set to 0, then the Will QoS MUST be set to
+            // 0 and Will Retain MUST be set to 0.
+            return Err(ReasonCode::MalformedPacket.into());
         }
 
         if flags.will_qos != QoS::Invalid {
@@ -98,6 +103,25 @@ pub struct ConnectProperties {
     pub authentication_data: Option<AuthenticationData>,
 }
 
+impl ConnectProperties {
+    /// Checks the properties against what [`PropertyContext::Connect`] allows.
+    pub fn validate(&self) -> crate::Result<()> {
+        let mut checker = PropertyChecker::new(PropertyContext::Connect);
+
+        checker.check_field(&self.session_expiry_interval)?;
+        checker.check_field(&self.receive_maximum)?;
+        checker.check_field(&self.maximum_packet_size)?;
+        checker.check_field(&self.topic_alias_maximum)?;
+        checker.check_field(&self.request_response_information)?;
+        checker.check_field(&self.request_problem_information)?;
+        checker.check_fields(&self.user_property)?;
+        checker.check_field(&self.authentication_method)?;
+        checker.check_field(&self.authentication_data)?;
+
+        Ok(())
+    }
+}
+
 impl Encoder for ConnectProperties {
     fn encode(&self, buffer: &mut BytesMut) {
         self.session_expiry_interval.encode(buffer);
@@ -142,13 +166,18 @@ impl Decoder for ConnectProperties {
         }
 
         let mut encoded_properties = buffer.take(len.0 as usize);
+        let mut checker = PropertyChecker::new(PropertyContext::Connect);
 
         while encoded_properties.has_remaining() {
-            match Property::decode(&mut encoded_properties)? {
+            let property = Property::decode(&mut encoded_properties)?;
+            checker.check(&property)?;
+
+            match property {
                 SessionExpiryInterval(v) => properties.session_expiry_interval = Some(v),
                 AuthenticationMethod(v) => properties.authentication_method = Some(v),
                 AuthenticationData(v) => properties.authentication_data = Some(v),
                 RequestProblemInformation(v) => properties.request_problem_information = Some(v),
+                RequestResponseInformation(v) => properties.request_response_information = Some(v),
                 ReceiveMaximum(v) => properties.receive_maximum = Some(v),
                 TopicAliasMaximum(v) => properties.topic_alias_maximum = Some(v),
                 MaximumPacketSize(v) => properties.maximum_packet_size = Some(v),
@@ -168,7 +197,7 @@ impl Decoder for ConnectProperties {
     }
 }
 
-#[derive(Default, Debug, PartialEq, Eq)]
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
 pub struct WillProperties {
     pub will_delay_interval: Option<WillDelayInterval>,
     pub payload_format_indicator: Option<PayloadFormatIndicator>,
@@ -179,6 +208,23 @@ pub struct WillProperties {
     pub user_property: Option<Vec<UserProperty>>,
 }
 
+impl WillProperties {
+    /// Checks the properties against what [`PropertyContext::Will`] allows.
+    pub fn validate(&self) -> crate::Result<()> {
+        let mut checker = PropertyChecker::new(PropertyContext::Will);
+
+        checker.check_field(&self.will_delay_interval)?;
+        checker.check_field(&self.payload_format_indicator)?;
+        checker.check_field(&self.message_expiry_interval)?;
+        checker.check_field(&self.content_type)?;
+        checker.check_field(&self.response_topic)?;
+        checker.check_field(&self.correlation_data)?;
+        checker.check_fields(&self.user_property)?;
+
+        Ok(())
+    }
+}
+
 impl Encoder for WillProperties {
     fn encode(&self, buffer: &mut BytesMut) {
         self.will_delay_interval.encode(buffer);
@@ -219,9 +265,13 @@ impl Decoder for WillProperties {
         }
 
         let mut encoded_properties = buffer.take(len.0 as usize);
+        let mut checker = PropertyChecker::new(PropertyContext::Will);
 
         while encoded_properties.has_remaining() {
-            match Property::decode(&mut encoded_properties)? {
+            let property = Property::decode(&mut encoded_properties)?;
+            checker.check(&property)?;
+
+            match property {
                 WillDelayInterval(v) => properties.will_delay_interval = Some(v),
                 PayloadFormatIndicator(v) => properties.payload_format_indicator = Some(v),
                 MessageExpiryInterval(v) => properties.message_expiry_interval = Some(v),
@@ -310,6 +360,9 @@ pub struct ConnectPacket {
 impl ConnectPacket {
     const PROTOCOL_NAME: &'static str = "MQTT";
     const PROTOCOL_VERSION: u8 = 5;
+
+    /// Protocol name of MQTT 3.1, which 3.1.1 renamed to `MQTT`.
+    const LEGACY_PROTOCOL_NAME: &'static str = "MQIsdp";
 }
 
 const PACKET_TYPE: u8 = 0x01;
@@ -345,16 +398,38 @@ impl Encoder for ConnectPacket {
 impl Decoder for ConnectPacket {
     fn decode<T: Buf>(buffer: &mut T) -> crate::Result<Self> {
         buffer.advance(1); // Packet type
-        let _ = VariableByteInteger::decode(buffer)?; //Remaining length
+        let remaining_len = VariableByteInteger::decode(buffer)?.0 as usize;
 
-        let protocol_name = String::decode(buffer)?;
-        if protocol_name != Self::PROTOCOL_NAME {
-            return Err(ReasonCode::MalformedPacket.into());
+        if buffer.remaining() < remaining_len {
+            return Err(Error::PacketIncomplete);
         }
 
+        let mut buffer = buffer.take(remaining_len);
+
+        match ConnectPacket::decode_contents(&mut buffer) {
+            // The whole packet is there, so fields the flags announce but
+            // that are missing are malformed, as are bytes left over.
+            Err(Error::PacketIncomplete) => Err(ReasonCode::MalformedPacket.into()),
+            Ok(_) if buffer.has_remaining() => Err(ReasonCode::MalformedPacket.into()),
+            result => result,
+        }
+    }
+}
+
+impl ConnectPacket {
+    /// Decodes what follows the fixed header.
+    fn decode_contents<T: Buf>(buffer: &mut T) -> crate::Result<Self> {
+        let protocol_name = String::decode(buffer)?;
         let protocol_version = u8::decode(buffer)?;
-        if protocol_version != 5 {
-            return Err(ReasonCode::UnsupportedProtocolVersion.into());
+
+        // Clients of older versions are told so, rather than their CONNECT
+        // being malformed
+        match (protocol_name.as_str(), protocol_version) {
+            (Self::PROTOCOL_NAME, Self::PROTOCOL_VERSION) => {}
+            (Self::PROTOCOL_NAME | Self::LEGACY_PROTOCOL_NAME, _) => {
+                return Err(ReasonCode::UnsupportedProtocolVersion.into())
+            }
+            _ => return Err(ReasonCode::MalformedPacket.into()),
         }
 
         let flags = ConnectFlags::decode(buffer)?;
@@ -387,7 +462,7 @@ impl Decoder for ConnectPacket {
 
 #[cfg(test)]
 mod tests {
-    use crate::connect::*;
+    use crate::{connect::*, ControlPacket};
 
     #[test]
     fn test_connect_packet_encoding() {
@@ -429,6 +504,73 @@ mod tests {
         assert_eq!(packet, new_packet);
     }
 
+    #[test]
+    fn test_connect_packet_flags_match_payload() {
+        let valid = [
+            0x10, 0x10, 0x00, 0x04, 0x4d, 0x51, 0x54, 0x54, 0x05, 0x02, 0x00, 0x3c, 0x03, 0x21,
+            0x00, 0x14, 0x00, 0x00,
+        ];
+
+        let decode = |bytes: Vec<u8>| ConnectPacket::decode(&mut Bytes::from(bytes));
+        let malformed = |bytes: Vec<u8>| {
+            matches!(
+                decode(bytes),
+                Err(Error::MQTTReasonCode(ReasonCode::MalformedPacket))
+            )
+        };
+
+        assert!(decode(valid.to_vec()).is_ok());
+
+        // Password flag set, without a password
+        let mut bytes = valid.to_vec();
+        bytes[9] = 0x42;
+        assert!(malformed(bytes));
+
+        // Will QoS without a will
+        let mut bytes = valid.to_vec();
+        bytes[9] = 0x0a;
+        assert!(malformed(bytes));
+
+        // A byte more than the flags announce
+        let mut bytes = valid.to_vec();
+        bytes[1] += 1;
+        bytes.push(0x00);
+        assert!(malformed(bytes));
+    }
+
+    #[test]
+    fn test_connect_packet_older_versions() {
+        let unsupported = |bytes: &[u8]| {
+            matches!(
+                ConnectPacket::decode(&mut Bytes::copy_from_slice(bytes)),
+                Err(Error::MQTTReasonCode(
+                    ReasonCode::UnsupportedProtocolVersion
+                ))
+            )
+        };
+
+        // MQTT 3.1
+        assert!(unsupported(&[
+            0x10, 0x0f, 0x00, 0x06, b'M', b'Q', b'I', b's', b'd', b'p', 0x03, 0x02, 0x00, 0x3c,
+            0x00, 0x01, b'a',
+        ]));
+
+        // MQTT 3.1.1
+        assert!(unsupported(&[
+            0x10, 0x0d, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04, 0x02, 0x00, 0x3c, 0x00, 0x01,
+            b'a',
+        ]));
+
+        // Not MQTT at all
+        assert!(matches!(
+            ConnectPacket::decode(&mut Bytes::from_static(&[
+                0x10, 0x0d, 0x00, 0x04, b'H', b'T', b'T', b'P', 0x05, 0x02, 0x00, 0x3c, 0x00, 0x01,
+                b'a',
+            ])),
+            Err(Error::MQTTReasonCode(ReasonCode::MalformedPacket))
+        ));
+    }
+
     #[test]
     fn test_connect_packet_encode_decode() {
         let expected = vec![
@@ -486,4 +628,71 @@ mod tests {
         let new_packet = ConnectPacket::decode(&mut bytes).expect("Unexpected error");
         assert_eq!(packet, new_packet);
     }
+
+    #[test]
+    fn test_connect_properties_request_information() {
+        let mut bytes = Bytes::from(vec![0x04, 0x19, 0x01, 0x17, 0x00]);
+
+        let properties = ConnectProperties::decode(&mut bytes).expect("Unexpected error");
+
+        assert_eq!(
+            properties,
+            ConnectProperties {
+                request_response_information: RequestResponseInformation::new(1).into(),
+                request_problem_information: RequestProblemInformation::new(0).into(),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_connect_properties_receive_maximum() {
+        let mut bytes = Bytes::from(vec![0x03, 0x21, 0x00, 0x0a]);
+        let properties = ConnectProperties::decode(&mut bytes).expect("Unexpected error");
+        assert_eq!(properties.receive_maximum, ReceiveMaximum::new(10).into());
+
+        // A client can't take no message at all
+        let mut bytes = Bytes::from(vec![0x03, 0x21, 0x00, 0x00]);
+        assert!(matches!(
+            ConnectProperties::decode(&mut bytes),
+            Err(Error::MQTTReasonCode(ReasonCode::ProtocolError))
+        ));
+    }
+
+    #[test]
+    fn test_connect_invalid_properties_are_not_encoded() {
+        let packet = ControlPacket::Connect(ConnectPacket {
+            flags: ConnectFlags::default(),
+            keepalive: 60,
+            properties: Some(ConnectProperties {
+                maximum_packet_size: MaximumPacketSize::new(0).into(),
+                ..Default::default()
+            }),
+            payload: ConnectPayload::default(),
+        });
+
+        assert!(matches!(
+            packet.validate(),
+            Err(Error::MQTTReasonCode(ReasonCode::ProtocolError))
+        ));
+
+        // The will is checked as well
+        let packet = ControlPacket::Connect(ConnectPacket {
+            flags: ConnectFlags::default(),
+            keepalive: 60,
+            properties: None,
+            payload: ConnectPayload {
+                will_properties: Some(WillProperties {
+                    content_type: ContentType::new("text\0".to_string()).into(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        });
+
+        assert!(matches!(
+            packet.validate(),
+            Err(Error::MQTTReasonCode(ReasonCode::MalformedPacket))
+        ));
+    }
 }
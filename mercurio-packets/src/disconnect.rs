@@ -9,10 +9,24 @@ use mercurio_core::{
 
 #[derive(Default, PartialEq, Eq, Debug)]
 pub struct DisconnectProperties {
-    session_expiry_interval: Option<SessionExpiryInterval>,
-    reason_string: Option<ReasonString>,
-    user_property: Option<Vec<UserProperty>>,
-    server_reference: Option<ServerReference>,
+    pub session_expiry_interval: Option<SessionExpiryInterval>,
+    pub reason_string: Option<ReasonString>,
+    pub user_property: Option<Vec<UserProperty>>,
+    pub server_reference: Option<ServerReference>,
+}
+
+impl DisconnectProperties {
+    /// Checks the properties against what [`PropertyContext::Disconnect`] allows.
+    pub fn validate(&self) -> crate::Result<()> {
+        let mut checker = PropertyChecker::new(PropertyContext::Disconnect);
+
+        checker.check_field(&self.session_expiry_interval)?;
+        checker.check_field(&self.reason_string)?;
+        checker.check_fields(&self.user_property)?;
+        checker.check_field(&self.server_reference)?;
+
+        Ok(())
+    }
 }
 
 impl Encoder for DisconnectProperties {
@@ -49,9 +63,13 @@ impl Decoder for DisconnectProperties {
         }
 
         let mut encoded_properties = buffer.take(len.0 as usize);
+        let mut checker = PropertyChecker::new(PropertyContext::Disconnect);
 
         while encoded_properties.has_remaining() {
-            match Property::decode(&mut encoded_properties)? {
+            let property = Property::decode(&mut encoded_properties)?;
+            checker.check(&property)?;
+
+            match property {
                 SessionExpiryInterval(v) => properties.session_expiry_interval = Some(v),
                 ReasonString(v) => properties.reason_string = Some(v),
                 UserProperty(v) => {
@@ -73,8 +91,8 @@ impl Decoder for DisconnectProperties {
 
 #[derive(PartialEq, Eq, Debug)]
 pub struct DisconnectPacket {
-    pub(crate) reason: ReasonCode,
-    pub(crate) properties: Option<DisconnectProperties>,
+    pub reason: ReasonCode,
+    pub properties: Option<DisconnectProperties>,
 }
 
 const PACKET_TYPE: u8 = 0x0e;
@@ -118,3 +136,34 @@ impl Decoder for DisconnectPacket {
         Ok(DisconnectPacket { reason, properties })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use mercurio_core::{
+        error::Error,
+        properties::{ServerReference, SessionExpiryInterval},
+        reason::ReasonCode,
+    };
+
+    use crate::{
+        disconnect::{DisconnectPacket, DisconnectProperties},
+        ControlPacket,
+    };
+
+    #[test]
+    fn test_disconnect_invalid_properties_are_not_encoded() {
+        let packet = ControlPacket::Disconnect(DisconnectPacket {
+            reason: ReasonCode::UseAnotherServer,
+            properties: Some(DisconnectProperties {
+                session_expiry_interval: Some(SessionExpiryInterval::new(0)),
+                server_reference: Some(ServerReference::new("a".repeat(70_000))),
+                ..Default::default()
+            }),
+        });
+
+        assert!(matches!(
+            packet.validate(),
+            Err(Error::MQTTReasonCode(ReasonCode::MalformedPacket))
+        ));
+    }
+}
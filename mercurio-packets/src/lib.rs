@@ -29,10 +29,21 @@ use mercurio_core::{
 };
 
 use crate::{
-    auth::AuthPacket, connack::ConnAckPacket, connect::ConnectPacket, disconnect::DisconnectPacket,
-    pingreq::PingReqPacket, pingresp::PingRespPacket, puback::PubAckPacket, pubcomp::PubCompPacket,
-    publish::PublishPacket, pubrec::PubRecPacket, pubrel::PubRelPacket, suback::SubAckPacket,
-    subscribe::SubscribePacket, unsuback::UnsubAckPacket, unsubscribe::UnsubscribePacket,
+    auth::AuthPacket,
+    connack::{ConnAckPacket, ConnAckProperties},
+    connect::{ConnectPacket, ConnectProperties, WillProperties},
+    disconnect::{DisconnectPacket, DisconnectProperties},
+    pingreq::PingReqPacket,
+    pingresp::PingRespPacket,
+    puback::{PubAckPacket, PubAckProperties},
+    pubcomp::{PubCompPacket, PubCompProperties},
+    publish::{PublishPacket, PublishProperties},
+    pubrec::{PubRecPacket, PubRecProperties},
+    pubrel::{PubRelPacket, PubRelProperties},
+    suback::{SubAckPacket, SubAckProperties},
+    subscribe::{SubscribePacket, SubscribeProperties},
+    unsuback::{UnsubAckPacket, UnsubAckProperties},
+    unsubscribe::{UnsubscribePacket, UnsubscribeProperties},
 };
 
 #[repr(u8)]
@@ -108,10 +119,7 @@ impl ControlPacket {
         let mut peeker = Cursor::new(&src[..]);
         let remaining_len_pos = 1;
 
-        let len = match peeker.seek(SeekFrom::End(0)) {
-            Ok(n) => n,
-            Err(err) => return Err(err.into()),
-        };
+        let len = peeker.seek(SeekFrom::End(0))?;
 
         peeker.set_position(remaining_len_pos);
 
@@ -151,6 +159,73 @@ impl ControlPacket {
     }
 }
 
+impl ControlPacket {
+    /// Checks the properties of the packet as its decoder does, so that one
+    /// which couldn't be received isn't sent either. Meant to be called
+    /// before encoding it.
+    pub fn validate(&self) -> Result<()> {
+        use ControlPacket::*;
+
+        match self {
+            Connect(p) => {
+                p.properties
+                    .as_ref()
+                    .map_or(Ok(()), ConnectProperties::validate)?;
+                p.payload
+                    .will_properties
+                    .as_ref()
+                    .map_or(Ok(()), WillProperties::validate)
+            }
+            ConnAck(p) => p
+                .properties
+                .as_ref()
+                .map_or(Ok(()), ConnAckProperties::validate),
+            Publish(p) => p
+                .properties
+                .as_ref()
+                .map_or(Ok(()), PublishProperties::validate),
+            PubAck(p) => p
+                .properties
+                .as_ref()
+                .map_or(Ok(()), PubAckProperties::validate),
+            PubRec(p) => p
+                .properties
+                .as_ref()
+                .map_or(Ok(()), PubRecProperties::validate),
+            PubRel(p) => p
+                .properties
+                .as_ref()
+                .map_or(Ok(()), PubRelProperties::validate),
+            PubComp(p) => p
+                .properties
+                .as_ref()
+                .map_or(Ok(()), PubCompProperties::validate),
+            Subscribe(p) => p
+                .properties
+                .as_ref()
+                .map_or(Ok(()), SubscribeProperties::validate),
+            SubAck(p) => p
+                .properties
+                .as_ref()
+                .map_or(Ok(()), SubAckProperties::validate),
+            Unsubscribe(p) => p
+                .properties
+                .as_ref()
+                .map_or(Ok(()), UnsubscribeProperties::validate),
+            UnsubAck(p) => p
+                .properties
+                .as_ref()
+                .map_or(Ok(()), UnsubAckProperties::validate),
+            PingReq(_) | PingResp(_) => Ok(()),
+            Disconnect(p) => p
+                .properties
+                .as_ref()
+                .map_or(Ok(()), DisconnectProperties::validate),
+            Auth(p) => p.properties.validate(),
+        }
+    }
+}
+
 impl Encoder for ControlPacket {
     fn encode(&self, buffer: &mut BytesMut) {
         use ControlPacket::*;
@@ -13,6 +13,18 @@ pub struct PubAckProperties {
     user_property: Option<Vec<UserProperty>>,
 }
 
+impl PubAckProperties {
+    /// Checks the properties against what [`PropertyContext::PubAck`] allows.
+    pub fn validate(&self) -> crate::Result<()> {
+        let mut checker = PropertyChecker::new(PropertyContext::PubAck);
+
+        checker.check_field(&self.reason_string)?;
+        checker.check_fields(&self.user_property)?;
+
+        Ok(())
+    }
+}
+
 impl Encoder for PubAckProperties {
     fn encode(&self, buffer: &mut BytesMut) {
         self.reason_string.encode(buffer);
@@ -43,9 +55,13 @@ impl Decoder for PubAckProperties {
         }
 
         let mut encoded_properties = buffer.take(len.0 as usize);
+        let mut checker = PropertyChecker::new(PropertyContext::PubAck);
 
         while encoded_properties.has_remaining() {
-            match Property::decode(&mut encoded_properties)? {
+            let property = Property::decode(&mut encoded_properties)?;
+            checker.check(&property)?;
+
+            match property {
                 ReasonString(v) => properties.reason_string = Some(v),
                 UserProperty(v) => {
                     if let Some(vec) = &mut properties.user_property {
@@ -133,10 +149,15 @@ mod tests {
 
     use mercurio_core::{
         codec::{Decoder, Encoder},
+        error::Error,
+        properties::ReasonString,
         reason::ReasonCode,
     };
 
-    use crate::puback::{PubAckPacket, PubAckProperties};
+    use crate::{
+        puback::{PubAckPacket, PubAckProperties},
+        ControlPacket,
+    };
 
     #[test]
     fn test_puback_packet_encode_decode() {
@@ -158,4 +179,21 @@ mod tests {
         let new_packet = PubAckPacket::decode(&mut bytes).expect("Unexpected error");
         assert_eq!(packet, new_packet);
     }
+
+    #[test]
+    fn test_puback_invalid_properties_are_not_encoded() {
+        let packet = ControlPacket::PubAck(PubAckPacket {
+            packet_id: 1,
+            reason: ReasonCode::Success,
+            properties: Some(PubAckProperties {
+                reason_string: Some(ReasonString::new("no\0such".to_string())),
+                user_property: None,
+            }),
+        });
+
+        assert!(matches!(
+            packet.validate(),
+            Err(Error::MQTTReasonCode(ReasonCode::MalformedPacket))
+        ));
+    }
 }
@@ -13,6 +13,18 @@ pub struct PubCompProperties {
     user_property: Option<Vec<UserProperty>>,
 }
 
+impl PubCompProperties {
+    /// Checks the properties against what [`PropertyContext::PubComp`] allows.
+    pub fn validate(&self) -> crate::Result<()> {
+        let mut checker = PropertyChecker::new(PropertyContext::PubComp);
+
+        checker.check_field(&self.reason_string)?;
+        checker.check_fields(&self.user_property)?;
+
+        Ok(())
+    }
+}
+
 impl Encoder for PubCompProperties {
     fn encode(&self, buffer: &mut BytesMut) {
         self.reason_string.encode(buffer);
@@ -43,9 +55,13 @@ impl Decoder for PubCompProperties {
         }
 
         let mut encoded_properties = buffer.take(len.0 as usize);
+        let mut checker = PropertyChecker::new(PropertyContext::PubComp);
 
         while encoded_properties.has_remaining() {
-            match Property::decode(&mut encoded_properties)? {
+            let property = Property::decode(&mut encoded_properties)?;
+            checker.check(&property)?;
+
+            match property {
                 ReasonString(v) => properties.reason_string = Some(v),
                 UserProperty(v) => {
                     if let Some(vec) = &mut properties.user_property {
@@ -131,10 +147,15 @@ mod tests {
 
     use mercurio_core::{
         codec::{Decoder, Encoder},
+        error::Error,
+        properties::UserProperty,
         reason::ReasonCode,
     };
 
-    use crate::pubcomp::{PubCompPacket, PubCompProperties};
+    use crate::{
+        pubcomp::{PubCompPacket, PubCompProperties},
+        ControlPacket,
+    };
 
     #[test]
     fn test_pubrel_packet_encode_decode() {
@@ -156,4 +177,24 @@ mod tests {
         let new_packet = PubCompPacket::decode(&mut bytes).expect("Unexpected error");
         assert_eq!(packet, new_packet);
     }
+
+    #[test]
+    fn test_pubcomp_invalid_properties_are_not_encoded() {
+        let packet = ControlPacket::PubComp(PubCompPacket {
+            packet_id: 1,
+            reason: ReasonCode::Success,
+            properties: Some(PubCompProperties {
+                reason_string: None,
+                user_property: Some(vec![UserProperty::new(
+                    "\0".to_string(),
+                    "value".to_string(),
+                )]),
+            }),
+        });
+
+        assert!(matches!(
+            packet.validate(),
+            Err(Error::MQTTReasonCode(ReasonCode::MalformedPacket))
+        ));
+    }
 }
@@ -10,14 +10,32 @@ use mercurio_core::{
 
 #[derive(Default, Debug, PartialEq, Eq, Clone)]
 pub struct PublishProperties {
-    payload_format_indicator: Option<PayloadFormatIndicator>,
-    message_expiry_interval: Option<MessageExpiryInterval>,
-    topic_alias: Option<TopicAlias>,
-    response_topic: Option<ResponseTopic>,
-    correlation_data: Option<CorrelationData>,
-    user_property: Option<Vec<UserProperty>>,
-    subscription_identifier: Option<SubscriptionIdentifier>,
-    content_type: Option<ContentType>,
+    pub payload_format_indicator: Option<PayloadFormatIndicator>,
+    pub message_expiry_interval: Option<MessageExpiryInterval>,
+    pub topic_alias: Option<TopicAlias>,
+    pub response_topic: Option<ResponseTopic>,
+    pub correlation_data: Option<CorrelationData>,
+    pub user_property: Option<Vec<UserProperty>>,
+    pub subscription_identifier: Option<Vec<SubscriptionIdentifier>>,
+    pub content_type: Option<ContentType>,
+}
+
+impl PublishProperties {
+    /// Checks the properties against what [`PropertyContext::Publish`] allows.
+    pub fn validate(&self) -> crate::Result<()> {
+        let mut checker = PropertyChecker::new(PropertyContext::Publish);
+
+        checker.check_field(&self.payload_format_indicator)?;
+        checker.check_field(&self.message_expiry_interval)?;
+        checker.check_field(&self.topic_alias)?;
+        checker.check_field(&self.response_topic)?;
+        checker.check_field(&self.correlation_data)?;
+        checker.check_fields(&self.user_property)?;
+        checker.check_fields(&self.subscription_identifier)?;
+        checker.check_field(&self.content_type)?;
+
+        Ok(())
+    }
 }
 
 impl Encoder for PublishProperties {
@@ -61,9 +79,13 @@ impl Decoder for PublishProperties {
         }
 
         let mut encoded_properties = buffer.take(len.0 as usize);
+        let mut checker = PropertyChecker::new(PropertyContext::Publish);
 
         while encoded_properties.has_remaining() {
-            match Property::decode(&mut encoded_properties)? {
+            let property = Property::decode(&mut encoded_properties)?;
+            checker.check(&property)?;
+
+            match property {
                 PayloadFormatIndicator(v) => properties.payload_format_indicator = Some(v),
                 MessageExpiryInterval(v) => properties.message_expiry_interval = Some(v),
                 TopicAlias(v) => properties.topic_alias = Some(v),
@@ -77,7 +99,14 @@ impl Decoder for PublishProperties {
                         properties.user_property = Some(vec);
                     }
                 }
-                SubscriptionIdentifier(v) => properties.subscription_identifier = Some(v),
+                SubscriptionIdentifier(v) => {
+                    if let Some(vec) = &mut properties.subscription_identifier {
+                        vec.push(v);
+                    } else {
+                        let vec = vec![v];
+                        properties.subscription_identifier = Some(vec);
+                    }
+                }
                 ContentType(v) => properties.content_type = Some(v),
                 _ => return Err(ReasonCode::MalformedPacket.into()),
             }
@@ -181,7 +210,7 @@ impl Decoder for PublishPacket {
 
 #[cfg(test)]
 mod tests {
-    use crate::publish::*;
+    use crate::{publish::*, ControlPacket};
 
     #[test]
     fn test_publish_packet_encode_decode() {
@@ -216,4 +245,68 @@ mod tests {
         let new_packet = PublishPacket::decode(&mut bytes).expect("Unexpected error");
         assert_eq!(packet, new_packet);
     }
+
+    #[test]
+    fn test_publish_packet_multiple_subscription_identifiers() {
+        let expected = vec![
+            0x30, 0x0b, 0x00, 0x01, 0x74, 0x05, 0x0b, 0x01, 0x0b, 0x80, 0x01, 0x68, 0x69,
+        ];
+
+        let packet = PublishPacket {
+            dup: false,
+            qos_level: QoS::AtMostOnce,
+            retain: false,
+            topic_name: "t".to_string(),
+            packet_id: None,
+            properties: PublishProperties {
+                subscription_identifier: vec![
+                    SubscriptionIdentifier::new(VariableByteInteger(1)),
+                    SubscriptionIdentifier::new(VariableByteInteger(128)),
+                ]
+                .into(),
+                ..Default::default()
+            }
+            .into(),
+            payload: Bytes::from("hi").into(),
+        };
+
+        let mut encoded = BytesMut::new();
+        packet.encode(&mut encoded);
+
+        assert_eq!(encoded, expected);
+
+        let mut bytes = Bytes::from(expected);
+
+        let new_packet = PublishPacket::decode(&mut bytes).expect("Unexpected error");
+        assert_eq!(packet, new_packet);
+    }
+
+    #[test]
+    fn test_publish_invalid_properties_are_not_encoded() {
+        let packet = |properties| {
+            ControlPacket::Publish(PublishPacket {
+                topic_name: "a/b".to_string(),
+                properties: Some(properties),
+                ..Default::default()
+            })
+        };
+
+        let invalid = [
+            PublishProperties {
+                response_topic: ResponseTopic::new("a/\0".to_string()).into(),
+                ..Default::default()
+            },
+            PublishProperties {
+                correlation_data: CorrelationData::new(Bytes::from(vec![0; 70_000])).into(),
+                ..Default::default()
+            },
+        ];
+
+        for properties in invalid {
+            assert!(matches!(
+                packet(properties).validate(),
+                Err(Error::MQTTReasonCode(ReasonCode::MalformedPacket))
+            ));
+        }
+    }
 }
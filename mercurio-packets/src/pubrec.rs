@@ -13,6 +13,18 @@ pub struct PubRecProperties {
     user_property: Option<Vec<UserProperty>>,
 }
 
+impl PubRecProperties {
+    /// Checks the properties against what [`PropertyContext::PubRec`] allows.
+    pub fn validate(&self) -> crate::Result<()> {
+        let mut checker = PropertyChecker::new(PropertyContext::PubRec);
+
+        checker.check_field(&self.reason_string)?;
+        checker.check_fields(&self.user_property)?;
+
+        Ok(())
+    }
+}
+
 impl Encoder for PubRecProperties {
     fn encode(&self, buffer: &mut BytesMut) {
         self.reason_string.encode(buffer);
@@ -43,9 +55,13 @@ impl Decoder for PubRecProperties {
         }
 
         let mut encoded_properties = buffer.take(len.0 as usize);
+        let mut checker = PropertyChecker::new(PropertyContext::PubRec);
 
         while encoded_properties.has_remaining() {
-            match Property::decode(&mut encoded_properties)? {
+            let property = Property::decode(&mut encoded_properties)?;
+            checker.check(&property)?;
+
+            match property {
                 ReasonString(v) => properties.reason_string = Some(v),
                 UserProperty(v) => {
                     if let Some(vec) = &mut properties.user_property {
@@ -135,10 +151,15 @@ mod tests {
 
     use mercurio_core::{
         codec::{Decoder, Encoder},
+        error::Error,
+        properties::ReasonString,
         reason::ReasonCode,
     };
 
-    use crate::pubrec::{PubRecPacket, PubRecProperties};
+    use crate::{
+        pubrec::{PubRecPacket, PubRecProperties},
+        ControlPacket,
+    };
 
     #[test]
     fn test_pubrec_packet_encode_decode() {
@@ -160,4 +181,22 @@ mod tests {
         let new_packet = PubRecPacket::decode(&mut bytes).expect("Unexpected error");
         assert_eq!(packet, new_packet);
     }
+
+    #[test]
+    fn test_pubrec_invalid_properties_are_not_encoded() {
+        // Its length wouldn't fit in two bytes
+        let packet = ControlPacket::PubRec(PubRecPacket {
+            packet_id: 1,
+            reason: ReasonCode::QuotaExceeded,
+            properties: Some(PubRecProperties {
+                reason_string: Some(ReasonString::new("a".repeat(70_000))),
+                user_property: None,
+            }),
+        });
+
+        assert!(matches!(
+            packet.validate(),
+            Err(Error::MQTTReasonCode(ReasonCode::MalformedPacket))
+        ));
+    }
 }
@@ -13,6 +13,18 @@ pub struct PubRelProperties {
     user_property: Option<Vec<UserProperty>>,
 }
 
+impl PubRelProperties {
+    /// Checks the properties against what [`PropertyContext::PubRel`] allows.
+    pub fn validate(&self) -> crate::Result<()> {
+        let mut checker = PropertyChecker::new(PropertyContext::PubRel);
+
+        checker.check_field(&self.reason_string)?;
+        checker.check_fields(&self.user_property)?;
+
+        Ok(())
+    }
+}
+
 impl Encoder for PubRelProperties {
     fn encode(&self, buffer: &mut BytesMut) {
         self.reason_string.encode(buffer);
@@ -43,9 +55,13 @@ impl Decoder for PubRelProperties {
         }
 
         let mut encoded_properties = buffer.take(len.0 as usize);
+        let mut checker = PropertyChecker::new(PropertyContext::PubRel);
 
         while encoded_properties.has_remaining() {
-            match Property::decode(&mut encoded_properties)? {
+            let property = Property::decode(&mut encoded_properties)?;
+            checker.check(&property)?;
+
+            match property {
                 ReasonString(v) => properties.reason_string = Some(v),
                 UserProperty(v) => {
                     if let Some(vec) = &mut properties.user_property {
@@ -135,10 +151,15 @@ mod tests {
 
     use mercurio_core::{
         codec::{Decoder, Encoder},
+        error::Error,
+        properties::UserProperty,
         reason::ReasonCode,
     };
 
-    use crate::pubrel::{PubRelPacket, PubRelProperties};
+    use crate::{
+        pubrel::{PubRelPacket, PubRelProperties},
+        ControlPacket,
+    };
 
     #[test]
     fn test_pubrel_packet_encode_decode() {
@@ -160,4 +181,21 @@ mod tests {
         let new_packet = PubRelPacket::decode(&mut bytes).expect("Unexpected error");
         assert_eq!(packet, new_packet);
     }
+
+    #[test]
+    fn test_pubrel_invalid_properties_are_not_encoded() {
+        let packet = ControlPacket::PubRel(PubRelPacket {
+            packet_id: 1,
+            reason: ReasonCode::Success,
+            properties: Some(PubRelProperties {
+                reason_string: None,
+                user_property: Some(vec![UserProperty::new("key".to_string(), "\0".to_string())]),
+            }),
+        });
+
+        assert!(matches!(
+            packet.validate(),
+            Err(Error::MQTTReasonCode(ReasonCode::MalformedPacket))
+        ));
+    }
 }
@@ -13,6 +13,18 @@ pub struct SubAckProperties {
     user_property: Option<Vec<UserProperty>>,
 }
 
+impl SubAckProperties {
+    /// Checks the properties against what [`PropertyContext::SubAck`] allows.
+    pub fn validate(&self) -> crate::Result<()> {
+        let mut checker = PropertyChecker::new(PropertyContext::SubAck);
+
+        checker.check_field(&self.reason_string)?;
+        checker.check_fields(&self.user_property)?;
+
+        Ok(())
+    }
+}
+
 impl Encoder for SubAckProperties {
     fn encode(&self, buffer: &mut BytesMut) {
         self.reason_string.encode(buffer);
@@ -43,9 +55,13 @@ impl Decoder for SubAckProperties {
         }
 
         let mut encoded_properties = buffer.take(len.0 as usize);
+        let mut checker = PropertyChecker::new(PropertyContext::SubAck);
 
         while encoded_properties.has_remaining() {
-            match Property::decode(&mut encoded_properties)? {
+            let property = Property::decode(&mut encoded_properties)?;
+            checker.check(&property)?;
+
+            match property {
                 ReasonString(v) => properties.reason_string = Some(v),
                 UserProperty(v) => {
                     if let Some(vec) = &mut properties.user_property {
@@ -153,7 +169,7 @@ impl Decoder for SubAckPacket {
 mod tests {
     use bytes::{Bytes, BytesMut};
 
-    use crate::suback::*;
+    use crate::{suback::*, ControlPacket};
 
     #[test]
     fn test_suback_packet_encode_decode() {
@@ -177,4 +193,21 @@ mod tests {
         let new_packet = SubAckPacket::decode(&mut bytes).expect("Unexpected error");
         assert_eq!(packet, new_packet);
     }
+
+    #[test]
+    fn test_suback_invalid_properties_are_not_encoded() {
+        let packet = ControlPacket::SubAck(SubAckPacket {
+            packet_id: 1,
+            properties: Some(SubAckProperties {
+                reason_string: Some(ReasonString::new("\0".to_string())),
+                user_property: None,
+            }),
+            payload: Vec::new(),
+        });
+
+        assert!(matches!(
+            packet.validate(),
+            Err(Error::MQTTReasonCode(ReasonCode::MalformedPacket))
+        ));
+    }
 }
@@ -9,8 +9,20 @@ use mercurio_core::{
 
 #[derive(Default, Debug, PartialEq, Eq)]
 pub struct SubscribeProperties {
-    subscription_id: Option<SubscriptionIdentifier>,
-    user_property: Option<Vec<UserProperty>>,
+    pub subscription_id: Option<SubscriptionIdentifier>,
+    pub user_property: Option<Vec<UserProperty>>,
+}
+
+impl SubscribeProperties {
+    /// Checks the properties against what [`PropertyContext::Subscribe`] allows.
+    pub fn validate(&self) -> crate::Result<()> {
+        let mut checker = PropertyChecker::new(PropertyContext::Subscribe);
+
+        checker.check_field(&self.subscription_id)?;
+        checker.check_fields(&self.user_property)?;
+
+        Ok(())
+    }
 }
 
 impl Encoder for SubscribeProperties {
@@ -43,9 +55,13 @@ impl Decoder for SubscribeProperties {
         }
 
         let mut encoded_properties = buffer.take(len.0 as usize);
+        let mut checker = PropertyChecker::new(PropertyContext::Subscribe);
 
         while encoded_properties.has_remaining() {
-            match Property::decode(&mut encoded_properties)? {
+            let property = Property::decode(&mut encoded_properties)?;
+            checker.check(&property)?;
+
+            match property {
                 SubscriptionIdentifier(v) => properties.subscription_id = Some(v),
                 UserProperty(v) => {
                     if let Some(vec) = &mut properties.user_property {
@@ -85,10 +101,10 @@ impl From<u8> for RetainHandling {
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct SubscriptionOptions {
-    qos: QoS,
-    no_local: bool,
-    retain_as_pub: bool,
-    retain_handling: RetainHandling,
+    pub qos: QoS,
+    pub no_local: bool,
+    pub retain_as_pub: bool,
+    pub retain_handling: RetainHandling,
 }
 
 impl Encoder for SubscriptionOptions {
@@ -117,6 +133,13 @@ impl Decoder for SubscriptionOptions {
     fn decode<T: Buf>(buffer: &mut T) -> crate::Result<Self> {
         let opt = buffer.get_u8();
 
+        // [MQTT-3.8.3-5]
+        // The Server MUST treat a SUBSCRIBE packet as malformed if any of
+        // Reserved bits in the Payload are non-zero.
+        if opt & 0b1100_0000 != 0 {
+            return Err(ReasonCode::MalformedPacket.into());
+        }
+
         let qos: QoS = (opt & 0b0000_0011).into();
 
         if qos == QoS::Invalid {
@@ -238,7 +261,9 @@ impl Decoder for SubscribePacket {
 mod tests {
     use bytes::{Bytes, BytesMut};
 
-    use crate::subscribe::*;
+    use mercurio_core::error::Error;
+
+    use crate::{subscribe::*, ControlPacket};
 
     #[test]
     fn test_subscribe_packet_encode_decode() {
@@ -279,4 +304,21 @@ mod tests {
         let new_packet = SubscribePacket::decode(&mut bytes).expect("Unexpected error");
         assert_eq!(packet, new_packet);
     }
+
+    #[test]
+    fn test_subscribe_invalid_properties_are_not_encoded() {
+        let packet = ControlPacket::Subscribe(SubscribePacket {
+            packet_id: 1,
+            properties: Some(SubscribeProperties {
+                subscription_id: None,
+                user_property: Some(vec![UserProperty::new("\0".to_string(), "\0".to_string())]),
+            }),
+            payload: Vec::new(),
+        });
+
+        assert!(matches!(
+            packet.validate(),
+            Err(Error::MQTTReasonCode(ReasonCode::MalformedPacket))
+        ));
+    }
 }
@@ -13,6 +13,18 @@ pub struct UnsubAckProperties {
     user_property: Option<Vec<UserProperty>>,
 }
 
+impl UnsubAckProperties {
+    /// Checks the properties against what [`PropertyContext::UnsubAck`] allows.
+    pub fn validate(&self) -> crate::Result<()> {
+        let mut checker = PropertyChecker::new(PropertyContext::UnsubAck);
+
+        checker.check_field(&self.reason_string)?;
+        checker.check_fields(&self.user_property)?;
+
+        Ok(())
+    }
+}
+
 impl Encoder for UnsubAckProperties {
     fn encode(&self, buffer: &mut BytesMut) {
         self.reason_string.encode(buffer);
@@ -43,9 +55,13 @@ impl Decoder for UnsubAckProperties {
         }
 
         let mut encoded_properties = buffer.take(len.0 as usize);
+        let mut checker = PropertyChecker::new(PropertyContext::UnsubAck);
 
         while encoded_properties.has_remaining() {
-            match Property::decode(&mut encoded_properties)? {
+            let property = Property::decode(&mut encoded_properties)?;
+            checker.check(&property)?;
+
+            match property {
                 ReasonString(v) => properties.reason_string = Some(v),
                 UserProperty(v) => {
                     if let Some(vec) = &mut properties.user_property {
@@ -65,7 +81,7 @@ impl Decoder for UnsubAckProperties {
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct UnsubAckPayload {
-    reason_code: ReasonCode,
+    pub reason_code: ReasonCode,
 }
 
 impl Encoder for UnsubAckPayload {
@@ -92,9 +108,9 @@ impl Decoder for UnsubAckPayload {
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct UnsubAckPacket {
-    packet_id: u16,
-    properties: Option<UnsubAckProperties>,
-    payload: Vec<UnsubAckPayload>,
+    pub packet_id: u16,
+    pub properties: Option<UnsubAckProperties>,
+    pub payload: Vec<UnsubAckPayload>,
 }
 
 const PACKET_TYPE: u8 = 0x0b;
@@ -153,7 +169,7 @@ impl Decoder for UnsubAckPacket {
 mod tests {
     use bytes::{Bytes, BytesMut};
 
-    use crate::unsuback::*;
+    use crate::{unsuback::*, ControlPacket};
 
     #[test]
     fn test_unsuback_packet_encode_decode() {
@@ -177,4 +193,21 @@ mod tests {
         let new_packet = UnsubAckPacket::decode(&mut bytes).expect("Unexpected error");
         assert_eq!(packet, new_packet);
     }
+
+    #[test]
+    fn test_unsuback_invalid_properties_are_not_encoded() {
+        let packet = ControlPacket::UnsubAck(UnsubAckPacket {
+            packet_id: 1,
+            properties: Some(UnsubAckProperties {
+                reason_string: Some(ReasonString::new("\0".to_string())),
+                user_property: None,
+            }),
+            payload: Vec::new(),
+        });
+
+        assert!(matches!(
+            packet.validate(),
+            Err(Error::MQTTReasonCode(ReasonCode::MalformedPacket))
+        ));
+    }
 }
@@ -12,6 +12,17 @@ pub struct UnsubscribeProperties {
     user_property: Option<Vec<UserProperty>>,
 }
 
+impl UnsubscribeProperties {
+    /// Checks the properties against what [`PropertyContext::Unsubscribe`] allows.
+    pub fn validate(&self) -> crate::Result<()> {
+        let mut checker = PropertyChecker::new(PropertyContext::Unsubscribe);
+
+        checker.check_fields(&self.user_property)?;
+
+        Ok(())
+    }
+}
+
 impl Encoder for UnsubscribeProperties {
     fn encode(&self, buffer: &mut bytes::BytesMut) {
         self.user_property.encode(buffer);
@@ -40,9 +51,13 @@ impl Decoder for UnsubscribeProperties {
         }
 
         let mut encoded_properties = buffer.take(len.0 as usize);
+        let mut checker = PropertyChecker::new(PropertyContext::Unsubscribe);
 
         while encoded_properties.has_remaining() {
-            match Property::decode(&mut encoded_properties)? {
+            let property = Property::decode(&mut encoded_properties)?;
+            checker.check(&property)?;
+
+            match property {
                 UserProperty(v) => {
                     if let Some(vec) = &mut properties.user_property {
                         vec.push(v);
@@ -61,7 +76,7 @@ impl Decoder for UnsubscribeProperties {
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct UnsubscribePayload {
-    topic_filter: String,
+    pub topic_filter: String,
 }
 
 impl Encoder for UnsubscribePayload {
@@ -88,9 +103,9 @@ impl Decoder for UnsubscribePayload {
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct UnsubscribePacket {
-    packet_id: u16,
-    properties: Option<UnsubscribeProperties>,
-    payload: Vec<UnsubscribePayload>,
+    pub packet_id: u16,
+    pub properties: Option<UnsubscribeProperties>,
+    pub payload: Vec<UnsubscribePayload>,
 }
 
 const PACKET_TYPE: u8 = 0x0a;
@@ -150,7 +165,7 @@ impl Decoder for UnsubscribePacket {
 mod tests {
     use bytes::{Bytes, BytesMut};
 
-    use crate::unsubscribe::*;
+    use crate::{unsubscribe::*, ControlPacket};
 
     #[test]
     fn test_unsubscribe_packet_encode_decode() {
@@ -177,4 +192,20 @@ mod tests {
         let new_packet = UnsubscribePacket::decode(&mut bytes).expect("Unexpected error");
         assert_eq!(packet, new_packet);
     }
+
+    #[test]
+    fn test_unsubscribe_invalid_properties_are_not_encoded() {
+        let packet = ControlPacket::Unsubscribe(UnsubscribePacket {
+            packet_id: 1,
+            properties: Some(UnsubscribeProperties {
+                user_property: Some(vec![UserProperty::new("key".to_string(), "\0".to_string())]),
+            }),
+            payload: Vec::new(),
+        });
+
+        assert!(matches!(
+            packet.validate(),
+            Err(Error::MQTTReasonCode(ReasonCode::MalformedPacket))
+        ));
+    }
 }
@@ -0,0 +1,311 @@
+use std::{
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tokio::{
+    fs::OpenOptions,
+    io::AsyncWriteExt,
+    sync::mpsc::{self, error::TrySendError},
+};
+use tracing::{error, warn};
+
+use mercurio_core::{message::Message, qos::QoS};
+
+use crate::{connection::ConnectionStatsSnapshot, fanout::FanoutPool};
+
+/// Number of events waiting to be written before new ones are dropped.
+const AUDIT_QUEUE_CAPACITY: usize = 1024;
+
+/// Destination of the audit events.
+///
+/// Whatever the sink, every event is written as a single JSON object, such as
+/// `{"timestamp":1700000000000,"event":"connect","client_id":"sensor-1",...}`
+/// where the timestamp is in milliseconds since the Unix epoch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditSink {
+    /// Appends one event per line to a file, created if needed.
+    Jsonl(PathBuf),
+
+    /// Sends events to the local syslog daemon, with the `log audit`
+    /// facility.
+    #[cfg(unix)]
+    Syslog,
+
+    /// Publishes events on `$SYS/events/<event>`, for instance
+    /// `$SYS/events/auth_failed`.
+    SysTopic,
+}
+
+/// Something worth knowing about who did what on the broker.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum AuditEvent {
+    Connect {
+        client_id: String,
+        user_name: Option<String>,
+        peer: Option<String>,
+        auth_method: Option<String>,
+    },
+    Disconnect {
+        client_id: String,
+        reason: String,
+
+        /// Counters of the connection, so abuse can be told apart.
+        #[serde(flatten)]
+        stats: ConnectionStatsSnapshot,
+    },
+    AuthFailed {
+        client_id: String,
+        user_name: Option<String>,
+        peer: Option<String>,
+        reason: String,
+    },
+    PublishDenied {
+        client_id: String,
+        topic: String,
+    },
+    Subscribe {
+        client_id: String,
+        filter: String,
+    },
+    SubscribeDenied {
+        client_id: String,
+        filter: String,
+    },
+
+    /// A command published under `$CONTROL/`.
+    Control {
+        client_id: Option<String>,
+        command: String,
+        payload: String,
+
+        /// `ok`, or why the command failed.
+        outcome: String,
+    },
+}
+
+impl AuditEvent {
+    /// Name of the event, as found in the `event` field.
+    fn name(&self) -> &'static str {
+        match self {
+            AuditEvent::Connect { .. } => "connect",
+            AuditEvent::Disconnect { .. } => "disconnect",
+            AuditEvent::AuthFailed { .. } => "auth_failed",
+            AuditEvent::PublishDenied { .. } => "publish_denied",
+            AuditEvent::Subscribe { .. } => "subscribe",
+            AuditEvent::SubscribeDenied { .. } => "subscribe_denied",
+            AuditEvent::Control { .. } => "control",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct AuditRecord {
+    timestamp: u64,
+
+    #[serde(flatten)]
+    event: AuditEvent,
+}
+
+/// Handle used to emit audit events.
+///
+/// Emitting never waits: events are queued for an [`AuditWriter`] and
+/// dropped, with a warning, if it can't keep up.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AuditLog {
+    sender: Option<mpsc::Sender<AuditRecord>>,
+}
+
+impl AuditLog {
+    /// Creates a log along with the receiving end of its queue.
+    pub(crate) fn new() -> (AuditLog, mpsc::Receiver<AuditRecord>) {
+        let (sender, receiver) = mpsc::channel(AUDIT_QUEUE_CAPACITY);
+
+        (
+            AuditLog {
+                sender: Some(sender),
+            },
+            receiver,
+        )
+    }
+
+    /// Creates a log discarding every event.
+    pub(crate) fn disabled() -> AuditLog {
+        AuditLog::default()
+    }
+
+    pub(crate) fn emit(&self, event: AuditEvent) {
+        let sender = match &self.sender {
+            Some(sender) => sender,
+            None => return,
+        };
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or_default();
+
+        match sender.try_send(AuditRecord { timestamp, event }) {
+            Ok(()) => {}
+            Err(TrySendError::Full(record)) => {
+                warn!("Audit queue is full, dropping event {:?}", record.event)
+            }
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+}
+
+/// Writes the audit events to the configured sink.
+///
+/// Runs until every [`AuditLog`] is gone, so events emitted by connections
+/// closing on shutdown are still written.
+pub(crate) struct AuditWriter {
+    sink: AuditSink,
+    receiver: mpsc::Receiver<AuditRecord>,
+    fanout: FanoutPool,
+}
+
+impl AuditWriter {
+    pub(crate) fn new(
+        sink: AuditSink,
+        receiver: mpsc::Receiver<AuditRecord>,
+        fanout: FanoutPool,
+    ) -> AuditWriter {
+        AuditWriter {
+            sink,
+            receiver,
+            fanout,
+        }
+    }
+
+    pub(crate) async fn run(&mut self) {
+        match self.sink.clone() {
+            AuditSink::Jsonl(path) => self.write_jsonl(path).await,
+            #[cfg(unix)]
+            AuditSink::Syslog => self.write_syslog().await,
+            AuditSink::SysTopic => self.publish().await,
+        }
+    }
+
+    async fn write_jsonl(&mut self, path: PathBuf) {
+        let mut file = match OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+        {
+            Ok(file) => file,
+            Err(err) => {
+                error!(cause = ?err, "Failed to open audit log {:?}", path);
+                return;
+            }
+        };
+
+        while let Some(record) = self.receiver.recv().await {
+            let mut line = json(&record);
+            line.push('\n');
+
+            if let Err(err) = file.write_all(line.as_bytes()).await {
+                error!(cause = ?err, "Failed to write audit log {:?}", path);
+            }
+        }
+
+        if let Err(err) = file.flush().await {
+            error!(cause = ?err, "Failed to write audit log {:?}", path);
+        }
+    }
+
+    #[cfg(unix)]
+    async fn write_syslog(&mut self) {
+        use tokio::net::UnixDatagram;
+
+        /// `log audit` facility (13), `informational` severity (6).
+        const PRIORITY: u8 = 13 * 8 + 6;
+
+        let socket = match UnixDatagram::unbound() {
+            Ok(socket) => socket,
+            Err(err) => {
+                error!(cause = ?err, "Failed to create syslog socket");
+                return;
+            }
+        };
+
+        while let Some(record) = self.receiver.recv().await {
+            let message = format!("<{}>mercurio: {}", PRIORITY, json(&record));
+
+            if let Err(err) = socket.send_to(message.as_bytes(), "/dev/log").await {
+                error!(cause = ?err, "Failed to send audit event to syslog");
+            }
+        }
+    }
+
+    async fn publish(&mut self) {
+        while let Some(record) = self.receiver.recv().await {
+            let topic = format!("$SYS/events/{}", record.event.name());
+            let message = Message::new(topic.clone(), json(&record), QoS::AtMostOnce);
+
+            if let Err(err) = self.fanout.publish(topic.clone(), message).await {
+                error!(cause = ?err, "Failed to publish `{}`", topic);
+            }
+        }
+    }
+}
+
+fn json(record: &AuditRecord) -> String {
+    serde_json::to_string(record).expect("audit records are always serializable")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::{broker::Broker, fanout::FanoutPool};
+
+    use super::{AuditEvent, AuditLog, AuditSink, AuditWriter};
+
+    #[tokio::test]
+    async fn test_jsonl_sink() {
+        let path =
+            std::env::temp_dir().join(format!("mercurio-audit-{}.jsonl", uuid::Uuid::new_v4()));
+
+        let (log, receiver) = AuditLog::new();
+        let mut writer = AuditWriter::new(
+            AuditSink::Jsonl(path.clone()),
+            receiver,
+            FanoutPool::without_workers(Broker::new(Default::default(), Default::default())),
+        );
+
+        log.emit(AuditEvent::AuthFailed {
+            client_id: "client".to_string(),
+            user_name: Some("user".to_string()),
+            peer: None,
+            reason: "Bad user name or password".to_string(),
+        });
+        log.emit(AuditEvent::PublishDenied {
+            client_id: "client".to_string(),
+            topic: "a/b".to_string(),
+        });
+        drop(log);
+
+        // Returns once the log is gone and the events written
+        writer.run().await;
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<serde_json::Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0]["timestamp"].as_u64().unwrap() > 0);
+        assert_eq!(lines[0]["event"], json!("auth_failed"));
+        assert_eq!(lines[0]["user_name"], json!("user"));
+        assert_eq!(lines[0]["peer"], json!(null));
+        assert_eq!(lines[1]["event"], json!("publish_denied"));
+        assert_eq!(lines[1]["topic"], json!("a/b"));
+    }
+}
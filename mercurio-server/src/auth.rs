@@ -0,0 +1,855 @@
+use std::{
+    collections::HashMap,
+    fmt, io,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bytes::Bytes;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::{info, warn};
+
+use mercurio_core::{reason::ReasonCode, Result};
+use mercurio_packets::connect::ConnectPacket;
+
+use crate::{control, topic_tree};
+
+/// Credentials presented by a client in its CONNECT packet.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Credentials {
+    pub client_id: String,
+    pub user_name: Option<String>,
+    pub password: Option<Bytes>,
+}
+
+impl From<&ConnectPacket> for Credentials {
+    fn from(packet: &ConnectPacket) -> Self {
+        Credentials {
+            client_id: packet.payload.client_id.clone(),
+            user_name: packet.payload.user_name.clone(),
+            password: packet.payload.password.clone(),
+        }
+    }
+}
+
+/// What an authenticated client is allowed to do.
+///
+/// Both lists hold topic filters. `None` places no restriction, while an
+/// empty list forbids everything. Administrative commands are the exception:
+/// publishing under `$CONTROL/` takes a filter such as `$CONTROL/#`, which
+/// `None` doesn't amount to.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct Authorization {
+    /// Filters of the topics the client may publish on.
+    #[serde(default)]
+    pub publish: Option<Vec<String>>,
+
+    /// Filters the client may subscribe to, or narrower ones.
+    #[serde(default)]
+    pub subscribe: Option<Vec<String>>,
+
+    /// User name the client proved to be, if the validator vouched for one.
+    /// Unlike the one of its CONNECT packet, it can be relied upon.
+    #[serde(skip)]
+    pub user_name: Option<String>,
+}
+
+impl Authorization {
+    pub(crate) fn can_publish(&self, topic: &str) -> bool {
+        match &self.publish {
+            Some(filters) => filters.iter().any(|f| topic_tree::matches(f, topic)),
+            None => !control::is_command(topic),
+        }
+    }
+
+    pub(crate) fn can_subscribe(&self, filter: &str) -> bool {
+        match &self.subscribe {
+            Some(filters) => filters.iter().any(|f| covers(f, filter)),
+            None => true,
+        }
+    }
+}
+
+/// Returns `true` if every topic matching `filter` also matches `allowed`.
+fn covers(allowed: &str, filter: &str) -> bool {
+    let mut allowed_levels = allowed.split('/');
+    let mut filter_levels = filter.split('/');
+
+    loop {
+        match (allowed_levels.next(), filter_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(level)) if level != "#" => {}
+            (Some(a), Some(f)) if a == f => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Checks the credentials of the clients connecting to the broker.
+#[async_trait]
+pub trait CredentialValidator: fmt::Debug + Send + Sync {
+    /// Returns what the client is allowed to do, or fails with the reason
+    /// code the connection is refused with.
+    async fn validate(&self, credentials: &Credentials) -> Result<Authorization>;
+
+    /// Reloads the credentials, for validators keeping a copy of them. Does
+    /// nothing by default.
+    async fn reload(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Outcome of a step of an enhanced authentication exchange.
+#[derive(Debug)]
+pub enum AuthStep {
+    /// The exchange goes on, with the data sent to the client in an AUTH
+    /// packet.
+    Continue(Bytes),
+
+    /// The client is authenticated. The data, if any, is sent along with the
+    /// CONNACK.
+    Success {
+        data: Option<Bytes>,
+        authorization: Authorization,
+    },
+}
+
+/// Enhanced authentication method, as requested by clients with the
+/// Authentication Method property of their CONNECT.
+pub trait AuthMethod: fmt::Debug + Send + Sync {
+    /// Name of the method, matched against the Authentication Method
+    /// property.
+    fn name(&self) -> &str;
+
+    /// Creates the state of an exchange with a connecting client.
+    fn start(&self, credentials: &Credentials) -> Box<dyn AuthSession>;
+}
+
+/// State of an enhanced authentication exchange with a single connection.
+///
+/// The session is fed the Authentication Data of the CONNECT first, then the
+/// one of every AUTH packet from the client, until it succeeds or fails.
+#[async_trait]
+pub trait AuthSession: Send {
+    /// Handles the Authentication Data of the CONNECT packet.
+    async fn start(&mut self, data: Option<Bytes>) -> Result<AuthStep>;
+
+    /// Handles the Authentication Data of an AUTH packet from the client.
+    async fn auth_continue(&mut self, data: Option<Bytes>) -> Result<AuthStep>;
+}
+
+/// Enhanced authentication methods supported by the server, by name.
+#[derive(Debug, Clone, Default)]
+pub struct AuthManager {
+    methods: HashMap<String, Arc<dyn AuthMethod>>,
+}
+
+impl AuthManager {
+    pub fn new() -> AuthManager {
+        AuthManager::default()
+    }
+
+    pub fn register(&mut self, method: Arc<dyn AuthMethod>) {
+        self.methods.insert(method.name().to_string(), method);
+    }
+
+    /// Starts an exchange with the method called `name`, if supported.
+    pub(crate) fn start(
+        &self,
+        name: &str,
+        credentials: &Credentials,
+    ) -> Option<Box<dyn AuthSession>> {
+        self.methods
+            .get(name)
+            .map(|method| method.start(credentials))
+    }
+}
+
+/// Fixed set of user names and passwords, granting full access.
+#[derive(Debug, Clone, Default)]
+pub struct StaticCredentials {
+    pub users: HashMap<String, Bytes>,
+}
+
+#[async_trait]
+impl CredentialValidator for StaticCredentials {
+    async fn validate(&self, credentials: &Credentials) -> Result<Authorization> {
+        let user_name = credentials.user_name.as_ref();
+
+        match user_name.and_then(|name| self.users.get(name)) {
+            Some(password) if credentials.password.as_ref() == Some(password) => {
+                Ok(Authorization {
+                    user_name: user_name.cloned(),
+                    ..Default::default()
+                })
+            }
+            _ => Err(ReasonCode::BadUserNameOrPassword.into()),
+        }
+    }
+}
+
+/// Accepts every client without looking at its credentials, granting them
+/// the same access, for instance read-only on an internal listener.
+#[derive(Debug, Clone, Default)]
+pub struct AnonymousAccess {
+    pub authorization: Authorization,
+}
+
+#[async_trait]
+impl CredentialValidator for AnonymousAccess {
+    async fn validate(&self, _credentials: &Credentials) -> Result<Authorization> {
+        Ok(self.authorization.clone())
+    }
+}
+
+/// User names and password hashes read from a file, which can be reloaded
+/// while the broker runs.
+///
+/// Each line holds a user name and the hash of their password, as made by
+/// [`PasswordFile::hash_password`], separated by a colon:
+///
+/// ```text
+/// # Comments and empty lines are ignored
+/// sensor-1:$pbkdf2-sha256$100000$<salt>$<hash>
+/// ```
+///
+/// Reloading swaps the whole set of users at once, and only once the file
+/// was read successfully. Connected clients stay connected, the new set only
+/// applies to clients connecting from then on.
+#[derive(Debug, Clone)]
+pub struct PasswordFile {
+    path: PathBuf,
+    users: Arc<RwLock<Arc<HashMap<String, PasswordHash>>>>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct PasswordHash {
+    iterations: u32,
+    salt: Vec<u8>,
+    hash: [u8; 32],
+}
+
+impl PasswordFile {
+    /// Name of the hashing scheme, at the start of every hash.
+    const SCHEME: &'static str = "pbkdf2-sha256";
+
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<PasswordFile> {
+        let path = path.into();
+        let users = PasswordFile::parse(&std::fs::read_to_string(&path)?)?;
+
+        Ok(PasswordFile {
+            path,
+            users: Arc::new(RwLock::new(Arc::new(users))),
+        })
+    }
+
+    /// Reads the file again. If it can't be, the users read last are kept.
+    ///
+    /// Meant to be called when the file changes, on SIGHUP, or from an
+    /// administration API, see also [`PasswordFile::watch`].
+    pub async fn reload(&self) -> io::Result<()> {
+        let content = tokio::fs::read_to_string(&self.path).await?;
+        let users = PasswordFile::parse(&content)?;
+
+        *self.users.write().unwrap() = Arc::new(users);
+
+        Ok(())
+    }
+
+    fn parse(content: &str) -> io::Result<HashMap<String, PasswordHash>> {
+        let mut users = HashMap::new();
+
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let entry = line
+                .split_once(':')
+                .and_then(|(user_name, hash)| Some((user_name, PasswordHash::parse(hash)?)));
+
+            match entry {
+                Some((user_name, hash)) => users.insert(user_name.to_string(), hash),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("malformed entry on line {}", number + 1),
+                    ))
+                }
+            };
+        }
+
+        Ok(users)
+    }
+
+    /// Reloads the file whenever its modification time changes, checking
+    /// every `interval`. Runs until the task it's spawned on is dropped.
+    pub async fn watch(self, interval: Duration) {
+        let modified = |path: PathBuf| async move {
+            tokio::fs::metadata(path)
+                .await
+                .and_then(|m| m.modified())
+                .ok()
+        };
+        let mut last_modified = modified(self.path.clone()).await;
+
+        loop {
+            tokio::time::sleep(interval).await;
+
+            let current = modified(self.path.clone()).await;
+            if current == last_modified {
+                continue;
+            }
+            last_modified = current;
+
+            match self.reload().await {
+                Ok(()) => info!("Reloaded password file {:?}", self.path),
+                Err(err) => warn!(cause = ?err, "Failed to reload password file {:?}", self.path),
+            }
+        }
+    }
+
+    /// Hashes `password` with a random salt, for an entry of the file.
+    pub fn hash_password(password: &[u8], iterations: u32) -> String {
+        let mut salt = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+
+        let hash = PasswordHash::new(password, &salt, iterations);
+
+        format!(
+            "${}${}${}${}",
+            PasswordFile::SCHEME,
+            hash.iterations,
+            BASE64.encode(&hash.salt),
+            BASE64.encode(hash.hash)
+        )
+    }
+}
+
+impl PasswordHash {
+    fn new(password: &[u8], salt: &[u8], iterations: u32) -> PasswordHash {
+        let mut hash = [0u8; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(password, salt, iterations, &mut hash);
+
+        PasswordHash {
+            iterations,
+            salt: salt.to_vec(),
+            hash,
+        }
+    }
+
+    /// Stands in for the hash of a user who isn't in the file, as costly to
+    /// check as the costliest one that is.
+    fn unknown_user(users: &HashMap<String, PasswordHash>) -> PasswordHash {
+        PasswordHash {
+            iterations: users
+                .values()
+                .map(|hash| hash.iterations)
+                .max()
+                .unwrap_or(1),
+            salt: vec![0; 16],
+            hash: [0; 32],
+        }
+    }
+
+    /// Parses `$pbkdf2-sha256$<iterations>$<salt>$<hash>`.
+    fn parse(hash: &str) -> Option<PasswordHash> {
+        let mut parts = hash.strip_prefix('$')?.split('$');
+
+        if parts.next()? != PasswordFile::SCHEME {
+            return None;
+        }
+
+        let iterations = parts.next()?.parse().ok()?;
+        let salt = BASE64.decode(parts.next()?).ok()?;
+        let hash = BASE64.decode(parts.next()?).ok()?.try_into().ok()?;
+
+        match parts.next() {
+            Some(_) => None,
+            None => Some(PasswordHash {
+                iterations,
+                salt,
+                hash,
+            }),
+        }
+    }
+
+    fn verify(&self, password: &[u8]) -> bool {
+        let candidate = PasswordHash::new(password, &self.salt, self.iterations);
+
+        // Compared in constant time
+        candidate
+            .hash
+            .iter()
+            .zip(self.hash)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+    }
+}
+
+#[async_trait]
+impl CredentialValidator for PasswordFile {
+    async fn validate(&self, credentials: &Credentials) -> Result<Authorization> {
+        let users = self.users.read().unwrap().clone();
+        let (user_name, password) = match (&credentials.user_name, &credentials.password) {
+            (Some(user_name), Some(password)) => (user_name.clone(), password.clone()),
+            _ => return Err(ReasonCode::BadUserNameOrPassword.into()),
+        };
+
+        // Hashing takes long on purpose, keep it off the runtime threads
+        let verified = tokio::task::spawn_blocking({
+            let user_name = user_name.clone();
+            move || match users.get(&user_name) {
+                Some(hash) => hash.verify(&password),
+                // Hashed all the same, so that how long it takes doesn't
+                // tell which user names exist
+                None => {
+                    std::hint::black_box(PasswordHash::unknown_user(&users).verify(&password));
+                    false
+                }
+            }
+        })
+        .await
+        .map_err(|_| ReasonCode::UnspecifiedError)?;
+
+        if verified {
+            Ok(Authorization {
+                user_name: Some(user_name),
+                ..Default::default()
+            })
+        } else {
+            Err(ReasonCode::BadUserNameOrPassword.into())
+        }
+    }
+
+    async fn reload(&self) -> Result<()> {
+        Ok(PasswordFile::reload(self).await?)
+    }
+}
+
+/// Delegates validation to an HTTP endpoint.
+///
+/// The credentials are POSTed as JSON with the `client_id`, `username` and
+/// `password` fields. The endpoint answers with `allow` set to whether the
+/// client may connect, and optionally the `publish` and `subscribe` lists of
+/// an [`Authorization`]. If the endpoint can't be reached, connections are
+/// refused with ServerUnavailable.
+#[derive(Debug, Clone)]
+pub struct WebhookValidator {
+    url: String,
+    client: reqwest::Client,
+}
+
+#[derive(Serialize)]
+struct WebhookRequest<'a> {
+    client_id: &'a str,
+    username: Option<&'a str>,
+    password: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct WebhookResponse {
+    allow: bool,
+
+    #[serde(flatten)]
+    authorization: Authorization,
+}
+
+impl WebhookValidator {
+    pub fn new(url: impl Into<String>, timeout: Duration) -> WebhookValidator {
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .build()
+            .expect("failed to build the webhook HTTP client");
+
+        WebhookValidator {
+            url: url.into(),
+            client,
+        }
+    }
+}
+
+#[async_trait]
+impl CredentialValidator for WebhookValidator {
+    async fn validate(&self, credentials: &Credentials) -> Result<Authorization> {
+        let request = WebhookRequest {
+            client_id: &credentials.client_id,
+            username: credentials.user_name.as_deref(),
+            password: credentials
+                .password
+                .as_ref()
+                .map(|p| String::from_utf8_lossy(p).into_owned()),
+        };
+
+        let response = self
+            .client
+            .post(&self.url)
+            .json(&request)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+
+        let response: WebhookResponse = match response {
+            Ok(response) => response.json().await,
+            Err(err) => Err(err),
+        }
+        .map_err(|err| {
+            warn!(cause = ?err, "Auth webhook `{}` failed", self.url);
+            ReasonCode::ServerUnavailable
+        })?;
+
+        // The endpoint vouched for the user name along with the password
+        match response.allow {
+            true => Ok(Authorization {
+                user_name: credentials.user_name.clone(),
+                ..response.authorization
+            }),
+            false => Err(ReasonCode::BadUserNameOrPassword.into()),
+        }
+    }
+}
+
+/// Accepts JSON Web Tokens passed as the password.
+///
+/// Tokens must be signed with the configured key and not be expired. If the
+/// token has a `sub` claim and the client sent a user name, they must be
+/// equal. The `sub` claim is the user name the client is known by from then
+/// on, it has none without. The optional `publish` and `subscribe` claims
+/// restrict what the client may do, as in an [`Authorization`].
+#[derive(Clone)]
+pub struct JwtValidator {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: Option<String>,
+
+    #[serde(flatten)]
+    authorization: Authorization,
+}
+
+impl JwtValidator {
+    pub fn new(key: DecodingKey, algorithm: Algorithm) -> JwtValidator {
+        JwtValidator {
+            key,
+            validation: Validation::new(algorithm),
+        }
+    }
+
+    /// Validates tokens signed with HMAC-SHA256 using `secret`.
+    pub fn hs256(secret: &[u8]) -> JwtValidator {
+        JwtValidator::new(DecodingKey::from_secret(secret), Algorithm::HS256)
+    }
+}
+
+impl fmt::Debug for JwtValidator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JwtValidator")
+            .field("algorithms", &self.validation.algorithms)
+            .finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl CredentialValidator for JwtValidator {
+    async fn validate(&self, credentials: &Credentials) -> Result<Authorization> {
+        let token = credentials
+            .password
+            .as_ref()
+            .and_then(|p| std::str::from_utf8(p).ok())
+            .ok_or(ReasonCode::BadUserNameOrPassword)?;
+
+        let claims = jsonwebtoken::decode::<Claims>(token, &self.key, &self.validation)
+            .map_err(|_| ReasonCode::BadUserNameOrPassword)?
+            .claims;
+
+        if let (Some(sub), Some(user_name)) = (&claims.sub, &credentials.user_name) {
+            if sub != user_name {
+                return Err(ReasonCode::BadUserNameOrPassword.into());
+            }
+        }
+
+        // Only the subject of the token identifies the client, not a user
+        // name it came up with
+        Ok(Authorization {
+            user_name: claims.sub,
+            ..claims.authorization
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use bytes::Bytes;
+    use jsonwebtoken::{EncodingKey, Header};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use mercurio_core::{error::Error, reason::ReasonCode};
+
+    use super::{
+        Authorization, CredentialValidator, Credentials, JwtValidator, PasswordFile, PasswordHash,
+        StaticCredentials, WebhookValidator,
+    };
+
+    fn credentials(user_name: &str, password: &str) -> Credentials {
+        Credentials {
+            client_id: "client".to_string(),
+            user_name: Some(user_name.to_string()),
+            password: Some(Bytes::from(password.to_string())),
+        }
+    }
+
+    fn reason(result: mercurio_core::Result<Authorization>) -> ReasonCode {
+        match result {
+            Err(Error::MQTTReasonCode(reason)) => reason,
+            other => panic!("Expected a reason code, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_authorization() {
+        let authorization = Authorization {
+            publish: Some(vec!["devices/+/data".to_string()]),
+            subscribe: Some(vec!["commands/#".to_string(), "status/+".to_string()]),
+            ..Default::default()
+        };
+
+        assert!(authorization.can_publish("devices/1/data"));
+        assert!(!authorization.can_publish("devices/1/other"));
+
+        assert!(authorization.can_subscribe("commands/#"));
+        assert!(authorization.can_subscribe("commands/+/reboot"));
+        assert!(authorization.can_subscribe("status/device"));
+        assert!(!authorization.can_subscribe("status/#"));
+        assert!(!authorization.can_subscribe("#"));
+
+        assert!(Authorization::default().can_subscribe("#"));
+
+        // Administrative commands take an explicit grant
+        assert!(!Authorization::default().can_publish("$CONTROL/retained/delete"));
+        assert!(!authorization.can_publish("$CONTROL/retained/delete"));
+
+        let admin = Authorization {
+            publish: Some(vec!["$CONTROL/#".to_string()]),
+            ..Default::default()
+        };
+        assert!(admin.can_publish("$CONTROL/retained/delete"));
+    }
+
+    #[tokio::test]
+    async fn test_static_credentials() {
+        let mut validator = StaticCredentials::default();
+        validator
+            .users
+            .insert("user".to_string(), Bytes::from("secret"));
+
+        assert!(validator
+            .validate(&credentials("user", "secret"))
+            .await
+            .is_ok());
+        assert_eq!(
+            reason(validator.validate(&credentials("user", "wrong")).await),
+            ReasonCode::BadUserNameOrPassword
+        );
+        assert_eq!(
+            reason(validator.validate(&Credentials::default()).await),
+            ReasonCode::BadUserNameOrPassword
+        );
+    }
+
+    #[tokio::test]
+    async fn test_password_file_reload() {
+        let path = std::env::temp_dir().join(format!("mercurio-passwd-{}", uuid::Uuid::new_v4()));
+        let entry = |user_name: &str, password: &str| {
+            format!(
+                "{}:{}\n",
+                user_name,
+                PasswordFile::hash_password(password.as_bytes(), 1000)
+            )
+        };
+
+        std::fs::write(&path, format!("# Users\n{}", entry("alice", "secret"))).unwrap();
+        let validator = PasswordFile::open(&path).unwrap();
+
+        assert!(validator
+            .validate(&credentials("alice", "secret"))
+            .await
+            .is_ok());
+        assert_eq!(
+            reason(validator.validate(&credentials("alice", "wrong")).await),
+            ReasonCode::BadUserNameOrPassword
+        );
+
+        // Alice is revoked, Bob added
+        std::fs::write(&path, entry("bob", "hunter2")).unwrap();
+        validator.reload().await.unwrap();
+
+        assert_eq!(
+            reason(validator.validate(&credentials("alice", "secret")).await),
+            ReasonCode::BadUserNameOrPassword
+        );
+        assert!(validator
+            .validate(&credentials("bob", "hunter2"))
+            .await
+            .is_ok());
+
+        // A malformed file leaves the users as they were
+        std::fs::write(&path, "carol:plaintext\n").unwrap();
+        assert!(validator.reload().await.is_err());
+        assert!(validator
+            .validate(&credentials("bob", "hunter2"))
+            .await
+            .is_ok());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_password_file_unknown_user() {
+        let path = std::env::temp_dir().join(format!("mercurio-passwd-{}", uuid::Uuid::new_v4()));
+        std::fs::write(
+            &path,
+            format!(
+                "alice:{}\nbob:{}\n",
+                PasswordFile::hash_password(b"secret", 1000),
+                PasswordFile::hash_password(b"hunter2", 2000)
+            ),
+        )
+        .unwrap();
+        let validator = PasswordFile::open(&path).unwrap();
+
+        assert_eq!(
+            reason(validator.validate(&credentials("carol", "secret")).await),
+            ReasonCode::BadUserNameOrPassword
+        );
+
+        // Checked as slowly as the slowest known user
+        let users = validator.users.read().unwrap().clone();
+        let unknown = PasswordHash::unknown_user(&users);
+        assert_eq!(unknown.iterations, 2000);
+        assert!(!unknown.verify(b"secret"));
+        assert!(!unknown.verify(b""));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_jwt_validator() {
+        let exp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 60;
+        let claims = serde_json::json!({
+            "sub": "user",
+            "exp": exp,
+            "publish": ["devices/user/#"],
+        });
+        let token = jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+
+        let validator = JwtValidator::hs256(b"secret");
+
+        let authorization = validator
+            .validate(&credentials("user", &token))
+            .await
+            .unwrap();
+        assert!(authorization.can_publish("devices/user/data"));
+        assert!(!authorization.can_publish("devices/other/data"));
+        assert!(authorization.can_subscribe("anything"));
+        assert_eq!(authorization.user_name.as_deref(), Some("user"));
+
+        assert_eq!(
+            reason(validator.validate(&credentials("other", &token)).await),
+            ReasonCode::BadUserNameOrPassword
+        );
+        assert_eq!(
+            reason(
+                JwtValidator::hs256(b"other")
+                    .validate(&credentials("user", &token))
+                    .await
+            ),
+            ReasonCode::BadUserNameOrPassword
+        );
+    }
+
+    /// Answers a single HTTP request with `body`.
+    async fn serve_once(listener: TcpListener, body: &'static str) {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+
+        // Read until the end of the JSON body
+        while !request.ends_with(b"}") {
+            let mut buf = [0; 1024];
+            let n = socket.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+        }
+
+        assert!(String::from_utf8_lossy(&request).contains(r#""username":"user""#));
+
+        let response = format!(
+            "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        socket.write_all(response.as_bytes()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_webhook_validator() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/auth", listener.local_addr().unwrap());
+        let validator = WebhookValidator::new(url, Duration::from_secs(1));
+
+        let server = tokio::spawn(serve_once(
+            listener,
+            r#"{"allow":true,"subscribe":["a/#"]}"#,
+        ));
+        let authorization = validator
+            .validate(&credentials("user", "secret"))
+            .await
+            .unwrap();
+        server.await.unwrap();
+
+        assert!(authorization.can_subscribe("a/b"));
+        assert!(!authorization.can_subscribe("b"));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/auth", listener.local_addr().unwrap());
+        let validator = WebhookValidator::new(url, Duration::from_secs(1));
+
+        let server = tokio::spawn(serve_once(listener, r#"{"allow":false}"#));
+        assert_eq!(
+            reason(validator.validate(&credentials("user", "secret")).await),
+            ReasonCode::BadUserNameOrPassword
+        );
+        server.await.unwrap();
+
+        // Nobody listening
+        let validator = WebhookValidator::new("http://127.0.0.1:1/auth", Duration::from_secs(1));
+        assert_eq!(
+            reason(validator.validate(&credentials("user", "secret")).await),
+            ReasonCode::ServerUnavailable
+        );
+    }
+}
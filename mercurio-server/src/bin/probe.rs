@@ -0,0 +1,272 @@
+//! Checks that a broker can be connected to, and reports what it advertises.
+//!
+//! ```text
+//! mercurio-probe [ADDRESS] [--client-id ID] [--username NAME] [--password PASSWORD]
+//! ```
+//!
+//! Connects to `ADDRESS`, `127.0.0.1:1883` by default, prints the CONNACK
+//! along with the time it took, measures the round trip of a PINGREQ, then
+//! disconnects. Exits with a failure if the connection is refused, so it can
+//! be used to validate deployments from CI.
+
+use std::{fmt::Debug, process::ExitCode, time::Duration};
+
+use bytes::Bytes;
+use tokio::{
+    net::TcpStream,
+    time::{self, Instant},
+};
+
+use mercurio_core::reason::ReasonCode;
+use mercurio_packets::{
+    connack::ConnAckPacket,
+    connect::{ConnectFlags, ConnectPacket, ConnectPayload},
+    disconnect::DisconnectPacket,
+    pingreq::PingReqPacket,
+    ControlPacket,
+};
+use mercurio_server::connection::Connection;
+
+/// Time each step can take before the broker is deemed unreachable.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Default)]
+struct Args {
+    address: Option<String>,
+    client_id: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{}", err);
+            eprintln!(
+                "Usage: mercurio-probe [ADDRESS] [--client-id ID] [--username NAME] [--password PASSWORD]"
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match probe(args).await {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(err) => {
+            eprintln!("Probe failed: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args::default();
+
+    while let Some(arg) = args.next() {
+        let field = match arg.as_str() {
+            "--client-id" => &mut parsed.client_id,
+            "--username" => &mut parsed.username,
+            "--password" => &mut parsed.password,
+            _ if arg.starts_with("--") => return Err(format!("Unknown option `{}`", arg)),
+            _ if parsed.address.is_none() => {
+                parsed.address = Some(arg);
+                continue;
+            }
+            _ => return Err(format!("Unexpected argument `{}`", arg)),
+        };
+
+        *field = Some(args.next().ok_or(format!("Missing value of `{}`", arg))?);
+    }
+
+    Ok(parsed)
+}
+
+/// Returns whether the broker accepted the connection.
+async fn probe(args: Args) -> Result<bool, String> {
+    let address = args.address.as_deref().unwrap_or("127.0.0.1:1883");
+    let started = Instant::now();
+
+    let socket = time::timeout(TIMEOUT, TcpStream::connect(address))
+        .await
+        .map_err(|_| format!("Couldn't connect to {} within {:?}", address, TIMEOUT))?
+        .map_err(|err| format!("Couldn't connect to {}: {}", address, err))?;
+    let mut connection = Connection::new(socket);
+
+    let connect = ConnectPacket {
+        flags: ConnectFlags {
+            user_name: args.username.is_some(),
+            password: args.password.is_some(),
+            clean_start: true,
+            ..Default::default()
+        },
+        keepalive: 0,
+        properties: None,
+        payload: ConnectPayload {
+            client_id: args.client_id.unwrap_or_default(),
+            user_name: args.username,
+            password: args.password.map(Bytes::from),
+            ..Default::default()
+        },
+    };
+
+    connection
+        .write_packet(ControlPacket::Connect(connect))
+        .await
+        .map_err(|err| err.to_string())?;
+
+    let connack = match read_packet(&mut connection).await? {
+        ControlPacket::ConnAck(connack) => connack,
+        packet => return Err(format!("Expected a CONNACK, got {:?}", packet)),
+    };
+
+    println!("Connected to {} in {:?}", address, started.elapsed());
+    println!("Protocol version: 5");
+    report_connack(&connack);
+
+    if connack.reason_code.get_code() >= 0x80 {
+        return Ok(false);
+    }
+
+    let started = Instant::now();
+
+    connection
+        .write_packet(ControlPacket::PingReq(PingReqPacket {}))
+        .await
+        .map_err(|err| err.to_string())?;
+
+    match read_packet(&mut connection).await? {
+        ControlPacket::PingResp(_) => println!("Ping round trip: {:?}", started.elapsed()),
+        packet => return Err(format!("Expected a PINGRESP, got {:?}", packet)),
+    }
+
+    let disconnect = DisconnectPacket {
+        reason: ReasonCode::NormalDisconnection,
+        properties: None,
+    };
+
+    connection
+        .write_packet(ControlPacket::Disconnect(disconnect))
+        .await
+        .map_err(|err| err.to_string())?;
+
+    Ok(true)
+}
+
+async fn read_packet(connection: &mut Connection) -> Result<ControlPacket, String> {
+    time::timeout(TIMEOUT, connection.read_packet())
+        .await
+        .map_err(|_| format!("No answer within {:?}", TIMEOUT))?
+        .map_err(|err| err.to_string())?
+        .ok_or_else(|| "The broker closed the connection".to_string())
+}
+
+fn report_connack(connack: &ConnAckPacket) {
+    println!(
+        "CONNACK: {} (0x{:02x})",
+        connack.reason_code,
+        connack.reason_code.get_code()
+    );
+    println!("Session present: {}", connack.flags.session_present);
+
+    let properties = match &connack.properties {
+        Some(properties) => properties,
+        None => return,
+    };
+
+    let value = |name: &str, value: Option<&dyn Debug>| {
+        if let Some(value) = value {
+            println!("{}: {:?}", name, value);
+        }
+    };
+
+    value(
+        "Session Expiry Interval",
+        properties
+            .session_expiry_interval
+            .as_ref()
+            .map(|p| &p.value as _),
+    );
+    value(
+        "Receive Maximum",
+        properties.receive_maximum.as_ref().map(|p| &p.value as _),
+    );
+    value(
+        "Maximum QoS",
+        properties.maximum_qos.as_ref().map(|p| &p.value as _),
+    );
+    value(
+        "Retain Available",
+        properties.retain_available.as_ref().map(|p| &p.value as _),
+    );
+    value(
+        "Maximum Packet Size",
+        properties
+            .maximum_packet_size
+            .as_ref()
+            .map(|p| &p.value as _),
+    );
+    value(
+        "Assigned Client Identifier",
+        properties
+            .assigned_client_id
+            .as_ref()
+            .map(|p| &p.value as _),
+    );
+    value(
+        "Topic Alias Maximum",
+        properties.topic_alias_max.as_ref().map(|p| &p.value as _),
+    );
+    value(
+        "Reason String",
+        properties.reason_string.as_ref().map(|p| &p.value as _),
+    );
+    value(
+        "Wildcard Subscription Available",
+        properties
+            .wildcard_subscription_available
+            .as_ref()
+            .map(|p| &p.value as _),
+    );
+    value(
+        "Subscription Identifier Available",
+        properties
+            .subscription_identifier_available
+            .as_ref()
+            .map(|p| &p.value as _),
+    );
+    value(
+        "Shared Subscription Available",
+        properties
+            .shared_subscription_available
+            .as_ref()
+            .map(|p| &p.value as _),
+    );
+    value(
+        "Server Keep Alive",
+        properties.server_keepalive.as_ref().map(|p| &p.value as _),
+    );
+    value(
+        "Response Information",
+        properties
+            .response_information
+            .as_ref()
+            .map(|p| &p.value as _),
+    );
+    value(
+        "Server Reference",
+        properties.server_reference.as_ref().map(|p| &p.value as _),
+    );
+    value(
+        "Authentication Method",
+        properties
+            .authentication_method
+            .as_ref()
+            .map(|p| &p.value as _),
+    );
+
+    for property in properties.user_property.iter().flatten() {
+        println!("User Property: {:?} = {:?}", property.key, property.value);
+    }
+}
@@ -0,0 +1,802 @@
+use std::collections::HashSet;
+
+use bytes::Bytes;
+use tokio::{
+    net::TcpStream,
+    sync::mpsc,
+    time::{self, Duration, Instant},
+};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use mercurio_core::{
+    error::Error,
+    message::Message,
+    properties::{
+        ContentType, CorrelationData, MessageExpiryInterval, PayloadFormatIndicator,
+        ReceiveMaximum, ResponseTopic,
+    },
+    qos::QoS,
+    reason::ReasonCode,
+    Result,
+};
+use mercurio_packets::{
+    connect::{ConnectFlags, ConnectPacket, ConnectPayload, ConnectProperties},
+    disconnect::DisconnectPacket,
+    pingreq::PingReqPacket,
+    puback::PubAckPacket,
+    pubcomp::PubCompPacket,
+    publish::{PublishPacket, PublishProperties},
+    pubrec::PubRecPacket,
+    pubrel::PubRelPacket,
+    subscribe::{RetainHandling, SubscribePacket, SubscribePayload, SubscriptionOptions},
+    ControlPacket,
+};
+
+use crate::{
+    broker::{self, Broker},
+    cluster,
+    connection::Connection,
+    fanout::FanoutPool,
+    session::{expires_at, remaining_secs},
+    shutdown::Shutdown,
+    topic_tree,
+};
+
+/// Direction in which messages flow over a bridged topic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BridgeDirection {
+    /// Messages published on the remote broker are forwarded to this broker.
+    In,
+    /// Messages published on this broker are forwarded to the remote broker.
+    Out,
+    /// Messages are forwarded in both directions.
+    Both,
+}
+
+/// A topic pattern forwarded over a bridge.
+///
+/// The pattern is relative to the prefixes: it is subscribed to as
+/// `local_prefix + pattern` on this broker and as `remote_prefix + pattern` on
+/// the remote one, and matching topics have one prefix swapped for the other
+/// when crossing the bridge.
+#[derive(Debug, Clone)]
+pub struct BridgeTopic {
+    pub pattern: String,
+    pub direction: BridgeDirection,
+
+    /// Maximum QoS used when forwarding messages over the bridge.
+    pub qos: QoS,
+    pub local_prefix: String,
+    pub remote_prefix: String,
+}
+
+impl BridgeTopic {
+    pub fn new(pattern: impl Into<String>, direction: BridgeDirection, qos: QoS) -> BridgeTopic {
+        BridgeTopic {
+            pattern: pattern.into(),
+            direction,
+            qos,
+            local_prefix: String::new(),
+            remote_prefix: String::new(),
+        }
+    }
+
+    fn is_inbound(&self) -> bool {
+        self.direction != BridgeDirection::Out
+    }
+
+    fn is_outbound(&self) -> bool {
+        self.direction != BridgeDirection::In
+    }
+
+    fn local_filter(&self) -> String {
+        format!("{}{}", self.local_prefix, self.pattern)
+    }
+
+    fn remote_filter(&self) -> String {
+        format!("{}{}", self.remote_prefix, self.pattern)
+    }
+
+    /// Maps a topic published on this broker to its name on the remote one.
+    fn to_remote(&self, topic: &str) -> Option<String> {
+        remap(
+            topic,
+            &self.local_prefix,
+            &self.remote_prefix,
+            &self.pattern,
+        )
+    }
+
+    /// Maps a topic published on the remote broker to its local name.
+    fn to_local(&self, topic: &str) -> Option<String> {
+        remap(
+            topic,
+            &self.remote_prefix,
+            &self.local_prefix,
+            &self.pattern,
+        )
+    }
+}
+
+fn remap(topic: &str, from: &str, to: &str, pattern: &str) -> Option<String> {
+    let relative = topic.strip_prefix(from)?;
+
+    if !topic_tree::matches(pattern, relative) {
+        return None;
+    }
+
+    Some(format!("{}{}", to, relative))
+}
+
+/// Configuration of a bridge to a remote broker.
+#[derive(Debug, Clone)]
+pub struct BridgeConfig {
+    /// Name used to identify the bridge in logs.
+    pub name: String,
+
+    /// Address of the remote broker, as `host:port`.
+    pub address: String,
+
+    /// Client identifier used when connecting to the remote broker. Messages
+    /// carrying this identifier as their origin are never forwarded back.
+    pub client_id: String,
+    pub clean_start: bool,
+    pub keepalive: u16,
+    pub user_name: Option<String>,
+    pub password: Option<Bytes>,
+
+    /// QoS 1 and 2 messages the remote broker may have in flight to the
+    /// bridge, sent as the Receive Maximum of its CONNECT, 65535 if `None`.
+    /// They're only acknowledged once the local broker took them, so the
+    /// remote broker is held back while the bridge is busy. Going over it
+    /// closes the connection with Receive Maximum Exceeded.
+    pub receive_maximum: Option<u16>,
+
+    /// Time to wait before reconnecting after the connection is lost.
+    pub reconnect_delay: Duration,
+    pub topics: Vec<BridgeTopic>,
+}
+
+impl BridgeConfig {
+    pub fn new(name: impl Into<String>, address: impl Into<String>) -> BridgeConfig {
+        let name = name.into();
+
+        BridgeConfig {
+            client_id: name.clone(),
+            name,
+            address: address.into(),
+            clean_start: false,
+            keepalive: 60,
+            user_name: None,
+            password: None,
+            receive_maximum: None,
+            reconnect_delay: Duration::from_secs(5),
+            topics: Vec::new(),
+        }
+    }
+}
+
+/// Maintains the connection to a remote broker, forwarding messages between
+/// it and the local broker until shutdown is signalled.
+pub(crate) struct Bridge {
+    config: BridgeConfig,
+    broker: Broker,
+    fanout: FanoutPool,
+    shutdown: Shutdown,
+    next_packet_id: u16,
+
+    /// Identifier the bridge subscribes to local messages with. It's its
+    /// own, so links sharing a client identifier, as those of a cluster node
+    /// do, don't take each other's messages nor collide with a client.
+    subscriber_id: String,
+
+    /// Identifiers of the QoS 2 messages received from the remote broker
+    /// whose PUBREL hasn't arrived yet.
+    pending_releases: HashSet<u16>,
+}
+
+impl Bridge {
+    pub(crate) fn new(
+        config: BridgeConfig,
+        broker: Broker,
+        fanout: FanoutPool,
+        shutdown: Shutdown,
+    ) -> Bridge {
+        let subscriber_id = format!(
+            "{}bridge/{}/{}",
+            broker::INTERNAL_PREFIX,
+            config.name,
+            Uuid::new_v4().hyphenated()
+        );
+
+        Bridge {
+            config,
+            broker,
+            fanout,
+            shutdown,
+            next_packet_id: 0,
+            subscriber_id,
+            pending_releases: HashSet::new(),
+        }
+    }
+
+    pub(crate) async fn run(&mut self) {
+        while !self.shutdown.is_shutdown() {
+            match self.connect().await {
+                Ok(mut connection) => {
+                    info!("Bridge `{}` connected", self.config.name);
+
+                    if let Err(err) = self.forward(&mut connection).await {
+                        error!(cause = ?err, "Bridge `{}` error", self.config.name);
+                    }
+                }
+                Err(err) => {
+                    error!(cause = ?err, "Bridge `{}` failed to connect", self.config.name);
+                }
+            }
+
+            let delay = self.config.reconnect_delay;
+
+            tokio::select! {
+                _ = time::sleep(delay) => {}
+                _ = self.shutdown.recv() => {}
+            }
+        }
+    }
+
+    async fn connect(&mut self) -> Result<Connection> {
+        let socket = TcpStream::connect(&self.config.address).await?;
+        let mut connection = Connection::new(socket);
+
+        let connect = ConnectPacket {
+            flags: ConnectFlags {
+                user_name: self.config.user_name.is_some(),
+                password: self.config.password.is_some(),
+                clean_start: self.config.clean_start,
+                ..Default::default()
+            },
+            keepalive: self.config.keepalive,
+            properties: Some(ConnectProperties {
+                receive_maximum: self.config.receive_maximum.map(ReceiveMaximum::new),
+                ..Default::default()
+            }),
+            payload: ConnectPayload {
+                client_id: self.config.client_id.clone(),
+                user_name: self.config.user_name.clone(),
+                password: self.config.password.clone(),
+                ..Default::default()
+            },
+        };
+
+        connection
+            .write_packet(ControlPacket::Connect(connect))
+            .await?;
+
+        match connection.read_packet().await? {
+            Some(ControlPacket::ConnAck(ack)) if ack.reason_code == ReasonCode::Success => {
+                // Messages still waiting for their PUBREL went away along
+                // with the remote session
+                if !ack.flags.session_present {
+                    self.pending_releases.clear();
+                }
+            }
+            Some(ControlPacket::ConnAck(ack)) => return Err(ack.reason_code.into()),
+            _ => return Err(ReasonCode::ProtocolError.into()),
+        }
+
+        let payload: Vec<SubscribePayload> = self
+            .config
+            .topics
+            .iter()
+            .filter(|t| t.is_inbound())
+            .map(|t| SubscribePayload {
+                topic_filter: t.remote_filter(),
+                subs_opt: SubscriptionOptions {
+                    qos: t.qos,
+                    // Don't get our own forwarded messages back
+                    no_local: true,
+                    retain_as_pub: true,
+                    retain_handling: RetainHandling::SendRetained,
+                },
+            })
+            .collect();
+
+        if !payload.is_empty() {
+            let subscribe = SubscribePacket {
+                packet_id: self.packet_id(),
+                properties: None,
+                payload,
+            };
+
+            connection
+                .write_packet(ControlPacket::Subscribe(subscribe))
+                .await?;
+        }
+
+        Ok(connection)
+    }
+
+    async fn forward(&mut self, connection: &mut Connection) -> Result<()> {
+        let mut local = self.subscribe_local();
+        let res = self.relay(connection, &mut local).await;
+
+        // Nothing is kept for the remote broker while disconnected
+        self.broker.unsubscribe_all(&self.subscriber_id);
+
+        res
+    }
+
+    /// Subscribes to the local messages to be forwarded to the remote broker.
+    fn subscribe_local(&self) -> mpsc::Receiver<Message> {
+        let (queue, local) = self.broker.queue();
+
+        for topic in self.config.topics.iter().filter(|t| t.is_outbound()) {
+            self.broker
+                .subscribe(&topic.local_filter(), &self.subscriber_id, queue.clone());
+        }
+
+        local
+    }
+
+    async fn relay(
+        &mut self,
+        connection: &mut Connection,
+        local: &mut mpsc::Receiver<Message>,
+    ) -> Result<()> {
+        let period = Duration::from_secs(u64::from(self.config.keepalive.max(1)));
+        let mut ping = time::interval_at(Instant::now() + period, period);
+
+        while !self.shutdown.is_shutdown() {
+            tokio::select! {
+                maybe_packet = connection.read_packet() => {
+                    let packet = match maybe_packet? {
+                        Some(packet) => packet,
+                        None => {
+                            info!("Bridge `{}` closed by remote", self.config.name);
+                            return Ok(());
+                        }
+                    };
+
+                    match self.process_remote(packet).await? {
+                        // The bridge gave up on the remote broker
+                        Some(ControlPacket::Disconnect(disconnect)) => {
+                            let reason = disconnect.reason;
+                            connection.write_packet(ControlPacket::Disconnect(disconnect)).await?;
+
+                            return Err(reason.into());
+                        }
+                        Some(res) => connection.write_packet(res).await?,
+                        None => {}
+                    }
+                }
+
+                Some(message) = local.recv() => {
+                    if let Some(publish) = self.process_local(message) {
+                        connection.write_packet(ControlPacket::Publish(publish)).await?;
+                    }
+                }
+
+                _ = ping.tick() => {
+                    connection.write_packet(ControlPacket::PingReq(PingReqPacket {})).await?;
+                }
+
+                _ = self.shutdown.recv() => {
+                    let disconnect = DisconnectPacket {
+                        reason: ReasonCode::NormalDisconnection,
+                        properties: None,
+                    };
+
+                    connection.write_packet(ControlPacket::Disconnect(disconnect)).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handles a packet received from the remote broker, returning the
+    /// response to be sent back, if any.
+    async fn process_remote(&mut self, packet: ControlPacket) -> Result<Option<ControlPacket>> {
+        match packet {
+            ControlPacket::Publish(packet) => {
+                // [MQTT-4.3.3-10]
+                // A message redelivered before its PUBREL is acknowledged
+                // again, but not forwarded twice.
+                if let (QoS::ExactlyOnce, Some(packet_id)) = (packet.qos_level, packet.packet_id) {
+                    if self.pending_releases.contains(&packet_id) {
+                        return Ok(ControlPacket::PubRec(PubRecPacket {
+                            packet_id,
+                            reason: ReasonCode::Success,
+                            properties: None,
+                        })
+                        .into());
+                    }
+
+                    // [MQTT-3.3.4-8]
+                    // The Client uses the value of Receive Maximum as an
+                    // upper bound of the QoS 2 messages it awaits the PUBREL
+                    // of. Other messages are acknowledged right away.
+                    let maximum = self.config.receive_maximum.unwrap_or(u16::MAX);
+
+                    if self.pending_releases.len() >= usize::from(maximum) {
+                        warn!(
+                            "Bridge `{}` got more messages than its Receive Maximum",
+                            self.config.name
+                        );
+
+                        return Ok(ControlPacket::Disconnect(DisconnectPacket {
+                            reason: ReasonCode::ReceiveMaximumExceeded,
+                            properties: None,
+                        })
+                        .into());
+                    }
+                }
+
+                let topic = self
+                    .config
+                    .topics
+                    .iter()
+                    .filter(|t| t.is_inbound())
+                    .find_map(|t| t.to_local(&packet.topic_name).map(|name| (name, t.qos)));
+
+                let mut reason = ReasonCode::Success;
+
+                if let Some((topic_name, max_qos)) = topic {
+                    let properties = packet.properties.unwrap_or_default();
+                    let message = Message {
+                        packet_id: packet.packet_id,
+                        topic: topic_name.clone(),
+                        dup: false,
+                        qos: packet.qos_level.min(max_qos),
+                        retain: packet.retain,
+                        payload: packet.payload,
+                        origin: Some(self.config.client_id.clone()),
+                        user_properties: properties.user_property,
+                        response_topic: properties.response_topic.map(|topic| topic.value),
+                        correlation_data: properties.correlation_data.map(|data| data.value),
+                        content_type: properties
+                            .content_type
+                            .map(|content_type| content_type.value),
+                        payload_format_indicator: properties
+                            .payload_format_indicator
+                            .map(|f| f.value),
+                        expires_at: expires_at(properties.message_expiry_interval),
+                    };
+
+                    // Refused if it couldn't be routed at all
+                    if let Err(err) = self.fanout.publish(topic_name.clone(), message).await {
+                        warn!(
+                            cause = ?err,
+                            "Bridge `{}` failed to forward `{}`", self.config.name, topic_name
+                        );
+
+                        reason = match err {
+                            Error::MQTTReasonCode(reason) => reason,
+                            _ => ReasonCode::UnspecifiedError,
+                        };
+                    }
+                }
+
+                let res = match (packet.qos_level, packet.packet_id) {
+                    (QoS::AtLeastOnce, Some(packet_id)) => ControlPacket::PubAck(PubAckPacket {
+                        packet_id,
+                        reason,
+                        properties: None,
+                    })
+                    .into(),
+                    (QoS::ExactlyOnce, Some(packet_id)) => {
+                        // A refused message is done with, no PUBREL follows
+                        if reason == ReasonCode::Success {
+                            self.pending_releases.insert(packet_id);
+                        }
+
+                        ControlPacket::PubRec(PubRecPacket {
+                            packet_id,
+                            reason,
+                            properties: None,
+                        })
+                        .into()
+                    }
+                    _ => None,
+                };
+
+                Ok(res)
+            }
+            ControlPacket::PubRec(packet) => Ok(ControlPacket::PubRel(PubRelPacket {
+                packet_id: packet.packet_id,
+                reason: ReasonCode::Success,
+                properties: None,
+            })
+            .into()),
+            ControlPacket::PubRel(packet) => {
+                self.pending_releases.remove(&packet.packet_id);
+
+                Ok(ControlPacket::PubComp(PubCompPacket {
+                    packet_id: packet.packet_id,
+                    reason: ReasonCode::Success,
+                    properties: None,
+                })
+                .into())
+            }
+            ControlPacket::SubAck(ack) => {
+                for res in ack.payload {
+                    if res.reason_code.get_code() >= 0x80 {
+                        warn!(
+                            "Bridge `{}` subscription rejected: {}",
+                            self.config.name, res.reason_code
+                        );
+                    }
+                }
+
+                Ok(None)
+            }
+            ControlPacket::PubAck(_) | ControlPacket::PubComp(_) | ControlPacket::PingResp(_) => {
+                Ok(None)
+            }
+            ControlPacket::Disconnect(packet) => Err(packet.reason.into()),
+            _ => Err(ReasonCode::ProtocolError.into()),
+        }
+    }
+
+    /// Builds the PUBLISH forwarding a local message to the remote broker, or
+    /// `None` if the message must not cross the bridge.
+    fn process_local(&mut self, message: Message) -> Option<PublishPacket> {
+        // Loop prevention: never send back what came from the remote broker,
+        // nor what another cluster node already took care of forwarding.
+        if let Some(origin) = &message.origin {
+            if *origin == self.config.client_id || cluster::is_peer(origin) {
+                return None;
+            }
+        }
+
+        let (topic_name, max_qos) = self
+            .config
+            .topics
+            .iter()
+            .filter(|t| t.is_outbound())
+            .find_map(|t| t.to_remote(&message.topic).map(|name| (name, t.qos)))?;
+        let qos_level = message.qos.min(max_qos);
+
+        // Expired messages are dropped, the others cross the bridge with
+        // what's left of their Message Expiry Interval
+        let message_expiry_interval = match message.expires_at {
+            Some(expires_at) => {
+                match expires_at.checked_duration_since(std::time::Instant::now()) {
+                    Some(remaining) if !remaining.is_zero() => {
+                        Some(MessageExpiryInterval::new(remaining_secs(remaining)))
+                    }
+                    _ => return None,
+                }
+            }
+            None => None,
+        };
+
+        let properties = PublishProperties {
+            user_property: message.user_properties,
+            response_topic: message.response_topic.map(ResponseTopic::new),
+            correlation_data: message.correlation_data.map(CorrelationData::new),
+            content_type: message.content_type.map(ContentType::new),
+            payload_format_indicator: message
+                .payload_format_indicator
+                .map(PayloadFormatIndicator::new),
+            message_expiry_interval,
+            ..Default::default()
+        };
+
+        let packet_id = match qos_level {
+            QoS::AtMostOnce => None,
+            _ => Some(self.packet_id()),
+        };
+
+        Some(PublishPacket {
+            dup: false,
+            qos_level,
+            retain: message.retain,
+            topic_name,
+            packet_id,
+            properties: Some(properties).filter(|p| *p != PublishProperties::default()),
+            payload: message.payload,
+        })
+    }
+
+    fn packet_id(&mut self) -> u16 {
+        // Packet identifiers are non-zero
+        self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+        self.next_packet_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use tokio::sync::broadcast;
+
+    use mercurio_core::{properties::ResponseTopic, qos::QoS, reason::ReasonCode};
+    use mercurio_packets::{
+        publish::{PublishPacket, PublishProperties},
+        pubrel::PubRelPacket,
+        ControlPacket,
+    };
+
+    use super::{Bridge, BridgeConfig, BridgeDirection, BridgeTopic};
+    use crate::{broker::Broker, cluster::ClusterConfig, fanout::FanoutPool, shutdown::Shutdown};
+
+    #[test]
+    fn test_bridge_topic_prefix_remapping() {
+        let mut topic = BridgeTopic::new("sensors/#", BridgeDirection::Both, QoS::AtLeastOnce);
+        topic.local_prefix = "site1/".to_string();
+        topic.remote_prefix = "cloud/site1/".to_string();
+
+        assert_eq!(topic.local_filter(), "site1/sensors/#");
+        assert_eq!(topic.remote_filter(), "cloud/site1/sensors/#");
+
+        assert_eq!(
+            topic.to_remote("site1/sensors/temp"),
+            Some("cloud/site1/sensors/temp".to_string())
+        );
+        assert_eq!(
+            topic.to_local("cloud/site1/sensors/temp"),
+            Some("site1/sensors/temp".to_string())
+        );
+
+        assert_eq!(topic.to_remote("site2/sensors/temp"), None);
+        assert_eq!(topic.to_local("cloud/site1/actuators/valve"), None);
+    }
+
+    #[tokio::test]
+    async fn test_bridge_qos2_redelivery() {
+        let broker = Broker::new(Default::default(), Default::default());
+        let (queue, mut messages) = broker.queue();
+        broker.subscribe("#", "subscriber", queue);
+
+        let mut config = BridgeConfig::new("remote", "127.0.0.1:1883");
+        config
+            .topics
+            .push(BridgeTopic::new("#", BridgeDirection::In, QoS::ExactlyOnce));
+
+        let (_notify, receiver) = broadcast::channel(1);
+        let fanout = FanoutPool::without_workers(broker.clone());
+        let mut bridge = Bridge::new(config, broker, fanout, Shutdown::new(receiver));
+
+        let publish = |dup| {
+            ControlPacket::Publish(PublishPacket {
+                dup,
+                qos_level: QoS::ExactlyOnce,
+                retain: false,
+                topic_name: "request".to_string(),
+                packet_id: Some(1),
+                properties: Some(PublishProperties {
+                    response_topic: Some(ResponseTopic::new("reply".to_string())),
+                    ..Default::default()
+                }),
+                payload: Some(Bytes::from("ping")),
+            })
+        };
+
+        let pubrel = || {
+            ControlPacket::PubRel(PubRelPacket {
+                packet_id: 1,
+                reason: Default::default(),
+                properties: None,
+            })
+        };
+
+        assert!(matches!(
+            bridge.process_remote(publish(false)).await,
+            Ok(Some(ControlPacket::PubRec(_)))
+        ));
+
+        // Redelivered before the PUBREL, acknowledged but not forwarded
+        assert!(matches!(
+            bridge.process_remote(publish(true)).await,
+            Ok(Some(ControlPacket::PubRec(_)))
+        ));
+
+        let message = messages.try_recv().unwrap();
+        assert_eq!(message.response_topic.as_deref(), Some("reply"));
+        assert!(messages.try_recv().is_err());
+
+        assert!(matches!(
+            bridge.process_remote(pubrel()).await,
+            Ok(Some(ControlPacket::PubComp(_)))
+        ));
+
+        // Released, the identifier can be used again
+        assert!(bridge.process_remote(publish(false)).await.is_ok());
+        assert!(messages.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_bridge_receive_maximum() {
+        let broker = Broker::new(Default::default(), Default::default());
+        let mut config = BridgeConfig::new("remote", "127.0.0.1:1883");
+        config.receive_maximum = Some(1);
+        config
+            .topics
+            .push(BridgeTopic::new("#", BridgeDirection::In, QoS::ExactlyOnce));
+
+        let (_notify, receiver) = broadcast::channel(1);
+        let fanout = FanoutPool::without_workers(broker.clone());
+        let mut bridge = Bridge::new(config, broker, fanout, Shutdown::new(receiver));
+
+        let publish = |packet_id| {
+            ControlPacket::Publish(PublishPacket {
+                dup: false,
+                qos_level: QoS::ExactlyOnce,
+                retain: false,
+                topic_name: "a".to_string(),
+                packet_id: Some(packet_id),
+                properties: None,
+                payload: Some(Bytes::from("1")),
+            })
+        };
+
+        assert!(matches!(
+            bridge.process_remote(publish(1)).await,
+            Ok(Some(ControlPacket::PubRec(_)))
+        ));
+
+        // A second message before the first one is released is too many
+        match bridge.process_remote(publish(2)).await {
+            Ok(Some(ControlPacket::Disconnect(disconnect))) => {
+                assert_eq!(disconnect.reason, ReasonCode::ReceiveMaximumExceeded)
+            }
+            res => panic!("Expected a DISCONNECT, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_bridge_cluster_peers() {
+        let broker = Broker::new(Default::default(), Default::default());
+        let (_notify, receiver) = broadcast::channel(1);
+
+        let cluster = ClusterConfig {
+            node_id: "node1".to_string(),
+            peers: vec!["10.0.0.2:1883".to_string(), "10.0.0.3:1883".to_string()],
+        };
+
+        let mut bridges: Vec<Bridge> = cluster
+            .peer_links()
+            .into_iter()
+            .map(|link| {
+                let fanout = FanoutPool::without_workers(broker.clone());
+                Bridge::new(
+                    link,
+                    broker.clone(),
+                    fanout,
+                    Shutdown::new(receiver.resubscribe()),
+                )
+            })
+            .collect();
+
+        // A client using the identifier of the links doesn't get in the way
+        let (queue, mut client) = broker.queue();
+        broker.subscribe("#", &bridges[0].config.client_id, queue);
+
+        let mut first = bridges[0].subscribe_local();
+        let mut second = bridges[1].subscribe_local();
+
+        broker
+            .publish_external("sensors/temp", Bytes::from("21"), QoS::AtLeastOnce, false)
+            .unwrap();
+
+        // Every peer gets the message
+        let message = first.try_recv().unwrap();
+        assert!(bridges[0].process_local(message).is_some());
+        let message = second.try_recv().unwrap();
+        assert!(bridges[1].process_local(message).is_some());
+        assert!(client.try_recv().is_ok());
+
+        // One link going away leaves the other's subscriptions alone
+        broker.unsubscribe_all(&bridges[0].subscriber_id);
+
+        broker
+            .publish_external("sensors/temp", Bytes::from("22"), QoS::AtLeastOnce, false)
+            .unwrap();
+
+        assert!(first.try_recv().is_err());
+        assert!(second.try_recv().is_ok());
+        assert!(client.try_recv().is_ok());
+    }
+}
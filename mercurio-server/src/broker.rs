@@ -1,45 +1,992 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
 
-use tokio::sync::broadcast;
+use bytes::Bytes;
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    oneshot,
+};
+use tracing::warn;
+use uuid::Uuid;
 
-use crate::topic_tree::TopicTree;
-use mercurio_core::{message::Message, Result};
+use crate::{
+    cluster,
+    config::{
+        Capabilities, Config, Quotas, SharedSubscriptionPolicy, SlowConsumerAction,
+        SlowConsumerPolicy, TopicPolicy, TopicRewrite,
+    },
+    topic_tree::{self, TopicTree},
+};
+use mercurio_core::{message::Message, qos::QoS, reason::ReasonCode, Result};
 
+/// Prefix of the identifiers of the subscribers within the broker itself,
+/// rather than clients.
+pub(crate) const INTERNAL_PREFIX: &str = "$internal/";
+
+/// Routes messages between the sessions, and whatever else subscribes.
+///
+/// Applications embedding the server can create it themselves, to publish
+/// and subscribe without going through an MQTT connection, and hand it to
+/// [`run_with_broker`](crate::server::run_with_broker).
 #[derive(Debug, Clone)]
-pub(crate) struct Broker {
+pub struct Broker {
     shared: Arc<Shared>,
 }
 
 #[derive(Debug)]
 struct Shared {
-    state: Mutex<State>,
+    quotas: Quotas,
+    capabilities: Capabilities,
+    topic_policies: Vec<TopicPolicy>,
+    topic_rewrites: Vec<TopicRewrite>,
+    shared_subscription_policy: SharedSubscriptionPolicy,
+
+    /// Connections the listeners failed to accept.
+    accept_errors: AtomicU64,
+
+    /// Connections refused because of the address they came from.
+    rejected_connections: AtomicU64,
+
+    /// Messages are published with a read lock, so they're routed in
+    /// parallel. Whatever changes the subscriptions waits for them to be
+    /// done.
+    ///
+    /// Locked before the others, if along with them.
+    subscriptions: RwLock<TopicTree<SubscriberQueue>>,
+
+    /// Shared subscriptions, by `$share/<share name>/<filter>`.
+    shared_subscriptions: Mutex<HashMap<String, SharedSubscription>>,
+
+    retained: Mutex<Retained>,
+}
+
+#[derive(Debug, Default)]
+struct Retained {
+    /// Last message published with the retain flag, by topic, along with
+    /// its place in the order they were retained in.
+    messages: HashMap<String, (u64, Message)>,
+
+    /// Place of the next retained message.
+    sequence: u64,
+}
+
+impl Shared {
+    /// Subscribes with the subscriptions already locked for writing.
+    fn subscribe(
+        &self,
+        subscriptions: &mut TopicTree<SubscriberQueue>,
+        filter: &str,
+        subscriber_id: &str,
+        queue: SubscriberQueue,
+    ) {
+        let topic_filter = match topic_tree::shared_subscription(filter) {
+            Some((_, topic_filter)) => topic_filter,
+            None => {
+                subscriptions.subscribe(filter, subscriber_id, queue);
+                return;
+            }
+        };
+
+        let mut shared_subscriptions = self.shared_subscriptions.lock().unwrap();
+        let shared = shared_subscriptions
+            .entry(filter.to_string())
+            .or_insert_with(|| SharedSubscription::new(topic_filter));
+
+        match shared
+            .members
+            .iter_mut()
+            .find(|(id, _)| id == subscriber_id)
+        {
+            Some(member) => member.1 = queue,
+            None => shared.members.push((subscriber_id.to_string(), queue)),
+        }
+    }
+}
+
+impl Retained {
+    /// Returns the retained messages whose topic matches `filter`, in the
+    /// order they were published.
+    fn matching(&self, filter: &str) -> Vec<Message> {
+        let mut retained: Vec<&(u64, Message)> = self
+            .messages
+            .values()
+            .filter(|(_, message)| topic_tree::matches(filter, &message.topic))
+            .collect();
+
+        retained.sort_by_key(|(sequence, _)| *sequence);
+        retained
+            .into_iter()
+            .map(|(_, message)| message.clone())
+            .collect()
+    }
+}
+
+/// Subscribers sharing a subscription, each message going to a single one
+/// of them, in turn.
+#[derive(Debug)]
+struct SharedSubscription {
+    filter: String,
+    members: Vec<(String, SubscriberQueue)>,
+
+    /// Index of the member next in line.
+    next: usize,
+
+    /// Member the messages of each publishing client went to, with the
+    /// sticky policy.
+    sticky: HashMap<String, String>,
+}
+
+/// Sending half of a subscriber's message queue.
+///
+/// Each subscriber (usually a session) owns a single bounded queue, fed by
+/// all of its subscriptions. Publishing never waits on a subscriber: when the
+/// queue is full the message is dropped for that subscriber and accounted
+/// for, so a slow consumer can't stall the publisher nor other subscribers.
+/// How many were is published under `$SYS/`, and the slow consumer policy
+/// can disconnect subscribers before it comes to that.
+#[derive(Debug, Clone)]
+pub(crate) struct SubscriberQueue {
+    sender: mpsc::Sender<Message>,
+    dropped: Arc<AtomicU64>,
+    quota_exceeded: Arc<AtomicU64>,
+    slow_consumer: Option<SlowConsumerPolicy>,
+
+    /// Lets the subscriber's connection know it's too slow, with the number
+    /// of queued messages.
+    eviction: Arc<Mutex<Option<oneshot::Sender<usize>>>>,
+}
+
+/// Statistics of a subscriber, as published under `$SYS/`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SubscriberStats {
+    /// Messages dropped because the queue was full.
+    pub(crate) dropped: u64,
+
+    /// Messages waiting in the queue.
+    pub(crate) queued: usize,
+
+    pub(crate) subscriptions: usize,
+
+    /// Operations refused because of a quota.
+    pub(crate) quota_exceeded: u64,
+}
+
+/// Outcome of handing a message to a subscriber's queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Delivery {
+    Queued,
+    Dropped,
+
+    /// The subscriber is gone and its queue was closed.
+    Gone,
+}
+
+impl SubscriberQueue {
+    fn new(
+        capacity: usize,
+        slow_consumer: Option<SlowConsumerPolicy>,
+    ) -> (SubscriberQueue, mpsc::Receiver<Message>) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let queue = SubscriberQueue {
+            sender,
+            dropped: Arc::new(AtomicU64::new(0)),
+            quota_exceeded: Arc::new(AtomicU64::new(0)),
+            slow_consumer,
+            eviction: Arc::new(Mutex::new(None)),
+        };
+
+        (queue, receiver)
+    }
+
+    fn queued(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+
+    /// Returns a receiver getting the number of queued messages if the
+    /// subscriber is to be disconnected for being too slow. Meant to be
+    /// called for every connection of the subscriber, only the latest one
+    /// is notified.
+    pub(crate) fn on_eviction(&self) -> oneshot::Receiver<usize> {
+        let (sender, receiver) = oneshot::channel();
+        *self.eviction.lock().unwrap() = Some(sender);
+
+        receiver
+    }
+
+    /// Accounts for an operation of the subscriber refused because of a
+    /// quota.
+    pub(crate) fn exceed_quota(&self) {
+        self.quota_exceeded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn deliver(&self, subscriber_id: &str, message: Message) -> Delivery {
+        if let Some(policy) = &self.slow_consumer {
+            let queued = self.queued();
+
+            if queued >= policy.threshold {
+                match policy.action {
+                    SlowConsumerAction::DropQoS0 if message.qos == QoS::AtMostOnce => {
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                        return Delivery::Dropped;
+                    }
+                    SlowConsumerAction::DropQoS0 => {}
+                    SlowConsumerAction::Disconnect => {
+                        if let Some(eviction) = self.eviction.lock().unwrap().take() {
+                            warn!(
+                                "Subscriber `{}` is too slow with {} queued messages, disconnecting it",
+                                subscriber_id, queued
+                            );
+
+                            let _ = eviction.send(queued);
+                        }
+                    }
+                }
+            }
+        }
+
+        match self.sender.try_send(message) {
+            Ok(()) => Delivery::Queued,
+            Err(TrySendError::Full(message)) => {
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+
+                if message.qos != QoS::AtMostOnce {
+                    warn!(
+                        "Queue of subscriber `{}` is full, dropping {:?} message on `{}` ({} dropped so far)",
+                        subscriber_id, message.qos, message.topic, dropped
+                    );
+                }
+
+                Delivery::Dropped
+            }
+            Err(TrySendError::Closed(_)) => Delivery::Gone,
+        }
+    }
 }
 
+impl SharedSubscription {
+    fn new(filter: &str) -> SharedSubscription {
+        SharedSubscription {
+            filter: filter.to_string(),
+            members: Vec::new(),
+            next: 0,
+            sticky: HashMap::new(),
+        }
+    }
+
+    /// Delivers to the member picked by `policy` among those still there,
+    /// if any.
+    fn deliver(&mut self, message: Message, policy: SharedSubscriptionPolicy) -> Delivery {
+        while !self.members.is_empty() {
+            let index = self.pick(&message, policy);
+            let (subscriber_id, queue) = &self.members[index];
+            let delivery = queue.deliver(subscriber_id, message.clone());
+
+            if delivery != Delivery::Gone {
+                self.next = index + 1;
+
+                if let (SharedSubscriptionPolicy::Sticky, Some(origin)) = (policy, &message.origin)
+                {
+                    self.sticky.insert(origin.clone(), subscriber_id.clone());
+                }
+
+                return delivery;
+            }
+
+            let (subscriber_id, _) = self.members.remove(index);
+            self.sticky.retain(|_, member| *member != subscriber_id);
+        }
+
+        Delivery::Gone
+    }
+
+    /// Returns the index of the member the message goes to.
+    fn pick(&self, message: &Message, policy: SharedSubscriptionPolicy) -> usize {
+        let next = self.next % self.members.len();
+        let mut in_turn = (0..self.members.len()).map(|i| (next + i) % self.members.len());
+
+        match policy {
+            SharedSubscriptionPolicy::RoundRobin => next,
+            SharedSubscriptionPolicy::Sticky => message
+                .origin
+                .as_ref()
+                .and_then(|origin| self.sticky.get(origin))
+                .and_then(|member| self.members.iter().position(|(id, _)| id == member))
+                .unwrap_or(next),
+            SharedSubscriptionPolicy::LeastBacklog => in_turn
+                .min_by_key(|&i| self.members[i].1.queued())
+                .unwrap_or(next),
+            SharedSubscriptionPolicy::LocalFirst => in_turn
+                .find(|&i| !cluster::is_peer(&self.members[i].0))
+                .unwrap_or(next),
+        }
+    }
+}
+
+/// Subscription made from within the application, through
+/// [`Broker::subscribe_external`]. Unsubscribes when dropped.
 #[derive(Debug)]
-struct State {
-    subscriptions: TopicTree<Message>,
+pub struct ExternalSubscription {
+    broker: Broker,
+    subscriber_id: String,
+    messages: mpsc::Receiver<Message>,
+}
+
+impl ExternalSubscription {
+    /// Waits for the next message matching the filter. Messages are dropped
+    /// if they aren't received as fast as they're published, as for any
+    /// subscriber.
+    pub async fn recv(&mut self) -> Option<Message> {
+        self.messages.recv().await
+    }
+}
+
+impl Drop for ExternalSubscription {
+    fn drop(&mut self) {
+        self.broker.unsubscribe_all(&self.subscriber_id);
+    }
 }
 
 impl Broker {
-    pub(crate) fn new() -> Broker {
+    /// Creates a broker with the quotas, capabilities and topic policies of
+    /// `config`.
+    pub fn from_config(config: &Config) -> Broker {
         let shared = Arc::new(Shared {
-            state: Mutex::new(State {
-                subscriptions: TopicTree::new(),
-            }),
+            quotas: config.quotas,
+            capabilities: config.capabilities,
+            topic_policies: config.topic_policies.clone(),
+            topic_rewrites: config.topic_rewrites.clone(),
+            shared_subscription_policy: config.shared_subscription_policy,
+            accept_errors: AtomicU64::new(0),
+            rejected_connections: AtomicU64::new(0),
+            subscriptions: RwLock::new(TopicTree::new()),
+            shared_subscriptions: Mutex::new(HashMap::new()),
+            retained: Mutex::new(Retained::default()),
         });
 
         Broker { shared }
     }
 
-    pub(crate) fn subscribe(&self, topic: String) -> broadcast::Receiver<Message> {
-        let mut state = self.shared.state.lock().unwrap();
-        state.subscriptions.subscribe(topic)
+    #[cfg(test)]
+    pub(crate) fn new(quotas: Quotas, capabilities: Capabilities) -> Broker {
+        Broker::from_config(&Config {
+            quotas,
+            capabilities,
+            ..Default::default()
+        })
+    }
+
+    pub(crate) fn quotas(&self) -> &Quotas {
+        &self.shared.quotas
+    }
+
+    pub(crate) fn capabilities(&self) -> &Capabilities {
+        &self.shared.capabilities
+    }
+
+    /// Checks a message published on `topic` against the policies of the
+    /// topics matching it.
+    pub(crate) fn check_topic_policies(
+        &self,
+        topic: &str,
+        qos: QoS,
+        payload_size: usize,
+    ) -> Result<()> {
+        self.shared
+            .topic_policies
+            .iter()
+            .filter(|policy| topic_tree::matches(&policy.filter, topic))
+            .try_for_each(|policy| policy.check(qos, payload_size))
+    }
+
+    pub(crate) fn rewrites_topics(&self) -> bool {
+        !self.shared.topic_rewrites.is_empty()
+    }
+
+    /// Returns a topic name or filter of a client rewritten by the first
+    /// rewrite applying to it, if any. Fails if the client lacks the
+    /// identity that rewrite needs.
+    pub(crate) fn rewrite_topic(
+        &self,
+        topic: &str,
+        client_id: &str,
+        user_name: Option<&str>,
+    ) -> Result<Option<String>> {
+        self.shared
+            .topic_rewrites
+            .iter()
+            .find_map(|rewrite| rewrite.apply(topic, client_id, user_name).transpose())
+            .transpose()
+    }
+
+    /// Returns a topic delivered to a client with the prefix given by the
+    /// first rewrite it has back to the one the client used, if any.
+    pub(crate) fn restore_topic(
+        &self,
+        topic: &str,
+        client_id: &str,
+        user_name: Option<&str>,
+    ) -> Option<String> {
+        self.shared
+            .topic_rewrites
+            .iter()
+            .find_map(|rewrite| rewrite.restore(topic, client_id, user_name))
+    }
+
+    /// Creates a queue for a new subscriber, with the configured capacity.
+    pub(crate) fn queue(&self) -> (SubscriberQueue, mpsc::Receiver<Message>) {
+        SubscriberQueue::new(
+            self.shared.quotas.max_queued_messages,
+            self.shared.quotas.slow_consumer,
+        )
+    }
+
+    /// Subscribes `subscriber_id` to `filter`, which may be a shared
+    /// subscription.
+    pub(crate) fn subscribe(&self, filter: &str, subscriber_id: &str, queue: SubscriberQueue) {
+        let mut subscriptions = self.shared.subscriptions.write().unwrap();
+        self.shared
+            .subscribe(&mut subscriptions, filter, subscriber_id, queue);
+    }
+
+    /// Subscribes `subscriber_id` to `filter`, returning the retained
+    /// messages matching it in the order they were published.
+    ///
+    /// Nothing is published in between, so every message reaches the
+    /// subscriber once: those published before as retained messages, the
+    /// ones after through its queue. Delivering the former first keeps them
+    /// ahead of any live message of the subscription, as birth certificates
+    /// and the like expect.
+    pub(crate) fn subscribe_retained(
+        &self,
+        filter: &str,
+        subscriber_id: &str,
+        queue: SubscriberQueue,
+    ) -> Vec<Message> {
+        let mut subscriptions = self.shared.subscriptions.write().unwrap();
+        self.shared
+            .subscribe(&mut subscriptions, filter, subscriber_id, queue);
+
+        let retained = self.shared.retained.lock().unwrap();
+        retained.matching(filter)
+    }
+
+    /// Deletes the retained messages whose topic matches `filter`, returning
+    /// how many there were.
+    pub(crate) fn delete_retained(&self, filter: &str) -> Result<usize> {
+        if !topic_tree::is_valid_topic_filter(filter) {
+            return Err(ReasonCode::TopicFilterInvalid.into());
+        }
+
+        let mut retained = self.shared.retained.lock().unwrap();
+        let before = retained.messages.len();
+        retained
+            .messages
+            .retain(|topic, _| !topic_tree::matches(filter, topic));
+
+        Ok(before - retained.messages.len())
+    }
+
+    /// Removes the subscription of `subscriber_id` to `filter`. Returns
+    /// `false` if there was no such subscription.
+    pub(crate) fn unsubscribe(&self, filter: &str, subscriber_id: &str) -> bool {
+        if topic_tree::shared_subscription(filter).is_none() {
+            let mut subscriptions = self.shared.subscriptions.write().unwrap();
+            return subscriptions.unsubscribe(filter, subscriber_id);
+        }
+
+        let mut shared_subscriptions = self.shared.shared_subscriptions.lock().unwrap();
+        let shared = match shared_subscriptions.get_mut(filter) {
+            Some(shared) => shared,
+            None => return false,
+        };
+
+        let members = shared.members.len();
+        shared.members.retain(|(id, _)| id != subscriber_id);
+        let existed = shared.members.len() != members;
+
+        if shared.members.is_empty() {
+            shared_subscriptions.remove(filter);
+        }
+
+        existed
+    }
+
+    /// Removes every subscription of `subscriber_id`, whose queue is gone.
+    pub(crate) fn unsubscribe_all(&self, subscriber_id: &str) {
+        let mut subscriptions = self.shared.subscriptions.write().unwrap();
+        subscriptions.unsubscribe_all(subscriber_id);
+
+        let mut shared_subscriptions = self.shared.shared_subscriptions.lock().unwrap();
+        for shared in shared_subscriptions.values_mut() {
+            shared.members.retain(|(id, _)| id != subscriber_id);
+        }
+        shared_subscriptions.retain(|_, shared| !shared.members.is_empty());
+    }
+
+    pub(crate) fn count_accept_error(&self) {
+        self.shared.accept_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of connections the listeners failed to accept.
+    pub(crate) fn accept_errors(&self) -> u64 {
+        self.shared.accept_errors.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn count_rejected_connection(&self) {
+        self.shared
+            .rejected_connections
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of connections refused because of the address
+    /// they came from.
+    pub(crate) fn rejected_connections(&self) -> u64 {
+        self.shared.rejected_connections.load(Ordering::Relaxed)
+    }
+
+    /// Returns the statistics of every subscriber with at least one
+    /// subscription.
+    pub(crate) fn subscriber_stats(&self) -> HashMap<String, SubscriberStats> {
+        let subscriptions = self.shared.subscriptions.read().unwrap();
+
+        subscriptions
+            .subscribers()
+            .into_iter()
+            .map(|(subscriber_id, queues)| {
+                let queue = queues[0];
+                let stats = SubscriberStats {
+                    dropped: queue.dropped.load(Ordering::Relaxed),
+                    queued: queue.queued(),
+                    subscriptions: queues.len(),
+                    quota_exceeded: queue.quota_exceeded.load(Ordering::Relaxed),
+                };
+
+                (subscriber_id.to_string(), stats)
+            })
+            .collect()
+    }
+
+    /// Returns the retained messages whose topic matches `filter`, in the
+    /// order they were published.
+    #[cfg(test)]
+    pub(crate) fn retained(&self, filter: &str) -> Vec<Message> {
+        let retained = self.shared.retained.lock().unwrap();
+        retained.matching(filter)
+    }
+
+    /// Publishes a message on behalf of the application, as if a client had
+    /// published it.
+    pub fn publish_external(
+        &self,
+        topic: &str,
+        payload: Bytes,
+        qos: QoS,
+        retain: bool,
+    ) -> Result<()> {
+        if !topic_tree::is_valid_topic_name(topic) {
+            return Err(ReasonCode::TopicNameInvalid.into());
+        }
+
+        if qos == QoS::Invalid {
+            return Err(ReasonCode::QoSNotSupported.into());
+        }
+
+        let message = Message {
+            retain,
+            ..Message::new(topic, payload, qos)
+        };
+
+        self.publish(topic, message)
+    }
+
+    /// Subscribes the application to `filter`, which may be a shared
+    /// subscription.
+    pub fn subscribe_external(&self, filter: &str) -> Result<ExternalSubscription> {
+        if !topic_tree::is_valid_topic_filter(filter) {
+            return Err(ReasonCode::TopicFilterInvalid.into());
+        }
+
+        let subscriber_id = format!("{}{}", INTERNAL_PREFIX, Uuid::new_v4().hyphenated());
+        let (queue, messages) = self.queue();
+        self.subscribe(filter, &subscriber_id, queue);
+
+        Ok(ExternalSubscription {
+            broker: self.clone(),
+            subscriber_id,
+            messages,
+        })
     }
 
+    /// Queues `message` for every matching subscriber. Subscribers whose
+    /// queue is full miss it, which is accounted for in their statistics,
+    /// the others get it anyway.
     pub(crate) fn publish(&self, topic: &str, message: Message) -> Result<()> {
-        let mut state = self.shared.state.lock().unwrap();
-        state.subscriptions.publish(topic, message);
+        let subscriptions = self.shared.subscriptions.read().unwrap();
+        let mut gone = Vec::new();
+
+        // A subscriber gets a single copy, even if several of its
+        // subscriptions match the topic.
+        for (subscriber_id, queues) in subscriptions.matches(topic)? {
+            if queues[0].deliver(subscriber_id, message.clone()) == Delivery::Gone {
+                gone.push(subscriber_id.to_string());
+            }
+        }
+
+        // A message matching a shared subscription goes to a single one of
+        // its subscribers.
+        {
+            let mut shared_subscriptions = self.shared.shared_subscriptions.lock().unwrap();
+            for shared in shared_subscriptions.values_mut() {
+                if topic_tree::matches(&shared.filter, topic) {
+                    shared.deliver(message.clone(), self.shared.shared_subscription_policy);
+                }
+            }
+            shared_subscriptions.retain(|_, shared| !shared.members.is_empty());
+        }
+
+        // [MQTT-3.3.1-6], [MQTT-3.3.1-7]
+        // A retained message replaces the one on the same topic, if any, and
+        // one with an empty payload removes it.
+        if message.retain {
+            let mut retained = self.shared.retained.lock().unwrap();
+
+            match &message.payload {
+                Some(payload) if !payload.is_empty() => {
+                    let sequence = retained.sequence;
+                    retained.sequence += 1;
+                    retained
+                        .messages
+                        .insert(topic.to_string(), (sequence, message.clone()));
+                }
+                _ => {
+                    retained.messages.remove(topic);
+                }
+            }
+        }
+
+        drop(subscriptions);
+
+        // Subscribers that went away without unsubscribing are only noticed
+        // here, drop what's left of them. Unless they subscribed again in the
+        // meantime, with a new queue.
+        if !gone.is_empty() {
+            let mut subscriptions = self.shared.subscriptions.write().unwrap();
+            let gone: Vec<String> = subscriptions
+                .matches(topic)?
+                .into_iter()
+                .filter(|(subscriber_id, queues)| {
+                    gone.iter().any(|id| id == subscriber_id) && queues[0].sender.is_closed()
+                })
+                .map(|(subscriber_id, _)| subscriber_id.to_string())
+                .collect();
+
+            for subscriber_id in gone {
+                subscriptions.unsubscribe_all(&subscriber_id);
+            }
+        }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use bytes::Bytes;
+    use mercurio_core::{message::Message, qos::QoS};
+    use tokio::sync::mpsc;
+
+    use crate::config::{
+        Config, Quotas, SharedSubscriptionPolicy, SlowConsumerAction, SlowConsumerPolicy,
+    };
+
+    use super::Broker;
+
+    fn quotas(max_queued_messages: usize) -> Quotas {
+        Quotas {
+            max_queued_messages,
+            ..Default::default()
+        }
+    }
+
+    fn message(topic: &str) -> Message {
+        message_with_qos(topic, QoS::AtLeastOnce)
+    }
+
+    fn message_with_qos(topic: &str, qos: QoS) -> Message {
+        Message::new(topic, Bytes::new(), qos)
+    }
+
+    #[test]
+    fn test_full_queue_drops_and_counts() {
+        let broker = Broker::new(quotas(2), Default::default());
+        let (queue, mut rx) = broker.queue();
+        broker.subscribe("a/+", "client", queue.clone());
+        broker.subscribe("a/b", "client", queue);
+
+        // The publisher isn't held responsible for the subscriber
+        for _ in 0..3 {
+            broker.publish("a/b", message("a/b")).unwrap();
+        }
+
+        let stats = broker.subscriber_stats()["client"];
+        assert_eq!(stats.dropped, 1);
+        assert_eq!(stats.queued, 2);
+        assert_eq!(stats.subscriptions, 2);
+
+        assert!(rx.try_recv().is_ok());
+        assert!(rx.try_recv().is_ok());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_slow_consumer() {
+        let slow_consumer = |action| Quotas {
+            max_queued_messages: 4,
+            slow_consumer: Some(SlowConsumerPolicy {
+                threshold: 2,
+                action,
+            }),
+            ..Default::default()
+        };
+
+        // QoS 0 messages are dropped past the threshold, but not the others
+        let broker = Broker::new(
+            slow_consumer(SlowConsumerAction::DropQoS0),
+            Default::default(),
+        );
+        let (queue, _rx) = broker.queue();
+        broker.subscribe("a", "client", queue);
+
+        for qos in [
+            QoS::AtMostOnce,
+            QoS::AtMostOnce,
+            QoS::AtMostOnce,
+            QoS::AtLeastOnce,
+        ] {
+            broker.publish("a", message_with_qos("a", qos)).unwrap();
+        }
+
+        let stats = broker.subscriber_stats()["client"];
+        assert_eq!(stats.dropped, 1);
+        assert_eq!(stats.queued, 3);
+
+        // The connection is let know past the threshold, once
+        let broker = Broker::new(
+            slow_consumer(SlowConsumerAction::Disconnect),
+            Default::default(),
+        );
+        let (queue, _rx) = broker.queue();
+        let mut eviction = queue.on_eviction();
+        broker.subscribe("a", "client", queue);
+
+        for _ in 0..2 {
+            broker.publish("a", message("a")).unwrap();
+        }
+        assert!(eviction.try_recv().is_err());
+
+        broker.publish("a", message("a")).unwrap();
+        assert_eq!(eviction.try_recv().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_retained_messages() {
+        let broker = Broker::new(Default::default(), Default::default());
+        let retained = |topic: &str, payload: &'static [u8]| Message {
+            retain: true,
+            payload: Some(Bytes::from_static(payload)),
+            ..message(topic)
+        };
+
+        broker.publish("a/b", retained("a/b", b"1")).unwrap();
+        broker.publish("a/b", retained("a/b", b"2")).unwrap();
+        broker.publish("a/c", retained("a/c", b"3")).unwrap();
+        broker.publish("a/d", message("a/d")).unwrap();
+
+        // Only the last one is kept per topic, in the order they came in
+        let payloads = |filter: &str| -> Vec<Bytes> {
+            broker
+                .retained(filter)
+                .into_iter()
+                .map(|message| message.payload.unwrap())
+                .collect()
+        };
+        assert_eq!(payloads("a/+"), vec![Bytes::from("2"), Bytes::from("3")]);
+
+        broker.publish("a/b", retained("a/b", b"4")).unwrap();
+        assert_eq!(payloads("a/+"), vec![Bytes::from("3"), Bytes::from("4")]);
+
+        // An empty payload removes it
+        broker.publish("a/b", retained("a/b", b"")).unwrap();
+        assert!(broker.retained("a/b").is_empty());
+        assert_eq!(broker.retained("#").len(), 1);
+    }
+
+    #[test]
+    fn test_shared_subscriptions() {
+        let broker = Broker::new(quotas(8), Default::default());
+        let (queue1, mut rx1) = broker.queue();
+        let (queue2, mut rx2) = broker.queue();
+        broker.subscribe("$share/group/a/+", "client1", queue1);
+        broker.subscribe("$share/group/a/+", "client2", queue2);
+
+        for _ in 0..4 {
+            broker.publish("a/b", message("a/b")).unwrap();
+        }
+
+        // Handed out in turn
+        let received =
+            |rx: &mut mpsc::Receiver<Message>| std::iter::from_fn(|| rx.try_recv().ok()).count();
+        assert_eq!(received(&mut rx1), 2);
+        assert_eq!(received(&mut rx2), 2);
+
+        // The remaining member gets everything
+        assert!(broker.unsubscribe("$share/group/a/+", "client1"));
+        assert!(!broker.unsubscribe("$share/group/a/+", "client1"));
+        broker.publish("a/b", message("a/b")).unwrap();
+        broker.publish("a/c", message("a/c")).unwrap();
+        assert_eq!(received(&mut rx1), 0);
+        assert_eq!(received(&mut rx2), 2);
+
+        // Gone once its last member is
+        drop(rx2);
+        broker.publish("a/b", message("a/b")).unwrap();
+        assert!(!broker.unsubscribe("$share/group/a/+", "client2"));
+    }
+
+    #[test]
+    fn test_shared_subscription_policies() {
+        let received =
+            |rx: &mut mpsc::Receiver<Message>| std::iter::from_fn(|| rx.try_recv().ok()).count();
+        let from = |origin: &str| Message {
+            origin: Some(origin.to_string()),
+            ..message("a/b")
+        };
+
+        let broker = Broker::from_config(&Config {
+            shared_subscription_policy: SharedSubscriptionPolicy::Sticky,
+            ..Default::default()
+        });
+        let (queue1, mut rx1) = broker.queue();
+        let (queue2, mut rx2) = broker.queue();
+        broker.subscribe("$share/group/a/+", "client1", queue1);
+        broker.subscribe("$share/group/a/+", "client2", queue2);
+
+        // Each publisher sticks to the member it got first
+        for _ in 0..3 {
+            broker.publish("a/b", from("device1")).unwrap();
+            broker.publish("a/b", from("device2")).unwrap();
+        }
+        assert_eq!(received(&mut rx1), 3);
+        assert_eq!(received(&mut rx2), 3);
+
+        // Until that member is gone
+        drop(rx1);
+        broker.publish("a/b", from("device1")).unwrap();
+        assert_eq!(received(&mut rx2), 1);
+
+        let broker = Broker::from_config(&Config {
+            shared_subscription_policy: SharedSubscriptionPolicy::LeastBacklog,
+            ..Default::default()
+        });
+        let (queue1, mut rx1) = broker.queue();
+        let (queue2, _rx2) = broker.queue();
+        broker.subscribe("$share/group/a/+", "client1", queue1);
+        broker.subscribe("$share/group/a/+", "client2", queue2);
+
+        for _ in 0..3 {
+            broker.publish("a/b", message("a/b")).unwrap();
+        }
+        assert_eq!(received(&mut rx1), 2);
+
+        // The member that caught up gets the next one, even though it's not
+        // its turn
+        broker.publish("a/b", message("a/b")).unwrap();
+        assert_eq!(received(&mut rx1), 1);
+    }
+
+    #[test]
+    fn test_closed_queue_is_unsubscribed() {
+        let broker = Broker::new(quotas(2), Default::default());
+        let (queue, rx) = broker.queue();
+        broker.subscribe("a/+", "client", queue.clone());
+        broker.subscribe("a/b", "client", queue);
+
+        drop(rx);
+        broker.publish("a/b", message("a/b")).unwrap();
+
+        assert!(!broker.unsubscribe("a/+", "client"));
+        assert!(!broker.unsubscribe("a/b", "client"));
+    }
+
+    #[tokio::test]
+    async fn test_external_publish_and_subscribe() {
+        let broker = Broker::new(quotas(2), Default::default());
+        let mut subscription = broker.subscribe_external("sensors/+").unwrap();
+
+        broker
+            .publish_external("sensors/1", Bytes::from("21.5"), QoS::AtLeastOnce, false)
+            .unwrap();
+
+        let message = subscription.recv().await.unwrap();
+        assert_eq!(message.topic, "sensors/1");
+        assert_eq!(message.payload, Some(Bytes::from("21.5")));
+
+        assert!(broker.subscribe_external("sensors/#/x").is_err());
+        assert!(broker
+            .publish_external("sensors/+", Bytes::new(), QoS::AtMostOnce, false)
+            .is_err());
+
+        // Dropping the subscription unsubscribes
+        drop(subscription);
+        assert!(broker.subscriber_stats().is_empty());
+    }
+
+    /// Measures the fan-out path (matching and enqueueing). Run with
+    /// `cargo test --release -- --ignored --nocapture bench_fanout`.
+    #[test]
+    #[ignore]
+    fn bench_fanout() {
+        const SUBSCRIBERS: usize = 50_000;
+        const MESSAGES: usize = 20;
+
+        let broker = Broker::new(quotas(MESSAGES), Default::default());
+        let mut receivers = Vec::with_capacity(SUBSCRIBERS);
+
+        for i in 0..SUBSCRIBERS {
+            let (queue, rx) = broker.queue();
+            let filter = if i % 2 == 0 {
+                "bench/+/data"
+            } else {
+                "bench/#"
+            };
+            broker.subscribe(filter, &format!("client-{}", i), queue);
+            receivers.push(rx);
+        }
+
+        let start = Instant::now();
+
+        for _ in 0..MESSAGES {
+            broker
+                .publish("bench/device/data", message("bench/device/data"))
+                .unwrap();
+        }
+
+        let elapsed = start.elapsed();
+        let _ = tracing_subscriber::fmt().with_test_writer().try_init();
+        tracing::info!(
+            "{} deliveries in {:?} ({:.0} deliveries/s)",
+            SUBSCRIBERS * MESSAGES,
+            elapsed,
+            (SUBSCRIBERS * MESSAGES) as f64 / elapsed.as_secs_f64()
+        );
+
+        assert!(receivers.iter_mut().all(|rx| rx.try_recv().is_ok()));
+    }
+}
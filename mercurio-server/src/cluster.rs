@@ -0,0 +1,68 @@
+use mercurio_core::qos::QoS;
+
+use crate::bridge::{BridgeConfig, BridgeDirection, BridgeTopic};
+
+/// Prefix of the client identifiers cluster nodes use to connect to their
+/// peers.
+const PEER_CLIENT_ID_PREFIX: &str = "mercurio-cluster-";
+
+/// Configuration of a full-mesh cluster.
+///
+/// Every node keeps a link to each of its peers and pushes the messages
+/// published by its own clients through them, so subscribers receive
+/// messages regardless of which node they are connected to. Messages received
+/// from a peer are never forwarded to other peers.
+#[derive(Debug, Clone)]
+pub struct ClusterConfig {
+    /// Identifier of this node, unique within the cluster.
+    pub node_id: String,
+
+    /// Addresses of the other nodes, as `host:port`.
+    pub peers: Vec<String>,
+}
+
+impl ClusterConfig {
+    /// Builds the bridge configurations for the links to every peer.
+    pub(crate) fn peer_links(&self) -> Vec<BridgeConfig> {
+        self.peers
+            .iter()
+            .map(|peer| {
+                let mut link = BridgeConfig::new(format!("cluster:{}", peer), peer.clone());
+                link.client_id = format!("{}{}", PEER_CLIENT_ID_PREFIX, self.node_id);
+                link.clean_start = true;
+                link.topics = vec![BridgeTopic::new(
+                    "#",
+                    BridgeDirection::Out,
+                    QoS::ExactlyOnce,
+                )];
+
+                link
+            })
+            .collect()
+    }
+}
+
+/// Returns `true` if `client_id` belongs to a link from another cluster node.
+pub(crate) fn is_peer(client_id: &str) -> bool {
+    client_id.starts_with(PEER_CLIENT_ID_PREFIX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_peer, ClusterConfig};
+
+    #[test]
+    fn test_cluster_peer_links() {
+        let config = ClusterConfig {
+            node_id: "node1".to_string(),
+            peers: vec!["10.0.0.2:1883".to_string(), "10.0.0.3:1883".to_string()],
+        };
+
+        let links = config.peer_links();
+
+        assert_eq!(links.len(), 2);
+        assert_eq!(links[1].address, "10.0.0.3:1883");
+        assert!(links.iter().all(|link| is_peer(&link.client_id)));
+        assert!(!is_peer("node1"));
+    }
+}
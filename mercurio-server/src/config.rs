@@ -0,0 +1,663 @@
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
+use ipnet::IpNet;
+
+use mercurio_core::{
+    properties::{
+        MaximumPacketSize, MaximumQoS, ReceiveMaximum, RetainAvailable,
+        SharedSubscriptionAvailable, SubscriptionIdentifierAvailable, TopicAliasMaximum,
+        WildcardSubscriptionAvailable,
+    },
+    qos::QoS,
+    reason::ReasonCode,
+    Result,
+};
+use mercurio_packets::{connack::ConnAckProperties, connect::ConnectPacket};
+
+use crate::{
+    audit::AuditSink,
+    auth::{AuthManager, CredentialValidator},
+    bridge::BridgeConfig,
+    cluster::ClusterConfig,
+};
+
+#[cfg(feature = "quic")]
+use crate::quic::QuicConfig;
+
+/// Broker configuration.
+///
+/// The default configuration runs a standalone broker with no bridges.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Connections to remote brokers to be maintained by this broker.
+    pub bridges: Vec<BridgeConfig>,
+
+    /// Cluster this broker is a node of, if any.
+    pub cluster: Option<ClusterConfig>,
+
+    /// Validator of the credentials of connecting clients. Without one,
+    /// every client is accepted with full access.
+    pub credential_validator: Option<Arc<dyn CredentialValidator>>,
+
+    /// Enhanced authentication methods clients can use instead.
+    pub auth_manager: AuthManager,
+
+    /// Response Information given to clients, for request/response.
+    pub response_information: Option<ResponseInformationConfig>,
+
+    /// Where connections, authentication failures and denied operations
+    /// are recorded, if anywhere.
+    pub audit_sink: Option<AuditSink>,
+
+    /// Topic the status of each client is published on when it connects and
+    /// disconnects, as a retained message, if at all. `{client_id}` stands
+    /// for the client identifier, as in `$SYS/clients/{client_id}/status`.
+    pub presence_topic: Option<String>,
+
+    /// Features of MQTT the broker offers to clients.
+    pub capabilities: Capabilities,
+
+    /// Limits on what a single client can hold on the broker.
+    pub quotas: Quotas,
+
+    /// Limits on the messages published on some topics, each message having
+    /// to comply with every policy whose filter matches its topic.
+    pub topic_policies: Vec<TopicPolicy>,
+
+    /// Rewrites of the topics clients publish on and subscribe to, the
+    /// first one applying to a topic being used.
+    pub topic_rewrites: Vec<TopicRewrite>,
+
+    /// Which member of a shared subscription each message goes to.
+    pub shared_subscription_policy: SharedSubscriptionPolicy,
+
+    /// Workers routing the messages published by clients to the
+    /// subscribers, one per CPU by default. With none, each client's task
+    /// routes its own messages, not reading its next packets meanwhile.
+    pub fanout_workers: usize,
+
+    /// Interval at which broker statistics are published under `$SYS/`, if
+    /// at all.
+    pub sys_interval: Option<Duration>,
+
+    /// Keep track of Sparkplug B edge nodes and devices, publishing their
+    /// state and sequence errors under `$SYS/sparkplug/`.
+    pub sparkplug: bool,
+
+    /// Carry out the administrative commands published under `$CONTROL/`,
+    /// such as deleting retained messages. Only clients explicitly allowed
+    /// to publish there, with a `$CONTROL/#` filter for instance, can send
+    /// them.
+    pub control: bool,
+
+    /// Listener accepting MQTT over QUIC, if any.
+    #[cfg(feature = "quic")]
+    pub quic: Option<QuicConfig>,
+
+    /// Time given to new connections to send their CONNECT and complete
+    /// authentication, from when they're accepted, before they're closed.
+    pub connect_timeout: Duration,
+
+    /// Time given to connections to close on shutdown before they're
+    /// dropped.
+    pub shutdown_timeout: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bridges: Vec::new(),
+            cluster: None,
+            credential_validator: None,
+            auth_manager: AuthManager::new(),
+            response_information: None,
+            audit_sink: None,
+            presence_topic: None,
+            capabilities: Capabilities::default(),
+            quotas: Quotas::default(),
+            topic_policies: Vec::new(),
+            topic_rewrites: Vec::new(),
+            shared_subscription_policy: SharedSubscriptionPolicy::default(),
+            fanout_workers: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            sys_interval: None,
+            sparkplug: false,
+            control: false,
+            #[cfg(feature = "quic")]
+            quic: None,
+            connect_timeout: Duration::from_secs(10),
+            shutdown_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Settings of one of the sockets the broker accepts connections on.
+#[derive(Debug, Clone, Default)]
+pub struct ListenerConfig {
+    /// How clients connecting on this listener are authenticated, instead of
+    /// the `credential_validator` and `auth_manager` of the [`Config`].
+    pub auth: Option<AuthConfig>,
+
+    /// Addresses clients can connect from.
+    pub ip_filter: IpFilter,
+}
+
+/// Addresses clients can connect from, checked as soon as they're accepted
+/// so the others don't cost more than a socket.
+///
+/// Connections refused this way are counted under `$SYS/`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IpFilter {
+    /// Networks clients have to connect from, any if empty.
+    pub allow: Vec<IpNet>,
+
+    /// Networks clients can't connect from, even when allowed.
+    pub deny: Vec<IpNet>,
+}
+
+impl IpFilter {
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of dual-stack sockets show up as IPv4-mapped IPv6
+        // addresses
+        let ip = ip.to_canonical();
+
+        let allowed = self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip));
+        allowed && !self.deny.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// How clients are authenticated, and through the [`Authorization`] their
+/// credentials are given, what they're allowed to do.
+///
+/// [`Authorization`]: crate::auth::Authorization
+#[derive(Debug, Clone, Default)]
+pub struct AuthConfig {
+    /// Validator of the credentials of connecting clients. Without one,
+    /// every client is accepted with full access.
+    pub credential_validator: Option<Arc<dyn CredentialValidator>>,
+
+    /// Enhanced authentication methods clients can use instead.
+    pub auth_manager: AuthManager,
+}
+
+/// Response Information given to the clients requesting it in their CONNECT,
+/// for them to build the Response Topic of their requests.
+///
+/// Every client gets `<prefix>/<client id>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseInformationConfig {
+    pub prefix: String,
+
+    /// Refuse requests whose Response Topic isn't under the client's own
+    /// Response Information, so clients can't have responses sent to one
+    /// another.
+    pub enforce: bool,
+}
+
+/// Limits on the state kept for each client, so a single one can't exhaust
+/// the broker's memory.
+///
+/// How often they're hit is published under `$SYS/` along with the other
+/// statistics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quotas {
+    /// Messages queued for a session, whether its client is connected or
+    /// not, before new ones are dropped.
+    pub max_queued_messages: usize,
+
+    /// QoS 1 and 2 messages in flight in each direction. Deliveries to the
+    /// client wait for acknowledgements beyond it, and messages published by
+    /// the client are refused with Quota Exceeded.
+    pub max_inflight_messages: usize,
+
+    /// Subscriptions of a session. Those beyond it are refused with Quota
+    /// Exceeded.
+    pub max_subscriptions: usize,
+
+    /// What to do about clients falling behind, if anything.
+    pub slow_consumer: Option<SlowConsumerPolicy>,
+
+    /// Bytes per second a packet has to be received at, once its first bytes
+    /// arrived, if limited. Connections trickling in a large packet are
+    /// closed rather than holding on to a growing buffer.
+    pub min_ingest_rate: Option<u32>,
+}
+
+impl Default for Quotas {
+    fn default() -> Self {
+        Quotas {
+            max_queued_messages: 1024,
+            max_inflight_messages: u16::MAX as usize,
+            max_subscriptions: 1024,
+            slow_consumer: None,
+            min_ingest_rate: None,
+        }
+    }
+}
+
+impl Quotas {
+    /// Sets the properties telling a client how much it can have in flight.
+    pub(crate) fn advertise(&self, properties: &mut ConnAckProperties) {
+        // [MQTT-3.2.2.3.3]
+        // The Server uses this value to limit the number of QoS 1 and QoS 2
+        // publications that it is willing to process concurrently for the
+        // Client. If the Receive Maximum value is absent, then its value
+        // defaults to 65,535.
+        if self.max_inflight_messages < usize::from(u16::MAX) {
+            let receive_maximum = self.max_inflight_messages.max(1) as u16;
+            properties.receive_maximum = Some(ReceiveMaximum::new(receive_maximum));
+        }
+    }
+}
+
+/// Limits on the messages published on the topics matching a filter, on top
+/// of the [`Capabilities`] of the broker.
+///
+/// Clients aren't told about them. Messages breaking them are refused with
+/// Quota Exceeded when too large, and Not Authorized when their QoS is too
+/// high, QoS 0 ones being dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicPolicy {
+    /// Topic filter the policy applies to, wildcards included.
+    pub filter: String,
+
+    /// Size of the largest payload, in bytes, if limited.
+    pub max_payload_size: Option<usize>,
+
+    /// Highest QoS messages can be published with, if limited.
+    pub maximum_qos: Option<QoS>,
+}
+
+impl TopicPolicy {
+    /// Checks a message published with `qos` and a payload of `payload_size`
+    /// bytes on one of the topics of the policy.
+    pub(crate) fn check(&self, qos: QoS, payload_size: usize) -> Result<()> {
+        if matches!(self.maximum_qos, Some(maximum_qos) if qos > maximum_qos) {
+            return Err(ReasonCode::NotAuthorized.into());
+        }
+
+        if matches!(self.max_payload_size, Some(max) if payload_size > max) {
+            return Err(ReasonCode::QuotaExceeded.into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Replaces the start of the topics clients publish on, and of the topic
+/// filters they subscribe and unsubscribe to, say to move legacy clients to
+/// a new namespace or keep tenants apart.
+///
+/// Topics are rewritten before anything else, authorization included, so
+/// the rules of the broker only ever see rewritten topics. Only the filter
+/// of shared subscriptions is, not their share name. Messages delivered
+/// because of a rewritten subscription get their prefix swapped back, so
+/// clients see the topics they subscribed to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicRewrite {
+    /// Prefix of the topics rewritten, which can be empty to rewrite them
+    /// all.
+    pub from: String,
+
+    /// What the prefix is replaced with. `{client_id}` and `{username}`
+    /// stand for the identifier of the client and the user name it
+    /// authenticated as, as in `tenants/{username}/`.
+    ///
+    /// Clients the rewrite applies to are refused when it has `{username}`
+    /// but they didn't authenticate as anyone, or when their identifier or
+    /// user name would add levels or wildcards to the topic.
+    pub to: String,
+}
+
+impl TopicRewrite {
+    /// Returns `topic` rewritten for a client, or `None` if the rewrite
+    /// doesn't apply to it. Fails if it does, but the client has no
+    /// identity fit for it.
+    pub(crate) fn apply(
+        &self,
+        topic: &str,
+        client_id: &str,
+        user_name: Option<&str>,
+    ) -> Result<Option<String>> {
+        let rest = match topic.strip_prefix(&self.from) {
+            Some(rest) => rest,
+            None => return Ok(None),
+        };
+
+        let prefix = self
+            .prefix(client_id, user_name)
+            .ok_or(ReasonCode::NotAuthorized)?;
+
+        Ok(Some(prefix + rest))
+    }
+
+    /// Returns a topic rewritten for a client with its original prefix, or
+    /// `None` if it doesn't have the rewritten one.
+    pub(crate) fn restore(
+        &self,
+        topic: &str,
+        client_id: &str,
+        user_name: Option<&str>,
+    ) -> Option<String> {
+        let rest = topic.strip_prefix(&self.prefix(client_id, user_name)?)?;
+
+        Some(format!("{}{}", self.from, rest))
+    }
+
+    /// Returns the prefix replacing `from` for a client, unless it lacks
+    /// the identity to fill its placeholders.
+    fn prefix(&self, client_id: &str, user_name: Option<&str>) -> Option<String> {
+        let mut prefix = self.to.clone();
+
+        for (placeholder, value) in [("{client_id}", Some(client_id)), ("{username}", user_name)] {
+            if !prefix.contains(placeholder) {
+                continue;
+            }
+
+            match value {
+                Some(value) if crate::topic_tree::is_valid_topic_level(value) => {
+                    prefix = prefix.replace(placeholder, value);
+                }
+                _ => return None,
+            }
+        }
+
+        Some(prefix)
+    }
+}
+
+/// How the messages matching a shared subscription are spread among its
+/// members.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SharedSubscriptionPolicy {
+    /// Each member in turn.
+    #[default]
+    RoundRobin,
+
+    /// The messages of a publishing client keep going to the same member,
+    /// as long as it's there, for workers keeping state per device.
+    Sticky,
+
+    /// The member with the fewest messages waiting in its queue, the first
+    /// in turn among those with as few.
+    LeastBacklog,
+
+    /// Each member in turn, skipping the links from other cluster nodes
+    /// unless they're the only members left.
+    LocalFirst,
+}
+
+/// Handling of clients whose queue of messages keeps growing because they
+/// don't read fast enough.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlowConsumerPolicy {
+    /// Number of queued messages from which a client is considered slow.
+    pub threshold: usize,
+
+    pub action: SlowConsumerAction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowConsumerAction {
+    /// Drop the QoS 0 messages for the client, keeping what's left of its
+    /// queue for QoS 1 and 2 ones.
+    DropQoS0,
+
+    /// Disconnect the client with Quota Exceeded and a reason string. Its
+    /// session is kept.
+    Disconnect,
+}
+
+/// Features of MQTT the broker offers to clients, advertised in the CONNACK
+/// and enforced on the corresponding packets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Highest QoS clients can publish with, and be granted when
+    /// subscribing.
+    pub maximum_qos: QoS,
+
+    /// Whether clients can publish retained messages, wills included.
+    pub retain_available: bool,
+
+    /// Whether topic filters can contain wildcards.
+    pub wildcard_subscriptions: bool,
+
+    /// Whether subscriptions can have an identifier.
+    pub subscription_identifiers: bool,
+
+    /// Whether clients can share subscriptions, with `$share/` topic
+    /// filters.
+    pub shared_subscriptions: bool,
+
+    /// Highest Topic Alias clients can use in their PUBLISH packets, 0 if
+    /// they can't.
+    pub topic_alias_maximum: u16,
+
+    /// Keep alive clients have to use instead of the one they asked for, if
+    /// any.
+    pub server_keep_alive: Option<u16>,
+
+    /// Highest keep alive clients can use, if limited. Those asking for more,
+    /// or for none at all, are given this one instead.
+    pub max_keep_alive: Option<u16>,
+
+    /// Size of the largest packet clients can send, in bytes, if limited.
+    /// Connections sending a larger one are closed with Packet Too Large as
+    /// soon as its size is known, before it's received.
+    pub maximum_packet_size: Option<u32>,
+}
+
+impl Capabilities {
+    /// Sets the properties telling a client what it can't do, or may not
+    /// expect.
+    pub(crate) fn advertise(&self, properties: &mut ConnAckProperties) {
+        // [MQTT-3.2.2-9]
+        // If a Server does not support QoS 1 or QoS 2 PUBLISH packets it
+        // MUST send a Maximum QoS in the CONNACK packet specifying the
+        // highest QoS it supports.
+        if self.maximum_qos < QoS::ExactlyOnce {
+            properties.maximum_qos = Some(MaximumQoS::new(self.maximum_qos as u8));
+        }
+
+        if !self.retain_available {
+            properties.retain_available = Some(RetainAvailable::new(false));
+        }
+
+        if !self.shared_subscriptions {
+            properties.shared_subscription_available =
+                Some(SharedSubscriptionAvailable::new(false));
+        }
+
+        if !self.wildcard_subscriptions {
+            properties.wildcard_subscription_available =
+                Some(WildcardSubscriptionAvailable::new(false));
+        }
+
+        if !self.subscription_identifiers {
+            properties.subscription_identifier_available =
+                Some(SubscriptionIdentifierAvailable::new(false));
+        }
+
+        if self.topic_alias_maximum > 0 {
+            properties.topic_alias_max = Some(TopicAliasMaximum::new(self.topic_alias_maximum));
+        }
+
+        properties.maximum_packet_size = self.maximum_packet_size.map(MaximumPacketSize::new);
+    }
+
+    /// Checks that the will of a connecting client, if any, only uses what's
+    /// available.
+    pub(crate) fn check_will(&self, connect_packet: &ConnectPacket) -> Result<()> {
+        let flags = &connect_packet.flags;
+
+        if !flags.will_flag {
+            return Ok(());
+        }
+
+        // [MQTT-3.2.2-12]
+        // If a Server receives a CONNECT packet containing a Will QoS that
+        // exceeds its capabilities, it MUST reject the connection.
+        if flags.will_qos > self.maximum_qos {
+            return Err(ReasonCode::QoSNotSupported.into());
+        }
+
+        // [MQTT-3.2.2-13]
+        // If the Server receives a CONNECT packet containing a Will Message
+        // with the Will Retain 1, and it does not support retained messages,
+        // the Server MUST reject the connection request.
+        if flags.will_retain && !self.retain_available {
+            return Err(ReasonCode::RetainNotSupported.into());
+        }
+
+        Ok(())
+    }
+
+    /// Returns the keep alive, in seconds, of a client asking for
+    /// `requested`, 0 meaning it has none.
+    pub(crate) fn keep_alive(&self, requested: u16) -> u16 {
+        match (self.server_keep_alive, self.max_keep_alive) {
+            (Some(keep_alive), _) => keep_alive,
+            (None, Some(max)) if requested == 0 || requested > max => max,
+            _ => requested,
+        }
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Capabilities {
+            maximum_qos: QoS::ExactlyOnce,
+            retain_available: true,
+            wildcard_subscriptions: true,
+            subscription_identifiers: true,
+            shared_subscriptions: true,
+            topic_alias_maximum: 0,
+            server_keep_alive: None,
+            max_keep_alive: None,
+            maximum_packet_size: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mercurio_core::{error::Error, properties::ReceiveMaximum, qos::QoS, reason::ReasonCode};
+    use mercurio_packets::connack::ConnAckProperties;
+
+    use super::{Capabilities, IpFilter, Quotas, TopicPolicy, TopicRewrite};
+
+    #[test]
+    fn test_keep_alive() {
+        let capabilities = Capabilities::default();
+        assert_eq!(capabilities.keep_alive(0), 0);
+        assert_eq!(capabilities.keep_alive(600), 600);
+
+        let capabilities = Capabilities {
+            max_keep_alive: Some(300),
+            ..Default::default()
+        };
+        assert_eq!(capabilities.keep_alive(0), 300);
+        assert_eq!(capabilities.keep_alive(60), 60);
+        assert_eq!(capabilities.keep_alive(600), 300);
+
+        let capabilities = Capabilities {
+            server_keep_alive: Some(30),
+            max_keep_alive: Some(300),
+            ..Default::default()
+        };
+        assert_eq!(capabilities.keep_alive(0), 30);
+        assert_eq!(capabilities.keep_alive(60), 30);
+    }
+
+    #[test]
+    fn test_receive_maximum() {
+        let mut properties = ConnAckProperties::default();
+        Quotas::default().advertise(&mut properties);
+        assert_eq!(properties.receive_maximum, None);
+
+        let quotas = Quotas {
+            max_inflight_messages: 10,
+            ..Default::default()
+        };
+        quotas.advertise(&mut properties);
+        assert_eq!(properties.receive_maximum, Some(ReceiveMaximum::new(10)));
+    }
+
+    #[test]
+    fn test_topic_policy() {
+        let policy = TopicPolicy {
+            filter: "commands/#".to_string(),
+            max_payload_size: Some(1024),
+            maximum_qos: Some(QoS::AtLeastOnce),
+        };
+
+        assert!(policy.check(QoS::AtLeastOnce, 1024).is_ok());
+        assert!(matches!(
+            policy.check(QoS::AtMostOnce, 1025),
+            Err(Error::MQTTReasonCode(ReasonCode::QuotaExceeded))
+        ));
+        assert!(matches!(
+            policy.check(QoS::ExactlyOnce, 0),
+            Err(Error::MQTTReasonCode(ReasonCode::NotAuthorized))
+        ));
+    }
+
+    #[test]
+    fn test_ip_filter() {
+        let filter = IpFilter {
+            allow: vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()],
+            deny: vec!["10.0.1.0/24".parse().unwrap()],
+        };
+
+        assert!(filter.allows("10.0.0.1".parse().unwrap()));
+        assert!(filter.allows("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(filter.allows("fd00::1".parse().unwrap()));
+        assert!(!filter.allows("10.0.1.1".parse().unwrap()));
+        assert!(!filter.allows("192.168.0.1".parse().unwrap()));
+
+        // Anyone but the denied ones without an allow list
+        let filter = IpFilter {
+            deny: vec!["192.168.0.0/16".parse().unwrap()],
+            ..Default::default()
+        };
+
+        assert!(filter.allows("10.0.0.1".parse().unwrap()));
+        assert!(!filter.allows("192.168.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_topic_rewrite() {
+        let rewrite = TopicRewrite {
+            from: "legacy/".to_string(),
+            to: "tenants/{username}/".to_string(),
+        };
+
+        assert_eq!(
+            rewrite
+                .apply("legacy/sensors/#", "client", Some("acme"))
+                .unwrap(),
+            Some("tenants/acme/sensors/#".to_string())
+        );
+        assert_eq!(
+            rewrite.apply("sensors/1", "client", Some("acme")).unwrap(),
+            None
+        );
+        assert_eq!(rewrite.apply("sensors/1", "client", None).unwrap(), None);
+
+        assert_eq!(
+            rewrite.restore("tenants/acme/sensors/1", "client", Some("acme")),
+            Some("legacy/sensors/1".to_string())
+        );
+        assert_eq!(
+            rewrite.restore("tenants/other/sensors/1", "client", Some("acme")),
+            None
+        );
+
+        // Without a user name, or one that would escape its tenant
+        for user_name in [None, Some("+"), Some("a/b")] {
+            assert!(matches!(
+                rewrite.apply("legacy/sensors/1", "client", user_name),
+                Err(Error::MQTTReasonCode(ReasonCode::NotAuthorized))
+            ));
+        }
+    }
+}
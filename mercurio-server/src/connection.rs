@@ -1,32 +1,197 @@
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
 use bytes::BytesMut;
+use serde::Serialize;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufWriter},
-    net::TcpStream,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter},
+    time::{self, Duration, Instant},
 };
+use tracing::trace_span;
 
 use mercurio_core::{codec::Encoder, error::Error, reason::ReasonCode, Result};
 use mercurio_packets::ControlPacket;
 
+/// Number of packets written without being flushed, beyond which they're
+/// flushed no matter what.
+const MAX_QUEUED_PACKETS: usize = 64;
+
+/// Initial capacity of the read buffer, which it's brought back to once a
+/// larger packet was read.
+const BUFFER_CAPACITY: usize = 8192;
+
+/// Time a packet can take to be received on top of what the minimum ingest
+/// rate allows for its size.
+const SLOW_READ_GRACE: Duration = Duration::from_secs(5);
+
+/// Transport packets are exchanged over, such as a TCP socket.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
 pub struct Connection {
-    stream: BufWriter<TcpStream>,
+    stream: BufWriter<Box<dyn Stream>>,
     buffer: BytesMut,
+
+    /// Packets written to the stream, not flushed yet.
+    queued: usize,
+
+    /// Size of the largest packet accepted from the peer, if limited.
+    max_packet_size: Option<usize>,
+
+    /// Bytes per second a packet must be received at, if limited.
+    min_ingest_rate: Option<u32>,
+
+    /// When the first bytes of the packet being received arrived, if any.
+    packet_started: Option<Instant>,
+
+    stats: Arc<ConnectionStats>,
+}
+
+/// Counters of a connection, updated as packets go through it and by the
+/// session it's used for.
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionStats {
+    pub(crate) packets_received: AtomicU64,
+    pub(crate) packets_sent: AtomicU64,
+    pub(crate) bytes_received: AtomicU64,
+    pub(crate) bytes_sent: AtomicU64,
+
+    /// Messages of the client refused by the broker.
+    pub(crate) publishes_dropped: AtomicU64,
+
+    /// Messages sent to the client waiting for their acknowledgement.
+    pub(crate) acks_pending: AtomicU64,
+
+    /// When the last packet was received, in seconds since the Unix epoch.
+    pub(crate) last_activity: AtomicU64,
+}
+
+/// Values of the [`ConnectionStats`] at some point, as reported under
+/// `$SYS/`, by the `$CONTROL/` commands and in the audit log.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub(crate) struct ConnectionStatsSnapshot {
+    pub(crate) packets_received: u64,
+    pub(crate) packets_sent: u64,
+    pub(crate) bytes_received: u64,
+    pub(crate) bytes_sent: u64,
+    pub(crate) publishes_dropped: u64,
+    pub(crate) acks_pending: u64,
+    pub(crate) last_activity: u64,
+}
+
+impl ConnectionStats {
+    pub(crate) fn snapshot(&self) -> ConnectionStatsSnapshot {
+        ConnectionStatsSnapshot {
+            packets_received: self.packets_received.load(Ordering::Relaxed),
+            packets_sent: self.packets_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            publishes_dropped: self.publishes_dropped.load(Ordering::Relaxed),
+            acks_pending: self.acks_pending.load(Ordering::Relaxed),
+            last_activity: self.last_activity.load(Ordering::Relaxed),
+        }
+    }
 }
 
 impl Connection {
-    pub fn new(socket: TcpStream) -> Connection {
+    pub fn new(socket: impl Stream + 'static) -> Connection {
         Connection {
-            stream: BufWriter::new(socket),
-            buffer: BytesMut::with_capacity(8192),
+            stream: BufWriter::new(Box::new(socket)),
+            buffer: BytesMut::with_capacity(BUFFER_CAPACITY),
+            queued: 0,
+            max_packet_size: None,
+            min_ingest_rate: None,
+            packet_started: None,
+            stats: Arc::default(),
         }
     }
 
+    pub(crate) fn stats(&self) -> Arc<ConnectionStats> {
+        self.stats.clone()
+    }
+
+    /// Limits the size of the packets read from the peer, and the time they
+    /// can take to be received.
+    ///
+    /// Packets larger than `max_packet_size` fail with Packet Too Large, and
+    /// those received at less than `min_ingest_rate` bytes per second with a
+    /// timed out I/O error.
+    pub fn set_read_limits(&mut self, max_packet_size: Option<u32>, min_ingest_rate: Option<u32>) {
+        self.max_packet_size = max_packet_size.map(|size| size as usize);
+        self.min_ingest_rate = min_ingest_rate;
+    }
+
     pub async fn read_packet(&mut self) -> Result<Option<ControlPacket>> {
         loop {
+            let size = packet_size(&self.buffer);
+
+            // [MQTT-3.2.2-15]
+            // The Client MUST NOT send packets exceeding Maximum Packet Size
+            // to the Server.
+            if let (Some(size), Some(max_packet_size)) = (size, self.max_packet_size) {
+                if size > max_packet_size {
+                    return Err(ReasonCode::PacketTooLarge.into());
+                }
+            }
+
+            let buffered = self.buffer.len();
+
             if let Some(e) = self.parse_packet()? {
+                self.packet_started = None;
+
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|elapsed| elapsed.as_secs())
+                    .unwrap_or_default();
+
+                self.stats.packets_received.fetch_add(1, Ordering::Relaxed);
+                self.stats
+                    .bytes_received
+                    .fetch_add((buffered - self.buffer.len()) as u64, Ordering::Relaxed);
+                self.stats.last_activity.store(now, Ordering::Relaxed);
+
+                // Don't keep a large buffer around because of a single large
+                // packet
+                if self.buffer.is_empty() && self.buffer.capacity() > BUFFER_CAPACITY {
+                    self.buffer = BytesMut::with_capacity(BUFFER_CAPACITY);
+                }
+
                 return Ok(Some(e));
             }
 
-            if 0 == self.stream.read_buf(&mut self.buffer).await? {
+            // Once a packet started coming in, the rest of it has to follow
+            let deadline = match self.min_ingest_rate {
+                Some(rate) if !self.buffer.is_empty() => {
+                    let started = *self.packet_started.get_or_insert_with(Instant::now);
+                    let size = size.unwrap_or(self.buffer.len());
+
+                    Some(
+                        started
+                            + SLOW_READ_GRACE
+                            + Duration::from_secs_f64(size as f64 / f64::from(rate.max(1))),
+                    )
+                }
+                _ => None,
+            };
+
+            let read = self.stream.read_buf(&mut self.buffer);
+
+            let read = match deadline {
+                Some(deadline) => match time::timeout_at(deadline, read).await {
+                    Ok(read) => read?,
+                    Err(_) => return Err(io::Error::from(io::ErrorKind::TimedOut).into()),
+                },
+                None => read.await?,
+            };
+
+            if 0 == read {
                 if self.buffer.is_empty() {
                     return Ok(None);
                 } else {
@@ -36,21 +201,72 @@ impl Connection {
         }
     }
 
+    /// Refuses a client speaking an older version of the protocol, which
+    /// can't read MQTT 5 packets, with the CONNACK of MQTT 3.1 and 3.1.1:
+    /// Connection Refused, unacceptable protocol version.
+    pub async fn refuse_protocol_version(&mut self) -> Result<()> {
+        self.stream.write_all(&[0x20, 0x02, 0x00, 0x01]).await?;
+        self.stream.flush().await?;
+
+        Ok(())
+    }
+
+    /// Writes a packet and flushes it, along with those queued before.
     pub async fn write_packet(&mut self, packet: ControlPacket) -> Result<()> {
+        self.queue_packet(packet).await?;
+        self.flush().await
+    }
+
+    /// Writes a packet without flushing it, so that it's sent with the next
+    /// ones in as few syscalls as possible.
+    ///
+    /// Callers are expected to [`flush`](Connection::flush) once there's
+    /// nothing more to send right away, or the connection [is
+    /// full](Connection::is_full).
+    ///
+    /// Packets whose properties the peer would refuse aren't written at all.
+    pub async fn queue_packet(&mut self, packet: ControlPacket) -> Result<()> {
         let mut buf = BytesMut::new();
 
+        packet.validate()?;
         packet.encode(&mut buf);
 
         self.stream.write_all(&buf).await?;
-        self.stream.flush().await?;
+        self.queued += 1;
+
+        self.stats.packets_sent.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .bytes_sent
+            .fetch_add(buf.len() as u64, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    pub async fn flush(&mut self) -> Result<()> {
+        if self.queued > 0 {
+            self.stream.flush().await?;
+            self.queued = 0;
+        }
 
         Ok(())
     }
 
+    /// Whether enough packets are queued that they should be flushed.
+    pub fn is_full(&self) -> bool {
+        self.queued >= MAX_QUEUED_PACKETS
+    }
+
+    /// Whether a whole packet was already received, and can be read without
+    /// waiting.
+    pub fn has_packet(&mut self) -> bool {
+        ControlPacket::check(&mut self.buffer).is_ok()
+    }
+
     fn parse_packet(&mut self) -> Result<Option<ControlPacket>> {
         match ControlPacket::check(&mut self.buffer) {
             Ok(_) => {
-                let packet = ControlPacket::parse(&mut self.buffer)?;
+                let packet =
+                    trace_span!("decode").in_scope(|| ControlPacket::parse(&mut self.buffer))?;
 
                 Ok(Some(packet))
             }
@@ -62,3 +278,106 @@ impl Connection {
         }
     }
 }
+
+/// Returns the size of the packet at the start of `buffer`, fixed header
+/// included, once enough of it was received to know.
+fn packet_size(buffer: &[u8]) -> Option<usize> {
+    let mut remaining_length = 0;
+
+    // The Remaining Length is a Variable Byte Integer of up to 4 bytes,
+    // following the first byte of the fixed header
+    for (i, byte) in buffer.iter().skip(1).take(4).enumerate() {
+        remaining_length |= usize::from(byte & 0x7f) << (7 * i);
+
+        if byte & 0x80 == 0 {
+            return Some(1 + (i + 1) + remaining_length);
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use mercurio_core::{error::Error, reason::ReasonCode};
+    use mercurio_packets::{pingresp::PingRespPacket, ControlPacket};
+
+    use super::{packet_size, Connection};
+
+    #[test]
+    fn test_packet_size() {
+        assert_eq!(packet_size(&[]), None);
+        assert_eq!(packet_size(&[0x30]), None);
+        assert_eq!(packet_size(&[0xc0, 0x00]), Some(2));
+        assert_eq!(packet_size(&[0x30, 0x7f]), Some(2 + 127));
+        assert_eq!(packet_size(&[0x30, 0x80]), None);
+        assert_eq!(packet_size(&[0x30, 0x80, 0x01]), Some(3 + 128));
+        assert_eq!(
+            packet_size(&[0x30, 0xff, 0xff, 0xff, 0x7f]),
+            Some(5 + 268_435_455)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_refuse_older_protocol_versions() {
+        let connects: [&[u8]; 2] = [
+            // MQTT 3.1
+            &[
+                0x10, 0x0f, 0x00, 0x06, b'M', b'Q', b'I', b's', b'd', b'p', 0x03, 0x02, 0x00, 0x3c,
+                0x00, 0x01, b'a',
+            ],
+            // MQTT 3.1.1
+            &[
+                0x10, 0x0d, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04, 0x02, 0x00, 0x3c, 0x00, 0x01,
+                b'a',
+            ],
+        ];
+
+        for connect in connects {
+            let (mut client, server) = tokio::io::duplex(64);
+            let mut connection = Connection::new(server);
+
+            client.write_all(connect).await.unwrap();
+
+            assert!(matches!(
+                connection.read_packet().await,
+                Err(Error::MQTTReasonCode(
+                    ReasonCode::UnsupportedProtocolVersion
+                ))
+            ));
+            connection.refuse_protocol_version().await.unwrap();
+
+            // The CONNACK of MQTT 3.1 and 3.1.1, which they can read
+            let mut connack = [0u8; 4];
+            client.read_exact(&mut connack).await.unwrap();
+            assert_eq!(connack, [0x20, 0x02, 0x00, 0x01]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut connection = Connection::new(server);
+        let stats = connection.stats();
+
+        // Two PUBACKs
+        let pubacks = [0x40, 0x02, 0x00, 0x01, 0x40, 0x02, 0x00, 0x02];
+        client.write_all(&pubacks).await.unwrap();
+        for _ in 0..2 {
+            let packet = connection.read_packet().await.unwrap();
+            assert!(matches!(packet, Some(ControlPacket::PubAck(_))));
+        }
+
+        let pingresp = ControlPacket::PingResp(PingRespPacket {});
+        connection.write_packet(pingresp).await.unwrap();
+
+        let stats = stats.snapshot();
+        assert_eq!(stats.packets_received, 2);
+        assert_eq!(stats.bytes_received, 8);
+        assert_eq!(stats.packets_sent, 1);
+        assert_eq!(stats.bytes_sent, 2);
+        assert!(stats.last_activity > 0);
+    }
+}
@@ -0,0 +1,345 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::{error, info};
+
+use mercurio_core::{message::Message, qos::QoS};
+
+use crate::{
+    audit::{AuditEvent, AuditLog},
+    auth::CredentialValidator,
+    broker::{Broker, ExternalSubscription},
+    fanout::FanoutPool,
+    session_manager::SessionManager,
+    shutdown::Shutdown,
+    topic_tree,
+};
+
+/// Namespace of the administrative commands, `$CONTROL/<command>`.
+const NAMESPACE: &str = "$CONTROL";
+
+/// Carries out the administrative commands clients publish under
+/// `$CONTROL/`, as JSON objects:
+///
+/// - `$CONTROL/retained/delete`, `{"filter":"sensors/#"}`, deletes the
+///   retained messages matching the filter.
+/// - `$CONTROL/clients/disconnect`, `{"client_id":"sensor-1"}`, disconnects
+///   the client with Administrative Action, keeping its session.
+/// - `$CONTROL/clients/stats`, `{"client_id":"sensor-1"}`, returns the
+///   counters of the client's connection, such as `{"packets_received":12,
+///   "bytes_sent":340,...}`.
+/// - `$CONTROL/credentials/reload`, `{}`, has the credential validators
+///   read the credentials again.
+///
+/// The outcome, such as `{"status":"ok","deleted":2}` or
+/// `{"status":"error","error":"..."}`, is published on the Response Topic of
+/// the command, with its Correlation Data, or on `<command topic>/response`
+/// without one. Commands with a Response Topic their client isn't allowed to
+/// publish on are refused. Every command is recorded in the audit log.
+pub(crate) struct ControlHandler {
+    broker: Broker,
+    fanout: FanoutPool,
+    session_manager: SessionManager,
+    credential_validators: Vec<Arc<dyn CredentialValidator>>,
+    audit: AuditLog,
+    subscription: ExternalSubscription,
+    shutdown: Shutdown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    DeleteRetained,
+    Disconnect,
+    ClientStats,
+    ReloadCredentials,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeleteRetained {
+    filter: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Disconnect {
+    client_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClientStats {
+    client_id: String,
+}
+
+impl ControlHandler {
+    pub(crate) fn new(
+        broker: Broker,
+        fanout: FanoutPool,
+        session_manager: SessionManager,
+        credential_validators: Vec<Arc<dyn CredentialValidator>>,
+        audit: AuditLog,
+        shutdown: Shutdown,
+    ) -> ControlHandler {
+        let subscription = broker
+            .subscribe_external(&format!("{}/#", NAMESPACE))
+            .expect("the control namespace is a valid topic filter");
+
+        ControlHandler {
+            broker,
+            fanout,
+            session_manager,
+            credential_validators,
+            audit,
+            subscription,
+            shutdown,
+        }
+    }
+
+    pub(crate) async fn run(&mut self) {
+        while !self.shutdown.is_shutdown() {
+            tokio::select! {
+                message = self.subscription.recv() => match message {
+                    Some(message) => self.handle(&message).await,
+                    None => return,
+                },
+                _ = self.shutdown.recv() => {}
+            }
+        }
+    }
+
+    async fn handle(&mut self, message: &Message) {
+        // Responses end up here as well
+        let command = match parse_command(&message.topic) {
+            Some(command) => command,
+            None => return,
+        };
+
+        info!(
+            "Client `{}` sent {:?}",
+            message.origin.as_deref().unwrap_or_default(),
+            command
+        );
+
+        // The client chose where the outcome goes, it has to be allowed to
+        // publish there itself
+        if let (Some(client_id), Some(topic)) = (&message.origin, &message.response_topic) {
+            let allowed = self
+                .session_manager
+                .authorization(client_id)
+                .await
+                .is_some_and(|authorization| authorization.can_publish(topic));
+
+            if !allowed {
+                info!(
+                    "Client `{}` isn't allowed to publish on `{}`, ignoring its {:?}",
+                    client_id, topic, command
+                );
+
+                self.audit(message, "not allowed to publish on the response topic");
+                return;
+            }
+        }
+
+        let payload = message.payload.as_deref().unwrap_or_default();
+        let response = match self.execute(command, payload).await {
+            Ok(Value::Object(mut response)) => {
+                self.audit(message, "ok");
+                response.insert("status".to_string(), json!("ok"));
+                Value::Object(response)
+            }
+            Ok(_) => {
+                self.audit(message, "ok");
+                json!({ "status": "ok" })
+            }
+            Err(err) => {
+                self.audit(message, &err);
+                json!({ "status": "error", "error": err })
+            }
+        };
+
+        let topic = message
+            .response_topic
+            .clone()
+            .unwrap_or_else(|| format!("{}/response", message.topic));
+
+        self.respond(topic, message.correlation_data.clone(), response)
+            .await;
+    }
+
+    fn audit(&self, message: &Message, outcome: &str) {
+        let payload = message.payload.as_deref().unwrap_or_default();
+
+        self.audit.emit(AuditEvent::Control {
+            client_id: message.origin.clone(),
+            command: message.topic.clone(),
+            payload: String::from_utf8_lossy(payload).into_owned(),
+            outcome: outcome.to_string(),
+        });
+    }
+
+    async fn execute(&self, command: Command, payload: &[u8]) -> Result<Value, String> {
+        match command {
+            Command::DeleteRetained => {
+                let DeleteRetained { filter } = parse_payload(payload)?;
+                let deleted = self
+                    .broker
+                    .delete_retained(&filter)
+                    .map_err(|err| err.to_string())?;
+
+                Ok(json!({ "deleted": deleted }))
+            }
+            Command::Disconnect => {
+                let Disconnect { client_id } = parse_payload(payload)?;
+
+                match self.session_manager.kick(&client_id).await {
+                    true => Ok(Value::Null),
+                    false => Err(format!("Client `{}` isn't connected", client_id)),
+                }
+            }
+            Command::ClientStats => {
+                let ClientStats { client_id } = parse_payload(payload)?;
+
+                match self.session_manager.client_stats(&client_id).await {
+                    Some(stats) => serde_json::to_value(stats).map_err(|err| err.to_string()),
+                    None => Err(format!("Client `{}` isn't connected", client_id)),
+                }
+            }
+            Command::ReloadCredentials => {
+                for validator in &self.credential_validators {
+                    validator.reload().await.map_err(|err| err.to_string())?;
+                }
+
+                Ok(Value::Null)
+            }
+        }
+    }
+
+    async fn respond(&self, topic: String, correlation_data: Option<Bytes>, response: Value) {
+        let message = Message {
+            correlation_data,
+            ..Message::new(topic.clone(), response.to_string(), QoS::AtMostOnce)
+        };
+
+        if let Err(err) = self.fanout.publish(topic.clone(), message).await {
+            error!(cause = ?err, "Failed to publish `{}`", topic);
+        }
+    }
+}
+
+/// Returns `true` if `topic` is in the namespace of the commands.
+pub(crate) fn is_command(topic: &str) -> bool {
+    topic_tree::matches(&format!("{}/#", NAMESPACE), topic)
+}
+
+fn parse_command(topic: &str) -> Option<Command> {
+    let command = topic.strip_prefix(NAMESPACE)?.strip_prefix('/')?;
+
+    match command {
+        "retained/delete" => Some(Command::DeleteRetained),
+        "clients/disconnect" => Some(Command::Disconnect),
+        "clients/stats" => Some(Command::ClientStats),
+        "credentials/reload" => Some(Command::ReloadCredentials),
+        _ => None,
+    }
+}
+
+fn parse_payload<'a, T: Deserialize<'a>>(payload: &'a [u8]) -> Result<T, String> {
+    serde_json::from_slice(payload).map_err(|err| format!("Invalid command: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use serde_json::{json, Value};
+    use tokio::{sync::broadcast, time::timeout};
+
+    use mercurio_core::{message::Message, qos::QoS};
+
+    use crate::{
+        audit::AuditLog, broker::Broker, fanout::FanoutPool, session_manager::SessionManager,
+        shutdown::Shutdown,
+    };
+
+    use super::ControlHandler;
+
+    fn message(topic: &str, payload: &str, retain: bool) -> Message {
+        Message {
+            retain,
+            ..Message::new(topic, payload.to_string(), QoS::AtMostOnce)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delete_retained() {
+        let broker = Broker::new(Default::default(), Default::default());
+        let (_notify, receiver) = broadcast::channel(1);
+        let (audit, mut records) = AuditLog::new();
+        let mut control = ControlHandler::new(
+            broker.clone(),
+            FanoutPool::without_workers(broker.clone()),
+            SessionManager::new(),
+            Vec::new(),
+            audit,
+            Shutdown::new(receiver),
+        );
+
+        for topic in ["sensors/1", "sensors/2", "actuators/1"] {
+            broker.publish(topic, message(topic, "42", true)).unwrap();
+        }
+
+        let mut responses = broker
+            .subscribe_external("$CONTROL/retained/delete/response")
+            .unwrap();
+
+        let mut command = message(
+            "$CONTROL/retained/delete",
+            r#"{"filter":"sensors/#"}"#,
+            false,
+        );
+        control.handle(&command).await;
+
+        let response = responses.recv().await.unwrap();
+        let response: Value = serde_json::from_slice(&response.payload.unwrap()).unwrap();
+        assert_eq!(response, json!({ "status": "ok", "deleted": 2 }));
+        assert!(broker.retained("sensors/#").is_empty());
+        assert_eq!(broker.retained("#").len(), 1);
+
+        // Answered on the Response Topic, with the Correlation Data
+        command.payload = Some(Bytes::from("{}"));
+        command.response_topic = Some("admin/responses".to_string());
+        command.correlation_data = Some(Bytes::from("1"));
+
+        let mut responses = broker.subscribe_external("admin/responses").unwrap();
+        control.handle(&command).await;
+
+        let response = responses.recv().await.unwrap();
+        assert_eq!(response.correlation_data, Some(Bytes::from("1")));
+
+        let response: Value = serde_json::from_slice(&response.payload.unwrap()).unwrap();
+        assert_eq!(response["status"], json!("error"));
+
+        // A client can't have the outcome published where it couldn't itself
+        command.origin = Some("client".to_string());
+        command.payload = Some(Bytes::from(r##"{"filter":"#"}"##));
+        control.handle(&command).await;
+
+        assert!(timeout(Duration::from_millis(10), responses.recv())
+            .await
+            .is_err());
+        assert_eq!(broker.retained("#").len(), 1);
+
+        // Every command is audited, along with its outcome
+        let outcomes: Vec<Value> = std::iter::from_fn(|| records.try_recv().ok())
+            .map(|record| serde_json::to_value(record).unwrap())
+            .map(|record| record["outcome"].clone())
+            .collect();
+        assert_eq!(outcomes.len(), 3);
+        assert_eq!(outcomes[0], json!("ok"));
+        assert_eq!(
+            outcomes[2],
+            json!("not allowed to publish on the response topic")
+        );
+    }
+}
@@ -0,0 +1,256 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use tokio::sync::{
+    mpsc::{self, error::SendError},
+    oneshot,
+};
+use tracing::error;
+
+use mercurio_core::{message::Message, qos::QoS, reason::ReasonCode, Result};
+
+use crate::broker::Broker;
+
+/// Messages waiting for each worker before publishers have to wait.
+const SHARD_QUEUE_CAPACITY: usize = 1024;
+
+/// A message handed to a worker, along with where to report the outcome of
+/// routing it, if anyone waits for it.
+type Job = (String, Message, Option<oneshot::Sender<Result<()>>>);
+
+/// Routes the messages published to their subscribers, away from the tasks
+/// reading from the clients.
+///
+/// Messages of the clients, wills included, of the bridges and of the broker
+/// itself all go through it. Each client, or topic for messages published by
+/// nobody in particular, is assigned a worker, which routes its messages in
+/// the order they were published. Workers route in parallel, the broker only
+/// holds them up while the subscriptions change.
+///
+/// Matching the topic against the subscriptions and queuing the message for
+/// each of them can take a while with many subscribers, during which the
+/// publishing client's packets would otherwise go unread. That's only spared
+/// for QoS 0 messages, which just wait while their worker is behind by a
+/// full queue. The others wait until they're routed so they're only
+/// acknowledged once every subscriber has them, the read loop of their
+/// client waiting along.
+#[derive(Debug, Clone)]
+pub(crate) struct FanoutPool {
+    broker: Broker,
+    shards: Arc<[mpsc::Sender<Job>]>,
+    stats: FanoutStats,
+}
+
+/// Statistics of each worker of a [`FanoutPool`], as published under
+/// `$SYS/`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct FanoutStats {
+    shards: Arc<[ShardStats]>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ShardStats {
+    /// Messages routed so far.
+    pub(crate) routed: AtomicU64,
+
+    /// Messages waiting to be routed.
+    pub(crate) queued: AtomicU64,
+}
+
+impl FanoutPool {
+    /// Spawns `workers` workers. Without any, messages are routed by the
+    /// publishing tasks themselves.
+    ///
+    /// Workers stop once every handle to the pool is gone and they're done
+    /// with the messages left, dropping `shutdown_complete` then.
+    pub(crate) fn new(
+        broker: Broker,
+        workers: usize,
+        shutdown_complete: mpsc::Sender<()>,
+    ) -> FanoutPool {
+        let stats = FanoutStats {
+            shards: (0..workers).map(|_| ShardStats::default()).collect(),
+        };
+
+        let shards = (0..workers)
+            .map(|shard| {
+                let (sender, mut receiver) = mpsc::channel::<Job>(SHARD_QUEUE_CAPACITY);
+                let broker = broker.clone();
+                let stats = stats.clone();
+                let shutdown_complete = shutdown_complete.clone();
+
+                tokio::spawn(async move {
+                    while let Some((topic, message, routed)) = receiver.recv().await {
+                        let shard = &stats.shards[shard];
+                        shard.queued.fetch_sub(1, Ordering::Relaxed);
+
+                        let res = broker.publish(&topic, message);
+
+                        match routed {
+                            Some(routed) => {
+                                let _ = routed.send(res);
+                            }
+                            None => {
+                                if let Err(err) = res {
+                                    error!(cause = ?err, "Failed to publish message");
+                                }
+                            }
+                        }
+
+                        shard.routed.fetch_add(1, Ordering::Relaxed);
+                    }
+
+                    drop(shutdown_complete);
+                });
+
+                sender
+            })
+            .collect();
+
+        FanoutPool {
+            broker,
+            shards,
+            stats,
+        }
+    }
+
+    /// Creates a pool without workers, routing messages right away.
+    #[cfg(test)]
+    pub(crate) fn without_workers(broker: Broker) -> FanoutPool {
+        let (shutdown_complete, _) = mpsc::channel(1);
+        FanoutPool::new(broker, 0, shutdown_complete)
+    }
+
+    pub(crate) fn stats(&self) -> FanoutStats {
+        self.stats.clone()
+    }
+
+    /// Hands a message to the worker of its publisher. Messages other than
+    /// QoS 0 ones are waited for, returning whether they were routed.
+    pub(crate) async fn publish(&self, topic: String, message: Message) -> Result<()> {
+        if self.shards.is_empty() {
+            return self.broker.publish(&topic, message);
+        }
+
+        let mut hasher = DefaultHasher::new();
+        message
+            .origin
+            .as_deref()
+            .unwrap_or(&topic)
+            .hash(&mut hasher);
+        let shard = (hasher.finish() % self.shards.len() as u64) as usize;
+
+        let (routed, outcome) = match message.qos {
+            QoS::AtMostOnce => (None, None),
+            _ => {
+                let (sender, receiver) = oneshot::channel();
+                (Some(sender), Some(receiver))
+            }
+        };
+
+        self.stats.shards[shard]
+            .queued
+            .fetch_add(1, Ordering::Relaxed);
+
+        // The workers only go away once the pool does
+        if let Err(SendError((topic, message, _))) =
+            self.shards[shard].send((topic, message, routed)).await
+        {
+            self.stats.shards[shard]
+                .queued
+                .fetch_sub(1, Ordering::Relaxed);
+
+            return self.broker.publish(&topic, message);
+        }
+
+        match outcome {
+            Some(outcome) => outcome
+                .await
+                .unwrap_or_else(|_| Err(ReasonCode::UnspecifiedError.into())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl FanoutStats {
+    /// Returns the statistics of each worker, by index.
+    pub(crate) fn shards(&self) -> &[ShardStats] {
+        &self.shards
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use mercurio_core::{message::Message, qos::QoS};
+    use tokio::sync::mpsc;
+
+    use crate::broker::Broker;
+
+    use super::FanoutPool;
+
+    fn message(origin: &str, payload: &'static str) -> Message {
+        Message {
+            origin: Some(origin.to_string()),
+            ..Message::new("a/b", payload, QoS::AtLeastOnce)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_messages_are_routed_in_order() {
+        let broker = Broker::new(Default::default(), Default::default());
+        let (queue, mut rx) = broker.queue();
+        broker.subscribe("a/b", "subscriber", queue);
+
+        let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel(1);
+        let fanout = FanoutPool::new(broker, 4, shutdown_complete_tx);
+        let stats = fanout.stats();
+
+        for payload in ["1", "2", "3"] {
+            fanout
+                .publish("a/b".to_string(), message("client", payload))
+                .await
+                .unwrap();
+        }
+
+        // The workers finish routing once the pool is gone
+        drop(fanout);
+        assert!(shutdown_complete_rx.recv().await.is_none());
+
+        let payloads: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|message| message.payload.unwrap())
+            .collect();
+        assert_eq!(payloads, ["1", "2", "3"]);
+
+        let routed: u64 = stats
+            .shards()
+            .iter()
+            .map(|shard| shard.routed.load(Ordering::Relaxed))
+            .sum();
+        assert_eq!(routed, 3);
+    }
+
+    #[tokio::test]
+    async fn test_publish_waits_for_routing() {
+        let broker = Broker::new(Default::default(), Default::default());
+        let (queue, mut rx) = broker.queue();
+        broker.subscribe("a/b", "subscriber", queue);
+
+        let (shutdown_complete_tx, _shutdown_complete_rx) = mpsc::channel(1);
+        let fanout = FanoutPool::new(broker, 2, shutdown_complete_tx);
+
+        // Once a QoS 1 message is published, it's queued for its subscribers
+        fanout
+            .publish("a/b".to_string(), message("client", "1"))
+            .await
+            .unwrap();
+        assert_eq!(rx.try_recv().unwrap().payload.unwrap(), "1");
+    }
+}
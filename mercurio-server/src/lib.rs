@@ -1,7 +1,21 @@
-mod broker;
+pub mod audit;
+pub mod auth;
+pub mod bridge;
+pub mod broker;
+pub mod cluster;
+pub mod config;
 pub mod connection;
+mod control;
+mod fanout;
+mod presence;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod scram;
 pub mod server;
 mod session;
 pub mod session_manager;
 mod shutdown;
+mod sparkplug;
+mod sys;
+mod telemetry;
 mod topic_tree;
@@ -0,0 +1,116 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+use tracing::{debug, error};
+
+use mercurio_core::{message::Message, qos::QoS};
+
+use crate::{fanout::FanoutPool, topic_tree};
+
+/// Publishes whether clients are connected, as retained messages on a topic
+/// of their own.
+///
+/// Unlike a will, the status is published by the broker itself, so it stays
+/// accurate however the connection ends. It isn't for clients whose
+/// identifier contains `+`, `#` or `/`.
+#[derive(Debug, Clone)]
+pub(crate) struct Presence {
+    /// Topic with `{client_id}` standing for the client identifier, if the
+    /// status of clients is published at all.
+    topic: Option<String>,
+    fanout: FanoutPool,
+}
+
+impl Presence {
+    pub(crate) fn new(topic: Option<String>, fanout: FanoutPool) -> Presence {
+        Presence { topic, fanout }
+    }
+
+    /// Publishes `{"status":"online","timestamp":...}` for the client.
+    pub(crate) async fn online(&self, client_id: &str) {
+        self.publish(
+            client_id,
+            json!({ "status": "online", "timestamp": timestamp() }),
+        )
+        .await;
+    }
+
+    /// Publishes `{"status":"offline","reason":...,"timestamp":...}` for the
+    /// client, with the reason its connection was closed.
+    pub(crate) async fn offline(&self, client_id: &str, reason: &str) {
+        self.publish(
+            client_id,
+            json!({ "status": "offline", "reason": reason, "timestamp": timestamp() }),
+        )
+        .await;
+    }
+
+    async fn publish(&self, client_id: &str, status: serde_json::Value) {
+        let topic = match &self.topic {
+            Some(topic) => topic.replace("{client_id}", client_id),
+            None => return,
+        };
+
+        // An identifier with wildcards or levels of its own would make the
+        // topic invalid, or a different one
+        if !topic_tree::is_valid_topic_level(client_id) {
+            debug!(
+                "Not publishing the status of client `{}`, its identifier can't be in a topic",
+                client_id
+            );
+            return;
+        }
+
+        let message = Message {
+            retain: true,
+            ..Message::new(topic.clone(), status.to_string(), QoS::AtMostOnce)
+        };
+
+        if let Err(err) = self.fanout.publish(topic.clone(), message).await {
+            error!(cause = ?err, "Failed to publish `{}`", topic);
+        }
+    }
+}
+
+/// Milliseconds since the Unix epoch.
+fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use crate::{broker::Broker, fanout::FanoutPool};
+
+    use super::Presence;
+
+    #[tokio::test]
+    async fn test_status_is_retained() {
+        let broker = Broker::new(Default::default(), Default::default());
+        let presence = Presence::new(
+            Some("$SYS/clients/{client_id}/status".to_string()),
+            FanoutPool::without_workers(broker.clone()),
+        );
+
+        let status = |client_id: &str| -> Value {
+            let retained = broker.retained(&format!("$SYS/clients/{}/status", client_id));
+            serde_json::from_slice(&retained[0].payload.clone().unwrap()).unwrap()
+        };
+
+        presence.online("sensor-1").await;
+        assert_eq!(status("sensor-1")["status"], json!("online"));
+
+        presence.offline("sensor-1", "Keep alive timeout").await;
+        assert_eq!(status("sensor-1")["status"], json!("offline"));
+        assert_eq!(status("sensor-1")["reason"], json!("Keep alive timeout"));
+
+        // Identifiers that don't make a single topic level are left out
+        presence.online("+").await;
+        presence.online("a/b").await;
+        assert_eq!(broker.retained("$SYS/clients/#").len(), 1);
+    }
+}
@@ -0,0 +1,136 @@
+use std::{fs::File, io, io::BufReader, net::SocketAddr, path::PathBuf};
+
+use tokio::{
+    io::Join,
+    sync::mpsc,
+    time::{self, Duration},
+};
+use tracing::{debug, warn};
+
+use crate::{
+    broker::Broker,
+    config::{IpFilter, ListenerConfig},
+};
+
+/// Number of established connections waiting for the listener to pick them
+/// up.
+const ACCEPT_QUEUE_CAPACITY: usize = 16;
+
+/// Listener accepting MQTT over QUIC, experimental.
+///
+/// Clients exchange MQTT packets over the first bidirectional stream they
+/// open on the connection.
+#[derive(Debug, Clone)]
+pub struct QuicConfig {
+    /// Address the UDP socket is bound to.
+    pub address: SocketAddr,
+
+    /// PEM file holding the certificate chain of the server.
+    pub certificate_chain: PathBuf,
+
+    /// PEM file holding the private key of the certificate.
+    pub private_key: PathBuf,
+
+    /// Settings of the listener, as for TCP ones.
+    pub listener: ListenerConfig,
+}
+
+pub(crate) type QuicStream = Join<quinn::RecvStream, quinn::SendStream>;
+
+/// Accepts QUIC connections in the background, so slow handshakes don't
+/// hold up the other clients.
+pub(crate) struct QuicAcceptor {
+    connections: mpsc::Receiver<(QuicStream, SocketAddr)>,
+}
+
+impl QuicAcceptor {
+    /// Binds the endpoint, giving clients `handshake_timeout` to establish
+    /// the connection and open their stream.
+    ///
+    /// Clients the IP filter of the listener refuses are turned away before
+    /// the handshake, and counted by `broker`.
+    pub(crate) fn bind(
+        config: &QuicConfig,
+        handshake_timeout: Duration,
+        broker: Broker,
+    ) -> io::Result<Self> {
+        let certificate_chain =
+            rustls_pemfile::certs(&mut BufReader::new(File::open(&config.certificate_chain)?))
+                .collect::<io::Result<Vec<_>>>()?;
+        let private_key =
+            rustls_pemfile::private_key(&mut BufReader::new(File::open(&config.private_key)?))?
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "No private key found")
+                })?;
+
+        let server_config = quinn::ServerConfig::with_single_cert(certificate_chain, private_key)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let endpoint = quinn::Endpoint::server(server_config, config.address)?;
+
+        let (sender, connections) = mpsc::channel(ACCEPT_QUEUE_CAPACITY);
+        tokio::spawn(accept(
+            endpoint,
+            sender,
+            handshake_timeout,
+            config.listener.ip_filter.clone(),
+            broker,
+        ));
+
+        Ok(QuicAcceptor { connections })
+    }
+
+    /// Returns the next established connection, or `None` once the endpoint
+    /// is closed.
+    pub(crate) async fn accept(&mut self) -> Option<(QuicStream, SocketAddr)> {
+        self.connections.recv().await
+    }
+}
+
+async fn accept(
+    endpoint: quinn::Endpoint,
+    sender: mpsc::Sender<(QuicStream, SocketAddr)>,
+    handshake_timeout: Duration,
+    ip_filter: IpFilter,
+    broker: Broker,
+) {
+    loop {
+        // Stops along with the listener
+        let incoming = tokio::select! {
+            Some(incoming) = endpoint.accept() => incoming,
+            _ = sender.closed() => break,
+            else => break,
+        };
+
+        if !ip_filter.allows(incoming.remote_address().ip()) {
+            debug!("Refusing connection from {}", incoming.remote_address());
+            broker.count_rejected_connection();
+            incoming.refuse();
+            continue;
+        }
+
+        let sender = sender.clone();
+
+        tokio::spawn(async move {
+            let peer = incoming.remote_address();
+            let establish = async {
+                let connection = incoming.await?;
+                let (send, recv) = connection.accept_bi().await?;
+
+                Ok::<_, quinn::ConnectionError>(tokio::io::join(recv, send))
+            };
+
+            match time::timeout(handshake_timeout, establish).await {
+                Ok(Ok(stream)) => {
+                    let _ = sender.send((stream, peer)).await;
+                }
+                Ok(Err(err)) => warn!(cause = ?err, "QUIC handshake with {} failed", peer),
+                Err(_) => warn!(
+                    "QUIC handshake with {} didn't complete within {:?}",
+                    peer, handshake_timeout
+                ),
+            }
+        });
+    }
+
+    endpoint.close(0u32.into(), b"");
+}
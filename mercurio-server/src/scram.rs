@@ -0,0 +1,345 @@
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use mercurio_core::{reason::ReasonCode, Result};
+
+use crate::auth::{AuthMethod, AuthSession, AuthStep, Authorization, Credentials};
+
+/// Name of the method, as sent in the Authentication Method property.
+pub const SCRAM_SHA_256: &str = "SCRAM-SHA-256";
+
+/// Default number of PBKDF2 iterations, as recommended by RFC 7677.
+pub const DEFAULT_ITERATIONS: u32 = 4096;
+
+/// Keys stored for a user, from which the password can't be recovered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScramCredentials {
+    pub salt: Vec<u8>,
+    pub iterations: u32,
+    pub stored_key: [u8; 32],
+    pub server_key: [u8; 32],
+}
+
+impl ScramCredentials {
+    /// Derives the keys of a user from their password.
+    pub fn new(password: &[u8], salt: &[u8], iterations: u32) -> ScramCredentials {
+        let mut salted_password = [0u8; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(password, salt, iterations, &mut salted_password);
+
+        let client_key = hmac(&salted_password, b"Client Key");
+
+        ScramCredentials {
+            salt: salt.to_vec(),
+            iterations,
+            stored_key: Sha256::digest(client_key).into(),
+            server_key: hmac(&salted_password, b"Server Key"),
+        }
+    }
+}
+
+/// SCRAM-SHA-256 enhanced authentication (RFC 5802 and RFC 7677).
+///
+/// The client proves it knows the password without sending it, and the
+/// server proves it knows the user's keys in the Authentication Data of the
+/// CONNACK. The user name of the CONNECT packet, if any, has to be the one
+/// authenticated. Channel binding isn't supported.
+#[derive(Debug, Default)]
+pub struct ScramSha256 {
+    users: Arc<HashMap<String, ScramCredentials>>,
+}
+
+/// Exchange with a single client.
+struct ScramSession {
+    users: Arc<HashMap<String, ScramCredentials>>,
+
+    /// User name of the CONNECT packet, if any, which has to be the one
+    /// authenticated.
+    user_name: Option<String>,
+
+    /// Set once the client-first message was handled.
+    exchange: Option<Exchange>,
+}
+
+struct Exchange {
+    user_name: String,
+    gs2_header: String,
+    client_first_bare: String,
+    server_first: String,
+    nonce: String,
+    credentials: ScramCredentials,
+}
+
+impl ScramSha256 {
+    pub fn new() -> ScramSha256 {
+        ScramSha256::default()
+    }
+
+    pub fn add_user(&mut self, user_name: impl Into<String>, credentials: ScramCredentials) {
+        Arc::make_mut(&mut self.users).insert(user_name.into(), credentials);
+    }
+}
+
+impl ScramSession {
+    fn client_first(&self, message: &str) -> Result<(Exchange, String)> {
+        // gs2-header: the channel binding flag, an optional authzid
+        let mut parts = message.splitn(3, ',');
+        let (cb_flag, authzid, bare) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(cb_flag), Some(authzid), Some(bare)) => (cb_flag, authzid, bare),
+            _ => return Err(ReasonCode::NotAuthorized.into()),
+        };
+
+        if cb_flag != "n" && cb_flag != "y" {
+            return Err(ReasonCode::NotAuthorized.into());
+        }
+
+        let attributes = attributes(bare)?;
+        let user_name = attributes
+            .get("n")
+            .map(|name| name.replace("=2C", ",").replace("=3D", "="))
+            .ok_or(ReasonCode::NotAuthorized)?;
+        let client_nonce = attributes.get("r").ok_or(ReasonCode::NotAuthorized)?;
+
+        if self
+            .user_name
+            .as_ref()
+            .is_some_and(|name| *name != user_name)
+        {
+            return Err(ReasonCode::BadUserNameOrPassword.into());
+        }
+
+        let credentials = self
+            .users
+            .get(&user_name)
+            .cloned()
+            .ok_or(ReasonCode::BadUserNameOrPassword)?;
+
+        let mut server_nonce = [0u8; 18];
+        rand::thread_rng().fill_bytes(&mut server_nonce);
+        let nonce = format!("{}{}", client_nonce, BASE64.encode(server_nonce));
+
+        let server_first = format!(
+            "r={},s={},i={}",
+            nonce,
+            BASE64.encode(&credentials.salt),
+            credentials.iterations
+        );
+
+        let exchange = Exchange {
+            user_name,
+            gs2_header: format!("{},{},", cb_flag, authzid),
+            client_first_bare: bare.to_string(),
+            server_first: server_first.clone(),
+            nonce,
+            credentials,
+        };
+
+        Ok((exchange, server_first))
+    }
+}
+
+impl Exchange {
+    fn client_final(&self, message: &str) -> Result<String> {
+        let (without_proof, proof) = message
+            .rsplit_once(",p=")
+            .ok_or(ReasonCode::NotAuthorized)?;
+
+        let attributes = attributes(without_proof)?;
+
+        if attributes.get("c") != Some(&BASE64.encode(&self.gs2_header).as_str())
+            || attributes.get("r") != Some(&self.nonce.as_str())
+        {
+            return Err(ReasonCode::NotAuthorized.into());
+        }
+
+        let proof = BASE64
+            .decode(proof)
+            .map_err(|_| ReasonCode::NotAuthorized)?;
+
+        if proof.len() != 32 {
+            return Err(ReasonCode::NotAuthorized.into());
+        }
+
+        let auth_message = format!(
+            "{},{},{}",
+            self.client_first_bare, self.server_first, without_proof
+        );
+
+        let client_signature = hmac(&self.credentials.stored_key, auth_message.as_bytes());
+        let client_key: Vec<u8> = proof
+            .iter()
+            .zip(client_signature)
+            .map(|(p, s)| p ^ s)
+            .collect();
+
+        let stored_key: [u8; 32] = Sha256::digest(client_key).into();
+
+        if stored_key != self.credentials.stored_key {
+            return Err(ReasonCode::BadUserNameOrPassword.into());
+        }
+
+        let server_signature = hmac(&self.credentials.server_key, auth_message.as_bytes());
+
+        Ok(format!("v={}", BASE64.encode(server_signature)))
+    }
+}
+
+impl AuthMethod for ScramSha256 {
+    fn name(&self) -> &str {
+        SCRAM_SHA_256
+    }
+
+    fn start(&self, credentials: &Credentials) -> Box<dyn AuthSession> {
+        Box::new(ScramSession {
+            users: self.users.clone(),
+            user_name: credentials.user_name.clone(),
+            exchange: None,
+        })
+    }
+}
+
+#[async_trait]
+impl AuthSession for ScramSession {
+    async fn start(&mut self, data: Option<Bytes>) -> Result<AuthStep> {
+        let (exchange, server_first) = self.client_first(&utf8(&data)?)?;
+        self.exchange = Some(exchange);
+
+        Ok(AuthStep::Continue(Bytes::from(server_first)))
+    }
+
+    async fn auth_continue(&mut self, data: Option<Bytes>) -> Result<AuthStep> {
+        // The client-final message is expected once, right after the
+        // server-first one
+        let exchange = self.exchange.take().ok_or(ReasonCode::ProtocolError)?;
+        let server_final = exchange.client_final(&utf8(&data)?)?;
+
+        // The client is known by the user name it authenticated with
+        Ok(AuthStep::Success {
+            data: Some(Bytes::from(server_final)),
+            authorization: Authorization {
+                user_name: Some(exchange.user_name),
+                ..Default::default()
+            },
+        })
+    }
+}
+
+fn hmac(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+fn utf8(data: &Option<Bytes>) -> Result<String> {
+    data.as_ref()
+        .and_then(|data| std::str::from_utf8(data).ok())
+        .map(str::to_string)
+        .ok_or_else(|| ReasonCode::NotAuthorized.into())
+}
+
+/// Parses comma separated `name=value` attributes.
+fn attributes(message: &str) -> Result<HashMap<&str, &str>> {
+    message
+        .split(',')
+        .map(|attribute| {
+            attribute
+                .split_once('=')
+                .ok_or_else(|| ReasonCode::NotAuthorized.into())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+    use bytes::Bytes;
+    use sha2::{Digest, Sha256};
+
+    use crate::auth::{AuthMethod, AuthStep, Credentials};
+
+    use super::{hmac, ScramCredentials, ScramSha256};
+
+    /// Computes the client-final message for the given server-first.
+    fn client_final(password: &[u8], client_first_bare: &str, server_first: &str) -> String {
+        let mut attributes = server_first.split(',');
+        let nonce = &attributes.next().unwrap()[2..];
+        let salt = BASE64.decode(&attributes.next().unwrap()[2..]).unwrap();
+        let iterations: u32 = attributes.next().unwrap()[2..].parse().unwrap();
+
+        let mut salted_password = [0u8; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(password, &salt, iterations, &mut salted_password);
+        let client_key = hmac(&salted_password, b"Client Key");
+        let stored_key: [u8; 32] = Sha256::digest(client_key).into();
+
+        let without_proof = format!("c=biws,r={}", nonce);
+        let auth_message = format!("{},{},{}", client_first_bare, server_first, without_proof);
+        let signature = hmac(&stored_key, auth_message.as_bytes());
+        let proof: Vec<u8> = client_key
+            .iter()
+            .zip(signature)
+            .map(|(k, s)| k ^ s)
+            .collect();
+
+        format!("{},p={}", without_proof, BASE64.encode(proof))
+    }
+
+    async fn exchange(scram: &ScramSha256, password: &[u8]) -> mercurio_core::Result<AuthStep> {
+        let mut session = scram.start(&Credentials::default());
+
+        let client_first_bare = "n=user,r=rOprNGfwEbeRWgbNEkqO";
+        let client_first = Bytes::from(format!("n,,{}", client_first_bare));
+
+        let server_first = match session.start(Some(client_first)).await? {
+            AuthStep::Continue(data) => String::from_utf8(data.to_vec()).unwrap(),
+            step => panic!("Unexpected step {:?}", step),
+        };
+
+        assert!(server_first.starts_with("r=rOprNGfwEbeRWgbNEkqO"));
+
+        let client_final = client_final(password, client_first_bare, &server_first);
+
+        let step = session
+            .auth_continue(Some(Bytes::from(client_final.clone())))
+            .await;
+
+        // The exchange is over, replaying the last message doesn't work
+        assert!(session
+            .auth_continue(Some(Bytes::from(client_final)))
+            .await
+            .is_err());
+
+        step
+    }
+
+    #[tokio::test]
+    async fn test_scram_exchange() {
+        let mut scram = ScramSha256::new();
+        scram.add_user("user", ScramCredentials::new(b"pencil", b"salt", 4096));
+
+        match exchange(&scram, b"pencil").await.unwrap() {
+            AuthStep::Success {
+                data,
+                authorization,
+            } => {
+                assert!(data.unwrap().starts_with(b"v="));
+                assert_eq!(authorization.user_name.as_deref(), Some("user"));
+            }
+            step => panic!("Unexpected step {:?}", step),
+        }
+
+        assert!(exchange(&scram, b"wrong").await.is_err());
+
+        // Authenticating as someone else than the user name of the CONNECT
+        let mut session = scram.start(&Credentials {
+            user_name: Some("other".to_string()),
+            ..Default::default()
+        });
+        let client_first = Bytes::from("n,,n=user,r=rOprNGfwEbeRWgbNEkqO");
+        assert!(session.start(Some(client_first)).await.is_err());
+    }
+}
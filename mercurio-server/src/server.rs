@@ -1,49 +1,313 @@
-use std::future::Future;
+use std::{
+    future::{self, Future},
+    io,
+    net::SocketAddr,
+    sync::Arc,
+};
 
 use tokio::{
     net::{TcpListener, TcpStream},
-    sync::broadcast,
-    time::{self, Duration},
+    sync::{broadcast, mpsc},
+    task::JoinSet,
+    time::{self, Duration, Instant},
 };
-use tracing::{error, info};
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
 
-use mercurio_core::Result;
-use mercurio_packets::{connect::ConnectPacket, ControlPacket};
+use mercurio_core::{
+    error::Error,
+    properties::{AuthenticationData, AuthenticationMethod, ReasonString},
+    reason::ReasonCode,
+    Result,
+};
+use mercurio_packets::{
+    auth::{AuthPacket, AuthProperties},
+    connack::{ConnAckPacket, ConnAckProperties},
+    connect::ConnectPacket,
+    disconnect::{DisconnectPacket, DisconnectProperties},
+    ControlPacket,
+};
 
+#[cfg(feature = "quic")]
+use crate::quic::QuicAcceptor;
 use crate::{
+    audit::{AuditEvent, AuditLog, AuditWriter},
+    auth::{AuthManager, AuthStep, Authorization, CredentialValidator, Credentials},
+    bridge::Bridge,
     broker::Broker,
+    config::{Config, IpFilter, ListenerConfig, ResponseInformationConfig},
     connection::Connection,
+    control::ControlHandler,
+    fanout::FanoutPool,
+    presence::Presence,
+    session::Session,
     session_manager::{SessionManager, SessionManagerDropGuard},
     shutdown::Shutdown,
+    sparkplug::SparkplugMonitor,
+    sys::SysPublisher,
+    telemetry,
 };
 
+/// Time waited before accepting connections again when out of resources,
+/// doubling with each failure in a row.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Longest time waited before accepting connections again.
+const ACCEPT_MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longest time taken telling a client why it's being disconnected, so that
+/// one not reading doesn't hold up its will.
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
 struct Listener {
-    listener: TcpListener,
+    listener: Acceptor,
     broker: Broker,
-    session_manager_holder: SessionManagerDropGuard,
+    fanout: FanoutPool,
+    session_manager: SessionManager,
+    credential_validator: Option<Arc<dyn CredentialValidator>>,
+    auth_manager: AuthManager,
+    response_information: Option<ResponseInformationConfig>,
+    audit: AuditLog,
+    presence: Presence,
+    connect_timeout: Duration,
+    ip_filter: IpFilter,
     notify_shutdown: broadcast::Sender<()>,
+    shutdown_complete_tx: mpsc::Sender<()>,
+}
+
+/// Source of the connections of a listener.
+enum Acceptor {
+    Tcp(TcpListener),
+    #[cfg(feature = "quic")]
+    Quic(QuicAcceptor),
 }
 
 struct Handler {
     broker: Broker,
+    fanout: FanoutPool,
     session_manager: SessionManager,
+    credential_validator: Option<Arc<dyn CredentialValidator>>,
+    auth_manager: AuthManager,
+    response_information: Option<ResponseInformationConfig>,
+    audit: AuditLog,
+    presence: Presence,
     connection: Connection,
+    peer: Option<SocketAddr>,
     shutdown: Shutdown,
+
+    /// When the client must be done connecting, authentication included.
+    handshake_deadline: Instant,
+
+    /// Not used directly. Dropped along with the handler, which lets the
+    /// listener know when all the connections are done.
+    _shutdown_complete: mpsc::Sender<()>,
 }
 
 pub async fn run(listener: TcpListener, shutdown: impl Future) {
+    run_with_config(listener, Config::default(), shutdown).await
+}
+
+pub async fn run_with_config(listener: TcpListener, config: Config, shutdown: impl Future) {
+    run_listeners(
+        vec![(listener, ListenerConfig::default())],
+        config,
+        shutdown,
+    )
+    .await
+}
+
+/// Runs the broker on several sockets, each with its own settings.
+///
+/// Clients share the same sessions and topics whichever listener they
+/// connect on.
+pub async fn run_listeners(
+    listeners: Vec<(TcpListener, ListenerConfig)>,
+    config: Config,
+    shutdown: impl Future,
+) {
+    let broker = Broker::from_config(&config);
+    run_with_broker(listeners, broker, config, shutdown).await
+}
+
+/// Runs the broker on several sockets, routing messages through `broker`,
+/// which the application keeps a handle to so it can publish and subscribe
+/// itself.
+///
+/// The quotas and capabilities are the ones `broker` was created with,
+/// usually through [`Broker::from_config`] with the same `config`.
+pub async fn run_with_broker(
+    listeners: Vec<(TcpListener, ListenerConfig)>,
+    broker: Broker,
+    config: Config,
+    shutdown: impl Future,
+) {
     let (notify_shutdown, _) = broadcast::channel(1);
+    let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel(1);
+
+    let fanout = FanoutPool::new(
+        broker.clone(),
+        config.fanout_workers,
+        shutdown_complete_tx.clone(),
+    );
+
+    // The writer stops once every connection, and with it every handle to
+    // the audit log, is gone.
+    let audit = match config.audit_sink.clone() {
+        Some(sink) => {
+            let (audit, receiver) = AuditLog::new();
+            let mut writer = AuditWriter::new(sink, receiver, fanout.clone());
+            let shutdown_complete = shutdown_complete_tx.clone();
+
+            tokio::spawn(async move {
+                writer.run().await;
+                drop(shutdown_complete);
+            });
+
+            audit
+        }
+        None => AuditLog::disabled(),
+    };
+
+    let presence = Presence::new(config.presence_topic.clone(), fanout.clone());
+    let session_manager_holder = SessionManagerDropGuard::new();
+
+    let mut servers = JoinSet::new();
+
+    // Every validator gets to reload its credentials on request
+    let mut credential_validators: Vec<Arc<dyn CredentialValidator>> = Vec::new();
+    let listener_auth = listeners.iter().map(|(_, listener)| &listener.auth);
+    #[cfg(feature = "quic")]
+    let listener_auth = listener_auth.chain(config.quic.iter().map(|quic| &quic.listener.auth));
 
-    let mut server = Listener {
-        listener,
-        broker: Broker::new(),
-        session_manager_holder: SessionManagerDropGuard::new(),
-        notify_shutdown,
+    for validator in listener_auth.filter_map(|auth| match auth {
+        Some(auth) => auth.credential_validator.as_ref(),
+        None => config.credential_validator.as_ref(),
+    }) {
+        if !credential_validators
+            .iter()
+            .any(|known| Arc::ptr_eq(known, validator))
+        {
+            credential_validators.push(validator.clone());
+        }
+    }
+
+    let new_listener = |listener: Acceptor, listener_config: ListenerConfig| {
+        let (credential_validator, auth_manager) = match listener_config.auth {
+            Some(auth) => (auth.credential_validator, auth.auth_manager),
+            None => (
+                config.credential_validator.clone(),
+                config.auth_manager.clone(),
+            ),
+        };
+
+        Listener {
+            listener,
+            broker: broker.clone(),
+            fanout: fanout.clone(),
+            session_manager: session_manager_holder.session_manager(),
+            credential_validator,
+            auth_manager,
+            response_information: config.response_information.clone(),
+            audit: audit.clone(),
+            presence: presence.clone(),
+            connect_timeout: config.connect_timeout,
+            ip_filter: listener_config.ip_filter,
+            notify_shutdown: notify_shutdown.clone(),
+            shutdown_complete_tx: shutdown_complete_tx.clone(),
+        }
     };
 
+    for (listener, listener_config) in listeners {
+        let mut server = new_listener(Acceptor::Tcp(listener), listener_config);
+        servers.spawn(async move { server.run().await });
+    }
+
+    #[cfg(feature = "quic")]
+    if let Some(quic) = &config.quic {
+        match QuicAcceptor::bind(quic, config.connect_timeout, broker.clone()) {
+            Ok(acceptor) => {
+                let mut server = new_listener(Acceptor::Quic(acceptor), quic.listener.clone());
+                servers.spawn(async move { server.run().await });
+            }
+            Err(err) => error!(cause = ?err, "Failed to listen for QUIC on {}", quic.address),
+        }
+    }
+
+    let peer_links = config
+        .cluster
+        .as_ref()
+        .map(|cluster| cluster.peer_links())
+        .unwrap_or_default();
+
+    for bridge_config in config.bridges.into_iter().chain(peer_links) {
+        let mut bridge = Bridge::new(
+            bridge_config,
+            broker.clone(),
+            fanout.clone(),
+            Shutdown::new(notify_shutdown.subscribe()),
+        );
+        let shutdown_complete = shutdown_complete_tx.clone();
+
+        tokio::spawn(async move {
+            bridge.run().await;
+            drop(shutdown_complete);
+        });
+    }
+
+    if let Some(interval) = config.sys_interval {
+        let mut sys = SysPublisher::new(
+            broker.clone(),
+            session_manager_holder.session_manager(),
+            fanout.clone(),
+            interval,
+            Shutdown::new(notify_shutdown.subscribe()),
+        );
+
+        let shutdown_complete = shutdown_complete_tx.clone();
+
+        tokio::spawn(async move {
+            sys.run().await;
+            drop(shutdown_complete);
+        });
+    }
+
+    if config.sparkplug {
+        let mut sparkplug = SparkplugMonitor::new(
+            &broker,
+            fanout.clone(),
+            Shutdown::new(notify_shutdown.subscribe()),
+        );
+
+        let shutdown_complete = shutdown_complete_tx.clone();
+
+        tokio::spawn(async move {
+            sparkplug.run().await;
+            drop(shutdown_complete);
+        });
+    }
+
+    if config.control {
+        let mut control = ControlHandler::new(
+            broker.clone(),
+            fanout.clone(),
+            session_manager_holder.session_manager(),
+            credential_validators,
+            audit.clone(),
+            Shutdown::new(notify_shutdown.subscribe()),
+        );
+
+        let shutdown_complete = shutdown_complete_tx.clone();
+
+        tokio::spawn(async move {
+            control.run().await;
+            drop(shutdown_complete);
+        });
+    }
+
+    // A listener that can't accept connections anymore brings the broker
+    // down
     tokio::select! {
-        result = server.run() => {
-            if result.is_err() {
+        Some(result) = servers.join_next() => {
+            if !matches!(result, Ok(Ok(()))) {
                 error!("Failed to accept new connection");
             }
         }
@@ -51,103 +315,866 @@ pub async fn run(listener: TcpListener, shutdown: impl Future) {
             info!("Shutting down!");
         }
     }
+
+    // Stop accepting connections, then let every task know it's time to
+    // wrap up. Connections send a DISCONNECT to their client before closing.
+    servers.shutdown().await;
+
+    drop(audit);
+    drop(presence);
+    drop(fanout);
+    drop(notify_shutdown);
+    drop(shutdown_complete_tx);
+
+    // All the tasks are done once every sender has been dropped
+    if time::timeout(config.shutdown_timeout, shutdown_complete_rx.recv())
+        .await
+        .is_err()
+    {
+        warn!(
+            "Some connections didn't finish within {:?}, dropping them",
+            config.shutdown_timeout
+        );
+    }
 }
 
 impl Listener {
     async fn run(&mut self) -> Result<()> {
         loop {
-            let socket = self.accept().await?;
+            let (mut connection, peer) = self.accept().await?;
 
-            info!("Got a connection: {:#?}", socket.peer_addr());
+            info!("Got a connection: {:#?}", peer);
+
+            connection.set_read_limits(
+                self.broker.capabilities().maximum_packet_size,
+                self.broker.quotas().min_ingest_rate,
+            );
 
             let mut handler = Handler {
                 broker: self.broker.clone(),
-                session_manager: self.session_manager_holder.session_manager(),
-                connection: Connection::new(socket),
+                fanout: self.fanout.clone(),
+                session_manager: self.session_manager.clone(),
+                credential_validator: self.credential_validator.clone(),
+                auth_manager: self.auth_manager.clone(),
+                response_information: self.response_information.clone(),
+                audit: self.audit.clone(),
+                presence: self.presence.clone(),
+                connection,
+                peer,
                 shutdown: Shutdown::new(self.notify_shutdown.subscribe()),
+                handshake_deadline: Instant::now() + self.connect_timeout,
+                _shutdown_complete: self.shutdown_complete_tx.clone(),
             };
 
-            tokio::spawn(async move {
-                match handler.connection.read_packet().await {
-                    // [MQTT-3.1.0-1]
-                    // After a Network Connection is established by a Client
-                    // to a Server, the first packet sent from the Client to
-                    // the Server MUST be a CONNECT packet.
-                    Ok(Some(ControlPacket::Connect(p))) => {
-                        if let Err(err) = handler.run(p).await {
-                            error!(cause = ?err, "Connection error");
+            let span = info_span!("connection", peer = ?peer, client_id = field::Empty);
+            let connect_timeout = self.connect_timeout;
+
+            tokio::spawn(
+                async move {
+                    // Sockets that never send anything don't get to hold on
+                    // to a task
+                    let connect = time::timeout_at(
+                        handler.handshake_deadline,
+                        handler.connection.read_packet(),
+                    );
+
+                    match connect.await {
+                        // [MQTT-3.1.0-1]
+                        // After a Network Connection is established by a Client
+                        // to a Server, the first packet sent from the Client to
+                        // the Server MUST be a CONNECT packet.
+                        Ok(Ok(Some(ControlPacket::Connect(p)))) => {
+                            if let Err(err) = handler.run(p).await {
+                                error!(cause = ?err, "Connection error");
+                            }
+                        }
+                        // [MQTT-3.1.2-2]
+                        // The Server MAY send a CONNACK and then MUST close
+                        // the Network Connection. It's sent the way the
+                        // client's version of the protocol has it.
+                        Ok(Err(Error::MQTTReasonCode(ReasonCode::UnsupportedProtocolVersion))) => {
+                            info!("Refusing client with an unsupported protocol version");
+
+                            if let Err(err) = handler.connection.refuse_protocol_version().await {
+                                error!(cause = ?err, "Connection error");
+                            }
                         }
+                        Err(_) => warn!("No CONNECT received within {:?}", connect_timeout),
+                        _ => error!("ConnectPacket expectation not met"),
                     }
-                    _ => error!("ConnectPacket expectation not met"),
                 }
-            });
+                .instrument(span),
+            );
         }
     }
 
-    async fn accept(&mut self) -> Result<TcpStream> {
-        let mut backoff = 1;
+    async fn accept(&mut self) -> Result<(Connection, Option<SocketAddr>)> {
+        match &mut self.listener {
+            Acceptor::Tcp(listener) => {
+                let socket = accept_tcp(listener, &self.ip_filter, &self.broker).await?;
+                let peer = socket.peer_addr().ok();
 
-        loop {
-            match self.listener.accept().await {
-                Ok((socket, _)) => return Ok(socket),
-                Err(err) => {
-                    if backoff > 64 {
-                        return Err(err.into());
-                    }
-                }
+                Ok((Connection::new(socket), peer))
             }
+            #[cfg(feature = "quic")]
+            Acceptor::Quic(acceptor) => match acceptor.accept().await {
+                Some((stream, peer)) => Ok((Connection::new(stream), Some(peer))),
+                None => Err(io::Error::from(io::ErrorKind::NotConnected).into()),
+            },
+        }
+    }
+}
 
-            time::sleep(Duration::from_secs(backoff)).await;
+/// Why a connection couldn't be accepted, which tells whether the listener
+/// can go on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AcceptFailure {
+    /// The client went away before it was accepted, the next one may be just
+    /// fine.
+    Connection,
 
-            backoff *= 2;
+    /// Out of file descriptors or memory, which connected clients should give
+    /// back sooner or later. They're served in the meantime.
+    Resources,
+
+    /// The socket isn't listening anymore.
+    Listener,
+}
+
+impl AcceptFailure {
+    fn of(err: &io::Error) -> AcceptFailure {
+        match err.kind() {
+            io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut => AcceptFailure::Connection,
+            io::ErrorKind::InvalidInput => AcceptFailure::Listener,
+            _ => AcceptFailure::Resources,
+        }
+    }
+}
+
+/// Accepts the next connection allowed by the filter, getting over the
+/// failures that only concern a single connection or last until resources
+/// are freed.
+async fn accept_tcp(
+    listener: &TcpListener,
+    ip_filter: &IpFilter,
+    broker: &Broker,
+) -> Result<TcpStream> {
+    let mut backoff = ACCEPT_BACKOFF;
+
+    loop {
+        let err = match listener.accept().await {
+            Ok((_, peer)) if !ip_filter.allows(peer.ip()) => {
+                debug!("Refusing connection from {}", peer);
+                broker.count_rejected_connection();
+                continue;
+            }
+            Ok((socket, _)) => return Ok(socket),
+            Err(err) => err,
+        };
+
+        broker.count_accept_error();
+
+        match AcceptFailure::of(&err) {
+            AcceptFailure::Connection => {
+                warn!(cause = ?err, "Failed to accept a connection");
+            }
+            AcceptFailure::Listener => return Err(err.into()),
+            AcceptFailure::Resources => {
+                warn!(
+                    cause = ?err,
+                    "Failed to accept a connection, trying again in {:?}", backoff
+                );
+
+                time::sleep(backoff).await;
+                backoff = (backoff * 2).min(ACCEPT_MAX_BACKOFF);
+            }
         }
     }
 }
 
 impl Handler {
     async fn run(&mut self, connect_packet: ConnectPacket) -> Result<()> {
+        if let Err(Error::MQTTReasonCode(reason)) =
+            self.broker.capabilities().check_will(&connect_packet)
+        {
+            info!(
+                "Refusing connection of client `{}`: {}",
+                connect_packet.payload.client_id, reason
+            );
+
+            self.refuse(reason).await?;
+            return Err(reason.into());
+        }
+
+        let (authorization, properties) = self.authenticate(&connect_packet).await?;
+        let auth_method = properties
+            .authentication_method
+            .as_ref()
+            .map(|method| method.value.clone());
+        let user_name = connect_packet.payload.user_name.clone();
+
         let mut session = self
             .session_manager
-            .start_session(&mut self.connection, connect_packet)
+            .start_session(
+                &mut self.connection,
+                connect_packet,
+                properties,
+                self.response_information.as_ref(),
+                &self.broker,
+            )
             .await?;
 
+        session.authorize(authorization, auth_method.clone()).await;
+
+        let client_id = session.get_client_id().await;
+        Span::current().record("client_id", client_id.as_str());
+        self.audit.emit(AuditEvent::Connect {
+            client_id: client_id.clone(),
+            user_name,
+            peer: self.peer.map(|peer| peer.to_string()),
+            auth_method,
+        });
+        self.presence.online(&client_id).await;
+
+        let result = self.serve(&mut session).await;
+
+        // Let the client know why the connection is being closed, if it's
+        // because of something it did wrong, before its will goes out. The
+        // session ends even if that fails.
+        if let Err(Error::MQTTReasonCode(reason)) = &result {
+            if reason.get_code() >= 0x80 {
+                match time::timeout(DISCONNECT_TIMEOUT, self.disconnect(*reason)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => debug!(cause = ?err, "Failed to send DISCONNECT"),
+                    Err(_) => debug!("Timed out sending DISCONNECT"),
+                }
+            }
+        }
+
+        // [MQTT-3.1.2-8]
+        // The Will Message MUST be published after the Network Connection is
+        // subsequently closed and either the Will Delay Interval has elapsed
+        // or the Session ends, unless the Will Message has been deleted by
+        // the Server on receipt of a DISCONNECT packet with Reason Code 0x00
+        // (Normal disconnection).
+        let publish_will = !matches!(result, Ok(Some(ReasonCode::NormalDisconnection)));
+        self.session_manager
+            .end_session(&session, &self.broker, &self.fanout, publish_will)
+            .await;
+
+        let reason = match &result {
+            Ok(Some(reason)) => reason.to_string(),
+            Ok(None) => "Connection closed".to_string(),
+            Err(err) => err.to_string(),
+        };
+        self.presence.offline(&client_id, &reason).await;
+        self.audit.emit(AuditEvent::Disconnect {
+            client_id,
+            reason,
+            stats: self.connection.stats().snapshot(),
+        });
+
+        result.map(|_| ())
+    }
+
+    /// Authenticates the client, refusing the connection with a CONNACK
+    /// carrying the reason if it fails.
+    ///
+    /// Returns what the client may do, along with the properties to include
+    /// in the CONNACK.
+    async fn authenticate(
+        &mut self,
+        connect_packet: &ConnectPacket,
+    ) -> Result<(Authorization, ConnAckProperties)> {
+        let method = connect_packet
+            .properties
+            .as_ref()
+            .and_then(|p| p.authentication_method.as_ref())
+            .map(|method| method.value.clone());
+
+        // Neither a client dragging out the exchange nor a slow validator
+        // get to hold on to the connection
+        let deadline = self.handshake_deadline;
+        let result = time::timeout_at(deadline, async {
+            match method {
+                Some(method) => self.authenticate_enhanced(connect_packet, method).await,
+                None => self
+                    .authenticate_credentials(connect_packet)
+                    .await
+                    .map(|authorization| (authorization, ConnAckProperties::default())),
+            }
+        })
+        .await;
+
+        let result = match result {
+            Ok(result) => result,
+            Err(_) => {
+                warn!(
+                    "Client `{}` didn't complete authentication in time",
+                    connect_packet.payload.client_id
+                );
+
+                return Err(io::Error::from(io::ErrorKind::TimedOut).into());
+            }
+        };
+
+        if let Err(Error::MQTTReasonCode(reason)) = &result {
+            if reason.get_code() >= 0x80 {
+                info!(
+                    "Refusing connection of client `{}`: {}",
+                    connect_packet.payload.client_id, reason
+                );
+
+                self.audit.emit(AuditEvent::AuthFailed {
+                    client_id: connect_packet.payload.client_id.clone(),
+                    user_name: connect_packet.payload.user_name.clone(),
+                    peer: self.peer.map(|peer| peer.to_string()),
+                    reason: reason.to_string(),
+                });
+
+                self.refuse(*reason).await?;
+            }
+        }
+
+        result
+    }
+
+    /// Refuses the connection with a CONNACK carrying the reason.
+    async fn refuse(&mut self, reason: ReasonCode) -> Result<()> {
+        let ack = ConnAckPacket {
+            reason_code: reason,
+            ..Default::default()
+        };
+
+        self.connection
+            .write_packet(ControlPacket::ConnAck(ack))
+            .await
+    }
+
+    async fn authenticate_credentials(
+        &mut self,
+        connect_packet: &ConnectPacket,
+    ) -> Result<Authorization> {
+        match &self.credential_validator {
+            Some(validator) => validator.validate(&Credentials::from(connect_packet)).await,
+            None => Ok(Authorization::default()),
+        }
+    }
+
+    /// Runs the enhanced authentication exchange of `method`, made of AUTH
+    /// packets going back and forth until the method is done.
+    async fn authenticate_enhanced(
+        &mut self,
+        connect_packet: &ConnectPacket,
+        method: String,
+    ) -> Result<(Authorization, ConnAckProperties)> {
+        let mut session = self
+            .auth_manager
+            .start(&method, &Credentials::from(connect_packet))
+            .ok_or(ReasonCode::BadAuthenticationMethod)?;
+
+        let data = connect_packet
+            .properties
+            .as_ref()
+            .and_then(|p| p.authentication_data.as_ref())
+            .map(|data| data.value.clone());
+
+        let mut step = session.start(data).await?;
+
+        loop {
+            let data = match step {
+                AuthStep::Continue(data) => data,
+                AuthStep::Success {
+                    data,
+                    authorization,
+                } => {
+                    let properties = ConnAckProperties {
+                        authentication_method: Some(AuthenticationMethod::new(method)),
+                        authentication_data: data.map(AuthenticationData::new),
+                        ..Default::default()
+                    };
+
+                    return Ok((authorization, properties));
+                }
+            };
+
+            let auth = AuthPacket {
+                reason: ReasonCode::ContinueAuthentication,
+                properties: AuthProperties {
+                    auth_method: Some(AuthenticationMethod::new(method.clone())),
+                    auth_data: Some(AuthenticationData::new(data)),
+                    ..Default::default()
+                },
+            };
+            self.connection
+                .write_packet(ControlPacket::Auth(auth))
+                .await?;
+
+            let auth = match self.connection.read_packet().await? {
+                Some(ControlPacket::Auth(auth)) => auth,
+                None => return Err(ReasonCode::NormalDisconnection.into()),
+                Some(_) => return Err(ReasonCode::ProtocolError.into()),
+            };
+
+            // [MQTT-4.12.0-5]
+            // The Authentication Method is a UTF-8 Encoded String and MUST be
+            // the same as in the CONNECT packet.
+            if auth.reason != ReasonCode::ContinueAuthentication
+                || auth.properties.auth_method.map(|m| m.value) != Some(method.clone())
+            {
+                return Err(ReasonCode::ProtocolError.into());
+            }
+
+            let data = auth.properties.auth_data.map(|data| data.value);
+            step = session.auth_continue(data).await?;
+        }
+    }
+
+    /// Serves the client until the connection is closed, returning the
+    /// reason it was closed with, if any.
+    async fn serve(&mut self, session: &mut Session) -> Result<Option<ReasonCode>> {
+        let mut slow_consumer = session.slow_consumer().await;
+        let mut kicked = session.kicked();
+        session.set_connection_stats(Some(self.connection.stats()));
+
+        let keep_alive = session.keep_alive_timeout().await;
+        let idle = time::sleep(keep_alive.unwrap_or(Duration::MAX));
+        tokio::pin!(idle);
+
         while !self.shutdown.is_shutdown() {
             tokio::select! {
                 // Try to read and process new incoming packet
                 maybe_packet = self.connection.read_packet() => {
+                    if let Some(keep_alive) = keep_alive {
+                        idle.as_mut().reset(Instant::now() + keep_alive);
+                    }
+
                     let packet = match maybe_packet? {
-                        None | Some(ControlPacket::Disconnect(_)) => {
-                            return Ok(());
+                        None => return Ok(None),
+                        Some(ControlPacket::Disconnect(disconnect)) => {
+                            let expiry_interval = disconnect
+                                .properties
+                                .and_then(|p| p.session_expiry_interval);
+
+                            if let Some(expiry_interval) = expiry_interval {
+                                session.set_expiry_interval(expiry_interval.value).await?;
+                            }
+
+                            self.connection.flush().await?;
+                            return Ok(Some(disconnect.reason));
                         }
                         Some(packet) => packet,
                     };
 
+                    let span = telemetry::incoming_span(&packet);
+
                     let maybe_res = session
                         .process_incoming(
                             packet,
                             &self.broker,
-                        ).await?;
+                            &self.fanout,
+                            &self.auth_manager,
+                            &self.audit,
+                        )
+                        .instrument(span.clone())
+                        .await?;
 
                     if let Some(res) = maybe_res {
                         tracing::debug!("Sending response packet:{:#?} to client {:?}", res, session.get_client_id().await);
-                        self.connection.write_packet(res).await?;
+                        self.connection.queue_packet(res).instrument(span).await?;
                     }
                 }
 
                 // Try to send outgoing packet
-                Some(packet) = session.process_outgoing() => {
+                Some(packet) = session.process_outgoing(&self.broker) => {
                     tracing::debug!("Sending outgoing packet: {:#?} to client {:?}", packet, session.get_client_id().await);
 
-                    self.connection.write_packet(packet).await?;
+                    let span = telemetry::outgoing_span(&packet);
+                    self.connection.queue_packet(packet).instrument(span).await?;
+                }
+
+                // The client went silent for too long
+                _ = &mut idle, if keep_alive.is_some() => {
+                    return Err(ReasonCode::KeepAliveTimeout.into());
+                }
+
+                // Evict the client if it can't keep up with its messages
+                Ok(queued) = &mut slow_consumer => {
+                    let reason_string = format!("Client is too slow, {} messages queued", queued);
+                    self.disconnect_with(ReasonCode::QuotaExceeded, Some(reason_string)).await?;
+                    return Ok(Some(ReasonCode::QuotaExceeded));
                 }
 
-                // Exit in case a signal is received
+                // An administrator wants the client gone, or another
+                // connection took over the session
+                Ok(reason) = &mut kicked => {
+                    self.disconnect(reason).await?;
+                    return Ok(Some(reason));
+                }
+
+                // Let the client know the server is going away
                 _ = self.shutdown.recv() => {
-                    return Ok(());
+                    self.disconnect(ReasonCode::ServerShuttingDown).await?;
+                    return Ok(Some(ReasonCode::ServerShuttingDown));
                 },
             }
+
+            // Send the deliveries that are ready along with what was just
+            // written, and hold on to them while more packets from the
+            // client are waiting, whose acknowledgements can go with them.
+            while !self.connection.is_full() {
+                let packet = tokio::select! {
+                    biased;
+                    packet = session.process_outgoing(&self.broker) => packet,
+                    _ = future::ready(()) => None,
+                };
+
+                match packet {
+                    Some(packet) => {
+                        let span = telemetry::outgoing_span(&packet);
+                        self.connection
+                            .queue_packet(packet)
+                            .instrument(span)
+                            .await?;
+                    }
+                    None => break,
+                }
+            }
+
+            if self.connection.is_full() || !self.connection.has_packet() {
+                self.connection.flush().await?;
+            }
+        }
+
+        Ok(Some(ReasonCode::ServerShuttingDown))
+    }
+
+    async fn disconnect(&mut self, reason: ReasonCode) -> Result<()> {
+        self.disconnect_with(reason, None).await
+    }
+
+    async fn disconnect_with(
+        &mut self,
+        reason: ReasonCode,
+        reason_string: Option<String>,
+    ) -> Result<()> {
+        let properties = reason_string.map(|reason_string| DisconnectProperties {
+            reason_string: Some(ReasonString::new(reason_string)),
+            ..Default::default()
+        });
+        let disconnect = DisconnectPacket { reason, properties };
+
+        self.connection
+            .write_packet(ControlPacket::Disconnect(disconnect))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        future,
+        io::{self, Read},
+        net::SocketAddr,
+        sync::Arc,
+    };
+
+    use async_trait::async_trait;
+    use bytes::{Bytes, BytesMut};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::oneshot,
+        time::{self, Duration},
+    };
+
+    use mercurio_core::{
+        codec::Encoder, properties::ContentType, qos::QoS, reason::ReasonCode, Result,
+    };
+    use mercurio_packets::{
+        connack::ConnAckPacket,
+        connect::{ConnectFlags, ConnectPacket, ConnectPayload, WillProperties},
+        ControlPacket,
+    };
+
+    use super::{run_listeners, run_with_broker, AcceptFailure};
+    use crate::{
+        auth::{
+            AnonymousAccess, Authorization, CredentialValidator, Credentials, StaticCredentials,
+        },
+        broker::Broker,
+        config::{AuthConfig, Config, ListenerConfig},
+        connection::Connection,
+    };
+
+    /// Opens a connection to `address` and sends a CONNECT as `client_id`.
+    async fn send_connect(
+        address: SocketAddr,
+        client_id: &str,
+        user_name: Option<&str>,
+        password: Option<&str>,
+    ) -> Connection {
+        let socket = TcpStream::connect(address).await.unwrap();
+        let mut connection = Connection::new(socket);
+
+        let connect = ConnectPacket {
+            flags: ConnectFlags {
+                user_name: user_name.is_some(),
+                password: password.is_some(),
+                clean_start: true,
+                ..Default::default()
+            },
+            keepalive: 0,
+            properties: None,
+            payload: ConnectPayload {
+                client_id: client_id.to_string(),
+                user_name: user_name.map(str::to_string),
+                password: password.map(|password| Bytes::from(password.to_string())),
+                ..Default::default()
+            },
+        };
+        connection
+            .write_packet(ControlPacket::Connect(connect))
+            .await
+            .unwrap();
+
+        connection
+    }
+
+    /// Connects to `address` as `client_id`, returning the connection along
+    /// with the CONNACK.
+    async fn connect(
+        address: SocketAddr,
+        client_id: &str,
+        user_name: Option<&str>,
+        password: Option<&str>,
+    ) -> (Connection, ConnAckPacket) {
+        let mut connection = send_connect(address, client_id, user_name, password).await;
+
+        match connection.read_packet().await.unwrap() {
+            Some(ControlPacket::ConnAck(connack)) => (connection, connack),
+            packet => panic!("Expected a CONNACK, got {:?}", packet),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_listeners_have_their_own_authentication() {
+        let public = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let internal = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let public_address = public.local_addr().unwrap();
+        let internal_address = internal.local_addr().unwrap();
+
+        let config = Config {
+            credential_validator: Some(Arc::new(StaticCredentials {
+                users: HashMap::from([("admin".to_string(), Bytes::from("secret"))]),
+            })),
+            ..Default::default()
+        };
+        let internal_config = ListenerConfig {
+            auth: Some(AuthConfig {
+                credential_validator: Some(Arc::new(AnonymousAccess::default())),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(run_listeners(
+            vec![(public, Default::default()), (internal, internal_config)],
+            config,
+            stopped,
+        ));
+
+        let (_, connack) = connect(public_address, "public", None, None).await;
+        assert_eq!(connack.reason_code, ReasonCode::BadUserNameOrPassword);
+
+        let (public, connack) =
+            connect(public_address, "admin", Some("admin"), Some("secret")).await;
+        assert_eq!(connack.reason_code, ReasonCode::Success);
+
+        // The internal listener doesn't ask for credentials
+        let (internal, connack) = connect(internal_address, "internal", None, None).await;
+        assert_eq!(connack.reason_code, ReasonCode::Success);
+
+        drop((public, internal));
+        stop.send(()).unwrap();
+        server.await.unwrap();
+    }
+
+    /// Validator that never makes up its mind.
+    #[derive(Debug)]
+    struct Stuck;
+
+    #[async_trait]
+    impl CredentialValidator for Stuck {
+        async fn validate(&self, _credentials: &Credentials) -> Result<Authorization> {
+            future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handshake_is_bounded() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let config = Config {
+            credential_validator: Some(Arc::new(Stuck)),
+            connect_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(run_listeners(
+            vec![(listener, Default::default())],
+            config,
+            stopped,
+        ));
+
+        // Closed without a CONNACK once the timeout elapsed, whether the
+        // client sends nothing or the validator takes too long
+        let socket = TcpStream::connect(address).await.unwrap();
+        let mut silent = Connection::new(socket);
+        let mut waiting = send_connect(address, "client", None, None).await;
+
+        for connection in [&mut silent, &mut waiting] {
+            let packet = time::timeout(Duration::from_secs(5), connection.read_packet())
+                .await
+                .unwrap();
+            assert!(matches!(packet, Ok(None) | Err(_)), "Got {:?}", packet);
+        }
+
+        stop.send(()).unwrap();
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn test_accept_failures() {
+        let failure = |kind| AcceptFailure::of(&io::Error::from(kind));
+        assert_eq!(
+            failure(io::ErrorKind::ConnectionAborted),
+            AcceptFailure::Connection
+        );
+        assert_eq!(
+            failure(io::ErrorKind::OutOfMemory),
+            AcceptFailure::Resources
+        );
+        assert_eq!(
+            failure(io::ErrorKind::InvalidInput),
+            AcceptFailure::Listener
+        );
+
+        // Too many open files, on Linux and macOS
+        let emfile = io::Error::from_raw_os_error(24);
+        assert_eq!(AcceptFailure::of(&emfile), AcceptFailure::Resources);
+    }
+
+    #[tokio::test]
+    async fn test_session_taken_over() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(run_listeners(
+            vec![(listener, Default::default())],
+            Default::default(),
+            stopped,
+        ));
+
+        let (mut first, _) = connect(address, "client", None, None).await;
+        let (second, connack) = connect(address, "client", None, None).await;
+        assert_eq!(connack.reason_code, ReasonCode::Success);
+
+        // The former connection is told why it's being closed
+        match first.read_packet().await.unwrap() {
+            Some(ControlPacket::Disconnect(disconnect)) => {
+                assert_eq!(disconnect.reason, ReasonCode::SessionTakenOver)
+            }
+            packet => panic!("Expected a DISCONNECT, got {:?}", packet),
+        }
+        assert!(matches!(first.read_packet().await, Ok(None)));
+
+        drop(second);
+        stop.send(()).unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_is_sent_before_will() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let config = Config::default();
+        let broker = Broker::from_config(&config);
+        let mut wills = broker.subscribe_external("wills/#").unwrap();
+
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(run_with_broker(
+            vec![(listener, Default::default())],
+            broker,
+            config,
+            stopped,
+        ));
+
+        // Written to the socket directly, to look at what the client has
+        // been sent by the time its will is published
+        let connect_packet = ConnectPacket {
+            flags: ConnectFlags {
+                will_flag: true,
+                will_qos: QoS::AtLeastOnce,
+                clean_start: true,
+                ..Default::default()
+            },
+            keepalive: 0,
+            properties: None,
+            payload: ConnectPayload {
+                client_id: "client".to_string(),
+                will_properties: Some(WillProperties {
+                    content_type: Some(ContentType::new("text/plain".to_string())),
+                    ..Default::default()
+                }),
+                will_topic: Some("wills/client".to_string()),
+                will_payload: Some(Bytes::from("gone")),
+                ..Default::default()
+            },
+        };
+
+        let mut socket = TcpStream::connect(address).await.unwrap();
+        let mut buffer = BytesMut::new();
+        ControlPacket::Connect(connect_packet).encode(&mut buffer);
+        socket.write_all(&buffer).await.unwrap();
+
+        let mut received = BytesMut::new();
+        while ControlPacket::check(&mut received).is_err() {
+            assert_ne!(socket.read_buf(&mut received).await.unwrap(), 0);
+        }
+        let connack = ControlPacket::parse(&mut received).unwrap();
+        assert!(matches!(connack, ControlPacket::ConnAck(_)));
+
+        // A second CONNECT is a Protocol Error
+        socket.write_all(&buffer).await.unwrap();
+
+        let will = wills.recv().await.unwrap();
+        assert_eq!(will.topic, "wills/client");
+
+        let mut socket = socket.into_std().unwrap();
+        let mut disconnect = [0u8; 64];
+        let size = socket.read(&mut disconnect).unwrap();
+        let mut disconnect = BytesMut::from(&disconnect[..size]);
+
+        match ControlPacket::parse(&mut disconnect).unwrap() {
+            ControlPacket::Disconnect(disconnect) => {
+                assert_eq!(disconnect.reason, ReasonCode::ProtocolError)
+            }
+            packet => panic!("Expected a DISCONNECT, got {:?}", packet),
         }
 
-        Ok(())
+        stop.send(()).unwrap();
+        server.await.unwrap();
     }
 }
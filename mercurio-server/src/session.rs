@@ -1,29 +1,52 @@
-use std::{pin::Pin, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
 
-use tokio::sync::{broadcast, Mutex};
-use tokio_stream::{Stream, StreamExt, StreamMap};
-use tracing::info;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tracing::{info, warn, Instrument};
 use uuid::Uuid;
 
-type Messages = Pin<Box<dyn Stream<Item = Message> + Send>>;
-
 use mercurio_core::{
-    message::Message, properties::AssignedClientIdentifier, qos::QoS, reason::ReasonCode, Result,
+    codec::VariableByteInteger,
+    error::Error,
+    message::Message,
+    properties::{
+        AssignedClientIdentifier, AuthenticationData, AuthenticationMethod, ContentType,
+        CorrelationData, MessageExpiryInterval, PayloadFormatIndicator, ResponseInformation,
+        ResponseTopic, ServerKeepAlive, SubscriptionIdentifier,
+    },
+    qos::QoS,
+    reason::ReasonCode,
+    Result,
 };
 use mercurio_packets::{
+    auth::{AuthPacket, AuthProperties},
     connack::{ConnAckPacket, ConnAckProperties},
     connect::ConnectPacket,
     pingresp::PingRespPacket,
     puback::PubAckPacket,
     pubcomp::PubCompPacket,
-    publish::PublishPacket,
+    publish::{PublishPacket, PublishProperties},
     pubrec::PubRecPacket,
     pubrel::PubRelPacket,
     suback::{SubAckPacket, SubAckPayload},
+    subscribe::{RetainHandling, SubscribePacket},
+    unsuback::{UnsubAckPacket, UnsubAckPayload},
+    unsubscribe::UnsubscribePacket,
     ControlPacket,
 };
 
-use crate::{broker::Broker, connection::Connection};
+use crate::{
+    audit::{AuditEvent, AuditLog},
+    auth::{AuthManager, AuthSession, AuthStep, Authorization, Credentials},
+    broker::{Broker, SubscriberQueue},
+    config::{Capabilities, Quotas, ResponseInformationConfig},
+    connection::{Connection, ConnectionStats},
+    fanout::FanoutPool,
+    telemetry, topic_tree,
+};
 
 pub struct SessionDropGuard {
     session: Session,
@@ -32,42 +55,191 @@ pub struct SessionDropGuard {
 #[derive(Clone)]
 pub struct Session {
     shared: Arc<Shared>,
+
+    /// Number of the connection the session is used by, as sessions are
+    /// taken over by the new connections of their client.
+    connection: u64,
 }
 
 struct Shared {
     state: Mutex<State>,
+
+    /// Messages queued for the session, apart from the state so waiting for
+    /// them doesn't keep it locked.
+    inbox: Mutex<Inbox>,
+
+    /// Lets the connection of the client know it's being closed, and why.
+    /// Apart from the state, which can be locked for a while by the
+    /// connection handling packets.
+    kick: std::sync::Mutex<Option<oneshot::Sender<ReasonCode>>>,
+
+    /// Counters of the connection the session is used by, if any.
+    connection_stats: std::sync::Mutex<Option<Arc<ConnectionStats>>>,
+}
+
+struct Inbox {
+    messages: mpsc::Receiver<Message>,
+
+    /// Message received but not delivered yet, kept if delivering it was
+    /// cancelled, or while retained messages go first.
+    received: Option<Message>,
 }
 
 struct State {
     pub connect_packet: ConnectPacket,
-    subscriptions: StreamMap<String, Messages>,
+    authorization: Authorization,
+
+    /// Enhanced authentication method the client connected with, if any.
+    auth_method: Option<String>,
+
+    /// Re-authentication exchange in progress, if any.
+    reauthentication: Option<Box<dyn AuthSession>>,
+
+    /// Topic the Response Topics of the client must be under, when enforced.
+    response_topic_prefix: Option<String>,
+
+    /// Topic names set by the client for Topic Aliases, valid for the
+    /// current connection only.
+    topic_aliases: HashMap<u16, String>,
+
+    subscriptions: HashMap<String, Subscription>,
+    queue: SubscriberQueue,
+
+    /// Retained messages to be delivered because of new subscriptions,
+    /// ahead of the queued ones.
+    retained_messages: VecDeque<Message>,
+
     unacknowledged_messages: Vec<PublishPacket>,
     pubrecs: Vec<PubRecPacket>,
+
+    /// Last packet identifier used for a message sent to the client.
+    last_packet_id: u16,
+
+    /// Packet identifiers of the QoS 2 messages received from the client,
+    /// waiting for their PUBREL.
+    pending_releases: HashSet<u16>,
+
+    /// Keep alive of the current connection, in seconds, 0 if it has none.
+    keep_alive: u16,
+
+    /// QoS 1 and 2 messages the client of the current connection is willing
+    /// to have in flight, by the Receive Maximum of its CONNECT.
+    receive_maximum: u16,
+
+    /// Seconds the session is kept once the connection is closed, 0 if it
+    /// ends with it, 0xFFFFFFFF if it never expires.
+    expiry_interval: u32,
+
+    quotas: Quotas,
+    capabilities: Capabilities,
+}
+
+/// A subscription of the session, by topic filter.
+struct Subscription {
+    /// Highest QoS the messages are delivered with.
+    qos: QoS,
+
+    /// Identifier sent along with the SUBSCRIBE, to be included in the
+    /// messages delivered because of this subscription.
+    id: Option<u32>,
+
+    /// Don't deliver the messages published by the session's own client.
+    no_local: bool,
+
+    /// Keep the retain flag of the messages as they were published.
+    retain_as_published: bool,
+
+    /// The filter was rewritten by a topic rewrite, so the topics of the
+    /// messages delivered are rewritten back.
+    rewritten: bool,
+}
+
+/// What the topic rewrites of the broker did to a topic of the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rewrite {
+    Unchanged,
+    Rewritten,
+
+    /// A rewrite applies to the topic, but the client lacks the identity
+    /// it needs.
+    Refused,
+}
+
+impl State {
+    /// Returns a packet identifier for a message sent to the client, which
+    /// isn't used by another one in flight.
+    fn packet_id(&mut self) -> u16 {
+        loop {
+            // Packet identifiers are non-zero
+            self.last_packet_id = self.last_packet_id.checked_add(1).unwrap_or(1);
+            let packet_id = self.last_packet_id;
+
+            let in_use = self
+                .unacknowledged_messages
+                .iter()
+                .any(|p| p.packet_id == Some(packet_id))
+                || self.pubrecs.iter().any(|p| p.packet_id == packet_id);
+
+            if !in_use {
+                return packet_id;
+            }
+        }
+    }
 }
 
 impl SessionDropGuard {
-    pub fn new(connect_packet: ConnectPacket) -> Self {
+    pub(crate) fn new(connect_packet: ConnectPacket, broker: &Broker) -> Self {
         SessionDropGuard {
-            session: Session::new(connect_packet),
+            session: Session::new(connect_packet, broker),
         }
     }
 
     pub(crate) fn session(&self) -> Session {
         self.session.clone()
     }
+
+    /// Hands the session over to a new connection of the client, returning
+    /// it as used by that connection.
+    pub(crate) fn reconnect(&mut self) -> Session {
+        self.session.connection += 1;
+        self.session()
+    }
 }
 
 impl Session {
-    pub fn new(connect_packet: ConnectPacket) -> Self {
+    pub(crate) fn new(connect_packet: ConnectPacket, broker: &Broker) -> Self {
+        let (queue, messages) = broker.queue();
+
         Session {
             shared: Arc::new(Shared {
                 state: Mutex::new(State {
                     connect_packet,
-                    subscriptions: StreamMap::new(),
+                    authorization: Authorization::default(),
+                    auth_method: None,
+                    reauthentication: None,
+                    response_topic_prefix: None,
+                    topic_aliases: HashMap::new(),
+                    subscriptions: HashMap::new(),
+                    queue,
+                    retained_messages: VecDeque::new(),
                     unacknowledged_messages: Vec::new(),
                     pubrecs: Vec::new(),
+                    last_packet_id: 0,
+                    pending_releases: HashSet::new(),
+                    keep_alive: 0,
+                    receive_maximum: u16::MAX,
+                    expiry_interval: 0,
+                    quotas: *broker.quotas(),
+                    capabilities: *broker.capabilities(),
                 }),
+                inbox: Mutex::new(Inbox {
+                    messages,
+                    received: None,
+                }),
+                kick: std::sync::Mutex::new(None),
+                connection_stats: std::sync::Mutex::new(None),
             }),
+            connection: 0,
         }
     }
 
@@ -76,12 +248,179 @@ impl Session {
         session.connect_packet = connect_packet;
     }
 
+    /// Sets what the client is allowed to do from now on, and the enhanced
+    /// authentication method it used.
+    pub(crate) async fn authorize(
+        &mut self,
+        authorization: Authorization,
+        auth_method: Option<String>,
+    ) {
+        let mut session = self.shared.state.lock().await;
+        session.authorization = authorization;
+        session.auth_method = auth_method;
+        session.reauthentication = None;
+    }
+
+    pub(crate) async fn authorization(&self) -> Authorization {
+        self.shared.state.lock().await.authorization.clone()
+    }
+
+    /// Resolves with the number of queued messages once the client is found
+    /// too slow, according to the slow consumer policy.
+    pub(crate) async fn slow_consumer(&self) -> oneshot::Receiver<usize> {
+        let session = self.shared.state.lock().await;
+        session.queue.on_eviction()
+    }
+
+    /// Resolves with the reason to give the client once it has to be
+    /// disconnected, by an administrator or a new connection taking over
+    /// the session.
+    pub(crate) fn kicked(&self) -> oneshot::Receiver<ReasonCode> {
+        let (sender, receiver) = oneshot::channel();
+        *self.shared.kick.lock().unwrap() = Some(sender);
+        receiver
+    }
+
+    /// Disconnects the client with `reason`, returning `false` if it isn't
+    /// connected.
+    pub(crate) fn kick(&self, reason: ReasonCode) -> bool {
+        match self.shared.kick.lock().unwrap().take() {
+            Some(kick) => kick.send(reason).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Sets the counters of the connection the session is used by, or
+    /// clears them once it's gone.
+    pub(crate) fn set_connection_stats(&self, stats: Option<Arc<ConnectionStats>>) {
+        *self.shared.connection_stats.lock().unwrap() = stats;
+    }
+
+    /// Returns the counters of the connection the session is used by, if
+    /// it's connected.
+    pub(crate) fn connection_stats(&self) -> Option<Arc<ConnectionStats>> {
+        self.shared.connection_stats.lock().unwrap().clone()
+    }
+
+    /// Accounts for a message of the client refused by the broker.
+    fn drop_publish(&self) {
+        if let Some(stats) = self.connection_stats() {
+            stats.publishes_dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns how long the connection can stay silent before it's closed,
+    /// if ever.
+    pub(crate) async fn keep_alive_timeout(&self) -> Option<Duration> {
+        let session = self.shared.state.lock().await;
+
+        // [MQTT-3.1.2-22]
+        // If the Keep Alive value is non-zero and the Server does not
+        // receive an MQTT Control Packet from the Client within one and a
+        // half times the Keep Alive time period, it MUST close the Network
+        // Connection to the Client as if the network had failed.
+        match session.keep_alive {
+            0 => None,
+            keep_alive => Some(Duration::from_millis(u64::from(keep_alive) * 1500)),
+        }
+    }
+
+    pub(crate) async fn expiry_interval(&self) -> u32 {
+        let session = self.shared.state.lock().await;
+        session.expiry_interval
+    }
+
+    /// Updates the Session Expiry Interval as the client is disconnecting.
+    pub(crate) async fn set_expiry_interval(&mut self, expiry_interval: u32) -> Result<()> {
+        let mut session = self.shared.state.lock().await;
+
+        // [MQTT-3.14.2-2]
+        // If the Session Expiry Interval in the CONNECT packet was zero, then
+        // it is a Protocol Error to set a non-zero Session Expiry Interval in
+        // the DISCONNECT packet sent by the Client.
+        if session.expiry_interval == 0 && expiry_interval != 0 {
+            return Err(ReasonCode::ProtocolError.into());
+        }
+
+        session.expiry_interval = expiry_interval;
+        Ok(())
+    }
+
+    /// Returns the will of the client, along with the delay it's published
+    /// after, if it has one it's allowed to publish.
+    pub(crate) async fn will(&self) -> Option<(Message, u32)> {
+        let session = self.shared.state.lock().await;
+        let connect_packet = &session.connect_packet;
+
+        if !connect_packet.flags.will_flag {
+            return None;
+        }
+
+        let topic = connect_packet.payload.will_topic.clone()?;
+
+        if !session.authorization.can_publish(&topic) {
+            info!(
+                "Client `{}` isn't allowed to publish its will on `{}`",
+                connect_packet.payload.client_id, topic
+            );
+            return None;
+        }
+
+        let properties = connect_packet
+            .payload
+            .will_properties
+            .clone()
+            .unwrap_or_default();
+        let delay = properties
+            .will_delay_interval
+            .map_or(0, |interval| interval.value);
+
+        let message = Message {
+            packet_id: None,
+            topic,
+            dup: false,
+            qos: connect_packet.flags.will_qos,
+            retain: connect_packet.flags.will_retain,
+            payload: connect_packet.payload.will_payload.clone(),
+            origin: Some(connect_packet.payload.client_id.clone()),
+            user_properties: properties.user_property,
+            response_topic: properties.response_topic.map(|topic| topic.value),
+            correlation_data: properties.correlation_data.map(|data| data.value),
+            content_type: properties
+                .content_type
+                .map(|content_type| content_type.value),
+            payload_format_indicator: properties.payload_format_indicator.map(|f| f.value),
+            expires_at: expires_at(properties.message_expiry_interval),
+        };
+
+        Some((message, delay))
+    }
+
+    /// Whether both are handles to the same session.
+    pub(crate) fn is(&self, other: &Session) -> bool {
+        Arc::ptr_eq(&self.shared, &other.shared)
+    }
+
+    /// Returns `true` if both are the same session, used by the same
+    /// connection.
+    pub(crate) fn is_connection(&self, other: &Session) -> bool {
+        self.is(other) && self.connection == other.connection
+    }
+
     pub(crate) async fn get_client_id(&self) -> String {
         let session = self.shared.state.lock().await;
         session.connect_packet.payload.client_id.clone()
     }
 
-    pub async fn begin(&mut self, connection: &mut Connection, resume: bool) -> Result<()> {
+    /// Starts serving the client, acknowledging its connection with a
+    /// CONNACK carrying `properties`.
+    pub async fn begin(
+        &mut self,
+        connection: &mut Connection,
+        resume: bool,
+        mut properties: ConnAckProperties,
+        response_information: Option<&ResponseInformationConfig>,
+    ) -> Result<()> {
         let mut ack = ConnAckPacket::default();
         ack.flags.session_present = resume;
 
@@ -91,14 +430,70 @@ impl Session {
             if session.connect_packet.payload.client_id.is_empty() {
                 let uuid = Uuid::new_v4();
                 session.connect_packet.payload.client_id = uuid.hyphenated().to_string();
-                ack.properties = Some(ConnAckProperties {
-                    assigned_client_id: Some(AssignedClientIdentifier::new(
-                        session.connect_packet.payload.client_id.clone(),
-                    )),
-                    ..Default::default()
-                });
+                properties.assigned_client_id = Some(AssignedClientIdentifier::new(
+                    session.connect_packet.payload.client_id.clone(),
+                ));
+            }
+
+            // Each client gets a topic of its own to receive responses on
+            let response_topic = response_information.map(|config| {
+                format!(
+                    "{}/{}",
+                    config.prefix.trim_end_matches('/'),
+                    session.connect_packet.payload.client_id
+                )
+            });
+
+            let requested = session
+                .connect_packet
+                .properties
+                .as_ref()
+                .and_then(|p| p.request_response_information.as_ref())
+                .is_some_and(|request| request.value == 1);
+
+            if requested {
+                properties.response_information =
+                    response_topic.clone().map(ResponseInformation::new);
             }
 
+            session.response_topic_prefix =
+                response_topic.filter(|_| response_information.is_some_and(|c| c.enforce));
+
+            session.capabilities.advertise(&mut properties);
+            session.quotas.advertise(&mut properties);
+
+            // [MQTT-3.1.2-21]
+            // If the Server returns a Server Keep Alive on the CONNACK
+            // packet, the Client MUST use that value instead of the value it
+            // sent as the Keep Alive.
+            // [MQTT-3.1.2-11]
+            // If the Session Expiry Interval is absent the value 0 is used.
+            session.expiry_interval = session
+                .connect_packet
+                .properties
+                .as_ref()
+                .and_then(|p| p.session_expiry_interval.as_ref())
+                .map_or(0, |interval| interval.value);
+
+            // [MQTT-3.1.2.11.3]
+            // If the Receive Maximum value is absent then its value defaults
+            // to 65,535.
+            session.receive_maximum = session
+                .connect_packet
+                .properties
+                .as_ref()
+                .and_then(|p| p.receive_maximum.as_ref())
+                .map_or(u16::MAX, |maximum| maximum.value);
+
+            let requested = session.connect_packet.keepalive;
+            session.keep_alive = session.capabilities.keep_alive(requested);
+
+            if session.keep_alive != requested {
+                properties.server_keepalive = Some(ServerKeepAlive::new(session.keep_alive));
+            }
+
+            session.topic_aliases.clear();
+
             info!(
                 "Client with id `{}` {} a session",
                 session.connect_packet.payload.client_id,
@@ -109,61 +504,361 @@ impl Session {
             );
         }
 
-        connection.write_packet(ControlPacket::ConnAck(ack)).await?;
+        if properties != ConnAckProperties::default() {
+            ack.properties = Some(properties);
+        }
 
-        Ok(())
+        connection.queue_packet(ControlPacket::ConnAck(ack)).await?;
+
+        if resume {
+            for packet in self.retransmissions().await {
+                connection.queue_packet(packet).await?;
+            }
+        }
+
+        connection.flush().await
+    }
+
+    /// Returns the packets sent on a previous connection which are still
+    /// waiting to be acknowledged, to be sent again before anything else.
+    ///
+    /// [MQTT-4.4.0-1]
+    /// When a Client reconnects with Clean Start set to 0 and a session is
+    /// present, both the Client and Server MUST resend any unacknowledged
+    /// PUBLISH packets (where QoS > 0) and PUBREL packets using their
+    /// original Packet Identifiers.
+    /// [MQTT-4.6.0-1], [MQTT-4.6.0-3]
+    /// PUBLISH packets are resent in the order they were originally sent,
+    /// PUBREL packets in the order the corresponding PUBREC packets were
+    /// received.
+    async fn retransmissions(&mut self) -> Vec<ControlPacket> {
+        let mut session = self.shared.state.lock().await;
+
+        let releases = session.pubrecs.iter().map(|pubrec| {
+            ControlPacket::PubRel(PubRelPacket {
+                packet_id: pubrec.packet_id,
+                reason: ReasonCode::Success,
+                properties: None,
+            })
+        });
+        let mut packets: Vec<ControlPacket> = releases.collect();
+
+        for publish in session.unacknowledged_messages.iter_mut() {
+            // [MQTT-3.3.1-1]
+            // The DUP flag MUST be set to 1 by the Client or Server when it
+            // attempts to re-deliver a PUBLISH packet.
+            publish.dup = true;
+            packets.push(ControlPacket::Publish(publish.clone()));
+        }
+
+        packets
     }
 
     async fn handle_publish(
         &mut self,
-        packet: PublishPacket,
+        mut packet: PublishPacket,
         broker: &Broker,
+        fanout: &FanoutPool,
+        audit: &AuditLog,
     ) -> Result<Option<ControlPacket>> {
-        match packet.qos_level {
-            QoS::AtMostOnce => Ok(None),
-            QoS::AtLeastOnce => {
-                if let Some(packet_id) = packet.packet_id {
-                    Ok(ControlPacket::PubAck(PubAckPacket {
-                        packet_id,
-                        reason: ReasonCode::Success,
-                        properties: None,
-                    })
-                    .into())
-                } else {
-                    Err(ReasonCode::ProtocolError.into())
-                }
+        // [MQTT-3.3.1-2]
+        // The DUP flag MUST be set to 0 for all QoS 0 messages.
+        if packet.qos_level == QoS::AtMostOnce && packet.dup {
+            return Err(ReasonCode::ProtocolError.into());
+        }
+
+        let capabilities = *broker.capabilities();
+
+        // A PUBLISH with a QoS greater than the Maximum QoS advertised in the
+        // CONNACK is answered with a DISCONNECT.
+        if packet.qos_level != QoS::Invalid && packet.qos_level > capabilities.maximum_qos {
+            return Err(ReasonCode::QoSNotSupported.into());
+        }
+
+        // [MQTT-3.2.2-14]
+        // A Client receiving Retain Available set to 0 from the Server MUST
+        // NOT send a PUBLISH packet with the RETAIN flag set to 1.
+        if packet.retain && !capabilities.retain_available {
+            return Err(ReasonCode::RetainNotSupported.into());
+        }
+
+        self.resolve_topic_alias(&mut packet, capabilities.topic_alias_maximum)
+            .await?;
+        let rewrites = self.rewrite_topics(broker, [&mut packet.topic_name]).await;
+
+        if !topic_tree::is_valid_topic_name(&packet.topic_name) {
+            return Err(ReasonCode::TopicNameInvalid.into());
+        }
+
+        let response_topic = packet
+            .properties
+            .as_ref()
+            .and_then(|p| p.response_topic.as_ref())
+            .map(|topic| topic.value.as_str());
+
+        // [MQTT-3.3.2-14]
+        // The Response Topic MUST NOT contain wildcard characters.
+        if let Some(response_topic) = response_topic {
+            if !topic_tree::is_valid_topic_name(response_topic) {
+                return Err(ReasonCode::ProtocolError.into());
             }
-            QoS::ExactlyOnce => {
-                if let Some(packet_id) = packet.packet_id {
-                    let mut session = self.shared.state.lock().await;
-                    session.unacknowledged_messages.push(packet.clone());
-                    Ok(ControlPacket::PubRec(PubRecPacket {
-                        packet_id,
-                        reason: ReasonCode::Success,
-                        properties: None,
-                    })
-                    .into())
-                } else {
-                    Err(ReasonCode::ProtocolError.into())
-                }
+        }
+
+        let (client_id, authorized) = {
+            let session = self.shared.state.lock().await;
+
+            // Responses have to be requested on the client's own topic
+            let response_allowed = match (&session.response_topic_prefix, response_topic) {
+                (Some(prefix), Some(topic)) => topic_tree::matches(&format!("{}/#", prefix), topic),
+                _ => true,
+            };
+
+            (
+                session.connect_packet.payload.client_id.clone(),
+                session.authorization.can_publish(&packet.topic_name)
+                    && response_allowed
+                    && !rewrites.contains(&Rewrite::Refused),
+            )
+        };
+
+        if !authorized {
+            info!(
+                "Client `{}` isn't allowed to publish on `{}`",
+                client_id, packet.topic_name
+            );
+
+            audit.emit(AuditEvent::PublishDenied {
+                client_id,
+                topic: packet.topic_name.clone(),
+            });
+
+            self.drop_publish();
+            return Ok(refuse_publish(
+                packet.qos_level,
+                packet.packet_id,
+                ReasonCode::NotAuthorized,
+            ));
+        }
+
+        let payload_size = packet.payload.as_ref().map_or(0, |payload| payload.len());
+
+        if let Err(Error::MQTTReasonCode(reason)) =
+            broker.check_topic_policies(&packet.topic_name, packet.qos_level, payload_size)
+        {
+            info!(
+                "Refusing message of client `{}` on `{}`: {}",
+                client_id, packet.topic_name, reason
+            );
+
+            self.drop_publish();
+            return Ok(refuse_publish(packet.qos_level, packet.packet_id, reason));
+        }
+
+        let packet_id = match (packet.qos_level, packet.packet_id) {
+            (QoS::AtMostOnce, _) => None,
+            (QoS::AtLeastOnce | QoS::ExactlyOnce, Some(packet_id)) => Some(packet_id),
+            _ => return Err(ReasonCode::ProtocolError.into()),
+        };
+
+        if let (QoS::ExactlyOnce, Some(packet_id)) = (packet.qos_level, packet_id) {
+            let session = self.shared.state.lock().await;
+
+            // [MQTT-4.3.3-10]
+            // Until it has received the corresponding PUBREL packet, the
+            // receiver MUST acknowledge any subsequent PUBLISH packet with
+            // the same Packet Identifier by sending a PUBREC. It MUST NOT
+            // cause duplicate messages to be delivered to any onward
+            // recipients in this case.
+            if session.pending_releases.contains(&packet_id) {
+                info!(
+                    "Client `{}` sent message {} again, not forwarding it",
+                    client_id, packet_id
+                );
+
+                return Ok(ControlPacket::PubRec(PubRecPacket {
+                    packet_id,
+                    reason: ReasonCode::Success,
+                    properties: None,
+                })
+                .into());
+            }
+
+            if session.pending_releases.len() >= session.quotas.max_inflight_messages {
+                info!(
+                    "Client `{}` has too many messages in flight, refusing `{}`",
+                    client_id, packet.topic_name
+                );
+
+                session.queue.exceed_quota();
+                self.drop_publish();
+
+                return Ok(ControlPacket::PubRec(PubRecPacket {
+                    packet_id,
+                    reason: ReasonCode::QuotaExceeded,
+                    properties: None,
+                })
+                .into());
             }
-            QoS::Invalid => Err(ReasonCode::ProtocolError.into()),
-        }
-        .and_then(|res| {
-            let topic = packet.topic_name.clone();
-            let message = Message {
-                packet_id: packet.packet_id,
-                topic: packet.topic_name,
-                dup: packet.dup,
-                retain: packet.retain,
-                qos: packet.qos_level,
-                payload: packet.payload,
+        }
+
+        let qos = packet.qos_level;
+        let topic = packet.topic_name.clone();
+        let properties = packet.properties.unwrap_or_default();
+        let mut message = Message {
+            packet_id: packet.packet_id,
+            topic: packet.topic_name,
+            dup: packet.dup,
+            retain: packet.retain,
+            qos,
+            payload: packet.payload,
+            origin: Some(client_id),
+            user_properties: properties.user_property,
+            response_topic: properties.response_topic.map(|topic| topic.value),
+            correlation_data: properties.correlation_data.map(|data| data.value),
+            content_type: properties
+                .content_type
+                .map(|content_type| content_type.value),
+            payload_format_indicator: properties.payload_format_indicator.map(|f| f.value),
+            expires_at: expires_at(properties.message_expiry_interval),
+        };
+
+        let span = telemetry::route_span(&topic);
+        span.in_scope(|| telemetry::inject(&mut message.user_properties));
+
+        // Refused if it couldn't be routed at all. Subscribers whose queue
+        // is full are the broker's problem, not the publisher's.
+        if let Err(err) = fanout.publish(topic, message).instrument(span).await {
+            let reason = match err {
+                Error::MQTTReasonCode(reason) => reason,
+                _ => ReasonCode::UnspecifiedError,
             };
 
-            broker.publish(&topic, message)?;
+            info!(
+                "Message of client `{}` couldn't be routed: {}",
+                self.get_client_id().await,
+                reason
+            );
 
-            Ok(res)
-        })
+            self.drop_publish();
+            return Ok(refuse_publish(qos, packet_id, reason));
+        }
+
+        // Messages are acknowledged once the fan-out worker of the client
+        // queued them for every matching session, whether its client is
+        // connected or not. Nothing is written to disk though, they're lost
+        // if the broker goes down.
+        match (qos, packet_id) {
+            (QoS::AtLeastOnce, Some(packet_id)) => Ok(ControlPacket::PubAck(PubAckPacket {
+                packet_id,
+                reason: ReasonCode::Success,
+                properties: None,
+            })
+            .into()),
+            (QoS::ExactlyOnce, Some(packet_id)) => {
+                let mut session = self.shared.state.lock().await;
+                session.pending_releases.insert(packet_id);
+
+                Ok(ControlPacket::PubRec(PubRecPacket {
+                    packet_id,
+                    reason: ReasonCode::Success,
+                    properties: None,
+                })
+                .into())
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Replaces the empty topic name of a PUBLISH with the one its Topic
+    /// Alias was set for, or sets the alias for the topic name.
+    async fn resolve_topic_alias(
+        &mut self,
+        packet: &mut PublishPacket,
+        maximum: u16,
+    ) -> Result<()> {
+        let alias = packet
+            .properties
+            .as_ref()
+            .and_then(|p| p.topic_alias.as_ref())
+            .map(|alias| alias.value);
+
+        let alias = match alias {
+            Some(alias) => alias,
+            None if packet.topic_name.is_empty() => return Err(ReasonCode::ProtocolError.into()),
+            None => return Ok(()),
+        };
+
+        // [MQTT-3.3.2-8]
+        // A Topic Alias of 0 is not permitted, nor is one greater than the
+        // Topic Alias Maximum.
+        if alias == 0 || alias > maximum {
+            return Err(ReasonCode::TopicAliasInvalid.into());
+        }
+
+        let mut session = self.shared.state.lock().await;
+
+        if packet.topic_name.is_empty() {
+            packet.topic_name = session
+                .topic_aliases
+                .get(&alias)
+                .cloned()
+                .ok_or(ReasonCode::ProtocolError)?;
+        } else {
+            session
+                .topic_aliases
+                .insert(alias, packet.topic_name.clone());
+        }
+
+        Ok(())
+    }
+
+    /// Rewrites topic names or filters of the client by the topic rewrites
+    /// of the broker, returning what became of each.
+    ///
+    /// `{username}` stands for the user name the client authenticated as,
+    /// never the one it merely declared in its CONNECT.
+    async fn rewrite_topics<'a>(
+        &self,
+        broker: &Broker,
+        topics: impl IntoIterator<Item = &'a mut String>,
+    ) -> Vec<Rewrite> {
+        let topics: Vec<&mut String> = topics.into_iter().collect();
+
+        if !broker.rewrites_topics() {
+            return vec![Rewrite::Unchanged; topics.len()];
+        }
+
+        let session = self.shared.state.lock().await;
+        let client_id = &session.connect_packet.payload.client_id;
+        let user_name = session.authorization.user_name.as_deref();
+
+        topics
+            .into_iter()
+            .map(|topic| {
+                // The share name of shared subscriptions stays as is
+                let rewritten = match topic_tree::shared_subscription(topic) {
+                    Some((name, filter)) => broker
+                        .rewrite_topic(filter, client_id, user_name)
+                        .map(|filter| filter.map(|f| format!("$share/{}/{}", name, f))),
+                    None => broker.rewrite_topic(topic, client_id, user_name),
+                };
+
+                match rewritten {
+                    Ok(Some(rewritten)) => {
+                        *topic = rewritten;
+                        Rewrite::Rewritten
+                    }
+                    Ok(None) => Rewrite::Unchanged,
+                    Err(_) => {
+                        info!(
+                            "Client `{}` has no identity to rewrite `{}` with",
+                            client_id, topic
+                        );
+                        Rewrite::Refused
+                    }
+                }
+            })
+            .collect()
     }
 
     async fn handle_puback(&mut self, packet: PubAckPacket) -> Result<Option<ControlPacket>> {
@@ -189,8 +884,18 @@ impl Session {
             session.unacknowledged_messages.remove(index);
         }
 
+        // A PUBREC with a Reason Code of 0x80 or greater ends the exchange,
+        // and frees the Packet Identifier, without a PUBREL.
+        if packet.reason.get_code() >= 0x80 {
+            return Ok(None);
+        }
+
+        // The client sends its PUBREC again if our PUBREL got lost, which
+        // is still released only once when the session resumes.
         let packet_id = packet.packet_id;
-        session.pubrecs.push(packet);
+        if !session.pubrecs.iter().any(|p| p.packet_id == packet_id) {
+            session.pubrecs.push(packet);
+        }
 
         Ok(ControlPacket::PubRel(PubRelPacket {
             packet_id,
@@ -215,13 +920,7 @@ impl Session {
 
     async fn handle_pubrel(&mut self, packet: PubRelPacket) -> Result<Option<ControlPacket>> {
         let mut session = self.shared.state.lock().await;
-        if let Some(index) = session
-            .pubrecs
-            .iter()
-            .position(|p| p.packet_id == packet.packet_id)
-        {
-            session.pubrecs.remove(index);
-        }
+        session.pending_releases.remove(&packet.packet_id);
 
         Ok(ControlPacket::PubComp(PubCompPacket {
             packet_id: packet.packet_id,
@@ -233,9 +932,24 @@ impl Session {
 
     async fn handle_subscribe(
         &mut self,
-        packet: mercurio_packets::subscribe::SubscribePacket,
+        mut packet: SubscribePacket,
         broker: &Broker,
+        audit: &AuditLog,
     ) -> Result<Option<ControlPacket>> {
+        // [MQTT-3.8.3-2]
+        // The Payload MUST contain at least one Topic Filter and Subscription
+        // Options pair.
+        if packet.payload.is_empty() {
+            return Err(ReasonCode::ProtocolError.into());
+        }
+
+        let rewrites = self
+            .rewrite_topics(
+                broker,
+                packet.payload.iter_mut().map(|sub| &mut sub.topic_filter),
+            )
+            .await;
+
         let mut session = self.shared.state.lock().await;
         let mut ack = SubAckPacket {
             packet_id: packet.packet_id,
@@ -243,81 +957,1368 @@ impl Session {
             payload: Vec::new(),
         };
 
-        for sub in &packet.payload {
-            let mut rx = broker.subscribe(sub.topic_filter.to_string());
-            ack.payload.push(SubAckPayload {
-                reason_code: ReasonCode::GrantedQoS0,
-            });
+        let id = packet
+            .properties
+            .as_ref()
+            .and_then(|p| p.subscription_id.as_ref())
+            .map(|id| id.value.0);
 
-            let rx = Box::pin(async_stream::stream! {
-                loop {
-                    match rx.recv().await {
-                        Ok(msg) => yield msg,
-                        // If we lagged in consuming messages, just resume.
-                        Err(broadcast::error::RecvError::Lagged(_)) => {}
-                        Err(_) => break,
-                    }
-                }
-            });
+        // [MQTT-3.8.2.1.2]
+        // It is a Protocol Error to send a Subscription Identifier when the
+        // Server didn't advertise support for them.
+        if id.is_some() && !session.capabilities.subscription_identifiers {
+            return Err(ReasonCode::SubscriptionIdentifiersNotSupported.into());
+        }
 
-            session
-                .subscriptions
-                .insert(sub.topic_filter.to_string(), rx);
+        // [MQTT-3.8.2.1.2]
+        // It is a Protocol Error if the Subscription Identifier has a value
+        // of 0.
+        if id == Some(0) {
+            return Err(ReasonCode::ProtocolError.into());
         }
 
-        Ok(ControlPacket::SubAck(ack).into())
-    }
+        for (sub, rewrite) in packet.payload.iter().zip(rewrites) {
+            if rewrite == Rewrite::Refused {
+                ack.payload.push(SubAckPayload {
+                    reason_code: ReasonCode::NotAuthorized,
+                });
+                continue;
+            }
 
-    pub(crate) async fn process_incoming(
-        &mut self,
-        packet: ControlPacket,
-        broker: &Broker,
-    ) -> Result<Option<ControlPacket>> {
-        match packet {
-            ControlPacket::Publish(packet) => self.handle_publish(packet, broker).await,
-            ControlPacket::PubAck(packet) => self.handle_puback(packet).await,
-            ControlPacket::PubRec(packet) => self.handle_pubrec(packet).await,
-            ControlPacket::PubRel(packet) => self.handle_pubrel(packet).await,
-            ControlPacket::PubComp(packet) => self.handle_pubcomp(packet).await,
-            ControlPacket::Subscribe(packet) => self.handle_subscribe(packet, broker).await,
-            ControlPacket::Unsubscribe(_) => todo!(),
-            ControlPacket::PingReq(_) => Ok(ControlPacket::PingResp(PingRespPacket {}).into()),
-            ControlPacket::Disconnect(packet) => Ok(ControlPacket::Disconnect(packet).into()),
-            ControlPacket::Auth(_) => todo!(),
+            if !topic_tree::is_valid_topic_filter(&sub.topic_filter) {
+                ack.payload.push(SubAckPayload {
+                    reason_code: ReasonCode::TopicFilterInvalid,
+                });
+                continue;
+            }
 
-            // Some packets are not supposed to be received by the server.
-            // Namely: ConnAck, UnsubAck, PingResp
-            // The Connect packet is handled before the session is created.
-            _ => Err(ReasonCode::ProtocolError.into()),
+            let shared = topic_tree::shared_subscription(&sub.topic_filter);
+
+            if shared.is_some() && !session.capabilities.shared_subscriptions {
+                ack.payload.push(SubAckPayload {
+                    reason_code: ReasonCode::SharedSubscriptionsNotSupported,
+                });
+                continue;
+            }
+
+            // [MQTT-3.8.3-4]
+            // It is a Protocol Error to set the No Local bit to 1 on a Shared
+            // Subscription.
+            if shared.is_some() && sub.subs_opt.no_local {
+                return Err(ReasonCode::ProtocolError.into());
+            }
+
+            if !session.capabilities.wildcard_subscriptions && sub.topic_filter.contains(['+', '#'])
+            {
+                ack.payload.push(SubAckPayload {
+                    reason_code: ReasonCode::WildcardSubscriptionsNotSupported,
+                });
+                continue;
+            }
+
+            if !session.subscriptions.contains_key(&sub.topic_filter)
+                && session.subscriptions.len() >= session.quotas.max_subscriptions
+            {
+                info!(
+                    "Client `{}` has too many subscriptions, refusing `{}`",
+                    session.connect_packet.payload.client_id, sub.topic_filter
+                );
+
+                session.queue.exceed_quota();
+
+                ack.payload.push(SubAckPayload {
+                    reason_code: ReasonCode::QuotaExceeded,
+                });
+                continue;
+            }
+
+            let topic_filter = shared.map_or(sub.topic_filter.as_str(), |(_, filter)| filter);
+
+            if !session.authorization.can_subscribe(topic_filter) {
+                audit.emit(AuditEvent::SubscribeDenied {
+                    client_id: session.connect_packet.payload.client_id.clone(),
+                    filter: sub.topic_filter.to_string(),
+                });
+
+                ack.payload.push(SubAckPayload {
+                    reason_code: ReasonCode::NotAuthorized,
+                });
+                continue;
+            }
+
+            // [MQTT-3.3.1-9], [MQTT-3.3.1-10], [MQTT-3.3.1-11]
+            // Retained messages are sent when the subscription is made,
+            // only if it didn't exist yet with Retain Handling 1, and never
+            // with 2. Shared subscriptions don't get them.
+            let existed = session.subscriptions.contains_key(&sub.topic_filter);
+            let send_retained = match sub.subs_opt.retain_handling {
+                RetainHandling::SendRetained => shared.is_none(),
+                RetainHandling::SendRetainedIfNonExisting => shared.is_none() && !existed,
+                _ => false,
+            };
+
+            let client_id = &session.connect_packet.payload.client_id;
+            let queue = session.queue.clone();
+
+            if send_retained {
+                let retained = broker.subscribe_retained(&sub.topic_filter, client_id, queue);
+                session.retained_messages.extend(retained);
+            } else {
+                broker.subscribe(&sub.topic_filter, client_id, queue);
+            }
+
+            let qos = sub.subs_opt.qos.min(session.capabilities.maximum_qos);
+            ack.payload.push(SubAckPayload {
+                reason_code: match qos {
+                    QoS::AtMostOnce => ReasonCode::GrantedQoS0,
+                    QoS::AtLeastOnce => ReasonCode::GrantedQoS1,
+                    _ => ReasonCode::GrantedQoS2,
+                },
+            });
+
+            audit.emit(AuditEvent::Subscribe {
+                client_id: session.connect_packet.payload.client_id.clone(),
+                filter: sub.topic_filter.to_string(),
+            });
+
+            session.subscriptions.insert(
+                sub.topic_filter.to_string(),
+                Subscription {
+                    qos,
+                    id,
+                    no_local: sub.subs_opt.no_local,
+                    retain_as_published: sub.subs_opt.retain_as_pub,
+                    rewritten: rewrite == Rewrite::Rewritten,
+                },
+            );
         }
+
+        Ok(ControlPacket::SubAck(ack).into())
     }
 
-    pub(crate) async fn process_outgoing(&mut self) -> Option<ControlPacket> {
+    async fn handle_unsubscribe(
+        &mut self,
+        mut packet: UnsubscribePacket,
+        broker: &Broker,
+    ) -> Result<Option<ControlPacket>> {
+        // [MQTT-3.10.3-2]
+        // The Payload of an UNSUBSCRIBE packet MUST contain at least one Topic
+        // Filter.
+        if packet.payload.is_empty() {
+            return Err(ReasonCode::ProtocolError.into());
+        }
+
+        let rewrites = self
+            .rewrite_topics(
+                broker,
+                packet
+                    .payload
+                    .iter_mut()
+                    .map(|unsub| &mut unsub.topic_filter),
+            )
+            .await;
+
         let mut session = self.shared.state.lock().await;
+        let mut ack = UnsubAckPacket {
+            packet_id: packet.packet_id,
+            properties: None,
+            payload: Vec::new(),
+        };
 
-        match session.subscriptions.next().await {
-            Some((topic, message)) => {
-                let publish = PublishPacket {
-                    dup: message.dup,
-                    qos_level: message.qos,
-                    retain: false,
-                    topic_name: topic,
-                    packet_id: message.packet_id,
-                    properties: None,
-                    payload: message.payload,
-                };
+        for (unsub, rewrite) in packet.payload.iter().zip(rewrites) {
+            if rewrite == Rewrite::Refused {
+                ack.payload.push(UnsubAckPayload {
+                    reason_code: ReasonCode::NotAuthorized,
+                });
+                continue;
+            }
 
-                match message.qos {
-                    mercurio_core::qos::QoS::AtMostOnce => {}
-                    mercurio_core::qos::QoS::AtLeastOnce | mercurio_core::qos::QoS::ExactlyOnce => {
-                        session.unacknowledged_messages.push(publish.clone());
+            if !topic_tree::is_valid_topic_filter(&unsub.topic_filter) {
+                ack.payload.push(UnsubAckPayload {
+                    reason_code: ReasonCode::TopicFilterInvalid,
+                });
+                continue;
+            }
+
+            let existed = broker.unsubscribe(
+                &unsub.topic_filter,
+                &session.connect_packet.payload.client_id,
+            );
+            session.subscriptions.remove(&unsub.topic_filter);
+
+            ack.payload.push(UnsubAckPayload {
+                reason_code: match existed {
+                    true => ReasonCode::Success,
+                    false => ReasonCode::NoSubscriptionExisted,
+                },
+            });
+        }
+
+        Ok(ControlPacket::UnsubAck(ack).into())
+    }
+
+    /// Handles AUTH packets re-authenticating the client, which can happen at
+    /// any time once connected. The exchange runs alongside the other
+    /// packets, and the client keeps its previous authorization until it
+    /// succeeds. Failing closes the connection.
+    async fn handle_auth(
+        &mut self,
+        packet: AuthPacket,
+        broker: &Broker,
+        auth_manager: &AuthManager,
+    ) -> Result<Option<ControlPacket>> {
+        // The validator may take a while, so the exchange is taken out of the
+        // session state and run without holding its lock, which deliveries
+        // to the client need too.
+        let (method, mut reauthentication, user_name) = {
+            let mut session = self.shared.state.lock().await;
+
+            // [MQTT-4.12.1-1]
+            // If the Client supplied an Authentication Method in the CONNECT
+            // packet it can initiate a re-authentication at any time after
+            // receiving a CONNACK, using the same Authentication Method.
+            let method = match (&session.auth_method, packet.properties.auth_method) {
+                (Some(method), Some(requested)) if *method == requested.value => method.clone(),
+                _ => return Err(ReasonCode::ProtocolError.into()),
+            };
+
+            let reauthentication = match (packet.reason, session.reauthentication.take()) {
+                (ReasonCode::ReAuthenticate, None) => {
+                    let credentials = Credentials::from(&session.connect_packet);
+                    auth_manager
+                        .start(&method, &credentials)
+                        .ok_or(ReasonCode::BadAuthenticationMethod)?
+                }
+                (ReasonCode::ContinueAuthentication, Some(reauthentication)) => reauthentication,
+                _ => return Err(ReasonCode::ProtocolError.into()),
+            };
+
+            (
+                method,
+                reauthentication,
+                session.authorization.user_name.clone(),
+            )
+        };
+
+        let data = packet.properties.auth_data.map(|data| data.value);
+
+        let step = match packet.reason {
+            ReasonCode::ReAuthenticate => reauthentication.start(data).await?,
+            _ => reauthentication.auth_continue(data).await?,
+        };
+
+        let mut session = self.shared.state.lock().await;
+
+        let (reason, data) = match step {
+            AuthStep::Continue(data) => {
+                session.reauthentication = Some(reauthentication);
+                (ReasonCode::ContinueAuthentication, Some(data))
+            }
+            AuthStep::Success {
+                data,
+                authorization,
+            } => {
+                let client_id = session.connect_packet.payload.client_id.clone();
+
+                // Topic rewrites and the audit trail rely on the user name,
+                // so a connection can't switch to another one.
+                if authorization.user_name != user_name {
+                    warn!(
+                        "Client `{}` re-authenticated as another user, disconnecting",
+                        client_id
+                    );
+                    return Err(ReasonCode::NotAuthorized.into());
+                }
+
+                info!("Client `{}` re-authenticated", client_id);
+
+                // The subscriptions made under the former authorization
+                // don't outlive it.
+                let revoked: Vec<String> = session
+                    .subscriptions
+                    .keys()
+                    .filter(|filter| {
+                        let shared = topic_tree::shared_subscription(filter);
+                        let filter = shared.map_or(filter.as_str(), |(_, filter)| filter);
+                        !authorization.can_subscribe(filter)
+                    })
+                    .cloned()
+                    .collect();
+
+                for filter in revoked {
+                    info!(
+                        "Client `{}` is no longer allowed to subscribe to `{}`",
+                        client_id, filter
+                    );
+                    broker.unsubscribe(&filter, &client_id);
+                    session.subscriptions.remove(&filter);
+                }
+
+                session.authorization = authorization;
+                (ReasonCode::Success, data)
+            }
+        };
+
+        Ok(ControlPacket::Auth(AuthPacket {
+            reason,
+            properties: AuthProperties {
+                auth_method: Some(AuthenticationMethod::new(method)),
+                auth_data: data.map(AuthenticationData::new),
+                ..Default::default()
+            },
+        })
+        .into())
+    }
+
+    pub(crate) async fn process_incoming(
+        &mut self,
+        packet: ControlPacket,
+        broker: &Broker,
+        fanout: &FanoutPool,
+        auth_manager: &AuthManager,
+        audit: &AuditLog,
+    ) -> Result<Option<ControlPacket>> {
+        match packet {
+            ControlPacket::Publish(packet) => {
+                self.handle_publish(packet, broker, fanout, audit).await
+            }
+            ControlPacket::PubAck(packet) => self.handle_puback(packet).await,
+            ControlPacket::PubRec(packet) => self.handle_pubrec(packet).await,
+            ControlPacket::PubRel(packet) => self.handle_pubrel(packet).await,
+            ControlPacket::PubComp(packet) => self.handle_pubcomp(packet).await,
+            ControlPacket::Subscribe(packet) => self.handle_subscribe(packet, broker, audit).await,
+            ControlPacket::Unsubscribe(packet) => self.handle_unsubscribe(packet, broker).await,
+            ControlPacket::PingReq(_) => Ok(ControlPacket::PingResp(PingRespPacket {}).into()),
+            ControlPacket::Disconnect(packet) => Ok(ControlPacket::Disconnect(packet).into()),
+            ControlPacket::Auth(packet) => self.handle_auth(packet, broker, auth_manager).await,
+
+            // [MQTT-3.1.0-2]
+            // The Server MUST process a second CONNECT packet sent from a
+            // Client as a Protocol Error and close the Network Connection.
+            ControlPacket::Connect(_) => Err(ReasonCode::ProtocolError.into()),
+
+            // Some packets are not supposed to be received by the server.
+            // Namely: ConnAck, UnsubAck, PingResp
+            // The Connect packet is handled before the session is created.
+            _ => Err(ReasonCode::ProtocolError.into()),
+        }
+    }
+
+    /// Returns the next message to deliver to the client, waiting for one if
+    /// needed.
+    ///
+    /// Messages are delivered in the order they were published, whatever
+    /// their QoS: the ones published by a client reach each of its
+    /// subscribers in order, as they all go through the same queue. A QoS 0
+    /// message doesn't overtake QoS 1 and 2 ones waiting for the inflight
+    /// window either, and those resent on reconnection go out before any new
+    /// one. Only messages delivered through a shared subscription, which
+    /// may go to different clients, have no order between them.
+    pub(crate) async fn process_outgoing(&mut self, broker: &Broker) -> Option<ControlPacket> {
+        let stats = self.connection_stats();
+
+        loop {
+            let mut inbox = self.shared.inbox.lock().await;
+            let mut session = self.shared.state.lock().await;
+
+            // Deliveries wait for acknowledgements once too many messages
+            // are in flight. This is dropped whenever a packet comes in, so
+            // they're checked again after every acknowledgement.
+            let inflight = session.unacknowledged_messages.len() + session.pubrecs.len();
+            if let Some(stats) = &stats {
+                stats.acks_pending.store(inflight as u64, Ordering::Relaxed);
+            }
+
+            // [MQTT-3.3.4-9]
+            // The Server MUST NOT send more than Receive Maximum QoS 1 and
+            // QoS 2 PUBLISH packets for which it has not received PUBACK,
+            // PUBCOMP, or PUBREC with a Reason Code of 128 or greater from
+            // the Client.
+            let maximum = session
+                .quotas
+                .max_inflight_messages
+                .min(usize::from(session.receive_maximum));
+
+            if inflight >= maximum {
+                drop(session);
+                drop(inbox);
+                return std::future::pending().await;
+            }
+
+            // The session isn't kept locked while waiting for a message.
+            // Once received, it's held on to until delivered, whether this
+            // is cancelled or not.
+            if session.retained_messages.is_empty() && inbox.received.is_none() {
+                drop(session);
+                inbox.received = Some(inbox.messages.recv().await?);
+                continue;
+            }
+
+            let (message, retained) = match session.retained_messages.pop_front() {
+                Some(message) => (message, true),
+                None => (inbox.received.take()?, false),
+            };
+            drop(inbox);
+
+            // [MQTT-3.3.2-5]
+            // If the Message Expiry Interval has passed and the Server has
+            // not managed to start onward delivery to a matching subscriber,
+            // then it MUST delete the copy of the message for that
+            // subscriber.
+            let message_expiry_interval = match message.expires_at {
+                Some(expires_at) => match expires_at.checked_duration_since(Instant::now()) {
+                    Some(remaining) if !remaining.is_zero() => {
+                        Some(MessageExpiryInterval::new(remaining_secs(remaining)))
                     }
-                    mercurio_core::qos::QoS::Invalid => unreachable!(),
-                };
+                    _ => continue,
+                },
+                None => None,
+            };
+
+            let client_id = &session.connect_packet.payload.client_id;
+            let own = message.origin.as_ref() == Some(client_id);
+
+            // [MQTT-3.8.3-3]
+            // Application Messages MUST NOT be forwarded to a connection with
+            // a ClientID equal to the ClientID of the publishing connection
+            // when No Local is set.
+            let subscriptions: Vec<&Subscription> = session
+                .subscriptions
+                .iter()
+                .filter(|(filter, _)| {
+                    let filter = topic_tree::shared_subscription(filter)
+                        .map_or(filter.as_str(), |(_, filter)| filter);
+
+                    topic_tree::matches(filter, &message.topic)
+                })
+                .map(|(_, subscription)| subscription)
+                .filter(|subscription| !(own && subscription.no_local))
+                .collect();
+
+            if subscriptions.is_empty() {
+                continue;
+            }
+
+            // [MQTT-3.3.1-12], [MQTT-3.3.1-13]
+            // The retain flag is set on retained messages sent because of a
+            // new subscription. Otherwise it's kept only for subscriptions
+            // with Retain As Published set.
+            let retain =
+                retained || (message.retain && subscriptions.iter().any(|s| s.retain_as_published));
+
+            // Delivered once, with the highest QoS granted to the matching
+            // subscriptions, but no higher than it was published with
+            let granted = subscriptions
+                .iter()
+                .map(|s| s.qos)
+                .max()
+                .unwrap_or_default();
+            let qos = message.qos.min(granted);
+
+            // Clients whose subscription was rewritten get the topic they
+            // subscribed to
+            let restore_topic = subscriptions.iter().any(|s| s.rewritten);
+
+            // The identifiers of every subscription the message matches
+            // are delivered along with it
+            let subscription_identifier: Vec<SubscriptionIdentifier> = subscriptions
+                .iter()
+                .filter_map(|subscription| subscription.id)
+                .map(|id| SubscriptionIdentifier::new(VariableByteInteger(id)))
+                .collect();
+
+            let properties = PublishProperties {
+                subscription_identifier: Some(subscription_identifier)
+                    .filter(|identifiers| !identifiers.is_empty()),
+                user_property: message.user_properties,
+                response_topic: message.response_topic.map(ResponseTopic::new),
+                correlation_data: message.correlation_data.map(CorrelationData::new),
+                content_type: message.content_type.map(ContentType::new),
+                payload_format_indicator: message
+                    .payload_format_indicator
+                    .map(PayloadFormatIndicator::new),
+                // [MQTT-3.3.2-6]
+                // The PUBLISH packet sent to a Client by the Server MUST
+                // contain a Message Expiry Interval set to the received value
+                // minus the time that the message has been waiting in the
+                // Server.
+                message_expiry_interval,
+                ..Default::default()
+            };
+            let properties = Some(properties).filter(|p| *p != PublishProperties::default());
+
+            // [MQTT-3.3.1-3]
+            // The DUP flag in the outgoing PUBLISH packet is set
+            // independently to the incoming PUBLISH packet. Packet
+            // identifiers are the session's own as well.
+            let packet_id = match qos {
+                QoS::AtMostOnce => None,
+                _ => Some(session.packet_id()),
+            };
+
+            let topic_name = match restore_topic {
+                true => broker
+                    .restore_topic(
+                        &message.topic,
+                        &session.connect_packet.payload.client_id,
+                        session.authorization.user_name.as_deref(),
+                    )
+                    .unwrap_or(message.topic),
+                false => message.topic,
+            };
+
+            let publish = PublishPacket {
+                dup: false,
+                qos_level: qos,
+                retain,
+                topic_name,
+                packet_id,
+                properties,
+                payload: message.payload,
+            };
+
+            match qos {
+                mercurio_core::qos::QoS::AtMostOnce => {}
+                mercurio_core::qos::QoS::AtLeastOnce | mercurio_core::qos::QoS::ExactlyOnce => {
+                    session.unacknowledged_messages.push(publish.clone());
+
+                    // Counted right away, not once the next delivery is
+                    // looked for
+                    if let Some(stats) = &stats {
+                        stats.acks_pending.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                mercurio_core::qos::QoS::Invalid => unreachable!(),
+            };
+
+            return Some(ControlPacket::Publish(publish));
+        }
+    }
+}
+
+/// Returns the acknowledgement refusing a PUBLISH, if it has one. QoS 0
+/// messages are dropped without a word.
+fn refuse_publish(
+    qos_level: QoS,
+    packet_id: Option<u16>,
+    reason: ReasonCode,
+) -> Option<ControlPacket> {
+    match (qos_level, packet_id) {
+        (QoS::AtLeastOnce, Some(packet_id)) => Some(ControlPacket::PubAck(PubAckPacket {
+            packet_id,
+            reason,
+            properties: None,
+        })),
+        (QoS::ExactlyOnce, Some(packet_id)) => Some(ControlPacket::PubRec(PubRecPacket {
+            packet_id,
+            reason,
+            properties: None,
+        })),
+        _ => None,
+    }
+}
+
+/// Returns when a message with the given Message Expiry Interval expires.
+pub(crate) fn expires_at(interval: Option<MessageExpiryInterval>) -> Option<Instant> {
+    interval.map(|interval| Instant::now() + Duration::from_secs(u64::from(interval.value)))
+}
+
+/// Rounds up, so a message about to expire isn't sent with an interval of 0.
+pub(crate) fn remaining_secs(remaining: Duration) -> u32 {
+    let secs = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+    secs.min(u64::from(u32::MAX)) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use tokio::{sync::mpsc, time};
+
+    use mercurio_core::{
+        codec::VariableByteInteger,
+        error::Error,
+        message::Message,
+        properties::{
+            AuthenticationMethod, ReceiveMaximum, ServerKeepAlive, SubscriptionIdentifier,
+        },
+        qos::QoS,
+        reason::ReasonCode,
+        Result,
+    };
+    use mercurio_packets::{
+        auth::{AuthPacket, AuthProperties},
+        connack::ConnAckPacket,
+        connect::{ConnectPacket, ConnectPayload, ConnectProperties},
+        puback::PubAckPacket,
+        publish::PublishPacket,
+        pubrec::PubRecPacket,
+        pubrel::PubRelPacket,
+        subscribe::{
+            RetainHandling, SubscribePacket, SubscribePayload, SubscribeProperties,
+            SubscriptionOptions,
+        },
+        ControlPacket,
+    };
+
+    use super::Session;
+    use crate::{
+        audit::AuditLog,
+        auth::{AuthManager, AuthMethod, AuthSession, AuthStep, Authorization, Credentials},
+        broker::Broker,
+        config::{Capabilities, Config, Quotas, TopicRewrite},
+        connection::{Connection, ConnectionStats},
+        fanout::FanoutPool,
+    };
+
+    fn connect_packet(client_id: &str, user_name: Option<&str>) -> ConnectPacket {
+        ConnectPacket {
+            flags: Default::default(),
+            keepalive: 0,
+            properties: None,
+            payload: ConnectPayload {
+                client_id: client_id.to_string(),
+                user_name: user_name.map(str::to_string),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn subscribe(packet_id: u16, filter: &str, qos: QoS) -> SubscribePacket {
+        SubscribePacket {
+            packet_id,
+            properties: None,
+            payload: vec![SubscribePayload {
+                topic_filter: filter.to_string(),
+                subs_opt: SubscriptionOptions {
+                    qos,
+                    no_local: false,
+                    retain_as_pub: false,
+                    retain_handling: RetainHandling::SendRetained,
+                },
+            }],
+        }
+    }
+
+    /// Starts `session` over an in-memory connection, returning the CONNACK
+    /// its client gets.
+    async fn begin(session: &mut Session) -> ConnAckPacket {
+        let (connack, _) = connect(session, false).await;
+        connack
+    }
+
+    /// Starts or resumes `session` over an in-memory connection, returning
+    /// the CONNACK along with the client's end, to read what follows.
+    async fn connect(session: &mut Session, resume: bool) -> (ConnAckPacket, Connection) {
+        let (client, server) = tokio::io::duplex(4096);
+        let mut connection = Connection::new(server);
+
+        session
+            .begin(&mut connection, resume, Default::default(), None)
+            .await
+            .unwrap();
+        connection.flush().await.unwrap();
+
+        let mut client = Connection::new(client);
+        match client.read_packet().await.unwrap() {
+            Some(ControlPacket::ConnAck(connack)) => (connack, client),
+            packet => panic!("Expected a CONNACK, got {:?}", packet),
+        }
+    }
+
+    fn publish(topic: &str, qos_level: QoS, packet_id: Option<u16>) -> PublishPacket {
+        PublishPacket {
+            dup: false,
+            qos_level,
+            retain: false,
+            topic_name: topic.to_string(),
+            packet_id,
+            properties: None,
+            payload: Some(Bytes::from("21")),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publish_to_full_queue_is_acknowledged() {
+        let broker = Broker::new(
+            Quotas {
+                max_queued_messages: 2,
+                ..Default::default()
+            },
+            Default::default(),
+        );
+        let (queue, _stuck_rx) = broker.queue();
+        broker.subscribe("a/b", "stuck", queue);
+        let (queue, mut rx) = broker.queue();
+        broker.subscribe("a/b", "subscriber", queue);
+
+        let (shutdown_complete_tx, _shutdown_complete_rx) = mpsc::channel(1);
+        let fanout = FanoutPool::new(broker.clone(), 1, shutdown_complete_tx);
+        let audit = AuditLog::disabled();
+
+        let mut session = Session::new(connect_packet("publisher", None), &broker);
+
+        let reason = |res| match res {
+            Ok(Some(ControlPacket::PubAck(ack))) => ack.reason,
+            Ok(Some(ControlPacket::PubRec(rec))) => rec.reason,
+            res => panic!("Expected a PUBACK or PUBREC, got {:?}", res),
+        };
+
+        for packet_id in 1..=2 {
+            let packet = publish("a/b", QoS::AtLeastOnce, Some(packet_id));
+            let res = session
+                .handle_publish(packet, &broker, &fanout, &audit)
+                .await;
+            assert_eq!(reason(res), ReasonCode::Success);
+            rx.recv().await.unwrap();
+        }
+
+        // A subscriber that doesn't keep up misses the message, which is
+        // still acknowledged, so the others don't get it twice
+        let packet = publish("a/b", QoS::ExactlyOnce, Some(3));
+        let res = session
+            .handle_publish(packet.clone(), &broker, &fanout, &audit)
+            .await;
+        assert_eq!(reason(res), ReasonCode::Success);
+
+        let res = session
+            .handle_publish(packet, &broker, &fanout, &audit)
+            .await;
+        assert_eq!(reason(res), ReasonCode::Success);
+
+        assert!(rx.recv().await.is_some());
+        assert!(rx.try_recv().is_err());
+        assert_eq!(broker.subscriber_stats()["stuck"].dropped, 1);
+    }
+
+    #[tokio::test]
+    async fn test_deliveries_wait_for_receive_maximum() {
+        let broker = Broker::new(Default::default(), Default::default());
+        let audit = AuditLog::disabled();
+
+        let connect_packet = ConnectPacket {
+            properties: Some(ConnectProperties {
+                receive_maximum: Some(ReceiveMaximum::new(1)),
+                ..Default::default()
+            }),
+            ..connect_packet("client", None)
+        };
+        let mut session = Session::new(connect_packet, &broker);
+        begin(&mut session).await;
+
+        let packet = subscribe(1, "a", QoS::AtLeastOnce);
+        session
+            .handle_subscribe(packet, &broker, &audit)
+            .await
+            .unwrap();
+
+        for payload in ["1", "2"] {
+            let message = Message::new("a", payload, QoS::AtLeastOnce);
+            broker.publish("a", message).unwrap();
+        }
+
+        let delivered = |packet| match packet {
+            Some(ControlPacket::Publish(publish)) => publish,
+            packet => panic!("Expected a PUBLISH, got {:?}", packet),
+        };
+
+        let first = delivered(session.process_outgoing(&broker).await);
+        assert_eq!(first.payload.unwrap(), "1");
+
+        // The client takes a single message at a time
+        let outgoing = session.process_outgoing(&broker);
+        assert!(time::timeout(Duration::from_millis(100), outgoing)
+            .await
+            .is_err());
+
+        let puback = PubAckPacket {
+            packet_id: first.packet_id.unwrap(),
+            reason: ReasonCode::Success,
+            properties: None,
+        };
+        session.handle_puback(puback).await.unwrap();
+
+        let second = delivered(session.process_outgoing(&broker).await);
+        assert_eq!(second.payload.unwrap(), "2");
+    }
+
+    #[tokio::test]
+    async fn test_receive_maximum_is_advertised_and_enforced() {
+        let broker = Broker::new(
+            Quotas {
+                max_inflight_messages: 1,
+                ..Default::default()
+            },
+            Default::default(),
+        );
+        let (shutdown_complete_tx, _shutdown_complete_rx) = mpsc::channel(1);
+        let fanout = FanoutPool::new(broker.clone(), 1, shutdown_complete_tx);
+        let audit = AuditLog::disabled();
+
+        let mut session = Session::new(connect_packet("publisher", None), &broker);
+        let connack = begin(&mut session).await;
+        let properties = connack.properties.unwrap();
+        assert_eq!(properties.receive_maximum, Some(ReceiveMaximum::new(1)));
+
+        let reason = |res| match res {
+            Ok(Some(ControlPacket::PubRec(rec))) => rec.reason,
+            res => panic!("Expected a PUBREC, got {:?}", res),
+        };
+
+        let packet = publish("a/b", QoS::ExactlyOnce, Some(1));
+        let res = session
+            .handle_publish(packet, &broker, &fanout, &audit)
+            .await;
+        assert_eq!(reason(res), ReasonCode::Success);
+
+        // The first one hasn't been released yet
+        let packet = publish("a/b", QoS::ExactlyOnce, Some(2));
+        let res = session
+            .handle_publish(packet, &broker, &fanout, &audit)
+            .await;
+        assert_eq!(reason(res), ReasonCode::QuotaExceeded);
+    }
+
+    #[tokio::test]
+    async fn test_waiting_for_messages_leaves_session_unlocked() {
+        let broker = Broker::new(Default::default(), Default::default());
+        let session = Session::new(connect_packet("client", None), &broker);
+
+        let mut delivering = session.clone();
+        let outgoing = tokio::spawn({
+            let broker = broker.clone();
+            async move { delivering.process_outgoing(&broker).await }
+        });
+        tokio::task::yield_now().await;
+
+        time::timeout(Duration::from_secs(1), session.authorization())
+            .await
+            .unwrap();
+
+        outgoing.abort();
+    }
+
+    #[tokio::test]
+    async fn test_topic_rewrite_round_trip() {
+        let broker = Broker::from_config(&Config {
+            topic_rewrites: vec![TopicRewrite {
+                from: "legacy/".to_string(),
+                to: "tenants/{username}/".to_string(),
+            }],
+            ..Default::default()
+        });
+        let (queue, mut rx) = broker.queue();
+        broker.subscribe("tenants/#", "observer", queue);
+
+        let (shutdown_complete_tx, _shutdown_complete_rx) = mpsc::channel(1);
+        let fanout = FanoutPool::new(broker.clone(), 1, shutdown_complete_tx);
+        let audit = AuditLog::disabled();
+
+        let subscribe = |packet_id| subscribe(packet_id, "legacy/#", QoS::AtMostOnce);
 
-                Some(ControlPacket::Publish(publish))
+        let suback = |res| match res {
+            Ok(Some(ControlPacket::SubAck(ack))) => ack.payload[0].reason_code,
+            res => panic!("Expected a SUBACK, got {:?}", res),
+        };
+
+        // The tenant is the user name the client authenticated as, not the
+        // one it declared
+        let mut session = Session::new(connect_packet("client", Some("other")), &broker);
+        let authorization = Authorization {
+            user_name: Some("acme".to_string()),
+            ..Default::default()
+        };
+        session.authorize(authorization, None).await;
+
+        let res = session
+            .handle_subscribe(subscribe(1), &broker, &audit)
+            .await;
+        assert_eq!(suback(res), ReasonCode::GrantedQoS0);
+
+        let packet = publish("legacy/sensors/1", QoS::AtMostOnce, None);
+        let res = session
+            .handle_publish(packet, &broker, &fanout, &audit)
+            .await;
+        assert!(matches!(res, Ok(None)));
+
+        let message = time::timeout(Duration::from_secs(1), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.topic, "tenants/acme/sensors/1");
+
+        // Delivered back on the topic the client used
+        let packet = time::timeout(Duration::from_secs(1), session.process_outgoing(&broker))
+            .await
+            .unwrap();
+        match packet {
+            Some(ControlPacket::Publish(publish)) => {
+                assert_eq!(publish.topic_name, "legacy/sensors/1")
             }
-            None => None,
+            packet => panic!("Expected a PUBLISH, got {:?}", packet),
+        }
+
+        // Without an authenticated user name, the client is refused instead
+        // of using the topics as they are
+        let mut session = Session::new(connect_packet("anonymous", Some("acme")), &broker);
+
+        let res = session
+            .handle_subscribe(subscribe(1), &broker, &audit)
+            .await;
+        assert_eq!(suback(res), ReasonCode::NotAuthorized);
+
+        let packet = publish("legacy/sensors/1", QoS::AtLeastOnce, Some(1));
+        match session
+            .handle_publish(packet, &broker, &fanout, &audit)
+            .await
+        {
+            Ok(Some(ControlPacket::PubAck(ack))) => {
+                assert_eq!(ack.reason, ReasonCode::NotAuthorized)
+            }
+            res => panic!("Expected a PUBACK, got {:?}", res),
+        }
+    }
+
+    /// Method granting whatever authorization it is given, at once.
+    #[derive(Debug)]
+    struct Grant(Authorization);
+
+    impl AuthMethod for Grant {
+        fn name(&self) -> &str {
+            "grant"
+        }
+
+        fn start(&self, _credentials: &Credentials) -> Box<dyn AuthSession> {
+            Box::new(Grant(self.0.clone()))
+        }
+    }
+
+    #[async_trait]
+    impl AuthSession for Grant {
+        async fn start(&mut self, _data: Option<Bytes>) -> Result<AuthStep> {
+            Ok(AuthStep::Success {
+                data: None,
+                authorization: self.0.clone(),
+            })
         }
+
+        async fn auth_continue(&mut self, _data: Option<Bytes>) -> Result<AuthStep> {
+            Err(ReasonCode::ProtocolError.into())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reauthentication_narrows_subscriptions() {
+        let broker = Broker::new(Default::default(), Default::default());
+        let audit = AuditLog::disabled();
+
+        let reauthenticate = || AuthPacket {
+            reason: ReasonCode::ReAuthenticate,
+            properties: AuthProperties {
+                auth_method: Some(AuthenticationMethod::new("grant".to_string())),
+                ..Default::default()
+            },
+        };
+        let auth_manager = |authorization| {
+            let mut auth_manager = AuthManager::new();
+            auth_manager.register(Arc::new(Grant(authorization)));
+            auth_manager
+        };
+
+        let mut session = Session::new(connect_packet("client", Some("acme")), &broker);
+        let authorization = Authorization {
+            user_name: Some("acme".to_string()),
+            ..Default::default()
+        };
+        session
+            .authorize(authorization, Some("grant".to_string()))
+            .await;
+
+        for (packet_id, filter) in [(1, "a/#"), (2, "b/#")] {
+            let packet = subscribe(packet_id, filter, QoS::AtMostOnce);
+            session
+                .handle_subscribe(packet, &broker, &audit)
+                .await
+                .unwrap();
+        }
+
+        let narrower = Authorization {
+            subscribe: Some(vec!["a/#".to_string()]),
+            user_name: Some("acme".to_string()),
+            ..Default::default()
+        };
+        match session
+            .handle_auth(reauthenticate(), &broker, &auth_manager(narrower))
+            .await
+        {
+            Ok(Some(ControlPacket::Auth(auth))) => assert_eq!(auth.reason, ReasonCode::Success),
+            res => panic!("Expected an AUTH, got {:?}", res),
+        }
+
+        // Only the subscription still allowed gets messages
+        for topic in ["b/1", "a/1"] {
+            let message = Message::new(topic, "21", QoS::AtMostOnce);
+            broker.publish(topic, message).unwrap();
+        }
+
+        match session.process_outgoing(&broker).await {
+            Some(ControlPacket::Publish(publish)) => assert_eq!(publish.topic_name, "a/1"),
+            packet => panic!("Expected a PUBLISH, got {:?}", packet),
+        }
+
+        // Switching to another user isn't allowed
+        let other = Authorization {
+            user_name: Some("other".to_string()),
+            ..Default::default()
+        };
+        let res = session
+            .handle_auth(reauthenticate(), &broker, &auth_manager(other))
+            .await;
+        assert!(matches!(
+            res,
+            Err(Error::MQTTReasonCode(ReasonCode::NotAuthorized))
+        ));
+        assert_eq!(session.authorization().await.user_name.unwrap(), "acme");
+    }
+
+    #[tokio::test]
+    async fn test_subscriptions_beyond_quota_are_refused() {
+        let broker = Broker::new(
+            Quotas {
+                max_subscriptions: 1,
+                ..Default::default()
+            },
+            Default::default(),
+        );
+        let audit = AuditLog::disabled();
+        let mut session = Session::new(connect_packet("client", None), &broker);
+
+        let suback = |res| match res {
+            Ok(Some(ControlPacket::SubAck(ack))) => ack.payload[0].reason_code,
+            res => panic!("Expected a SUBACK, got {:?}", res),
+        };
+
+        let packet = subscribe(1, "a", QoS::AtMostOnce);
+        let res = session.handle_subscribe(packet, &broker, &audit).await;
+        assert_eq!(suback(res), ReasonCode::GrantedQoS0);
+
+        let packet = subscribe(2, "b", QoS::AtMostOnce);
+        let res = session.handle_subscribe(packet, &broker, &audit).await;
+        assert_eq!(suback(res), ReasonCode::QuotaExceeded);
+
+        // Replacing an existing subscription doesn't count as a new one
+        let packet = subscribe(3, "a", QoS::AtLeastOnce);
+        let res = session.handle_subscribe(packet, &broker, &audit).await;
+        assert_eq!(suback(res), ReasonCode::GrantedQoS1);
+
+        let stats = broker.subscriber_stats()["client"];
+        assert_eq!(stats.subscriptions, 1);
+        assert_eq!(stats.quota_exceeded, 1);
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive_is_capped() {
+        let broker = Broker::new(
+            Default::default(),
+            Capabilities {
+                max_keep_alive: Some(60),
+                ..Default::default()
+            },
+        );
+
+        let connect = |keepalive| ConnectPacket {
+            keepalive,
+            ..connect_packet("client", None)
+        };
+
+        // A client asking for no keep alive is given the highest one
+        let mut session = Session::new(connect(0), &broker);
+        let connack = begin(&mut session).await;
+        let properties = connack.properties.unwrap();
+        assert_eq!(properties.server_keepalive, Some(ServerKeepAlive::new(60)));
+        assert_eq!(
+            session.keep_alive_timeout().await,
+            Some(Duration::from_secs(90))
+        );
+
+        // One asking for less keeps it, without being told
+        let mut session = Session::new(connect(10), &broker);
+        let connack = begin(&mut session).await;
+        assert_eq!(connack.properties.unwrap().server_keepalive, None);
+        assert_eq!(
+            session.keep_alive_timeout().await,
+            Some(Duration::from_secs(15))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disabled_subscription_features() {
+        let broker = Broker::new(
+            Default::default(),
+            Capabilities {
+                wildcard_subscriptions: false,
+                shared_subscriptions: false,
+                subscription_identifiers: false,
+                ..Default::default()
+            },
+        );
+        let audit = AuditLog::disabled();
+        let mut session = Session::new(connect_packet("client", None), &broker);
+
+        let suback = |res| match res {
+            Ok(Some(ControlPacket::SubAck(ack))) => ack.payload[0].reason_code,
+            res => panic!("Expected a SUBACK, got {:?}", res),
+        };
+
+        let packet = subscribe(1, "a/+", QoS::AtMostOnce);
+        let res = session.handle_subscribe(packet, &broker, &audit).await;
+        assert_eq!(suback(res), ReasonCode::WildcardSubscriptionsNotSupported);
+
+        let packet = subscribe(2, "$share/group/a/b", QoS::AtMostOnce);
+        let res = session.handle_subscribe(packet, &broker, &audit).await;
+        assert_eq!(suback(res), ReasonCode::SharedSubscriptionsNotSupported);
+
+        // A Subscription Identifier fails the whole packet
+        let packet = SubscribePacket {
+            properties: Some(SubscribeProperties {
+                subscription_id: Some(SubscriptionIdentifier::new(VariableByteInteger(1))),
+                ..Default::default()
+            }),
+            ..subscribe(3, "a/b", QoS::AtMostOnce)
+        };
+        let res = session.handle_subscribe(packet, &broker, &audit).await;
+        assert!(matches!(
+            res,
+            Err(Error::MQTTReasonCode(
+                ReasonCode::SubscriptionIdentifiersNotSupported
+            ))
+        ));
+
+        let packet = subscribe(4, "a/b", QoS::AtMostOnce);
+        let res = session.handle_subscribe(packet, &broker, &audit).await;
+        assert_eq!(suback(res), ReasonCode::GrantedQoS0);
+    }
+
+    #[tokio::test]
+    async fn test_qos2_message_sent_again_is_forwarded_once() {
+        let broker = Broker::new(Default::default(), Default::default());
+        let (queue, mut rx) = broker.queue();
+        broker.subscribe("a/b", "subscriber", queue);
+
+        let fanout = FanoutPool::without_workers(broker.clone());
+        let audit = AuditLog::disabled();
+        let mut session = Session::new(connect_packet("publisher", None), &broker);
+
+        let pubrec = |res| match res {
+            Ok(Some(ControlPacket::PubRec(rec))) => rec.reason,
+            res => panic!("Expected a PUBREC, got {:?}", res),
+        };
+
+        let packet = publish("a/b", QoS::ExactlyOnce, Some(1));
+        let res = session
+            .handle_publish(packet.clone(), &broker, &fanout, &audit)
+            .await;
+        assert_eq!(pubrec(res), ReasonCode::Success);
+
+        // Acknowledged again, but not forwarded before the PUBREL
+        let again = PublishPacket {
+            dup: true,
+            ..packet.clone()
+        };
+        let res = session
+            .handle_publish(again, &broker, &fanout, &audit)
+            .await;
+        assert_eq!(pubrec(res), ReasonCode::Success);
+
+        assert!(rx.try_recv().is_ok());
+        assert!(rx.try_recv().is_err());
+
+        let pubrel = PubRelPacket {
+            packet_id: 1,
+            reason: ReasonCode::Success,
+            properties: None,
+        };
+        session.handle_pubrel(pubrel).await.unwrap();
+
+        // The packet identifier is free to use for a new message afterwards
+        let res = session
+            .handle_publish(packet, &broker, &fanout, &audit)
+            .await;
+        assert_eq!(pubrec(res), ReasonCode::Success);
+        assert!(rx.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_resumed_session_resends_in_order() {
+        let broker = Broker::new(Default::default(), Default::default());
+        let audit = AuditLog::disabled();
+        let mut session = Session::new(connect_packet("client", None), &broker);
+        begin(&mut session).await;
+
+        let packet = subscribe(1, "a", QoS::ExactlyOnce);
+        session
+            .handle_subscribe(packet, &broker, &audit)
+            .await
+            .unwrap();
+
+        for (payload, qos) in [
+            ("1", QoS::ExactlyOnce),
+            ("2", QoS::AtLeastOnce),
+            ("3", QoS::AtLeastOnce),
+        ] {
+            let message = Message::new("a", payload, qos);
+            broker.publish("a", message).unwrap();
+        }
+
+        let mut packet_ids = Vec::new();
+        for _ in 0..3 {
+            match session.process_outgoing(&broker).await {
+                Some(ControlPacket::Publish(publish)) => {
+                    packet_ids.push(publish.packet_id.unwrap())
+                }
+                packet => panic!("Expected a PUBLISH, got {:?}", packet),
+            }
+        }
+
+        // The first one got as far as the PUBREC before the connection
+        // dropped
+        let pubrec = PubRecPacket {
+            packet_id: packet_ids[0],
+            reason: ReasonCode::Success,
+            properties: None,
+        };
+        session.handle_pubrec(pubrec).await.unwrap();
+
+        let message = Message::new("a", "4", QoS::AtMostOnce);
+        broker.publish("a", message).unwrap();
+
+        let (connack, mut client) = connect(&mut session, true).await;
+        assert!(connack.flags.session_present);
+
+        match client.read_packet().await.unwrap() {
+            Some(ControlPacket::PubRel(pubrel)) => assert_eq!(pubrel.packet_id, packet_ids[0]),
+            packet => panic!("Expected a PUBREL, got {:?}", packet),
+        }
+
+        for (packet_id, payload) in packet_ids[1..].iter().zip(["2", "3"]) {
+            match client.read_packet().await.unwrap() {
+                Some(ControlPacket::Publish(publish)) => {
+                    assert!(publish.dup);
+                    assert_eq!(publish.packet_id, Some(*packet_id));
+                    assert_eq!(publish.payload.unwrap(), payload);
+                }
+                packet => panic!("Expected a PUBLISH, got {:?}", packet),
+            }
+        }
+
+        // New messages come after the ones sent again
+        match session.process_outgoing(&broker).await {
+            Some(ControlPacket::Publish(publish)) => {
+                assert!(!publish.dup);
+                assert_eq!(publish.payload.unwrap(), "4");
+            }
+            packet => panic!("Expected a PUBLISH, got {:?}", packet),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_only_accepted_qos2_exchanges_are_resumed() {
+        let broker = Broker::new(Default::default(), Default::default());
+        let audit = AuditLog::disabled();
+        let mut session = Session::new(connect_packet("client", None), &broker);
+        begin(&mut session).await;
+
+        let packet = subscribe(1, "a", QoS::ExactlyOnce);
+        session
+            .handle_subscribe(packet, &broker, &audit)
+            .await
+            .unwrap();
+
+        for payload in ["1", "2"] {
+            let message = Message::new("a", payload, QoS::ExactlyOnce);
+            broker.publish("a", message).unwrap();
+        }
+
+        let mut packet_ids = Vec::new();
+        for _ in 0..2 {
+            match session.process_outgoing(&broker).await {
+                Some(ControlPacket::Publish(publish)) => {
+                    packet_ids.push(publish.packet_id.unwrap())
+                }
+                packet => panic!("Expected a PUBLISH, got {:?}", packet),
+            }
+        }
+
+        let pubrec = |packet_id, reason| PubRecPacket {
+            packet_id,
+            reason,
+            properties: None,
+        };
+
+        // Refused by the client, the first one ends without a PUBREL
+        let res = session
+            .handle_pubrec(pubrec(packet_ids[0], ReasonCode::QuotaExceeded))
+            .await;
+        assert!(matches!(res, Ok(None)));
+
+        // The second one is accepted, twice as our PUBREL got lost
+        for _ in 0..2 {
+            let res = session
+                .handle_pubrec(pubrec(packet_ids[1], ReasonCode::Success))
+                .await;
+            assert!(matches!(res, Ok(Some(ControlPacket::PubRel(_)))));
+        }
+
+        let (_, mut client) = connect(&mut session, true).await;
+
+        match client.read_packet().await.unwrap() {
+            Some(ControlPacket::PubRel(pubrel)) => assert_eq!(pubrel.packet_id, packet_ids[1]),
+            packet => panic!("Expected a PUBREL, got {:?}", packet),
+        }
+
+        // Nothing else is sent again
+        assert!(matches!(client.read_packet().await, Ok(None)));
+    }
+
+    #[tokio::test]
+    async fn test_connection_stats() {
+        let broker = Broker::new(Default::default(), Default::default());
+        let fanout = FanoutPool::without_workers(broker.clone());
+        let audit = AuditLog::disabled();
+
+        let mut session = Session::new(connect_packet("client", None), &broker);
+        let authorization = Authorization {
+            publish: Some(vec!["allowed/#".to_string()]),
+            ..Default::default()
+        };
+        session.authorize(authorization, None).await;
+
+        let stats = Arc::new(ConnectionStats::default());
+        session.set_connection_stats(Some(stats.clone()));
+
+        for topic in ["allowed/1", "denied/1"] {
+            let packet = publish(topic, QoS::AtMostOnce, None);
+            session
+                .handle_publish(packet, &broker, &fanout, &audit)
+                .await
+                .unwrap();
+        }
+
+        // Only the refused message counts
+        assert_eq!(stats.snapshot().publishes_dropped, 1);
+
+        // Deliveries waiting for a PUBACK
+        let packet = subscribe(1, "allowed/#", QoS::AtLeastOnce);
+        session
+            .handle_subscribe(packet, &broker, &audit)
+            .await
+            .unwrap();
+
+        let message = Message::new("allowed/1", "21", QoS::AtLeastOnce);
+        broker.publish("allowed/1", message).unwrap();
+        session.process_outgoing(&broker).await.unwrap();
+        assert_eq!(stats.snapshot().acks_pending, 1);
     }
 }
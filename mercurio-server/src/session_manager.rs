@@ -1,12 +1,17 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
-use tokio::sync::Mutex;
+use tokio::{sync::Mutex, task::JoinHandle, time};
+use tracing::{error, info};
 
-use mercurio_core::Result;
-use mercurio_packets::connect::ConnectPacket;
+use mercurio_core::{message::Message, reason::ReasonCode, Result};
+use mercurio_packets::{connack::ConnAckProperties, connect::ConnectPacket};
 
 use crate::{
-    connection::Connection,
+    auth::Authorization,
+    broker::Broker,
+    config::ResponseInformationConfig,
+    connection::{Connection, ConnectionStatsSnapshot},
+    fanout::FanoutPool,
     session::{Session, SessionDropGuard},
 };
 
@@ -25,6 +30,14 @@ struct Shared {
 
 struct State {
     sessions: HashMap<String, SessionDropGuard>,
+
+    /// Tasks ending the sessions whose client is gone once their expiry
+    /// interval elapsed, by client identifier.
+    expirations: HashMap<String, JoinHandle<()>>,
+
+    /// Tasks publishing the wills of clients once their Will Delay Interval
+    /// elapsed, by client identifier.
+    wills: HashMap<String, JoinHandle<()>>,
 }
 
 impl SessionManagerDropGuard {
@@ -44,6 +57,8 @@ impl SessionManager {
         let shared = Arc::new(Shared {
             state: Mutex::new(State {
                 sessions: HashMap::new(),
+                expirations: HashMap::new(),
+                wills: HashMap::new(),
             }),
         });
 
@@ -54,31 +69,397 @@ impl SessionManager {
         &mut self,
         connection: &mut Connection,
         connect_packet: ConnectPacket,
+        properties: ConnAckProperties,
+        response_information: Option<&ResponseInformationConfig>,
+        broker: &Broker,
     ) -> Result<Session> {
         let mut manager = self.shared.state.lock().await;
-        let mut resume = true;
+        let client_id = connect_packet.payload.client_id.clone();
+
+        if let Some(expiration) = manager.expirations.remove(&client_id) {
+            expiration.abort();
+        }
+
+        // [MQTT-3.1.3-9]
+        // If a new Network Connection to this Session is made before the
+        // Will Delay Interval has passed, the Server MUST NOT send the Will
+        // Message.
+        if let Some(will) = manager.wills.remove(&client_id) {
+            will.abort();
+        }
 
-        if connect_packet.flags.clean_start {
-            resume = false;
-            manager.sessions.remove(&connect_packet.payload.client_id);
+        // [MQTT-3.1.4-3]
+        // If the ClientID represents a Client already connected to the
+        // Server, the Server sends a DISCONNECT packet to the existing Client
+        // with Reason Code of 0x8E (Session taken over) and MUST close the
+        // Network Connection of the existing Client.
+        if let Some(existing) = manager.sessions.get(&client_id) {
+            if existing.session().kick(ReasonCode::SessionTakenOver) {
+                info!(
+                    "Client `{}` connected again, taking over its session",
+                    client_id
+                );
+            }
+        }
+
+        // [MQTT-3.1.2-4]
+        // If a CONNECT packet is received with Clean Start is set to 1, the
+        // Client and Server MUST discard any existing Session and start a
+        // new Session.
+        if connect_packet.flags.clean_start && manager.sessions.remove(&client_id).is_some() {
+            broker.unsubscribe_all(&client_id);
         }
 
+        // [MQTT-3.2.2-2], [MQTT-3.2.2-3]
+        // Session Present is set only if a session is resumed.
+        let resume = manager.sessions.contains_key(&client_id);
+
         let mut session = match manager
             .sessions
             .entry(connect_packet.payload.client_id.clone())
         {
             std::collections::hash_map::Entry::Occupied(e) => {
-                let mut s = e.into_mut().session();
+                let mut s = e.into_mut().reconnect();
                 s.set_connect_packet(connect_packet).await;
                 s
             }
             std::collections::hash_map::Entry::Vacant(e) => {
-                let new_session = SessionDropGuard::new(connect_packet);
+                let new_session = SessionDropGuard::new(connect_packet, broker);
                 e.insert(new_session).session()
             }
         };
 
-        session.begin(connection, resume).await?;
+        // The CONNACK is written once the session is registered, a client
+        // slow to read it mustn't hold up every other connection
+        drop(manager);
+
+        session
+            .begin(connection, resume, properties, response_information)
+            .await?;
         Ok(session)
     }
+
+    /// Disconnects a client with Administrative Action, returning `false` if
+    /// it isn't connected. Its session is kept.
+    pub(crate) async fn kick(&self, client_id: &str) -> bool {
+        let session = match self.shared.state.lock().await.sessions.get(client_id) {
+            Some(session) => session.session(),
+            None => return false,
+        };
+
+        session.kick(ReasonCode::AdministrativeAction)
+    }
+
+    /// Returns the counters of the connection of every connected client, by
+    /// client identifier.
+    pub(crate) async fn connection_stats(&self) -> Vec<(String, ConnectionStatsSnapshot)> {
+        let manager = self.shared.state.lock().await;
+
+        manager
+            .sessions
+            .iter()
+            .filter_map(|(client_id, session)| {
+                let stats = session.session().connection_stats()?;
+                Some((client_id.clone(), stats.snapshot()))
+            })
+            .collect()
+    }
+
+    /// Returns the counters of the connection of a client, if it's
+    /// connected.
+    pub(crate) async fn client_stats(&self, client_id: &str) -> Option<ConnectionStatsSnapshot> {
+        let manager = self.shared.state.lock().await;
+        let stats = manager
+            .sessions
+            .get(client_id)?
+            .session()
+            .connection_stats()?;
+
+        Some(stats.snapshot())
+    }
+
+    /// Returns what a client is allowed to do, if it has a session.
+    pub(crate) async fn authorization(&self, client_id: &str) -> Option<Authorization> {
+        let session = self
+            .shared
+            .state
+            .lock()
+            .await
+            .sessions
+            .get(client_id)?
+            .session();
+
+        // The session may be busy, the manager mustn't wait along with it
+        Some(session.authorization().await)
+    }
+
+    /// Ends the connection of a client to its session, which is discarded
+    /// right away or once its expiry interval elapsed, unless the client
+    /// connects again in the meantime.
+    ///
+    /// The will of the client is published as well, unless it disconnected
+    /// normally. It's delayed by its Will Delay Interval, but not past the
+    /// end of the session.
+    pub(crate) async fn end_session(
+        &mut self,
+        session: &Session,
+        broker: &Broker,
+        fanout: &FanoutPool,
+        publish_will: bool,
+    ) {
+        let client_id = session.get_client_id().await;
+        let expiry_interval = session.expiry_interval().await;
+        let will = match publish_will {
+            true => session.will().await,
+            false => None,
+        };
+        let mut manager = self.shared.state.lock().await;
+
+        // The client may have started another session meanwhile, or
+        // connected again to this one
+        let (current, taken_over) = match manager.sessions.get(&client_id) {
+            Some(other) => {
+                let other = other.session();
+                let current = other.is_connection(session);
+                (current, other.is(session) && !current)
+            }
+            None => (false, false),
+        };
+
+        if current {
+            session.set_connection_stats(None);
+        }
+
+        // Published once the manager is unlocked, routing it may take a
+        // while
+        let mut will_now = None;
+
+        if let Some((will, delay)) = will {
+            match delay.min(expiry_interval) {
+                0 => will_now = Some(will),
+                // [MQTT-3.1.3-9]
+                _ if taken_over => {}
+                _ if !current => will_now = Some(will),
+                delay => {
+                    let manager_handle = self.clone();
+                    let fanout = fanout.clone();
+                    let will_client_id = client_id.clone();
+
+                    let task = tokio::spawn(async move {
+                        time::sleep(Duration::from_secs(u64::from(delay))).await;
+
+                        manager_handle
+                            .shared
+                            .state
+                            .lock()
+                            .await
+                            .wills
+                            .remove(&will_client_id);
+                        publish(&fanout, will).await;
+                    });
+
+                    manager.wills.insert(client_id.clone(), task);
+                }
+            }
+        }
+
+        if current {
+            match expiry_interval {
+                // [MQTT-3.1.2-23]
+                // The session doesn't expire if the Session Expiry Interval
+                // is 0xFFFFFFFF.
+                u32::MAX => {}
+                0 => {
+                    manager.sessions.remove(&client_id);
+                    broker.unsubscribe_all(&client_id);
+                }
+                expiry_interval => {
+                    let manager_handle = self.clone();
+                    let broker = broker.clone();
+                    let expiring_client_id = client_id.clone();
+
+                    let expiration = tokio::spawn(async move {
+                        time::sleep(Duration::from_secs(u64::from(expiry_interval))).await;
+
+                        let mut manager = manager_handle.shared.state.lock().await;
+                        manager.expirations.remove(&expiring_client_id);
+                        manager.sessions.remove(&expiring_client_id);
+                        broker.unsubscribe_all(&expiring_client_id);
+
+                        info!("Session of client `{}` expired", expiring_client_id);
+                    });
+
+                    manager.expirations.insert(client_id, expiration);
+                }
+            }
+        }
+
+        drop(manager);
+
+        if let Some(will) = will_now {
+            publish(fanout, will).await;
+        }
+    }
+}
+
+async fn publish(fanout: &FanoutPool, will: Message) {
+    let topic = will.topic.clone();
+
+    if let Err(err) = fanout.publish(topic.clone(), will).await {
+        error!(cause = ?err, "Failed to publish will on `{}`", topic);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use mercurio_core::{
+        properties::{ContentType, CorrelationData, SessionExpiryInterval, UserProperty},
+        qos::QoS,
+    };
+    use mercurio_packets::{
+        connack::ConnAckPacket,
+        connect::{ConnectFlags, ConnectPacket, ConnectPayload, ConnectProperties, WillProperties},
+        ControlPacket,
+    };
+
+    use super::SessionManager;
+    use crate::{broker::Broker, connection::Connection, fanout::FanoutPool, session::Session};
+
+    fn connect_packet(clean_start: bool, expiry_interval: u32) -> ConnectPacket {
+        ConnectPacket {
+            flags: ConnectFlags {
+                clean_start,
+                ..Default::default()
+            },
+            keepalive: 0,
+            properties: Some(ConnectProperties {
+                session_expiry_interval: Some(SessionExpiryInterval::new(expiry_interval)),
+                ..Default::default()
+            }),
+            payload: ConnectPayload {
+                client_id: "client".to_string(),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Connects with `connect_packet`, returning the session and the
+    /// CONNACK the client gets.
+    async fn connect(
+        manager: &mut SessionManager,
+        broker: &Broker,
+        connect_packet: ConnectPacket,
+    ) -> (Session, ConnAckPacket) {
+        let (client, server) = tokio::io::duplex(4096);
+        let mut connection = Connection::new(server);
+
+        let session = manager
+            .start_session(
+                &mut connection,
+                connect_packet,
+                Default::default(),
+                None,
+                broker,
+            )
+            .await
+            .unwrap();
+        connection.flush().await.unwrap();
+
+        match Connection::new(client).read_packet().await.unwrap() {
+            Some(ControlPacket::ConnAck(connack)) => (session, connack),
+            packet => panic!("Expected a CONNACK, got {:?}", packet),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session_present() {
+        let broker = Broker::new(Default::default(), Default::default());
+        let fanout = FanoutPool::without_workers(broker.clone());
+        let mut manager = SessionManager::new();
+
+        let (session, connack) = connect(&mut manager, &broker, connect_packet(false, 60)).await;
+        assert!(!connack.flags.session_present);
+
+        let (queue, _rx) = broker.queue();
+        broker.subscribe("a/b", "client", queue);
+        manager.end_session(&session, &broker, &fanout, false).await;
+
+        // Kept for its expiry interval
+        let (session, connack) = connect(&mut manager, &broker, connect_packet(false, 60)).await;
+        assert!(connack.flags.session_present);
+        manager.end_session(&session, &broker, &fanout, false).await;
+
+        // Discarded along with its subscriptions on Clean Start
+        let (session, connack) = connect(&mut manager, &broker, connect_packet(true, 0)).await;
+        assert!(!connack.flags.session_present);
+        assert!(!broker.subscriber_stats().contains_key("client"));
+
+        // Ended with the connection, its expiry interval being 0
+        manager.end_session(&session, &broker, &fanout, false).await;
+        let (_, connack) = connect(&mut manager, &broker, connect_packet(false, 0)).await;
+        assert!(!connack.flags.session_present);
+    }
+
+    #[tokio::test]
+    async fn test_will_keeps_its_properties() {
+        let broker = Broker::new(Default::default(), Default::default());
+        let fanout = FanoutPool::without_workers(broker.clone());
+        let mut manager = SessionManager::new();
+
+        let (queue, mut rx) = broker.queue();
+        broker.subscribe("wills/#", "observer", queue);
+
+        let mut connect_packet = connect_packet(true, 0);
+        connect_packet.flags.will_flag = true;
+        connect_packet.flags.will_qos = QoS::AtLeastOnce;
+        connect_packet.flags.will_retain = true;
+        connect_packet.payload.will_topic = Some("wills/client".to_string());
+        connect_packet.payload.will_payload = Some(Bytes::from("gone"));
+        connect_packet.payload.will_properties = Some(WillProperties {
+            content_type: Some(ContentType::new("text/plain".to_string())),
+            correlation_data: Some(CorrelationData::new(Bytes::from("21"))),
+            user_property: Some(vec![UserProperty::new(
+                "reason".to_string(),
+                "test".to_string(),
+            )]),
+            ..Default::default()
+        });
+
+        let (session, _) = connect(&mut manager, &broker, connect_packet).await;
+        manager.end_session(&session, &broker, &fanout, true).await;
+
+        let will = rx.try_recv().unwrap();
+        assert_eq!(will.topic, "wills/client");
+        assert_eq!(will.qos, QoS::AtLeastOnce);
+        assert_eq!(will.payload.unwrap(), "gone");
+        assert_eq!(will.content_type.as_deref(), Some("text/plain"));
+        assert_eq!(will.correlation_data.unwrap(), "21");
+        assert_eq!(will.user_properties.unwrap().len(), 1);
+
+        // Kept as a retained message too
+        let (queue, _rx) = broker.queue();
+        let retained = broker.subscribe_retained("wills/#", "late", queue);
+        assert_eq!(retained.len(), 1);
+        assert_eq!(retained[0].payload.as_deref(), Some(&b"gone"[..]));
+    }
+
+    #[tokio::test]
+    async fn test_taken_over_connection_leaves_session() {
+        let broker = Broker::new(Default::default(), Default::default());
+        let fanout = FanoutPool::without_workers(broker.clone());
+        let mut manager = SessionManager::new();
+
+        let (first, _) = connect(&mut manager, &broker, connect_packet(false, 0)).await;
+        let (second, connack) = connect(&mut manager, &broker, connect_packet(false, 0)).await;
+        assert!(connack.flags.session_present);
+
+        // The session lives on with the connection that took it over
+        manager.end_session(&first, &broker, &fanout, true).await;
+        assert!(manager.authorization("client").await.is_some());
+
+        manager.end_session(&second, &broker, &fanout, true).await;
+        assert!(manager.authorization("client").await.is_none());
+    }
 }
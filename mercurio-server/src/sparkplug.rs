@@ -0,0 +1,336 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde_json::json;
+use tracing::{error, warn};
+
+use mercurio_core::{message::Message, qos::QoS};
+
+use crate::{
+    broker::{Broker, ExternalSubscription},
+    fanout::FanoutPool,
+    shutdown::Shutdown,
+};
+
+/// Namespace of the Sparkplug B topics,
+/// `spBv1.0/<group>/<message type>/<edge node>[/<device>]`.
+const NAMESPACE: &str = "spBv1.0";
+
+/// Keeps track of the Sparkplug B edge nodes and devices publishing through
+/// the broker.
+///
+/// The state of each edge node is published, retained, on
+/// `$SYS/sparkplug/<group>/<edge node>` whenever it changes, as in
+/// `{"devices":{"d1":"online"},"seq":12,"sequence_errors":0,"status":"online"}`.
+/// Messages whose sequence number doesn't follow the previous one of the
+/// node are counted as sequence errors.
+pub(crate) struct SparkplugMonitor {
+    fanout: FanoutPool,
+    subscription: ExternalSubscription,
+    shutdown: Shutdown,
+
+    /// State of the edge nodes, by group and edge node identifier.
+    nodes: HashMap<(String, String), EdgeNode>,
+}
+
+#[derive(Debug, Default)]
+struct EdgeNode {
+    online: bool,
+
+    /// Sequence number of the last message of the node, if any since its
+    /// birth.
+    seq: Option<u64>,
+
+    sequence_errors: u64,
+
+    /// Whether each device of the node is online.
+    devices: BTreeMap<String, bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MessageType {
+    NodeBirth,
+    NodeDeath,
+    DeviceBirth,
+    DeviceDeath,
+    NodeData,
+    DeviceData,
+    NodeCommand,
+    DeviceCommand,
+}
+
+/// Topic of a message of an edge node, or one of its devices.
+#[derive(Debug, PartialEq, Eq)]
+struct Topic<'a> {
+    group: &'a str,
+    message_type: MessageType,
+    edge_node: &'a str,
+    device: Option<&'a str>,
+}
+
+impl SparkplugMonitor {
+    pub(crate) fn new(broker: &Broker, fanout: FanoutPool, shutdown: Shutdown) -> SparkplugMonitor {
+        let subscription = broker
+            .subscribe_external(&format!("{}/#", NAMESPACE))
+            .expect("the Sparkplug namespace is a valid topic filter");
+
+        SparkplugMonitor {
+            fanout,
+            subscription,
+            shutdown,
+            nodes: HashMap::new(),
+        }
+    }
+
+    pub(crate) async fn run(&mut self) {
+        while !self.shutdown.is_shutdown() {
+            tokio::select! {
+                message = self.subscription.recv() => match message {
+                    Some(message) => self.handle(&message).await,
+                    None => return,
+                },
+                _ = self.shutdown.recv() => {}
+            }
+        }
+    }
+
+    async fn handle(&mut self, message: &Message) {
+        // Host application STATE messages and the like aren't about nodes
+        let topic = match parse_topic(&message.topic) {
+            Some(topic) => topic,
+            None => return,
+        };
+
+        let key = (topic.group.to_string(), topic.edge_node.to_string());
+        let node = self.nodes.entry(key).or_default();
+        let seq = message.payload.as_deref().and_then(sequence_number);
+
+        match topic.message_type {
+            MessageType::NodeBirth => {
+                // Devices are born again after their node
+                node.online = true;
+                node.seq = seq;
+                node.devices.clear();
+            }
+            MessageType::NodeDeath => {
+                node.online = false;
+                node.seq = None;
+                node.devices.values_mut().for_each(|online| *online = false);
+            }
+            MessageType::NodeCommand | MessageType::DeviceCommand => return,
+            message_type => {
+                // Sequence numbers go from 0 to 255, then back to 0
+                if let (Some(last), Some(seq)) = (node.seq, seq) {
+                    if seq != (last + 1) % 256 {
+                        node.sequence_errors += 1;
+
+                        warn!(
+                            "Edge node `{}/{}` sent sequence number {} after {}",
+                            topic.group, topic.edge_node, seq, last
+                        );
+                    }
+                }
+
+                node.seq = seq.or(node.seq);
+
+                if let Some(device) = topic.device {
+                    match message_type {
+                        MessageType::DeviceBirth => {
+                            node.devices.insert(device.to_string(), true);
+                        }
+                        MessageType::DeviceDeath => {
+                            node.devices.insert(device.to_string(), false);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        let state = json!({
+            "status": status(node.online),
+            "seq": node.seq,
+            "sequence_errors": node.sequence_errors,
+            "devices": node
+                .devices
+                .iter()
+                .map(|(device, online)| (device.clone(), json!(status(*online))))
+                .collect::<serde_json::Map<_, _>>(),
+        });
+
+        self.publish(topic.group, topic.edge_node, state).await;
+    }
+
+    async fn publish(&self, group: &str, edge_node: &str, state: serde_json::Value) {
+        let topic = format!("$SYS/sparkplug/{}/{}", group, edge_node);
+        let message = Message {
+            retain: true,
+            ..Message::new(topic.clone(), state.to_string(), QoS::AtMostOnce)
+        };
+
+        if let Err(err) = self.fanout.publish(topic.clone(), message).await {
+            error!(cause = ?err, "Failed to publish `{}`", topic);
+        }
+    }
+}
+
+fn status(online: bool) -> &'static str {
+    match online {
+        true => "online",
+        false => "offline",
+    }
+}
+
+fn parse_topic(topic: &str) -> Option<Topic<'_>> {
+    let mut levels = topic.split('/');
+
+    if levels.next()? != NAMESPACE {
+        return None;
+    }
+
+    let group = levels.next()?;
+    let message_type = match levels.next()? {
+        "NBIRTH" => MessageType::NodeBirth,
+        "NDEATH" => MessageType::NodeDeath,
+        "DBIRTH" => MessageType::DeviceBirth,
+        "DDEATH" => MessageType::DeviceDeath,
+        "NDATA" => MessageType::NodeData,
+        "DDATA" => MessageType::DeviceData,
+        "NCMD" => MessageType::NodeCommand,
+        "DCMD" => MessageType::DeviceCommand,
+        _ => return None,
+    };
+    let edge_node = levels.next()?;
+    let device = levels.next();
+
+    // Node messages have no device, device messages always do
+    let is_device_message = matches!(
+        message_type,
+        MessageType::DeviceBirth
+            | MessageType::DeviceDeath
+            | MessageType::DeviceData
+            | MessageType::DeviceCommand
+    );
+
+    if levels.next().is_some() || device.is_some() != is_device_message {
+        return None;
+    }
+
+    Some(Topic {
+        group,
+        message_type,
+        edge_node,
+        device,
+    })
+}
+
+/// Returns the `seq` field of a Sparkplug B payload, field 3 of the
+/// Protocol Buffers message.
+fn sequence_number(mut payload: &[u8]) -> Option<u64> {
+    while !payload.is_empty() {
+        let key = varint(&mut payload)?;
+
+        match (key >> 3, key & 0x07) {
+            (3, 0) => return varint(&mut payload),
+            (_, 0) => {
+                varint(&mut payload)?;
+            }
+            (_, 1) => payload = payload.get(8..)?,
+            (_, 2) => {
+                let length = usize::try_from(varint(&mut payload)?).ok()?;
+                payload = payload.get(length..)?;
+            }
+            (_, 5) => payload = payload.get(4..)?,
+            _ => return None,
+        }
+    }
+
+    None
+}
+
+/// Reads a Protocol Buffers varint off the front of `buffer`.
+fn varint(buffer: &mut &[u8]) -> Option<u64> {
+    let mut value = 0;
+
+    for (i, byte) in buffer.iter().take(10).enumerate() {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+
+        if byte & 0x80 == 0 {
+            *buffer = &buffer[i + 1..];
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use tokio::sync::broadcast;
+
+    use mercurio_core::{message::Message, qos::QoS};
+
+    use crate::{broker::Broker, fanout::FanoutPool, shutdown::Shutdown};
+
+    use super::SparkplugMonitor;
+
+    fn message(topic: &str, seq: Option<u8>) -> Message {
+        // A timestamp (field 1), then the sequence number (field 3)
+        let mut payload = vec![0x08, 0x96, 0x01];
+        payload.extend(seq.map(|seq| vec![0x18, seq]).unwrap_or_default());
+
+        Message::new(topic, payload, QoS::AtMostOnce)
+    }
+
+    #[tokio::test]
+    async fn test_edge_node_state() {
+        let broker = Broker::new(Default::default(), Default::default());
+        let (_notify, receiver) = broadcast::channel(1);
+        let fanout = FanoutPool::without_workers(broker.clone());
+        let mut monitor = SparkplugMonitor::new(&broker, fanout, Shutdown::new(receiver));
+
+        let state = || -> Value {
+            let retained = broker.retained("$SYS/sparkplug/plant/edge");
+            serde_json::from_slice(&retained[0].payload.clone().unwrap()).unwrap()
+        };
+
+        monitor
+            .handle(&message("spBv1.0/plant/NBIRTH/edge", Some(0)))
+            .await;
+        monitor
+            .handle(&message("spBv1.0/plant/DBIRTH/edge/pump", Some(1)))
+            .await;
+        monitor
+            .handle(&message("spBv1.0/plant/DDATA/edge/pump", Some(2)))
+            .await;
+
+        assert_eq!(
+            state(),
+            json!({
+                "status": "online",
+                "seq": 2,
+                "sequence_errors": 0,
+                "devices": { "pump": "online" },
+            })
+        );
+
+        // A message went missing
+        monitor
+            .handle(&message("spBv1.0/plant/DDATA/edge/pump", Some(4)))
+            .await;
+        assert_eq!(state()["sequence_errors"], json!(1));
+
+        monitor
+            .handle(&message("spBv1.0/plant/NDEATH/edge", None))
+            .await;
+        assert_eq!(state()["status"], json!("offline"));
+        assert_eq!(state()["devices"]["pump"], json!("offline"));
+
+        // Not about an edge node
+        monitor.handle(&message("spBv1.0/STATE/host", None)).await;
+        monitor
+            .handle(&message("spBv1.0/plant/NDATA/edge/pump", Some(0)))
+            .await;
+        assert_eq!(broker.retained("$SYS/sparkplug/#").len(), 1);
+    }
+}
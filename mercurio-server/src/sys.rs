@@ -0,0 +1,177 @@
+use std::sync::atomic::Ordering;
+
+use tokio::time::{self, Duration, Instant};
+use tracing::error;
+
+use mercurio_core::{message::Message, qos::QoS};
+
+use crate::{
+    broker::{self, Broker},
+    fanout::FanoutPool,
+    session_manager::SessionManager,
+    shutdown::Shutdown,
+    topic_tree,
+};
+
+/// Topic the total number of dropped messages is published on.
+const DROPPED_TOPIC: &str = "$SYS/broker/messages/dropped";
+
+/// Topic the total number of operations refused because of a quota is
+/// published on.
+const QUOTA_EXCEEDED_TOPIC: &str = "$SYS/broker/quota/exceeded";
+
+/// Topic the total number of connections the listeners failed to accept is
+/// published on.
+const ACCEPT_ERRORS_TOPIC: &str = "$SYS/broker/connections/accept_errors";
+
+/// Topic the total number of connections refused because of the address
+/// they came from is published on.
+const REJECTED_TOPIC: &str = "$SYS/broker/connections/rejected";
+
+/// Periodically publishes broker statistics under `$SYS/`.
+///
+/// Clients whose identifier contains `+`, `#` or `/`, which can't be put in
+/// a topic as is, are left out, as are the subscribers within the broker.
+///
+/// Per subscriber, under `$SYS/broker/subscribers/<id>/`:
+/// - `messages/dropped`: messages dropped because its queue was full, so
+///   operators can tell which consumers are lagging behind
+/// - `messages/queued`: messages waiting in its queue
+/// - `subscriptions`: number of subscriptions
+/// - `quota/exceeded`: operations refused because of a quota
+///
+/// Per connected client, under `$SYS/broker/clients/<id>/`:
+/// - `packets/received`, `packets/sent`: packets exchanged over its
+///   connection
+/// - `bytes/received`, `bytes/sent`: bytes exchanged over its connection
+/// - `publishes/dropped`: messages of the client refused by the broker
+/// - `acks/pending`: messages sent to the client waiting for their
+///   acknowledgement
+/// - `last_activity`: when it last sent a packet, in seconds since the Unix
+///   epoch
+///
+/// Per fan-out worker, under `$SYS/broker/fanout/<index>/`:
+/// - `messages/routed`: messages routed to their subscribers
+/// - `messages/queued`: messages waiting to be routed
+pub(crate) struct SysPublisher {
+    broker: Broker,
+    session_manager: SessionManager,
+    fanout: FanoutPool,
+    interval: Duration,
+    shutdown: Shutdown,
+}
+
+impl SysPublisher {
+    pub(crate) fn new(
+        broker: Broker,
+        session_manager: SessionManager,
+        fanout: FanoutPool,
+        interval: Duration,
+        shutdown: Shutdown,
+    ) -> SysPublisher {
+        SysPublisher {
+            broker,
+            session_manager,
+            fanout,
+            interval,
+            shutdown,
+        }
+    }
+
+    pub(crate) async fn run(&mut self) {
+        let mut ticks = time::interval_at(Instant::now() + self.interval, self.interval);
+
+        while !self.shutdown.is_shutdown() {
+            tokio::select! {
+                _ = ticks.tick() => self.publish_stats().await,
+                _ = self.shutdown.recv() => {}
+            }
+        }
+    }
+
+    async fn publish_stats(&self) {
+        let stats = self.broker.subscriber_stats();
+        let dropped: u64 = stats.values().map(|s| s.dropped).sum();
+        let quota_exceeded: u64 = stats.values().map(|s| s.quota_exceeded).sum();
+
+        for (subscriber_id, stats) in stats {
+            // Only clients are reported, under identifiers that make a
+            // single topic level
+            if subscriber_id.starts_with(broker::INTERNAL_PREFIX)
+                || !topic_tree::is_valid_topic_level(&subscriber_id)
+            {
+                continue;
+            }
+
+            let prefix = format!("$SYS/broker/subscribers/{}", subscriber_id);
+            self.publish(&format!("{}/messages/dropped", prefix), stats.dropped)
+                .await;
+            self.publish(&format!("{}/messages/queued", prefix), stats.queued as u64)
+                .await;
+            self.publish(
+                &format!("{}/subscriptions", prefix),
+                stats.subscriptions as u64,
+            )
+            .await;
+            self.publish(&format!("{}/quota/exceeded", prefix), stats.quota_exceeded)
+                .await;
+        }
+
+        for (client_id, stats) in self.session_manager.connection_stats().await {
+            if !topic_tree::is_valid_topic_level(&client_id) {
+                continue;
+            }
+
+            let prefix = format!("$SYS/broker/clients/{}", client_id);
+            self.publish(
+                &format!("{}/packets/received", prefix),
+                stats.packets_received,
+            )
+            .await;
+            self.publish(&format!("{}/packets/sent", prefix), stats.packets_sent)
+                .await;
+            self.publish(&format!("{}/bytes/received", prefix), stats.bytes_received)
+                .await;
+            self.publish(&format!("{}/bytes/sent", prefix), stats.bytes_sent)
+                .await;
+            self.publish(
+                &format!("{}/publishes/dropped", prefix),
+                stats.publishes_dropped,
+            )
+            .await;
+            self.publish(&format!("{}/acks/pending", prefix), stats.acks_pending)
+                .await;
+            self.publish(&format!("{}/last_activity", prefix), stats.last_activity)
+                .await;
+        }
+
+        self.publish(DROPPED_TOPIC, dropped).await;
+        self.publish(QUOTA_EXCEEDED_TOPIC, quota_exceeded).await;
+        self.publish(ACCEPT_ERRORS_TOPIC, self.broker.accept_errors())
+            .await;
+        self.publish(REJECTED_TOPIC, self.broker.rejected_connections())
+            .await;
+
+        for (index, shard) in self.fanout.stats().shards().iter().enumerate() {
+            let prefix = format!("$SYS/broker/fanout/{}", index);
+            self.publish(
+                &format!("{}/messages/routed", prefix),
+                shard.routed.load(Ordering::Relaxed),
+            )
+            .await;
+            self.publish(
+                &format!("{}/messages/queued", prefix),
+                shard.queued.load(Ordering::Relaxed),
+            )
+            .await;
+        }
+    }
+
+    async fn publish(&self, topic: &str, value: u64) {
+        let message = Message::new(topic, value.to_string(), QoS::AtMostOnce);
+
+        if let Err(err) = self.fanout.publish(topic.to_string(), message).await {
+            error!(cause = ?err, "Failed to publish `{}`", topic);
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use tracing::{debug_span, info_span, Span};
+
+use mercurio_core::properties::UserProperty;
+use mercurio_packets::ControlPacket;
+
+/// Creates the span under which a packet received from the client is
+/// handled.
+///
+/// A message goes through a `publish` span when received, a `route` span
+/// while it's handed to the subscribers, then a `deliver` span on each
+/// subscriber's connection. With the `telemetry` feature, the trace context
+/// in the `traceparent` and `tracestate` user properties of the PUBLISH, if
+/// any, becomes the parent of the `publish` span, and the context of the
+/// `route` span is passed on to the subscribers the same way. The global
+/// propagator is used, which the application sets along with its
+/// `tracing-opentelemetry` layer.
+pub(crate) fn incoming_span(packet: &ControlPacket) -> Span {
+    match packet {
+        ControlPacket::Publish(publish) => {
+            let span = info_span!(
+                "publish",
+                topic = %publish.topic_name,
+                qos = ?publish.qos_level,
+                packet_id = ?publish.packet_id,
+            );
+
+            let user_properties = publish
+                .properties
+                .as_ref()
+                .and_then(|p| p.user_property.as_deref());
+            set_parent(&span, user_properties);
+
+            span
+        }
+        ControlPacket::PubAck(ack) => info_span!("ack", kind = "PUBACK", packet_id = ack.packet_id),
+        ControlPacket::PubRec(ack) => info_span!("ack", kind = "PUBREC", packet_id = ack.packet_id),
+        ControlPacket::PubRel(ack) => info_span!("ack", kind = "PUBREL", packet_id = ack.packet_id),
+        ControlPacket::PubComp(ack) => {
+            info_span!("ack", kind = "PUBCOMP", packet_id = ack.packet_id)
+        }
+        packet => debug_span!("packet", kind = kind(packet)),
+    }
+}
+
+/// Creates the span under which a packet is sent to the client.
+pub(crate) fn outgoing_span(packet: &ControlPacket) -> Span {
+    match packet {
+        ControlPacket::Publish(publish) => {
+            let span = info_span!(
+                "deliver",
+                topic = %publish.topic_name,
+                qos = ?publish.qos_level,
+                packet_id = ?publish.packet_id,
+            );
+
+            let user_properties = publish
+                .properties
+                .as_ref()
+                .and_then(|p| p.user_property.as_deref());
+            set_parent(&span, user_properties);
+
+            span
+        }
+        packet => debug_span!("packet", kind = kind(packet)),
+    }
+}
+
+/// Creates the span under which a message is routed to the subscribers.
+pub(crate) fn route_span(topic: &str) -> Span {
+    info_span!("route", topic)
+}
+
+fn kind(packet: &ControlPacket) -> &'static str {
+    match packet {
+        ControlPacket::Connect(_) => "CONNECT",
+        ControlPacket::ConnAck(_) => "CONNACK",
+        ControlPacket::Publish(_) => "PUBLISH",
+        ControlPacket::PubAck(_) => "PUBACK",
+        ControlPacket::PubRec(_) => "PUBREC",
+        ControlPacket::PubRel(_) => "PUBREL",
+        ControlPacket::PubComp(_) => "PUBCOMP",
+        ControlPacket::Subscribe(_) => "SUBSCRIBE",
+        ControlPacket::SubAck(_) => "SUBACK",
+        ControlPacket::Unsubscribe(_) => "UNSUBSCRIBE",
+        ControlPacket::UnsubAck(_) => "UNSUBACK",
+        ControlPacket::PingReq(_) => "PINGREQ",
+        ControlPacket::PingResp(_) => "PINGRESP",
+        ControlPacket::Disconnect(_) => "DISCONNECT",
+        ControlPacket::Auth(_) => "AUTH",
+    }
+}
+
+/// Makes the trace context found in `user_properties`, if any, the parent
+/// of `span`.
+#[cfg(feature = "telemetry")]
+fn set_parent(span: &Span, user_properties: Option<&[UserProperty]>) {
+    use opentelemetry::{global, trace::TraceContextExt};
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    let user_properties = match user_properties {
+        Some(user_properties) => user_properties,
+        None => return,
+    };
+
+    let context = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&Extractor(user_properties))
+    });
+
+    // Without a remote context, the span stays within the connection's
+    if context.span().span_context().is_valid() {
+        let _ = span.set_parent(context);
+    }
+}
+
+#[cfg(not(feature = "telemetry"))]
+fn set_parent(_span: &Span, _user_properties: Option<&[UserProperty]>) {}
+
+/// Sets the trace context of the current span in `user_properties`,
+/// replacing the one of the publisher, if any.
+#[cfg(feature = "telemetry")]
+pub(crate) fn inject(user_properties: &mut Option<Vec<UserProperty>>) {
+    use opentelemetry::{global, trace::TraceContextExt};
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    let context = Span::current().context();
+
+    if !context.span().span_context().is_valid() {
+        return;
+    }
+
+    let mut injected = user_properties.take().unwrap_or_default();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut Injector(&mut injected))
+    });
+
+    *user_properties = Some(injected).filter(|p| !p.is_empty());
+}
+
+#[cfg(not(feature = "telemetry"))]
+pub(crate) fn inject(_user_properties: &mut Option<Vec<UserProperty>>) {}
+
+#[cfg(feature = "telemetry")]
+struct Extractor<'a>(&'a [UserProperty]);
+
+#[cfg(feature = "telemetry")]
+impl opentelemetry::propagation::Extractor for Extractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|property| property.key == key)
+            .map(|property| property.value.as_str())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0
+            .iter()
+            .map(|property| property.key.as_str())
+            .collect()
+    }
+}
+
+#[cfg(feature = "telemetry")]
+struct Injector<'a>(&'a mut Vec<UserProperty>);
+
+#[cfg(feature = "telemetry")]
+impl opentelemetry::propagation::Injector for Injector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        self.0.retain(|property| property.key != key);
+
+        // The propagator sets an empty `tracestate` when there's none
+        if !value.is_empty() {
+            self.0.push(UserProperty::new(key.to_string(), value));
+        }
+    }
+}
+
+#[cfg(all(test, feature = "telemetry"))]
+mod tests {
+    use opentelemetry::{global, trace::TracerProvider};
+    use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::SdkTracerProvider};
+    use tracing_subscriber::prelude::*;
+
+    use mercurio_core::{properties::UserProperty, qos::QoS};
+    use mercurio_packets::{
+        publish::{PublishPacket, PublishProperties},
+        ControlPacket,
+    };
+
+    use super::{incoming_span, inject, route_span};
+
+    #[test]
+    fn test_trace_context_propagation() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        let tracer = SdkTracerProvider::builder().build().tracer("test");
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let traceparent = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+        let publish = ControlPacket::Publish(PublishPacket {
+            dup: false,
+            qos_level: QoS::AtMostOnce,
+            retain: false,
+            topic_name: "a/b".to_string(),
+            packet_id: None,
+            properties: Some(PublishProperties {
+                user_property: Some(vec![UserProperty::new(
+                    "traceparent".to_string(),
+                    traceparent.to_string(),
+                )]),
+                ..Default::default()
+            }),
+            payload: None,
+        });
+
+        let mut user_properties = match &publish {
+            ControlPacket::Publish(publish) => publish.properties.clone().unwrap().user_property,
+            _ => unreachable!(),
+        };
+        incoming_span(&publish).in_scope(|| {
+            route_span("a/b").in_scope(|| inject(&mut user_properties));
+        });
+
+        // Subscribers get the same trace, with the broker's span as parent
+        let forwarded = user_properties.unwrap();
+        assert_eq!(forwarded.len(), 1);
+        assert_eq!(forwarded[0].key, "traceparent");
+        assert!(forwarded[0]
+            .value
+            .starts_with("00-0af7651916cd43dd8448eb211c80319c-"));
+        assert_ne!(forwarded[0].value, traceparent);
+    }
+}
@@ -1,155 +1,301 @@
-use std::{
-    collections::{HashMap, HashSet, VecDeque},
-    hash::{Hash, Hasher},
-    sync::{Arc, Mutex},
-};
+use std::collections::HashMap;
 
-use tokio::sync::broadcast;
-use tracing::error;
+use mercurio_core::{reason::ReasonCode, Result};
 
 #[derive(Debug)]
-struct TopicNode<T: Clone> {
-    channel: broadcast::Sender<T>,
+struct TopicNode<T> {
+    subscribers: HashMap<String, T>,
     children: HashMap<String, TopicNode<T>>,
-    level: usize,
 }
 
-impl<T: Clone> TopicNode<T> {
-    pub fn new(level: usize) -> TopicNode<T> {
-        let (sender, _) = broadcast::channel(5); // TODO: What size should this actually be?
-
+impl<T> TopicNode<T> {
+    pub fn new() -> TopicNode<T> {
         TopicNode {
-            channel: sender,
+            subscribers: HashMap::new(),
             children: HashMap::new(),
-            level,
         }
     }
-}
 
-impl<T: Clone> Hash for TopicNode<T> {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        let children_ptr: *const HashMap<String, TopicNode<T>> = &self.children;
-        children_ptr.hash(state);
-    }
-}
-impl<T: Clone> Eq for TopicNode<T> {}
-impl<T: Clone> PartialEq for TopicNode<T> {
-    fn eq(&self, other: &Self) -> bool {
-        self.children.eq(&other.children)
+    fn is_empty(&self) -> bool {
+        self.subscribers.is_empty() && self.children.is_empty()
     }
 }
 
+/// Index of subscriptions by topic filter.
+///
+/// Every subscriber registers a value per topic filter, and publishing on a
+/// topic yields the values of all subscribers with a matching filter. The
+/// tree doesn't deliver anything itself: delivering to the subscribers'
+/// queues is up to the caller.
 #[derive(Debug)]
-pub(crate) struct TopicTree<T: Clone> {
-    shared: Arc<Shared<T>>,
-}
-
-#[derive(Debug)]
-struct Shared<T: Clone> {
-    state: Mutex<State<T>>,
-}
-
-#[derive(Debug)]
-struct State<T: Clone> {
+pub(crate) struct TopicTree<T> {
     root: TopicNode<T>,
 }
 
-impl<T: Clone> TopicTree<T> {
+impl<T> TopicTree<T> {
     pub fn new() -> TopicTree<T> {
         TopicTree {
-            shared: Arc::new(Shared {
-                state: Mutex::new(State {
-                    root: TopicNode::new(usize::MAX),
-                }),
-            }),
+            root: TopicNode::new(),
         }
     }
 
-    pub fn subscribe(&mut self, topic: String) -> broadcast::Receiver<T> {
-        let levels = topic.split('/');
-        let root = &mut self.shared.state.lock().unwrap().root;
-        let mut next = root;
+    /// Registers `value` for `subscriber_id` under `filter`, replacing any
+    /// previous value the subscriber had for the same filter.
+    pub fn subscribe(&mut self, filter: &str, subscriber_id: &str, value: T) {
+        let mut next = &mut self.root;
 
-        for (idx, level) in levels.enumerate() {
+        for level in filter.split('/') {
             next = next
                 .children
                 .entry(level.to_string())
-                .or_insert_with(|| TopicNode::new(idx));
+                .or_insert_with(TopicNode::new);
         }
 
-        next.channel.subscribe()
+        next.subscribers.insert(subscriber_id.to_string(), value);
+    }
+
+    /// Removes the value `subscriber_id` registered under `filter`, pruning
+    /// the branches left empty. Returns `false` if there was no such
+    /// subscription.
+    pub fn unsubscribe(&mut self, filter: &str, subscriber_id: &str) -> bool {
+        let levels: Vec<&str> = filter.split('/').collect();
+
+        remove(&mut self.root, &levels, subscriber_id)
+    }
+
+    /// Removes every subscription of `subscriber_id`, pruning the branches
+    /// left empty.
+    pub fn unsubscribe_all(&mut self, subscriber_id: &str) {
+        remove_all(&mut self.root, subscriber_id);
     }
 
-    pub fn publish(&mut self, topic: &str, value: T) {
-        // TODO: Validate topic name
-        let mut visited = HashSet::<&TopicNode<T>>::new();
-        let mut stack = VecDeque::<&TopicNode<T>>::new();
+    /// Returns the values of all subscriptions, grouped by subscriber.
+    pub fn subscribers(&self) -> HashMap<&str, Vec<&T>> {
+        let mut subscribers = HashMap::<&str, Vec<&T>>::new();
+        collect_all(&self.root, &mut subscribers);
 
-        let root = &self.shared.state.lock().unwrap().root;
+        subscribers
+    }
+
+    /// Returns the values registered under filters matching `topic`, grouped
+    /// by subscriber. Fails if `topic` isn't a valid topic name.
+    pub fn matches(&self, topic: &str) -> Result<HashMap<&str, Vec<&T>>> {
+        if !is_valid_topic_name(topic) {
+            return Err(ReasonCode::TopicNameInvalid.into());
+        }
+
+        let mut matches = HashMap::<&str, Vec<&T>>::new();
         let levels: Vec<&str> = topic.split('/').collect();
 
-        stack.push_front(root);
-
-        while !stack.is_empty() {
-            let node = stack.pop_front().unwrap(); // Safe to unwrap, otherwise we
-                                                   // wouldn't get into the loop
-            if node.level == levels.len() - 1 {
-                // We reached the last level, send message to subscribers
-                if let Err(e) = node.channel.send(value.clone()) {
-                    error!("Error publishing value {}", e);
-                }
-
-                // Check if there is a children multi-level wildcard sub in the next level,
-                // if so send to them too
-                if let Some(next) = node.children.get("#") {
-                    if let Err(e) = next.channel.send(value) {
-                        error!("Error publishing value {}", e);
-                    }
-                }
-
-                break;
-            }
+        // [MQTT-4.7.2-1]
+        // The Server MUST NOT match Topic Filters starting with a wildcard
+        // character (# or +) with Topic Names beginning with a $ character.
+        let wildcards = !topic.starts_with('$');
 
-            // DFS alike
-            if let Some(next) = node.children.get(levels[node.level.overflowing_add(1).0]) {
-                if !visited.contains(next) {
-                    visited.insert(next);
-                    stack.push_front(next);
-                }
-            }
+        walk(&self.root, &levels, wildcards, &mut matches);
 
-            if let Some(next) = node.children.get("+") {
-                if !visited.contains(next) {
-                    visited.insert(next);
-                    stack.push_front(next);
-                }
-            }
+        Ok(matches)
+    }
+}
 
+/// Collects the subscribers of the filters below `node` matching the remaining
+/// topic `levels`. Wildcard children of `node` are skipped unless `wildcards`
+/// is set.
+fn walk<'a, T>(
+    node: &'a TopicNode<T>,
+    levels: &[&str],
+    wildcards: bool,
+    matches: &mut HashMap<&'a str, Vec<&'a T>>,
+) {
+    let (level, rest) = match levels.split_first() {
+        Some(split) => split,
+        None => {
+            collect(node, matches);
+
+            // `sport/#` also matches `sport`, the parent level
             if let Some(next) = node.children.get("#") {
-                if let Err(e) = next.channel.send(value.clone()) {
-                    error!("Error publishing value {}", e);
-                }
+                collect(next, matches);
+            }
+
+            return;
+        }
+    };
+
+    if let Some(next) = node.children.get(*level) {
+        walk(next, rest, true, matches);
+    }
+
+    if !wildcards {
+        return;
+    }
+
+    if let Some(next) = node.children.get("+") {
+        walk(next, rest, true, matches);
+    }
+
+    if let Some(next) = node.children.get("#") {
+        collect(next, matches);
+    }
+}
+
+fn remove<T>(node: &mut TopicNode<T>, levels: &[&str], subscriber_id: &str) -> bool {
+    let (level, rest) = match levels.split_first() {
+        Some(split) => split,
+        None => return node.subscribers.remove(subscriber_id).is_some(),
+    };
+
+    let child = match node.children.get_mut(*level) {
+        Some(child) => child,
+        None => return false,
+    };
+
+    let removed = remove(child, rest, subscriber_id);
+
+    if child.is_empty() {
+        node.children.remove(*level);
+    }
+
+    removed
+}
+
+fn remove_all<T>(node: &mut TopicNode<T>, subscriber_id: &str) {
+    node.subscribers.remove(subscriber_id);
+
+    node.children.retain(|_, child| {
+        remove_all(child, subscriber_id);
+        !child.is_empty()
+    });
+}
+
+fn collect<'a, T>(node: &'a TopicNode<T>, matches: &mut HashMap<&'a str, Vec<&'a T>>) {
+    for (id, value) in &node.subscribers {
+        matches.entry(id.as_str()).or_default().push(value);
+    }
+}
+
+fn collect_all<'a, T>(node: &'a TopicNode<T>, matches: &mut HashMap<&'a str, Vec<&'a T>>) {
+    collect(node, matches);
+
+    for child in node.children.values() {
+        collect_all(child, matches);
+    }
+}
+
+/// Returns `true` if `topic` matches the topic `filter`, taking the `+` and
+/// `#` wildcards into account.
+pub(crate) fn matches(filter: &str, topic: &str) -> bool {
+    // [MQTT-4.7.2-1]
+    // The Server MUST NOT match Topic Filters starting with a wildcard
+    // character (# or +) with Topic Names beginning with a $ character.
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Returns `true` if `name` is a topic name messages can be published on.
+pub(crate) fn is_valid_topic_name(name: &str) -> bool {
+    // [MQTT-4.7.0-1]
+    // The wildcard characters can be used in Topic Filters, but MUST NOT be
+    // used within a Topic Name.
+    !name.is_empty() && !name.contains(['+', '#'])
+}
+
+/// Returns `true` if `level` can stand as a single level of a topic name,
+/// as client identifiers put in topics have to.
+pub(crate) fn is_valid_topic_level(level: &str) -> bool {
+    !level.is_empty() && !level.contains(['+', '#', '/'])
+}
+
+/// Splits a shared subscription, `$share/<share name>/<filter>`, into its
+/// share name and topic filter. Returns `None` for other subscriptions.
+pub(crate) fn shared_subscription(filter: &str) -> Option<(&str, &str)> {
+    filter.strip_prefix("$share/")?.split_once('/')
+}
+
+/// Returns `true` if `filter` is a well formed topic filter.
+pub(crate) fn is_valid_topic_filter(filter: &str) -> bool {
+    if filter.is_empty() {
+        return false;
+    }
+
+    // [MQTT-4.8.2-1], [MQTT-4.8.2-2]
+    // A Shared Subscription's Topic Filter MUST start with $share/ and
+    // MUST contain a ShareName that is at least one character long. The
+    // ShareName MUST NOT contain the characters "/", "+" or "#", but MUST
+    // be followed by a "/" character and a Topic Filter.
+    if filter.starts_with("$share/") {
+        return match shared_subscription(filter) {
+            Some((name, filter)) => {
+                !name.is_empty() && !name.contains(['+', '#']) && is_valid_topic_filter(filter)
             }
+            None => false,
+        };
+    }
+
+    let mut levels = filter.split('/').peekable();
+
+    while let Some(level) = levels.next() {
+        match level {
+            // [MQTT-4.7.1-1]
+            // The multi-level wildcard character MUST be specified either on
+            // its own or following a topic level separator. In either case it
+            // MUST be the last character specified in the Topic Filter.
+            "#" => return levels.peek().is_none(),
+            "+" => {}
+            // [MQTT-4.7.1-2]
+            // The single-level wildcard can be used at any level in the Topic
+            // Filter, but it MUST occupy an entire level of the filter.
+            level if level.contains(['+', '#']) => return false,
+            _ => {}
         }
     }
+
+    true
 }
 
 #[cfg(test)]
 mod tests {
     use std::time::Duration;
 
-    use tokio::time::timeout;
+    use tokio::{sync::mpsc, time::timeout};
 
     use super::TopicTree;
 
+    type Tree = TopicTree<mpsc::Sender<String>>;
+
+    fn subscribe(tree: &mut Tree, filter: &str) -> mpsc::Receiver<String> {
+        let (tx, rx) = mpsc::channel(16);
+        tree.subscribe(filter, filter, tx);
+        rx
+    }
+
+    fn publish(tree: &Tree, topic: &str, value: &str) {
+        for senders in tree.matches(topic).unwrap().values() {
+            senders[0].try_send(value.to_string()).unwrap();
+        }
+    }
+
     #[tokio::test]
     async fn test_pubsub_normal_topics() {
-        let mut tree = TopicTree::<String>::new();
-        let mut subscriber = tree.subscribe("a/b/c".to_string());
-        let mut subscriber2 = tree.subscribe("/a/b/c".to_string());
+        let mut tree = Tree::new();
+        let mut subscriber = subscribe(&mut tree, "a/b/c");
+        let mut subscriber2 = subscribe(&mut tree, "/a/b/c");
 
-        tree.publish("a/b/c", "test_message".to_string());
-        tree.publish("/a/b/c", "test_message2".to_string());
+        publish(&tree, "a/b/c", "test_message");
+        publish(&tree, "/a/b/c", "test_message2");
 
         assert_eq!(
             timeout(Duration::from_millis(10), subscriber.recv())
@@ -178,16 +324,17 @@ mod tests {
 
     #[tokio::test]
     async fn test_pubsub_multi_level_wildcard() {
-        let mut tree = TopicTree::<String>::new();
+        let mut tree = Tree::new();
 
-        let mut subscriber = tree.subscribe("sport/tennis/player1/#".into());
-        tree.publish("sport/tennis/player1", "test_message".into());
+        let mut subscriber = subscribe(&mut tree, "sport/tennis/player1/#");
+        publish(&tree, "sport/tennis/player1", "test_message");
 
-        tree.publish("sport/tennis/player1/ranking", "test_message_1".into());
+        publish(&tree, "sport/tennis/player1/ranking", "test_message_1");
 
-        tree.publish(
+        publish(
+            &tree,
             "sport/tennis/player1/score/wimbledon",
-            "test_message_2".into(),
+            "test_message_2",
         );
 
         assert_eq!(
@@ -214,8 +361,8 @@ mod tests {
             "test_message_2".to_string()
         );
 
-        let mut subscriber = tree.subscribe("sport/#".to_string());
-        tree.publish("sport", "test_message_3".into());
+        let mut subscriber = subscribe(&mut tree, "sport/#");
+        publish(&tree, "sport", "test_message_3");
 
         assert_eq!(
             timeout(Duration::from_millis(10), subscriber.recv())
@@ -228,12 +375,12 @@ mod tests {
 
     #[tokio::test]
     async fn test_pubsub_single_level_wildcard() {
-        let mut tree = TopicTree::<String>::new();
-        let mut subscriber = tree.subscribe("sport/tennis/+".into());
-        let mut subscriber2 = tree.subscribe("sport/tennis/+/ranking".into());
-        tree.publish("sport/tennis/player1", "test_message".into());
-        tree.publish("sport/tennis/player1/ranking", "test_message".into());
-        tree.publish("sport/tennis", "test_message".into());
+        let mut tree = Tree::new();
+        let mut subscriber = subscribe(&mut tree, "sport/tennis/+");
+        let mut subscriber2 = subscribe(&mut tree, "sport/tennis/+/ranking");
+        publish(&tree, "sport/tennis/player1", "test_message");
+        publish(&tree, "sport/tennis/player1/ranking", "test_message");
+        publish(&tree, "sport/tennis", "test_message");
 
         assert_eq!(
             timeout(Duration::from_millis(10), subscriber.recv())
@@ -259,7 +406,7 @@ mod tests {
             .await
             .expect_err("Expected Elapsed error");
 
-        tree.publish("sport/tennis/", "test_message".into());
+        publish(&tree, "sport/tennis/", "test_message");
 
         assert_eq!(
             timeout(Duration::from_millis(10), subscriber.recv())
@@ -269,4 +416,181 @@ mod tests {
             "test_message".to_string()
         );
     }
+
+    #[test]
+    fn test_matches_matrix() {
+        let cases = [
+            ("a/b/c", "a/b/c", true),
+            ("a/b/c", "a/b", false),
+            ("a/b", "a/b/c", false),
+            ("/a", "/a", true),
+            ("/a", "a", false),
+            ("a/", "a/", true),
+            ("a/", "a", false),
+            ("#", "a", true),
+            ("#", "a/b/c/d", true),
+            ("#", "/", true),
+            ("#", "", false),
+            ("a/#", "a", true),
+            ("a/#", "a/", true),
+            ("a/#", "a/b", true),
+            ("a/#", "a/b/c/d", true),
+            ("a/#", "b/a", false),
+            ("a/b/#", "a/b/c/d/e", true),
+            ("a/b/#", "a/c/d", false),
+            ("+", "a", true),
+            ("+", "", false),
+            ("+", "a/b", false),
+            ("+", "/a", false),
+            ("+/+", "/a", true),
+            ("a/+", "a/b", true),
+            ("a/+", "a/", true),
+            ("a/+", "a", false),
+            ("a/+", "a/b/c", false),
+            ("a/+/c", "a/b/c", true),
+            ("a/+/c", "a//c", true),
+            ("a/+/c", "a/b/d", false),
+            ("+/b/#", "a/b/c/d", true),
+            ("+/b/#", "a/b", true),
+            ("+/+/#", "a", false),
+            ("+/#", "a", true),
+            ("#", "$SYS/uptime", false),
+            ("+/uptime", "$SYS/uptime", false),
+            ("$SYS/#", "$SYS/uptime", true),
+            ("$SYS/+", "$SYS/uptime", true),
+            ("a/#", "a/$b", true),
+            ("a/+", "a/+", false),
+            ("#", "a/#", false),
+        ];
+
+        for (filter, topic, expected) in cases {
+            let mut tree = TopicTree::<()>::new();
+            tree.subscribe(filter, "client", ());
+
+            // Wildcards and empty names aren't topic names, they're refused
+            // rather than matched
+            if !super::is_valid_topic_name(topic) {
+                assert!(!expected);
+                assert!(tree.matches(topic).is_err(), "topic `{}`", topic);
+                continue;
+            }
+
+            assert_eq!(
+                tree.matches(topic).unwrap().contains_key("client"),
+                expected,
+                "filter `{}` on topic `{}`",
+                filter,
+                topic
+            );
+            assert_eq!(super::matches(filter, topic), expected);
+        }
+
+        // Overlapping filters each yield their own value
+        let mut filters: Vec<&str> = cases.iter().map(|(filter, _, _)| *filter).collect();
+        filters.sort();
+        filters.dedup();
+
+        let mut tree = TopicTree::<&str>::new();
+        for filter in &filters {
+            tree.subscribe(filter, "client", filter);
+        }
+
+        for topic in ["a/b/c/d", "a/b", "a", "$SYS/uptime", "/a"] {
+            let expected: Vec<&str> = filters
+                .iter()
+                .copied()
+                .filter(|filter| super::matches(filter, topic))
+                .collect();
+
+            let mut values: Vec<&str> = tree
+                .matches(topic)
+                .unwrap()
+                .remove("client")
+                .unwrap_or_default()
+                .into_iter()
+                .copied()
+                .collect();
+            values.sort();
+
+            assert_eq!(values, expected, "topic `{}`", topic);
+        }
+    }
+
+    #[test]
+    fn test_topic_validation() {
+        for name in ["a", "a/b", "/", "a//b", "$SYS/a"] {
+            assert!(super::is_valid_topic_name(name), "`{}`", name);
+        }
+
+        for name in ["", "a/+", "a/#", "a+", "#"] {
+            assert!(!super::is_valid_topic_name(name), "`{}`", name);
+        }
+
+        assert!(super::is_valid_topic_level("sensor-1"));
+        for level in ["", "a/b", "a+", "#"] {
+            assert!(!super::is_valid_topic_level(level), "`{}`", level);
+        }
+
+        for filter in ["a", "#", "+", "a/#", "+/+", "/+/", "a/+/b/#", "a//b"] {
+            assert!(super::is_valid_topic_filter(filter), "`{}`", filter);
+        }
+
+        for filter in ["$share/group/a/#", "$share/g/+"] {
+            assert!(super::is_valid_topic_filter(filter), "`{}`", filter);
+        }
+
+        for filter in [
+            "$share/",
+            "$share/group",
+            "$share//a",
+            "$share/g+/a",
+            "$share/g/",
+        ] {
+            assert!(!super::is_valid_topic_filter(filter), "`{}`", filter);
+        }
+
+        for filter in ["", "a#", "a/#/b", "#/", "a+", "+a/b", "a/b#"] {
+            assert!(!super::is_valid_topic_filter(filter), "`{}`", filter);
+        }
+    }
+
+    #[test]
+    fn test_unsubscribe_prunes_empty_nodes() {
+        let mut tree = TopicTree::<u8>::new();
+        tree.subscribe("a/b/c", "client1", 1);
+        tree.subscribe("a/b/c", "client2", 2);
+        tree.subscribe("a/d", "client1", 3);
+
+        assert!(tree.unsubscribe("a/b/c", "client1"));
+        assert!(!tree.unsubscribe("a/b/c", "client1"));
+        assert!(!tree.unsubscribe("a/x", "client1"));
+        assert_eq!(tree.matches("a/b/c").unwrap()["client2"], vec![&2]);
+
+        assert!(tree.unsubscribe("a/b/c", "client2"));
+        assert!(tree.matches("a/b/c").unwrap().is_empty());
+        assert!(!tree.root.children["a"].children.contains_key("b"));
+
+        tree.unsubscribe_all("client1");
+        assert!(tree.root.is_empty());
+    }
+
+    #[test]
+    fn test_matches_grouped_by_subscriber() {
+        let mut tree = TopicTree::<u8>::new();
+        tree.subscribe("a/b", "client1", 1);
+        tree.subscribe("a/#", "client1", 2);
+        tree.subscribe("a/b", "client2", 3);
+        tree.subscribe("a/b", "client2", 4);
+
+        let matches = tree.matches("a/b").unwrap();
+
+        assert_eq!(matches.len(), 2);
+
+        let mut values = matches["client1"].clone();
+        values.sort();
+        assert_eq!(values, vec![&1, &2]);
+
+        // Subscribing again to the same filter replaces the previous value
+        assert_eq!(matches["client2"], vec![&4]);
+    }
 }